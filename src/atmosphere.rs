@@ -0,0 +1,292 @@
+use image::{Pixel, Primitive, Rgb};
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use crate::lights::{self, Environment, HdrImage, Light};
+use crate::phase::{HenyeyGreenstein, PhaseFunction, Rayleigh};
+use crate::spectrum::FromRgb;
+
+// Radii of the ground and the top of the atmosphere, in m.
+const GROUND: f64 = 6_360e3;
+const TOP: f64 = 6_420e3;
+
+// Scattering by the air (Rayleigh) at sea level per m, per channel at
+// about its wavelength, and the height over which it falls to 1/e.
+const RAYLEIGH: [f64; 3] = [5.802e-6, 13.558e-6, 33.1e-6];
+const RAYLEIGH_HEIGHT: f64 = 8_000.0;
+
+// Scattering and extinction by aerosols (Mie) at sea level per m, the
+// height over which they fall to 1/e and the asymmetry of their phase
+// function (towards forward scattering).
+const MIE: f64 = 3.996e-6;
+const MIE_EXTINCTION: f64 = 4.4e-6;
+const MIE_HEIGHT: f64 = 1_200.0;
+const MIE_G: f64 = 0.8;
+
+// Steps along the view rays and along the rays towards the sun.
+const VIEW_STEPS: usize = 16;
+const SUN_STEPS: usize = 8;
+
+// Angular radius of the sun, in radians.
+const SUN_RADIUS: f64 = 0.00465;
+
+// Illuminance of the sun above the atmosphere, in klx.
+const SUN_ILLUMINANCE: f64 = 128.0;
+
+/// Sky of a planet like the earth, seen from `altitude` (in m) above its
+/// ground, with the sun shining along `sun` (as for `lights::directional`):
+/// the light of the sun scattered once by the air and by aerosols in an
+/// atmosphere thinning out with height, after Nishita et al., "Display of
+/// the Earth Taking into Account Atmospheric Scattering" (1993).
+///
+/// High up (e.g. 400 km, in space) the sky is black and the atmosphere a
+/// thin glowing shell over the planet, which is black itself. Through
+/// `Environment::aerial`, surfaces fade into the haze with their distance,
+/// with `scale` m per unit of the scene; the scene's origin is at
+/// `altitude`. Radiance and irradiance are scaled by `brightness` as for
+/// `sky::sky`.
+pub fn atmosphere<'a, T, P>(
+    sun: Vector3<T>,
+    altitude: T,
+    scale: T,
+    brightness: T,
+) -> Arc<dyn 'a + Environment<T, P>>
+where
+    T: 'a + Float + Into<f64> + Primitive,
+    P: 'a + Pixel<Subpixel = T> + FromRgb<T> + Send + Sync,
+{
+    // Size of the image the shadow rays are picked from.
+    const WIDTH: u32 = 128;
+    const HEIGHT: u32 = 64;
+
+    let to_sun = vecmath::vec3_normalized(vecmath::vec3_neg(sun.map(|x| x.into())));
+    let model = Nishita {
+        to_sun,
+        altitude: altitude.into().max(0.0),
+        scale: scale.into(),
+    };
+
+    let image = HdrImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        let (dir, _) = lights::texel_dir(x, y, WIDTH, HEIGHT);
+        return Rgb(model.sky(dir).map(|c| c as f32));
+    });
+
+    let rgb = |c: [f64; 3]| P::from_rgb(Rgb(c.map(|x| T::from_f64(x) * brightness)));
+
+    // Sunlight getting through to the origin.
+    let sun_irradiance = model
+        .to_sun(model.at([0.0; 3]))
+        .map(|t| t * SUN_ILLUMINANCE);
+
+    Arc::new(Atmosphere {
+        sky: lights::environment(image, brightness),
+        sun: lights::directional(sun, rgb(sun_irradiance), T::from_f64(SUN_RADIUS)),
+        model,
+        cos_sun: SUN_RADIUS.cos(),
+        // As for `sky::sky`.
+        cos_spot: (SUN_RADIUS * 1.001).cos(),
+        sun_radiance: rgb(sun_irradiance.map(|x| x / (PI * SUN_RADIUS * SUN_RADIUS))),
+        brightness,
+    })
+}
+
+struct Atmosphere<'a, T, P> {
+    // The sky without the sun, to pick shadow rays from.
+    sky: Arc<dyn 'a + Environment<T, P>>,
+    sun: Arc<dyn 'a + Light<T, P>>,
+    model: Nishita,
+    cos_sun: f64,
+    cos_spot: f64, // towards which `sun` casts the shadow rays
+    sun_radiance: P,
+    brightness: T,
+}
+
+impl<'a, T, P> Light<T, P> for Atmosphere<'a, T, P>
+where
+    T: Float + Into<f64> + Primitive,
+    P: Pixel<Subpixel = T> + FromRgb<T> + Send + Sync,
+{
+    fn incident(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        let mut res = self.sky.incident(pos);
+        res.extend(self.sun.incident(pos));
+        return res;
+    }
+}
+
+impl<'a, T, P> Environment<T, P> for Atmosphere<'a, T, P>
+where
+    T: Float + Into<f64> + Primitive,
+    P: Pixel<Subpixel = T> + FromRgb<T> + Send + Sync,
+{
+    fn radiance(&self, dir: Vector3<T>) -> P {
+        let d = vecmath::vec3_normalized(dir.map(|x| x.into()));
+
+        if vecmath::vec3_dot(d, self.model.to_sun) > self.cos_sun {
+            return self.sun_radiance;
+        }
+
+        return self.radiance_besides_spots(dir);
+    }
+
+    fn spots(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        return self.sun.incident(pos);
+    }
+
+    fn radiance_besides_spots(&self, dir: Vector3<T>) -> P {
+        let dir = vecmath::vec3_normalized(dir.map(|x| x.into()));
+        let c = self.model.sky(dir);
+        return P::from_rgb(Rgb(c.map(|x| T::from_f64(x) * self.brightness)));
+    }
+
+    fn density(&self, pos: Vector3<T>, dir: Vector3<T>) -> T {
+        let d = vecmath::vec3_normalized(dir.map(|x| x.into()));
+
+        if vecmath::vec3_dot(d, self.model.to_sun) > self.cos_spot {
+            return T::from_f64(f64::INFINITY);
+        }
+
+        return self.sky.density(pos, dir);
+    }
+
+    fn aerial(&self, orig: Vector3<T>, dir: Vector3<T>, dist: T) -> Option<(P, P)> {
+        let dir = vecmath::vec3_normalized(dir.map(|x| x.into()));
+        let pos = self.model.at(orig.map(|x| x.into()));
+        let len = dist.into() * self.model.scale;
+
+        let (through, scattered) = self.model.march(pos, dir, len);
+
+        let scattered = scattered.map(|x| x * SUN_ILLUMINANCE);
+        return Some((
+            P::from_rgb(Rgb(through.map(T::from_f64))),
+            P::from_rgb(Rgb(scattered.map(|x| T::from_f64(x) * self.brightness))),
+        ));
+    }
+}
+
+/// The atmosphere for one position of the sun, with the planet's center at
+/// the origin and the scene's origin above it, in m.
+struct Nishita {
+    to_sun: Vector3<f64>,
+    altitude: f64,
+    scale: f64, // m per unit of the scene
+}
+
+impl Nishita {
+    /// Where point `p` of the scene is.
+    fn at(&self, p: Vector3<f64>) -> Vector3<f64> {
+        let [x, y, z] = p.map(|c| c * self.scale);
+        return [x, GROUND + self.altitude + y, z];
+    }
+
+    /// Linear sRGB radiance of the sky from direction `dir` (normalized),
+    /// seen from the scene's origin, in kcd/m².
+    fn sky(&self, dir: Vector3<f64>) -> [f64; 3] {
+        let pos = self.at([0.0; 3]);
+
+        // Into the atmosphere first, from space.
+        let (start, end) = match sphere(pos, dir, TOP) {
+            Some((near, far)) if far > 0.0 => (near.max(0.0), far),
+            _ => return [0.0; 3],
+        };
+
+        let end = match sphere(pos, dir, GROUND) {
+            Some((near, _)) if near > 0.0 => near,
+            _ => end,
+        };
+
+        let pos = vecmath::vec3_add(pos, vecmath::vec3_scale(dir, start));
+        let (_, scattered) = self.march(pos, dir, end - start);
+
+        return scattered.map(|x| x * SUN_ILLUMINANCE);
+    }
+
+    /// Share of the light getting through the first `len` of the ray from
+    /// `pos` along `dir` and that of the sun scattered into it along the
+    /// way, towards `pos`.
+    fn march(&self, pos: Vector3<f64>, dir: Vector3<f64>, len: f64) -> ([f64; 3], [f64; 3]) {
+        let ds = len / VIEW_STEPS as f64;
+
+        let cos = vecmath::vec3_dot(dir, self.to_sun);
+        let rayleigh_phase = Rayleigh.eval(cos);
+        let mie_phase = HenyeyGreenstein { g: MIE_G }.eval(cos);
+
+        let mut depth = [0.0; 2];
+        let mut scattered = [0.0; 3];
+
+        for i in 0..VIEW_STEPS {
+            let p = vecmath::vec3_add(pos, vecmath::vec3_scale(dir, (i as f64 + 0.5) * ds));
+            let [r, m] = densities(p);
+
+            // Up to the middle of the step.
+            let mid = [depth[0] + r * ds / 2.0, depth[1] + m * ds / 2.0];
+            let through = extinction(mid);
+            let sun = self.to_sun(p);
+
+            for k in 0..3 {
+                let s = RAYLEIGH[k] * r * rayleigh_phase + MIE * m * mie_phase;
+                scattered[k] += through[k] * sun[k] * s * ds;
+            }
+
+            depth = [depth[0] + r * ds, depth[1] + m * ds];
+        }
+
+        return (extinction(depth), scattered);
+    }
+
+    /// Share of the sun's light reaching `pos`, 0 in the planet's shadow.
+    fn to_sun(&self, pos: Vector3<f64>) -> [f64; 3] {
+        if let Some((near, _)) = sphere(pos, self.to_sun, GROUND) {
+            if near > 0.0 {
+                return [0.0; 3];
+            }
+        }
+
+        let len = match sphere(pos, self.to_sun, TOP) {
+            Some((_, far)) if far > 0.0 => far,
+            _ => return [1.0; 3],
+        };
+
+        let ds = len / SUN_STEPS as f64;
+        let mut depth = [0.0; 2];
+
+        for i in 0..SUN_STEPS {
+            let p = vecmath::vec3_add(pos, vecmath::vec3_scale(self.to_sun, (i as f64 + 0.5) * ds));
+            let [r, m] = densities(p);
+            depth = [depth[0] + r * ds, depth[1] + m * ds];
+        }
+
+        return extinction(depth);
+    }
+}
+
+// Densities of air and aerosols at `pos`, relative to sea level.
+fn densities(pos: Vector3<f64>) -> [f64; 2] {
+    let h = (vecmath::vec3_len(pos) - GROUND).max(0.0);
+    return [(-h / RAYLEIGH_HEIGHT).exp(), (-h / MIE_HEIGHT).exp()];
+}
+
+// Share of the light getting through the optical depths of air and
+// aerosols (in m at sea level density).
+fn extinction([r, m]: [f64; 2]) -> [f64; 3] {
+    return RAYLEIGH.map(|b| (-(b * r + MIE_EXTINCTION * m)).exp());
+}
+
+// Distances along the ray from `pos` along `dir` (normalized) to where it
+// enters and leaves the sphere of `radius` around the origin, if it meets
+// it.
+fn sphere(pos: Vector3<f64>, dir: Vector3<f64>, radius: f64) -> Option<(f64, f64)> {
+    let b = vecmath::vec3_dot(pos, dir);
+    let c = vecmath::vec3_dot(pos, pos) - radius * radius;
+    let d = b * b - c;
+
+    if d < 0.0 {
+        return None;
+    }
+
+    let d = d.sqrt();
+    return Some((-b - d, -b + d));
+}
@@ -0,0 +1,661 @@
+use image::Pixel;
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::fingerprint::Fingerprint;
+use crate::geom::{Hit, Poly, Ray, Sides};
+use crate::integrator::Integrator;
+use crate::medium::Stack;
+use crate::passes::{Lobe, Passes};
+use crate::sampling::{self, Rng};
+use crate::surface::{Black, Point, Surface};
+use crate::tracer::{Tracer, MAX_PATH_DEPTH};
+use crate::{emitters, interior, opaque, point_at, Scene};
+
+// A surface a subpath (see `shade`) bounced off.
+struct Vertex<'a, T, S, C> {
+    pos: Vector3<T>,
+    poly: &'a Poly<T, S>,
+    at: Point<T>,
+    o: Vector3<T>, // direction the subpath arrived in
+    beta: C,       // light (or importance) it carries, over its density
+    // Whether it left in a discrete direction, which no other subpath can
+    // connect through.
+    delta: bool,
+    // Densities per unit area of the subpath reaching here from its own
+    // end and from the other one, for `weight`. 0 for discrete directions.
+    fwd: T,
+    rev: T,
+    lobe: Lobe, // it left in, as for the first hit of `PathTracer::shade`
+}
+
+/// Bidirectional path tracer: as `PathTracer`, but connects each path from
+/// the camera to one from a random emitting poly, which finds light from
+/// small emitters and light bounced into the scene (e.g. from behind a
+/// lamp shade) far sooner. Clamping doesn't apply.
+pub struct Bidir {
+    max_depth: u32,
+}
+
+impl Bidir {
+    pub fn new(max_depth: u32) -> Bidir {
+        return Bidir { max_depth };
+    }
+}
+
+impl<T: Float + Into<f64>> Tracer<T> for Bidir {
+    fn max_depth(&self) -> u32 {
+        return self.max_depth;
+    }
+
+    fn clamp(&self) -> &[T] {
+        return &[];
+    }
+
+    fn limit(&self) -> u32 {
+        return MAX_PATH_DEPTH;
+    }
+
+    fn shade<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Hit<T>,
+        poly: &'a Poly<T, S>,
+        _from: Option<&'a Poly<T, S>>,
+        _depth: u32,
+        _diffuse: Option<T>,
+        _throughput: C,
+        _media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        return shade(self, scene, ray, hit, poly, rng);
+    }
+}
+
+impl<T, S, C> Integrator<T, S, C> for Bidir
+where
+    T: Float + Into<f64> + Sync,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black + PartialEq,
+{
+    fn light(
+        &self,
+        scene: &Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Option<(Hit<T>, &Poly<T, S>)>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        return self.primary(scene, ray, hit, rng);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"bidir");
+        h.write_u32(self.max_depth);
+    }
+}
+
+// Light leaving `poly` at `hit` into the direction of `ray`, the
+// first hit of a camera ray, for `Bidir`.
+//
+// A subpath from the camera and one from a random emitting poly are
+// connected at every pair of their vertices (Veach, "Robust Monte Carlo
+// Methods for Light Transport Simulation"), each connection weighted by
+// the power heuristic against the others making the same path. Lights
+// without geometry and the environment are sampled by shadow rays as with
+// paths. Light groups keep emitters from lighting polys outside of them
+// directly, by either subpath.
+fn shade<T, S, C>(
+    tracer: &Bidir,
+    scene: &Scene<T, S, C>,
+    ray: &Ray<T>,
+    hit: Hit<T>,
+    poly: &Poly<T, S>,
+    rng: &mut Rng,
+) -> Passes<C>
+where
+    T: Float + Into<f64>,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black + PartialEq,
+{
+    let max_depth = tracer.max_depth as usize;
+    let white = C::black().map(|_| T::one());
+
+    let mut camera = Vec::new();
+    let escaped = match scatter(ray, hit, poly, white, T::zero(), &mut camera, rng) {
+        Some((r, beta, pdf)) => walk(scene, r, beta, pdf, &mut camera, max_depth + 1, rng),
+        None => None,
+    };
+
+    let light = light_path(scene, max_depth, rng);
+
+    let mut all_light = Passes::black();
+
+    let mut add = |c: C, depth: usize, lobe: Option<Lobe>| {
+        let passes = Passes::emitted(c, depth as u32);
+        let passes = match lobe {
+            Some(lobe) => passes.tagged(lobe),
+            None => passes,
+        };
+        all_light = all_light.zip(&passes, |x, y| x.map2(y, |a, b| a + b));
+    };
+
+    // The light leaves the first hit in the lobe it was sampled in, or a
+    // diffuse one for connections to it.
+    let lobe_of = |t: usize| match t {
+        2 => Lobe::Diffuse,
+        _ => camera[0].lobe,
+    };
+
+    let infinity = T::from_f64(f64::INFINITY);
+
+    // `t` counts the camera as well.
+    for t in 2..=camera.len() + 1 {
+        let z = &camera[t - 2];
+        let n = *z.poly.n();
+
+        // Lights without geometry and the environment.
+        let incident = scene
+            .lights
+            .iter()
+            .filter(|l| t - 2 < max_depth && z.poly.lit_by(l.light_groups()))
+            .flat_map(|l| {
+                let links = (l.light_groups(), l.shadow_intensity());
+                l.incident(z.pos)
+                    .into_iter()
+                    .map(move |(dir, dist, irradiance)| (dir, dist, irradiance, links, infinity))
+            })
+            .chain(
+                scene
+                    .environment
+                    .iter()
+                    .filter(|e| t - 2 < max_depth && z.poly.lit_by(e.light_groups()))
+                    .flat_map(|e| {
+                        let links = (e.light_groups(), e.shadow_intensity());
+                        e.incident(z.pos)
+                            .into_iter()
+                            .map(move |(dir, dist, irradiance)| {
+                                (dir, dist, irradiance, links, e.density(z.pos, dir))
+                            })
+                    }),
+            );
+
+        for (dir, dist, irradiance, links, density) in incident {
+            let refl = z.poly.surface.reflected(z.at, dir, z.o);
+
+            if refl == C::black() {
+                continue;
+            }
+
+            let shadow = Ray::from_surface(z.pos, n, dir, bias(z.poly));
+            let pass = tracer.unblocked(scene, &shadow, Some(z.poly), dist, None, Some(links));
+
+            if pass == T::zero() {
+                continue;
+            }
+
+            let share = sampling::power_heuristic(density, z.poly.surface.pdf(z.at, z.o, dir));
+
+            // `reflected` is the BRDF scaled by pi.
+            let lambert =
+                abs(vecmath::vec3_dot(dir, n)) * pass * share / T::from_f64(std::f64::consts::PI);
+
+            let c = irradiance
+                .map2(&refl, |a, b| a * b * lambert)
+                .map2(&z.beta, |a, b| a * b);
+
+            add(c, t - 1, Some(lobe_of(t)));
+        }
+
+        for s in 0..=light.len() {
+            if s + t > max_depth + 2 {
+                break;
+            }
+
+            let prev = t.checked_sub(3).map(|i| &camera[i]);
+            let c = match connect(tracer, scene, &light[..s], prev, z) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            let w = weight(scene, &light[..s], &camera[..t - 1]);
+            let lobe = match (s, t) {
+                (0, 2) => None,
+                _ => Some(lobe_of(t)),
+            };
+
+            add(c.map(|x| x * w), s + t - 2, lobe);
+        }
+    }
+
+    // Unless the environment doesn't light the last vertex.
+    let escaped = escaped.filter(|_| scene.environment_lights(camera[camera.len() - 1].poly));
+    if let Some((r, pdf, beta)) = escaped {
+        let background = if pdf == T::zero() {
+            scene.background(&r)
+        } else {
+            scene.sampled_background(&r, pdf)
+        };
+
+        add(
+            background.map2(&beta, |a, b| a * b),
+            camera.len(),
+            Some(camera[0].lobe),
+        );
+    }
+
+    return all_light;
+}
+
+// Light the `light` subpath brings along a connection to `z`, the last
+// vertex of the camera subpath (after `prev`, unless that's the camera),
+// unweighted. With no light vertices `z` must emit it itself.
+fn connect<T, S, C>(
+    tracer: &Bidir,
+    scene: &Scene<T, S, C>,
+    light: &[Vertex<T, S, C>],
+    prev: Option<&Vertex<T, S, C>>,
+    z: &Vertex<T, S, C>,
+) -> Option<C>
+where
+    T: Float + Into<f64>,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black + PartialEq,
+{
+    let y = match light.last() {
+        Some(y) => y,
+        None => {
+            if prev.is_some_and(|p| !p.poly.lit_by(z.poly.light_groups())) {
+                return None;
+            }
+
+            let emitted = z.poly.surface.emitted(z.at);
+            return Some(emitted.map2(&z.beta, |a, b| a * b)).filter(|c| *c != C::black());
+        }
+    };
+
+    let d = vecmath::vec3_sub(y.pos, z.pos);
+    let dist2 = vecmath::vec3_dot(d, d);
+
+    if dist2 == T::zero() {
+        return None;
+    }
+
+    let dist = dist2.sqrt();
+    let dir = vecmath::vec3_scale(d, T::one() / dist);
+
+    // Light leaving `y` towards `z`.
+    let from_y = if light.len() == 1 {
+        if y.poly.sides() != Sides::Double && y.poly.is_back(dir) {
+            return None;
+        }
+
+        if !z.poly.lit_by(y.poly.light_groups()) {
+            return None;
+        }
+
+        let to_y = Ray {
+            orig: z.pos,
+            dir,
+            width: T::zero(),
+            spread: T::zero(),
+            diffs: None,
+            time: T::zero(),
+        };
+        y.poly
+            .surface
+            .emitted(point_at(&to_y, &y.poly.hit_at(y.pos), y.poly))
+    } else {
+        if y.poly.sides() == Sides::BackBlack && y.poly.is_back(dir) {
+            return None;
+        }
+
+        y.poly
+            .surface
+            .reflected(y.at, vecmath::vec3_neg(y.o), dir)
+            .map(|x| x / T::from_f64(std::f64::consts::PI))
+    };
+
+    let refl = z
+        .poly
+        .surface
+        .reflected(z.at, dir, z.o)
+        .map(|x| x / T::from_f64(std::f64::consts::PI));
+
+    let g =
+        abs(vecmath::vec3_dot(dir, *z.poly.n())) * abs(vecmath::vec3_dot(dir, *y.poly.n())) / dist2;
+
+    let c = from_y
+        .map2(&y.beta, |a, b| a * b)
+        .map2(&refl, |a, b| a * b)
+        .map2(&z.beta, |a, b| a * b * g);
+
+    if c == C::black() {
+        return None;
+    }
+
+    // Only light straight from an emitter is linked.
+    let links =
+        Some((y.poly.light_groups(), y.poly.shadow_intensity())).filter(|_| light.len() == 1);
+
+    let shadow = Ray::from_surface(z.pos, *z.poly.n(), dir, bias(z.poly));
+    let pass = tracer.unblocked(scene, &shadow, Some(z.poly), dist, Some(y.poly), links);
+
+    if pass == T::zero() {
+        return None;
+    }
+
+    return Some(c.map(|x| x * pass));
+}
+
+// Share of the light of connecting `light` to `camera` (without the camera
+// itself) that counts, by the power heuristic over all connections making
+// the same path.
+fn weight<T, S, C>(
+    scene: &Scene<T, S, C>,
+    light: &[Vertex<T, S, C>],
+    camera: &[Vertex<T, S, C>],
+) -> T
+where
+    T: Float + Into<f64>,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black + PartialEq,
+{
+    let (s, t) = (light.len(), camera.len() + 1);
+
+    if s + t == 2 {
+        return T::one();
+    }
+
+    // The densities of the vertices, as (fwd, rev, delta), which the
+    // connection changes at its ends.
+    let dens = |v: &Vertex<T, S, C>| (v.fwd, v.rev, v.delta);
+    let mut light_dens: Vec<_> = light.iter().map(dens).collect();
+    let mut camera_dens: Vec<_> = camera.iter().map(dens).collect();
+
+    let pt = &camera[t - 2];
+
+    match light.last() {
+        None => {
+            // The camera subpath found an emitter, which light subpaths may
+            // start from.
+            let i = scene.index(pt.poly);
+            let area = scene.emitters.chance(i) / emitters::area(pt.poly);
+
+            if area == T::zero() {
+                return T::one();
+            }
+
+            camera_dens[t - 2].1 = area;
+
+            if t > 2 {
+                let back = vecmath::vec3_neg(pt.o);
+                camera_dens[t - 3].1 = to_area(emission_pdf(pt, back), pt.pos, &camera[t - 3]);
+            }
+        }
+        Some(qs) => {
+            let dir = vecmath::vec3_normalized(vecmath::vec3_sub(qs.pos, pt.pos));
+            let back = vecmath::vec3_neg(dir);
+
+            let towards_pt = if s == 1 {
+                emission_pdf(qs, back)
+            } else {
+                qs.poly.surface.pdf(qs.at, qs.o, back)
+            };
+            camera_dens[t - 2].1 = to_area(towards_pt, qs.pos, pt);
+
+            if t > 2 {
+                let pdf = pt.poly.surface.pdf(pt.at, back, vecmath::vec3_neg(pt.o));
+                camera_dens[t - 3].1 = to_area(pdf, pt.pos, &camera[t - 3]);
+            }
+
+            let pdf = pt.poly.surface.pdf(pt.at, pt.o, dir);
+            light_dens[s - 1].1 = to_area(pdf, pt.pos, qs);
+
+            if s > 1 {
+                let pdf = qs.poly.surface.pdf(qs.at, dir, vecmath::vec3_neg(qs.o));
+                light_dens[s - 2].1 = to_area(pdf, qs.pos, &light[s - 2]);
+            }
+
+            light_dens[s - 1].2 = false;
+        }
+    }
+
+    camera_dens[t - 2].2 = false;
+
+    let remap = |x: T| if x == T::zero() { T::one() } else { x };
+
+    let mut sum = T::zero();
+
+    // Moving the connection towards the camera, leaving out connections
+    // straight to the camera.
+    let mut r = T::one();
+    for i in (2..t).rev() {
+        let (fwd, rev, delta) = camera_dens[i - 1];
+        r = r * remap(rev) / remap(fwd);
+
+        if !delta && !camera_dens[i - 2].2 {
+            sum += r * r;
+        }
+    }
+
+    // Moving it towards the emitter, down to a camera subpath finding it.
+    let mut r = T::one();
+    for i in (0..s).rev() {
+        let (fwd, rev, delta) = light_dens[i];
+        r = r * remap(rev) / remap(fwd);
+
+        if !delta && (i == 0 || !light_dens[i - 1].2) {
+            sum += r * r;
+        }
+    }
+
+    return T::one() / (T::one() + sum);
+}
+
+// Subpath from a random emitting poly with up to `max` vertices.
+fn light_path<'a, T, S, C>(
+    scene: &'a Scene<T, S, C>,
+    max: usize,
+    rng: &mut Rng,
+) -> Vec<Vertex<'a, T, S, C>>
+where
+    T: Float + Into<f64>,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black + PartialEq,
+{
+    let mut path = Vec::new();
+
+    if max == 0 {
+        return path;
+    }
+
+    let (poly, q, dir, light, area_density, dir_density) = match scene.emit(rng) {
+        Some(e) => e,
+        None => return path,
+    };
+
+    let ray = Ray::from_surface(q, *poly.n(), dir, poly.bias());
+
+    path.push(Vertex {
+        pos: q,
+        poly,
+        at: point_at(&ray, &poly.hit_at(q), poly),
+        o: vecmath::vec3_neg(dir),
+        beta: C::black().map(|_| T::one() / area_density),
+        delta: false,
+        fwd: area_density,
+        rev: T::zero(),
+        lobe: Lobe::Emission,
+    });
+
+    walk(scene, ray, light, dir_density, &mut path, max, rng);
+
+    // The light goes no further than the first hit if that isn't lit by it.
+    if path.len() > 1 && !path[1].poly.lit_by(poly.light_groups()) {
+        path.truncate(1);
+    }
+
+    return path;
+}
+
+// Follows `ray`, carrying `beta` and picked with density `pdf`, adding the
+// vertices it bounces off to `path` until it holds `max`. The ray that
+// escapes the scene, if any, with its density and what it carries.
+fn walk<'a, T, S, C>(
+    scene: &'a Scene<T, S, C>,
+    mut ray: Ray<T>,
+    mut beta: C,
+    mut pdf: T,
+    path: &mut Vec<Vertex<'a, T, S, C>>,
+    max: usize,
+    rng: &mut Rng,
+) -> Option<(Ray<T>, T, C)>
+where
+    T: Float + Into<f64>,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black + PartialEq,
+{
+    while path.len() < max {
+        let from = path.last().map(|v| v.poly);
+
+        let hit = scene.shoot(&ray, from, &opaque::<T, C, S>(&ray));
+        let (hit, poly) = match hit {
+            Some(hit) => hit,
+            None => return Some((ray, pdf, beta)),
+        };
+
+        if let Some(through) = interior(&ray, hit.point, poly) {
+            beta = beta.map2(&through, |a, b| a * b);
+        }
+
+        let (r, b, p) = scatter(&ray, hit, poly, beta, pdf, path, rng)?;
+        ray = r;
+        beta = b;
+        pdf = p;
+    }
+
+    return None;
+}
+
+// Adds the hit of `ray`, which carries `beta` and was picked with density
+// `pdf`, to `path`, and picks the ray it continues in, with what that
+// carries and its density (0 for discrete directions). `None` if the
+// subpath ends.
+fn scatter<'a, T, S, C>(
+    ray: &Ray<T>,
+    hit: Hit<T>,
+    poly: &'a Poly<T, S>,
+    beta: C,
+    pdf: T,
+    path: &mut Vec<Vertex<'a, T, S, C>>,
+    rng: &mut Rng,
+) -> Option<(Ray<T>, C, T)>
+where
+    T: Float + Into<f64>,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black + PartialEq,
+{
+    if poly.sides() == Sides::BackBlack && poly.is_back(ray.dir) {
+        return None;
+    }
+
+    let at = point_at(ray, &hit, poly);
+    let n = *poly.n();
+
+    let mut vertex = Vertex {
+        pos: hit.point,
+        poly,
+        at,
+        o: ray.dir,
+        beta,
+        delta: false,
+        fwd: T::zero(),
+        rev: T::zero(),
+        lobe: Lobe::Diffuse,
+    };
+
+    if let Some(prev) = path.last() {
+        vertex.fwd = to_area(pdf, prev.pos, &vertex);
+    }
+
+    let (dir, pdf, weight) = match poly.surface.sample(at, ray.dir, rng) {
+        Some(s) => s,
+        None => {
+            path.push(vertex);
+            return None;
+        }
+    };
+
+    // Directions out of a discrete set can't be found any other way.
+    let discrete = pdf == T::from_f64(f64::INFINITY);
+
+    let through =
+        (vecmath::vec3_dot(dir, n) > T::zero()) == (vecmath::vec3_dot(ray.dir, n) > T::zero());
+    vertex.delta = discrete;
+    vertex.lobe = match (discrete, through) {
+        (false, _) => Lobe::Diffuse,
+        (true, true) => Lobe::Transmission,
+        (true, false) => Lobe::Specular,
+    };
+
+    if let Some(prev) = path.last_mut() {
+        prev.rev = if discrete {
+            T::zero()
+        } else {
+            let back = poly
+                .surface
+                .pdf(at, vecmath::vec3_neg(dir), vecmath::vec3_neg(ray.dir));
+            to_area(back, hit.point, prev)
+        };
+    }
+
+    path.push(vertex);
+
+    let footprint = ray.footprint(at.dist);
+    let r = Ray {
+        width: footprint,
+        spread: ray.spread,
+        ..Ray::from_surface(hit.point, n, dir, bias(poly))
+    };
+
+    let pdf = if discrete { T::zero() } else { pdf };
+
+    return Some((r, beta.map2(&weight, |a, b| a * b), pdf));
+}
+
+// Density per unit area at `to` of a direction picked at `from` with
+// density `pdf` per steradian.
+fn to_area<T: Float, S, C>(pdf: T, from: Vector3<T>, to: &Vertex<T, S, C>) -> T {
+    let d = vecmath::vec3_sub(to.pos, from);
+    let dist2 = vecmath::vec3_dot(d, d);
+
+    if dist2 == T::zero() {
+        return T::zero();
+    }
+
+    let cos = abs(vecmath::vec3_dot(d, *to.poly.n())) / dist2.sqrt();
+    return pdf * cos / dist2;
+}
+
+// Density per steradian of `Scene::emit` sending light from `v` into `dir`.
+fn emission_pdf<T: Float, S, C>(v: &Vertex<T, S, C>, dir: Vector3<T>) -> T {
+    let cos = vecmath::vec3_dot(dir, *v.poly.n());
+
+    let sides = match v.poly.sides() {
+        Sides::Double => 2.0,
+        _ if cos < T::zero() => return T::zero(),
+        _ => 1.0,
+    };
+
+    return abs(cos) / T::from_f64(std::f64::consts::PI * sides);
+}
+
+fn bias<T: Float, S: Surface<T, C>, C>(poly: &Poly<T, S>) -> T {
+    return poly.surface.bias().unwrap_or_else(|| poly.bias());
+}
+
+fn abs<T: Float>(x: T) -> T {
+    return if x < T::zero() { -x } else { x };
+}
@@ -0,0 +1,522 @@
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::fingerprint::Fingerprint;
+use crate::geom::Ray;
+use crate::sampling::Rng;
+
+/// Maps the image to the rays seen through it, e.g. as through a pinhole
+/// (`Pinhole`) or a fisheye (`Fisheye`).
+pub trait Camera<T>: Sync {
+    /// Ray seen at `px`, `py` on the image, from -1 at its left (top) to 1
+    /// at its right (bottom) edge, if anything (fisheyes see nothing beyond
+    /// straight back).
+    /// Random choices, as of the point on the lens or the time, come from
+    /// `sample`, so rays from copies of it make the same ones.
+    fn generate_ray(&self, px: T, py: T, sample: &mut Rng) -> Option<Ray<T>>;
+
+    fn pose(&self) -> &Pose<T>;
+
+    fn pose_mut(&mut self) -> &mut Pose<T>;
+
+    /// Whether all rays leave from `pose().orig` within the frustum of the
+    /// corners of the image, so it may cull the polys outside of it.
+    fn pinhole(&self) -> bool {
+        return false;
+    }
+
+    /// Point `ray` (of the camera) hits where things at a distance of
+    /// `focus` are in focus: on a sphere around its origin.
+    fn in_focus(&self, ray: &Ray<T>, focus: T) -> Vector3<T>
+    where
+        T: Float,
+    {
+        return vecmath::vec3_add(ray.orig, vecmath::vec3_scale(ray.dir, focus));
+    }
+
+    /// Moves the camera back along its view (or widens it) until a sphere
+    /// at `center` of `radius` fits into it.
+    fn frame(&mut self, center: Vector3<T>, radius: T);
+
+    /// Adds everything that determines the rays to `h`, see `fingerprint`.
+    fn fingerprint(&self, h: &mut Fingerprint);
+}
+
+/// Where a camera is and how it moves.
+#[derive(Clone, Copy)]
+pub struct Pose<T> {
+    pub orig: Vector3<T>,
+    pub dir: Vector3<T>,
+    pub up: Vector3<T>,
+    // Times the shutter opens and closes. Moving along `velocity` (per unit
+    // of time) from `orig` at time 0 meanwhile, the camera blurs the image.
+    pub shutter: [T; 2],
+    pub velocity: Vector3<T>,
+}
+
+impl<T: Float> Pose<T> {
+    /// Camera at `eye` looking at `target`, with `up` (which needn't be
+    /// orthogonal to the view) pointing up in the image and no `shutter`
+    /// interval. Fails if `eye` and `target` coincide or if `up` runs along
+    /// the view.
+    pub fn look_at(eye: Vector3<T>, target: Vector3<T>, up: Vector3<T>) -> Result<Pose<T>, String> {
+        let to_target = vecmath::vec3_sub(target, eye);
+        let dist = vecmath::vec3_len(to_target);
+
+        if dist <= T::zero() {
+            return Err("the camera looks at itself".to_string());
+        }
+
+        let dir = vecmath::vec3_scale(to_target, T::one() / dist);
+        let right = vecmath::vec3_cross(dir, up);
+
+        // Or is 0.
+        if vecmath::vec3_len(right) <= T::from_f64(1e-6) * vecmath::vec3_len(up) {
+            return Err("the camera's up runs along its view".to_string());
+        }
+
+        let right = vecmath::vec3_normalized(right);
+
+        return Ok(Pose {
+            orig: eye,
+            dir,
+            up: vecmath::vec3_cross(right, dir),
+            shutter: [T::zero(); 2],
+            velocity: [T::zero(); 3],
+        });
+    }
+
+    /// Camera to world space: the directions of right and up in the image
+    /// and of the view, made orthonormal.
+    pub fn basis(&self) -> [Vector3<T>; 3] {
+        let forward = vecmath::vec3_normalized(self.dir);
+        let right = vecmath::vec3_normalized(vecmath::vec3_cross(forward, self.up));
+        let up = vecmath::vec3_cross(right, forward);
+        return [right, up, forward];
+    }
+
+    /// Whether the camera moves while the shutter is open.
+    pub fn moves(&self) -> bool {
+        return self.shutter[1] > self.shutter[0] && self.velocity != [T::zero(); 3];
+    }
+
+    /// Ray into `dir` from `offset` off the camera, shot at a time within
+    /// the shutter interval picked by `sample`, from where the camera is
+    /// then. The cone and the differentials are left to the caller.
+    pub fn shoot(&self, offset: Vector3<T>, dir: Vector3<T>, sample: &mut Rng) -> Ray<T> {
+        let [open, close] = self.shutter;
+        let time = if close > open {
+            open + (close - open) * T::from_f64(sample.unit())
+        } else {
+            T::zero()
+        };
+
+        let orig = vecmath::vec3_add(self.orig, offset);
+
+        return Ray {
+            orig: vecmath::vec3_add(orig, vecmath::vec3_scale(self.velocity, time)),
+            dir,
+            width: T::zero(),
+            spread: T::zero(),
+            diffs: None,
+            time,
+        };
+    }
+
+    // Moves the camera back along its view to `dist` from `center`.
+    fn back_off(&mut self, center: Vector3<T>, dist: T) {
+        let dir = vecmath::vec3_normalized(self.dir);
+        self.orig = vecmath::vec3_sub(center, vecmath::vec3_scale(dir, dist));
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint)
+    where
+        T: Into<f64>,
+    {
+        h.write_vec3(self.orig);
+        h.write_vec3(self.dir);
+        h.write_vec3(self.up);
+        h.write_f64(self.shutter[0]);
+        h.write_f64(self.shutter[1]);
+        h.write_vec3(self.velocity);
+    }
+}
+
+/// Projects onto a plane, as through a pinhole: straight lines stay
+/// straight.
+pub struct Pinhole<T> {
+    pub pose: Pose<T>,
+    // Horizontal and vertical angles of view in radians. Pixels are square
+    // if the tangents of the half angles are as the sides of the image.
+    pub fov: [T; 2],
+}
+
+impl<T: Float> Pinhole<T> {
+    /// Camera as of `Pose::look_at` with a horizontal angle of view of `fov`
+    /// radians and the vertical one for square pixels on an image of
+    /// `aspect` (width by height). Also fails if `fov` is not between 0 and
+    /// 180°.
+    pub fn look_at(
+        eye: Vector3<T>,
+        target: Vector3<T>,
+        up: Vector3<T>,
+        fov: T,
+        aspect: T,
+    ) -> Result<Pinhole<T>, String> {
+        if fov <= T::zero() || fov >= T::from_f64(std::f64::consts::PI) {
+            return Err("the camera's angle of view is not between 0 and 180°".to_string());
+        }
+
+        let vertical = T::from_f64(2.0) * ((fov * T::from_f64(0.5)).tan() / aspect).atan();
+
+        return Ok(Pinhole {
+            pose: Pose::look_at(eye, target, up)?,
+            fov: [fov, vertical],
+        });
+    }
+
+    /// Width by height ratio of the images the camera has square pixels on.
+    pub fn aspect(&self) -> T {
+        let [h, v] = self.fov.map(|a| (a * T::from_f64(0.5)).tan());
+        return h / v;
+    }
+
+    /// Sets the horizontal and vertical angles of view given, in radians,
+    /// keeping the pixels square if only one is.
+    pub fn set_fov(&mut self, horizontal: Option<T>, vertical: Option<T>) {
+        let half = T::from_f64(0.5);
+        let two = T::from_f64(2.0);
+        let aspect = self.aspect();
+
+        self.fov = match (horizontal, vertical) {
+            (None, None) => self.fov,
+            (Some(h), None) => [h, two * ((h * half).tan() / aspect).atan()],
+            (None, Some(v)) => [two * ((v * half).tan() * aspect).atan(), v],
+            (Some(h), Some(v)) => [h, v],
+        };
+    }
+}
+
+impl<T: Float + Into<f64> + Sync> Camera<T> for Pinhole<T> {
+    fn generate_ray(&self, px: T, py: T, sample: &mut Rng) -> Option<Ray<T>> {
+        let [right, up, forward] = self.pose.basis();
+        let [sx, sy] = self.fov.map(|a| (a * T::from_f64(0.5)).tan());
+        let on_plane = vecmath::vec3_sub(
+            vecmath::vec3_scale(right, px * sx),
+            vecmath::vec3_scale(up, py * sy),
+        );
+        let dir = vecmath::vec3_add(forward, on_plane);
+
+        return Some(
+            self.pose
+                .shoot([T::zero(); 3], vecmath::vec3_normalized(dir), sample),
+        );
+    }
+
+    fn pose(&self) -> &Pose<T> {
+        return &self.pose;
+    }
+
+    fn pose_mut(&mut self) -> &mut Pose<T> {
+        return &mut self.pose;
+    }
+
+    fn pinhole(&self) -> bool {
+        return !self.pose.moves();
+    }
+
+    /// On a plane facing the camera.
+    fn in_focus(&self, ray: &Ray<T>, focus: T) -> Vector3<T> {
+        let forward = vecmath::vec3_normalized(self.pose.dir);
+        let dist = focus / vecmath::vec3_dot(ray.dir, forward);
+        return vecmath::vec3_add(ray.orig, vecmath::vec3_scale(ray.dir, dist));
+    }
+
+    fn frame(&mut self, center: Vector3<T>, radius: T) {
+        let angle = self.fov[0].min(self.fov[1]) * T::from_f64(0.5);
+        self.pose.back_off(center, radius / angle.sin());
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"pinhole");
+        self.pose.fingerprint(h);
+        h.write_f64(self.fov[0]);
+        h.write_f64(self.fov[1]);
+    }
+}
+
+/// How a fisheye maps the angle off the view to the distance from the
+/// center of the image.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mapping {
+    /// In proportion to the angle, e.g. for dome projection.
+    Equidistant,
+    /// Keeping the areas of the sphere around the camera, in proportion to
+    /// the sine of half the angle.
+    Equisolid,
+}
+
+impl Mapping {
+    /// The mapping of the given name, as on the command line.
+    pub fn from_name(name: &str) -> Option<Mapping> {
+        return match name {
+            "equidistant" => Some(Mapping::Equidistant),
+            "equisolid" => Some(Mapping::Equisolid),
+            _ => None,
+        };
+    }
+
+    /// Distance from the center of the image of what is `angle` off the
+    /// view, in some unit.
+    pub fn radius<T: Float>(self, angle: T) -> T {
+        return match self {
+            Mapping::Equidistant => angle,
+            Mapping::Equisolid => (angle * T::from_f64(0.5)).sin(),
+        };
+    }
+
+    /// Angle off the view of what is `r` from the center of the image (as
+    /// for `radius`), if anything: nothing beyond straight back.
+    pub fn angle<T: Float>(self, r: T) -> Option<T> {
+        let angle = match self {
+            Mapping::Equidistant => r,
+            Mapping::Equisolid if r > T::one() => return None,
+            Mapping::Equisolid => T::from_f64(2.0) * r.asin(),
+        };
+
+        return Some(angle).filter(|a| *a <= T::from_f64(std::f64::consts::PI));
+    }
+}
+
+/// Sees all around at an angle of view of 360°, bending straight lines.
+pub struct Fisheye<T> {
+    pub pose: Pose<T>,
+    // Horizontal and vertical angles of view in radians. Pixels are square
+    // if the `Mapping::radius` of the half angles are as the sides of the
+    // image.
+    pub fov: [T; 2],
+    pub mapping: Mapping,
+}
+
+impl<T: Float> Fisheye<T> {
+    /// Camera at `pose` with a horizontal angle of view of `fov` radians and
+    /// the vertical one for square pixels on an image of `aspect` (width by
+    /// height), up to all around.
+    pub fn new(pose: Pose<T>, mapping: Mapping, fov: T, aspect: T) -> Fisheye<T> {
+        let all_around = mapping.radius(T::from_f64(std::f64::consts::PI));
+        let r = (mapping.radius(fov * T::from_f64(0.5)) / aspect).min(all_around);
+
+        return Fisheye {
+            pose,
+            fov: [fov, T::from_f64(2.0) * mapping.angle(r).unwrap()],
+            mapping,
+        };
+    }
+}
+
+impl<T: Float + Into<f64> + Sync> Camera<T> for Fisheye<T> {
+    fn generate_ray(&self, px: T, py: T, sample: &mut Rng) -> Option<Ray<T>> {
+        let [right, up, forward] = self.pose.basis();
+        let [sx, sy] = self.fov.map(|a| self.mapping.radius(a * T::from_f64(0.5)));
+        let (px, py) = (px * sx, py * sy);
+        let r = (px * px + py * py).sqrt();
+
+        let dir = if r == T::zero() {
+            forward
+        } else {
+            let angle = self.mapping.angle(r)?;
+            let on_plane =
+                vecmath::vec3_sub(vecmath::vec3_scale(right, px), vecmath::vec3_scale(up, py));
+            vecmath::vec3_add(
+                vecmath::vec3_scale(forward, angle.cos()),
+                vecmath::vec3_scale(on_plane, angle.sin() / r),
+            )
+        };
+
+        return Some(self.pose.shoot([T::zero(); 3], dir, sample));
+    }
+
+    fn pose(&self) -> &Pose<T> {
+        return &self.pose;
+    }
+
+    fn pose_mut(&mut self) -> &mut Pose<T> {
+        return &mut self.pose;
+    }
+
+    /// Up to a right angle off the view, beyond which moving back doesn't
+    /// help.
+    fn frame(&mut self, center: Vector3<T>, radius: T) {
+        let right_angle = T::from_f64(std::f64::consts::FRAC_PI_2);
+        let angle = (self.fov[0].min(self.fov[1]) * T::from_f64(0.5)).min(right_angle);
+        self.pose.back_off(center, radius / angle.sin());
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"fisheye");
+        self.pose.fingerprint(h);
+        h.write_f64(self.fov[0]);
+        h.write_f64(self.fov[1]);
+        h.write_u32(self.mapping as u32);
+    }
+}
+
+/// Projects along the view onto a plane: sizes don't shrink with distance,
+/// as for technical drawings.
+pub struct Ortho<T> {
+    pub pose: Pose<T>,
+    pub size: [T; 2], // width and height of the view
+}
+
+impl<T: Float + Into<f64> + Sync> Camera<T> for Ortho<T> {
+    fn generate_ray(&self, px: T, py: T, sample: &mut Rng) -> Option<Ray<T>> {
+        let [right, up, forward] = self.pose.basis();
+        let [sx, sy] = self.size.map(|s| s * T::from_f64(0.5));
+        let offset = vecmath::vec3_sub(
+            vecmath::vec3_scale(right, px * sx),
+            vecmath::vec3_scale(up, py * sy),
+        );
+
+        return Some(self.pose.shoot(offset, forward, sample));
+    }
+
+    fn pose(&self) -> &Pose<T> {
+        return &self.pose;
+    }
+
+    fn pose_mut(&mut self) -> &mut Pose<T> {
+        return &mut self.pose;
+    }
+
+    /// Widens the view (keeping its aspect), moving back just enough to see
+    /// all of the sphere.
+    fn frame(&mut self, center: Vector3<T>, radius: T) {
+        let scale = T::from_f64(2.0) * radius / self.size[0].min(self.size[1]);
+        self.size = self.size.map(|s| s * scale);
+        self.pose.back_off(center, radius);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"ortho");
+        self.pose.fingerprint(h);
+        h.write_f64(self.size[0]);
+        h.write_f64(self.size[1]);
+    }
+}
+
+/// Sees all around, with longitude across and latitude down the image
+/// (equirectangular, as for environment maps): the width spans 360° and the
+/// height 180°, so pixels are square on images twice as wide as high.
+pub struct Spherical<T> {
+    pub pose: Pose<T>,
+}
+
+impl<T: Float + Into<f64> + Sync> Camera<T> for Spherical<T> {
+    fn generate_ray(&self, px: T, py: T, sample: &mut Rng) -> Option<Ray<T>> {
+        let [right, up, forward] = self.pose.basis();
+        let pi = T::from_f64(std::f64::consts::PI);
+        let (lon, lat) = (px * pi, -py * pi * T::from_f64(0.5));
+
+        let around = vecmath::vec3_add(
+            vecmath::vec3_scale(right, lon.sin()),
+            vecmath::vec3_scale(forward, lon.cos()),
+        );
+        let dir = vecmath::vec3_add(
+            vecmath::vec3_scale(around, lat.cos()),
+            vecmath::vec3_scale(up, lat.sin()),
+        );
+
+        return Some(self.pose.shoot([T::zero(); 3], dir, sample));
+    }
+
+    fn pose(&self) -> &Pose<T> {
+        return &self.pose;
+    }
+
+    fn pose_mut(&mut self) -> &mut Pose<T> {
+        return &mut self.pose;
+    }
+
+    /// Sees everything from anywhere, so stays put.
+    fn frame(&mut self, _center: Vector3<T>, _radius: T) {}
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"spherical");
+        self.pose.fingerprint(h);
+    }
+}
+
+/// `camera` through a lens of radius `lens` instead of a pinhole, for depth
+/// of field: things at a distance of `focus` (as of `Camera::in_focus`)
+/// stay sharp, the rest blurs the more the larger the lens.
+pub struct ThinLens<T> {
+    pub camera: Box<dyn Camera<T>>,
+    pub lens: T,
+    pub focus: T,
+}
+
+impl<T: Float + Into<f64> + Sync> Camera<T> for ThinLens<T> {
+    fn generate_ray(&self, px: T, py: T, sample: &mut Rng) -> Option<Ray<T>> {
+        let ray = self.camera.generate_ray(px, py, sample)?;
+        let [right, up, _] = self.camera.pose().basis();
+
+        // Evenly over the disc of the lens.
+        let r = self.lens * T::from_f64(sample.unit().sqrt());
+        let phi = T::from_f64(2.0 * std::f64::consts::PI * sample.unit());
+        let offset = vecmath::vec3_add(
+            vecmath::vec3_scale(right, r * phi.cos()),
+            vecmath::vec3_scale(up, r * phi.sin()),
+        );
+
+        let in_focus = self.camera.in_focus(&ray, self.focus);
+        let orig = vecmath::vec3_add(ray.orig, offset);
+
+        return Some(Ray {
+            orig,
+            dir: vecmath::vec3_normalized(vecmath::vec3_sub(in_focus, orig)),
+            ..ray
+        });
+    }
+
+    fn pose(&self) -> &Pose<T> {
+        return self.camera.pose();
+    }
+
+    fn pose_mut(&mut self) -> &mut Pose<T> {
+        return self.camera.pose_mut();
+    }
+
+    fn frame(&mut self, center: Vector3<T>, radius: T) {
+        self.camera.frame(center, radius);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"thin lens");
+        self.camera.fingerprint(h);
+        h.write_f64(self.lens);
+        h.write_f64(self.focus);
+    }
+}
+
+/// How the images of a pair of eyes looking along parallel views make up
+/// one, for stereo viewing.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stereo {
+    /// The left eye's in the left half, the right eye's in the right, each
+    /// squeezed to half the width (as 3D displays take them).
+    SideBySide,
+    /// Red from the left eye and cyan (green and blue) from the right, for
+    /// red/cyan glasses.
+    Anaglyph,
+}
+
+impl Stereo {
+    /// The layout of the given name, as on the command line.
+    pub fn from_name(name: &str) -> Option<Stereo> {
+        return match name {
+            "side-by-side" => Some(Stereo::SideBySide),
+            "anaglyph" => Some(Stereo::Anaglyph),
+            _ => None,
+        };
+    }
+}
@@ -0,0 +1,118 @@
+use image::Pixel;
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+/// Which pixels of a checkerboard pattern get traced in a pass.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Checkerboard {
+    /// Trace every pixel.
+    Off,
+    /// Trace pixels where `x + y` is even.
+    Even,
+    /// Trace pixels where `x + y` is odd.
+    Odd,
+}
+
+/// Primary hit information used to guide reconstruction: distance to the
+/// camera and surface normal. `None` if the primary ray misses.
+pub type GSample<T> = Option<(T, Vector3<T>)>;
+
+impl Checkerboard {
+    pub fn traced(self, x: u32, y: u32) -> bool {
+        let odd = (x + y) % 2 == 1;
+
+        return match self {
+            Checkerboard::Off => true,
+            Checkerboard::Even => !odd,
+            Checkerboard::Odd => odd,
+        };
+    }
+
+    /// The mode of the next view, tracing the pixels this one skips.
+    pub fn next(self) -> Checkerboard {
+        return match self {
+            Checkerboard::Off => Checkerboard::Off,
+            Checkerboard::Even => Checkerboard::Odd,
+            Checkerboard::Odd => Checkerboard::Even,
+        };
+    }
+}
+
+/// Fills in the untraced pixels of `buf` (row major) from their traced
+/// neighbours, weighting each neighbour by how similar its primary hit is.
+pub fn reconstruct<T: Float, C: Pixel<Subpixel = T>>(
+    mode: Checkerboard,
+    width: u32,
+    height: u32,
+    gbuf: &[GSample<T>],
+    buf: &mut [C],
+) {
+    let idx = |x: u32, y: u32| (y * width + x) as usize;
+
+    for y in 0..height {
+        for x in 0..width {
+            if mode.traced(x, y) {
+                continue;
+            }
+
+            let center = &gbuf[idx(x, y)];
+
+            let neighbours = [
+                (x > 0, x.wrapping_sub(1), y),
+                (x + 1 < width, x + 1, y),
+                (y > 0, x, y.wrapping_sub(1)),
+                (y + 1 < height, x, y + 1),
+            ];
+
+            let mut weighted: Option<(C, T)> = None;
+            let mut plain: Option<(C, T)> = None;
+
+            for &(valid, nx, ny) in neighbours.iter() {
+                if !valid {
+                    continue;
+                }
+
+                let c = buf[idx(nx, ny)];
+                let w = similarity(center, &gbuf[idx(nx, ny)]);
+
+                plain = Some(accumulate(plain, c, T::one()));
+
+                if w > T::zero() {
+                    weighted = Some(accumulate(weighted, c, w));
+                }
+            }
+
+            // Fall back to a plain average if no neighbour looks alike.
+            if let Some((sum, total)) = weighted.or(plain) {
+                buf[idx(x, y)] = sum.map(|v| v / total);
+            }
+        }
+    }
+}
+
+fn accumulate<T: Float, C: Pixel<Subpixel = T>>(acc: Option<(C, T)>, c: C, w: T) -> (C, T) {
+    return match acc {
+        None => (c.map(|v| v * w), w),
+        Some((sum, total)) => (sum.map2(&c, |a, b| a + b * w), total + w),
+    };
+}
+
+fn similarity<T: Float>(a: &GSample<T>, b: &GSample<T>) -> T {
+    return match (a, b) {
+        (None, None) => T::one(),
+        (Some((da, na)), Some((db, nb))) => {
+            let cos = vecmath::vec3_dot(*na, *nb);
+
+            // Normals facing away from each other are treated as the same
+            // plane seen from the other side.
+            let cos = if cos < T::zero() { -cos } else { cos };
+
+            let rel = (*da - *db) / *da;
+            let rel = if rel < T::zero() { -rel } else { rel };
+
+            cos.powf(T::from_u32(8)) / (T::one() + T::from_u32(50) * rel)
+        }
+        _ => T::zero(),
+    };
+}
@@ -0,0 +1,1082 @@
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::camera::{Mapping, Stereo};
+use crate::checkerboard::Checkerboard;
+use crate::filter::Filter;
+use crate::output::{Bits, Hdr};
+use crate::progress::Progress;
+use crate::sampling::{self, Adaptive, Sampler};
+use crate::tonemap::Tonemap;
+use crate::volume::Pattern;
+
+pub const USAGE: &str = "usage: rs-raytrace [--preset NAME] [--threads N] [--low-priority] \
+                         [--output-dir DIR] [--obj FILE] [--texture FILE] [--triplanar SCALE] \
+                         [--roughness-map FILE] [--metalness-map FILE] [--light-texture FILE] \
+                         [--bias DIST] [--material-bias NAME,DIST] [--ies FILE] \
+                         [--light-groups TARGET=GROUPS[,...]] [--light-mask ID=MASK[,...]] \
+                         [--shadow-mask ID=MASK[,...]] [--shadow-intensity TARGET=X[,...]] \
+                         [--env FILE] [--sky TURBIDITY] [--atmosphere ALTITUDE,SCALE] \
+                         [--sun-elevation DEG] [--fog ABSORPTION,SCATTERING] [--volume FILE] \
+                         [--noise fbm|worley,FREQUENCY,THRESHOLD] [--asymmetry G] \
+                         [--paths SAMPLES] [--ssaa N] [--filter box|tent|gaussian|mitchell] \
+                         [--tonemap clip|reinhard|aces|exponential] [--exposure STOPS] [--linear] \
+                         [--bits 8|16] [--hdr exr|pfm] \
+                         [--bidir] [--photons COUNT] [--ao DIST] [--normals] \
+                         [--whitted] [--sampler r2|halton|sobol] [--adaptive NOISE] \
+                         [--max-samples N] [--importance FILE] [--preview FILE] [--region X,Y,W,H] \
+                         [--seed N] [--clamp MAX[,MAX...]] [--frame-all] [--anchor] \
+                         [--checkerboard] [--no-cull] [--fov DEG] [--vfov DEG] \
+                         [--fisheye equidistant|equisolid,FOV] [--ortho WIDTH] [--spherical] \
+                         [--lens RADIUS,FOCUS] [--stereo side-by-side|anaglyph,DISTANCE] \
+                         [--shutter OPEN,CLOSE] [--velocity X,Y,Z] \
+                         [--branching N[,N...]] [--progress-json] [--aovs] [--alpha] [--spectral]
+       rs-raytrace stress [--seed N] [--rounds N] [--threads N]
+       rs-raytrace furnace";
+
+/// What a setting by `--light-groups` or `--shadow-intensity` applies to.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Target {
+    Object(u32), // the polys with this id, see `Poly::id`
+    Environment, // that of --env, --sky or --atmosphere
+    Lamp,        // that of --ies
+}
+
+pub struct Args {
+    pub threads: usize, // 0 means one per core
+    pub low_priority: bool,
+    pub output_dir: Option<PathBuf>,
+    pub obj: Option<String>,  // render this OBJ or PLY mesh, not the demo
+    pub env: Option<String>,  // HDR environment map lighting the scene
+    pub sky: Option<f64>,     // light the scene by a clear sky of this turbidity
+    pub sun_elevation: f64,   // of the sun of `sky` or `atmosphere`, in degrees
+    pub paths: Option<u32>,   // path trace with this many samples per pixel
+    pub supersample: u32,     // rays per sample: an N×N grid over the pixel
+    pub filter: Filter,       // combines the samples into pixels
+    pub tonemap: Tonemap,     // brings the light into the range of the images
+    pub exposure: f64,        // scales the light by 2 to this power first
+    pub linear: bool,         // write the light as is, not sRGB encoded
+    pub bits: Bits,           // per channel of the PNGs
+    pub hdr: Option<Hdr>,     // also write the unmapped light in this format
+    pub bidir: bool,          // trace `paths` from the emitters too
+    pub photons: Option<u32>, // take bounced light from this many photons
+    pub ao: Option<f64>,      // render ambient occlusion within this distance
+    pub normals: bool,        // render the normals of the first hits
+    pub whitted: bool,        // only shadow rays, mirrors and lenses
+    pub clamp: Vec<f64>,      // see `Tracer::clamped`
+    pub branching: Vec<u32>,  // see `Grid::with_branching`
+    pub frame_all: bool,      // fit the whole scene into the cameras' views
+    pub anchor: bool,         // move the --obj mesh so the camera is at the origin
+    pub checkerboard: bool,   // trace every other pixel, see `checkerboard`
+    pub cull: bool,           // skip polys outside the view for primary rays, see `frustum`
+    pub fov: Option<f64>,     // horizontal angle of view of the cameras, in degrees
+    pub vfov: Option<f64>,    // vertical one, in degrees
+    pub fisheye: Option<(Mapping, f64)>, // of the cameras, with the angle of view in degrees
+    pub ortho: Option<f64>,   // project the cameras' views orthographically, this wide
+    pub spherical: bool,      // give the cameras an equirectangular view all around
+    pub stereo: Option<(Stereo, f64)>, // render pairs of eyes this far apart
+    pub lens: Option<[f64; 2]>, // radius and distance in focus of the cameras' lenses
+    pub shutter: Option<[f64; 2]>, // times the cameras' shutters open and close
+    pub velocity: [f64; 3],   // of the cameras while the shutters are open
+    pub progress: Progress,
+    pub sampler: &'static dyn Sampler, // see `sampling::SAMPLERS`
+    pub noise: Option<f64>,            // sample adaptively down to this noise
+    pub max_samples: Option<u32>,      // per pixel when sampling adaptively
+    pub importance: Option<String>,    // map whose brightness scales `paths` per pixel
+    pub preview: Option<String>,       // render guiding `paths`, see `sampling::importance_from`
+    pub region: Option<[u32; 4]>,      // the pixels getting all `paths`, see `sampling::region`
+    pub aovs: bool,                    // also write the AOVs of `passes` as images
+    pub alpha: bool,                   // transparent where camera rays hit nothing
+    pub spectral: bool,                // carry light as a `Spectrum` instead of RGB
+    pub fog: Option<[f64; 2]>,         // fill the scene with fog, see `medium::Fog`
+    pub texture: Option<String>,       // of the --obj mesh, see `surface::image_texture`
+    pub triplanar: Option<f64>,        // project `texture` along the axes, see `surface::triplanar`
+    pub roughness_map: Option<String>, // of the --obj mesh, see `surface::image_map`
+    pub metalness_map: Option<String>, // of the --obj mesh, see `surface::image_map`
+    pub light_texture: Option<String>, // of the light above --obj, see `surface::image_light`
+    pub bias: Option<f64>,             // of secondary rays off every poly, see `Poly::set_bias`
+    pub material_bias: Option<(String, f64)>, // of an --obj material, see `surface::biased`
+    pub ies: Option<String>,           // of a lamp above the scene, see `lights::profiled`
+    pub light_groups: Vec<(Target, u32)>, // see `Poly::set_light_groups` and `lights::linked`
+    pub light_mask: Vec<(u32, u32)>,   // by object id, see `Poly::set_light_mask`
+    pub shadow_mask: Vec<(u32, u32)>,  // by object id, see `Poly::set_shadow_mask`
+    pub shadow_intensity: Vec<(Target, f64)>, // see `Poly::set_shadow_intensity`
+    pub volume: Option<String>,        // density grid of the fog, see `volume::load`
+    pub fog_noise: Option<(Pattern, f64, f64)>, // density of the fog, see `volume::Noise`
+    pub asymmetry: Option<f64>,        // of the fog's `phase::HenyeyGreenstein`
+    pub atmosphere: Option<[f64; 2]>,  // light the scene by `atmosphere::atmosphere`
+    // Run the stress test instead of rendering, with `rounds` scenes per
+    // case starting at `seed`.
+    pub stress: bool,
+    // Run the white furnace test instead of rendering.
+    pub furnace: bool,
+    pub seed: u64, // of the random choices when rendering
+    pub rounds: u32,
+}
+
+impl Args {
+    /// Path to write the output image `name` to.
+    pub fn output(&self, name: &str) -> PathBuf {
+        return match &self.output_dir {
+            Some(dir) => dir.join(name),
+            None => PathBuf::from(name),
+        };
+    }
+
+    /// Adaptive sampling as requested, by default up to four times the
+    /// rays per pixel.
+    pub fn adaptive(&self) -> Option<Adaptive> {
+        return self.noise.map(|noise| Adaptive {
+            noise,
+            max_samples: self.max_samples.unwrap_or(self.rays().saturating_mul(4)),
+        });
+    }
+
+    /// Pixels the first view traces, the next ones alternating, see
+    /// `Checkerboard::next`.
+    pub fn pixels(&self) -> Checkerboard {
+        return if self.checkerboard {
+            Checkerboard::Even
+        } else {
+            Checkerboard::Off
+        };
+    }
+
+    /// The light groups `--light-groups` puts `target` in, if any.
+    pub fn light_groups_of(&self, target: Target) -> Option<u32> {
+        return self
+            .light_groups
+            .iter()
+            .rev()
+            .find(|(t, _)| *t == target)
+            .map(|(_, groups)| *groups);
+    }
+
+    /// The shadow intensity `--shadow-intensity` gives `target`, if any.
+    pub fn shadow_intensity_of(&self, target: Target) -> Option<f64> {
+        return self
+            .shadow_intensity
+            .iter()
+            .rev()
+            .find(|(t, _)| *t == target)
+            .map(|(_, intensity)| *intensity);
+    }
+
+    /// The light groups and the shadow intensity of the light `target`,
+    /// if linked at all, see `lights::linked`.
+    pub fn links_of(&self, target: Target) -> Option<(u32, f64)> {
+        let groups = self.light_groups_of(target);
+        let intensity = self.shadow_intensity_of(target);
+
+        if groups.is_none() && intensity.is_none() {
+            return None;
+        }
+
+        return Some((groups.unwrap_or(!0), intensity.unwrap_or(1.0)));
+    }
+
+    /// Renders making up an image of `cameras` views, see `Progress::start`.
+    pub fn views(&self, cameras: u32) -> u32 {
+        return if self.stereo.is_some() {
+            2 * cameras
+        } else {
+            cameras
+        };
+    }
+
+    /// Rays traced through each pixel (without adaptive sampling).
+    pub fn rays(&self) -> u32 {
+        return self.paths.unwrap_or(1) * self.supersample * self.supersample;
+    }
+}
+
+/// Parses `args`, with the settings of the selected preset from the user
+/// config file as defaults: those given explicitly, or excluding a flag
+/// given explicitly (like `paths` with `--photons`), are dropped.
+///
+/// The preset is picked with `--preset NAME`; without it the `default`
+/// preset is used if there is one.
+pub fn parse_with_presets(args: Vec<String>) -> Result<Args, String> {
+    // Presets hold render settings, which the subcommands don't take.
+    if args
+        .first()
+        .is_some_and(|a| a == "stress" || a == "furnace")
+    {
+        return parse(args.into_iter());
+    }
+
+    let name = match args.iter().position(|a| a == "--preset") {
+        Some(i) => Some(args.get(i + 1).ok_or("--preset needs a name")?.as_str()),
+        None => None,
+    };
+
+    let config = match config_path() {
+        Some(path) => fs::read_to_string(&path).ok(),
+        None => None,
+    };
+
+    let preset = match (&config, name) {
+        (Some(config), Some(name)) => {
+            preset_args(config, name)?.ok_or(format!("unknown preset: {}", name))?
+        }
+        (Some(config), None) => preset_args(config, "default")?.unwrap_or_default(),
+        (None, Some(name)) => return Err(format!("unknown preset: {}", name)),
+        (None, None) => Vec::new(),
+    };
+
+    // The flags given, without the dashes.
+    let given: Vec<&str> = args.iter().filter_map(|a| a.strip_prefix("--")).collect();
+
+    let mut flags: Vec<String> = preset
+        .into_iter()
+        .filter(|(key, _)| !given.iter().any(|g| g == key || excludes(g, key)))
+        .flat_map(|(_, flags)| flags)
+        .collect();
+    flags.extend(args);
+
+    return parse(flags.into_iter());
+}
+
+// Largest grid of `--ssaa`, a few thousand rays per sample.
+const MAX_SUPERSAMPLE: u32 = 64;
+
+// Flags excluding each other, as checked at the end of `parse`.
+const EXCLUSIVE: &[(&str, &[&str])] = &[
+    ("env", &["sky", "atmosphere", "light-texture"]),
+    ("sky", &["atmosphere", "light-texture"]),
+    ("atmosphere", &["light-texture"]),
+    ("paths", &["photons"]),
+    ("photons", &["bidir", "ao", "normals", "whitted"]),
+    ("bidir", &["ao", "normals", "whitted", "clamp", "fog"]),
+    ("ao", &["normals", "whitted", "clamp", "fog"]),
+    ("normals", &["whitted", "clamp", "fog"]),
+    ("noise", &["volume"]),
+    ("importance", &["preview", "region"]),
+    ("preview", &["region"]),
+    ("texture", &["roughness-map", "metalness-map"]),
+    (
+        "branching",
+        &["paths", "photons", "ao", "normals", "whitted"],
+    ),
+    ("fisheye", &["ortho", "spherical", "fov", "vfov"]),
+    ("ortho", &["spherical", "fov", "vfov"]),
+    ("spherical", &["fov", "vfov"]),
+];
+
+// Whether flags `a` and `b` (without the dashes) exclude each other.
+fn excludes(a: &str, b: &str) -> bool {
+    return EXCLUSIVE
+        .iter()
+        .any(|(x, ys)| (*x == a && ys.contains(&b)) || (*x == b && ys.contains(&a)));
+}
+
+/// Location of the user config file: `rs-raytrace/config` in
+/// `$XDG_CONFIG_HOME` (or `~/.config`), or in `%APPDATA%` on Windows.
+pub fn config_path() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+    };
+
+    return base.map(|b| b.join("rs-raytrace").join("config"));
+}
+
+/// A setting of a preset: its key and the flags it stands for.
+pub type Setting = (String, Vec<String>);
+
+/// Settings of preset `name` in `config`, or `None` if it has no such
+/// preset.
+///
+/// The config holds `[name]` sections of `key = value` lines, with keys
+/// named like the long flags; `#` starts a comment. Switches take `true` or
+/// `false`, e.g.
+///
+/// ```text
+/// [default]
+/// threads = 4
+///
+/// [night]
+/// low-priority = true
+/// output-dir = /tmp/renders
+/// ```
+pub fn preset_args(config: &str, name: &str) -> Result<Option<Vec<Setting>>, String> {
+    let mut res = None;
+    let mut section = None;
+
+    for (i, line) in config.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let err = |msg: &str| format!("config line {}: {}", i + 1, msg);
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            section = Some(line[1..line.len() - 1].trim());
+            if section == Some(name) {
+                res.get_or_insert_with(Vec::new);
+            }
+            continue;
+        }
+
+        let (key, value) = match line.find('=') {
+            Some(k) => (line[..k].trim(), line[k + 1..].trim()),
+            None => return Err(err("expected key = value")),
+        };
+
+        if section.is_none() {
+            return Err(err("setting outside of a preset"));
+        }
+
+        if section != Some(name) {
+            continue;
+        }
+
+        let flags = match key {
+            "low-priority" | "bidir" | "normals" | "whitted" | "frame-all" | "anchor"
+            | "checkerboard" | "no-cull" | "progress-json" | "aovs" | "alpha" | "spectral"
+            | "linear" | "spherical" => match value {
+                "true" => vec![format!("--{}", key)],
+                "false" => Vec::new(),
+                _ => return Err(err(&format!("{} must be true or false", key))),
+            },
+            "threads" | "output-dir" | "obj" | "texture" | "triplanar" | "roughness-map"
+            | "metalness-map" | "light-texture" | "bias" | "material-bias" | "ies"
+            | "light-groups" | "light-mask" | "shadow-mask" | "shadow-intensity" | "env"
+            | "sky" | "sun-elevation" | "fog" | "volume" | "noise" | "asymmetry" | "atmosphere"
+            | "paths" | "ssaa" | "filter" | "tonemap" | "exposure" | "bits" | "hdr" | "photons"
+            | "ao" | "sampler" | "adaptive" | "max-samples" | "importance" | "preview"
+            | "region" | "seed" | "clamp" | "branching" | "fisheye" | "lens" | "shutter"
+            | "velocity" | "stereo" | "fov" | "vfov" | "ortho" => {
+                vec![format!("--{}", key), value.to_string()]
+            }
+            _ => return Err(err(&format!("unknown setting: {}", key))),
+        };
+
+        res.get_or_insert_with(Vec::new)
+            .push((key.to_string(), flags));
+    }
+
+    return Ok(res);
+}
+
+/// What `s` names in `--light-groups` and `--shadow-intensity`: an object
+/// id, `env` or `lamp`.
+fn target(s: &str) -> Option<Target> {
+    return match s {
+        "env" => Some(Target::Environment),
+        "lamp" => Some(Target::Lamp),
+        _ => s.parse().ok().map(Target::Object),
+    };
+}
+
+/// Parses `KEY=VALUE[,KEY=VALUE...]`, the keys by `key` and the values by
+/// `value`.
+fn pairs<K, V>(
+    list: &str,
+    key: impl Fn(&str) -> Option<K>,
+    value: impl Fn(&str) -> Option<V>,
+) -> Result<Vec<(K, V)>, String> {
+    return list
+        .split(',')
+        .map(|pair| {
+            let (k, v) = pair
+                .split_once('=')
+                .ok_or(format!("expected KEY=VALUE: {}", pair))?;
+            return Ok((
+                key(k).ok_or(format!("invalid target: {}", k))?,
+                value(v).ok_or(format!("invalid value: {}", v))?,
+            ));
+        })
+        .collect();
+}
+
+pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut res = Args {
+        threads: 0,
+        low_priority: false,
+        output_dir: None,
+        obj: None,
+        texture: None,
+        triplanar: None,
+        roughness_map: None,
+        metalness_map: None,
+        light_texture: None,
+        bias: None,
+        material_bias: None,
+        ies: None,
+        light_groups: Vec::new(),
+        light_mask: Vec::new(),
+        shadow_mask: Vec::new(),
+        shadow_intensity: Vec::new(),
+        env: None,
+        sky: None,
+        sun_elevation: 40.0,
+        fog: None,
+        volume: None,
+        fog_noise: None,
+        asymmetry: None,
+        atmosphere: None,
+        paths: None,
+        supersample: 1,
+        filter: Filter::Box,
+        tonemap: Tonemap::Clip,
+        exposure: 0.0,
+        linear: false,
+        bits: Bits::Eight,
+        hdr: None,
+        bidir: false,
+        photons: None,
+        ao: None,
+        normals: false,
+        whitted: false,
+        clamp: Vec::new(),
+        branching: Vec::new(),
+        frame_all: false,
+        anchor: false,
+        checkerboard: false,
+        cull: true,
+        fov: None,
+        vfov: None,
+        fisheye: None,
+        ortho: None,
+        spherical: false,
+        stereo: None,
+        lens: None,
+        shutter: None,
+        velocity: [0.0; 3],
+        progress: Progress::Human,
+        sampler: sampling::SAMPLERS[0],
+        noise: None,
+        max_samples: None,
+        importance: None,
+        preview: None,
+        region: None,
+        aovs: false,
+        alpha: false,
+        spectral: false,
+        stress: false,
+        furnace: false,
+        seed: 0,
+        rounds: 10,
+    };
+
+    // Subcommands come first, and take only their own flags.
+    let mut args = args.peekable();
+    let subcommand: Option<(&str, &[&str])> = match args.peek().map(|a| a.as_str()) {
+        Some("stress") => Some(("stress", &["--seed", "--rounds", "--threads"])),
+        Some("furnace") => Some(("furnace", &[])),
+        _ => None,
+    };
+
+    if let Some((name, _)) = subcommand {
+        args.next();
+        res.stress = name == "stress";
+        res.furnace = name == "furnace";
+    }
+
+    while let Some(arg) = args.next() {
+        if let Some((name, flags)) = subcommand {
+            if !flags.contains(&arg.as_str()) {
+                return Err(format!("{} doesn't take {}", name, arg));
+            }
+        }
+
+        match arg.as_str() {
+            "--threads" => {
+                let n = args.next().ok_or("--threads needs a value")?;
+                res.threads = n
+                    .parse()
+                    .map_err(|_| format!("invalid thread count: {}", n))?;
+            }
+            "--low-priority" => res.low_priority = true,
+            "--output-dir" => {
+                res.output_dir = Some(args.next().ok_or("--output-dir needs a directory")?.into())
+            }
+            "--obj" => res.obj = Some(args.next().ok_or("--obj needs a file")?),
+            "--texture" => res.texture = Some(args.next().ok_or("--texture needs a file")?),
+            "--roughness-map" => {
+                res.roughness_map = Some(args.next().ok_or("--roughness-map needs a file")?)
+            }
+            "--metalness-map" => {
+                res.metalness_map = Some(args.next().ok_or("--metalness-map needs a file")?)
+            }
+            "--light-texture" => {
+                res.light_texture = Some(args.next().ok_or("--light-texture needs a file")?)
+            }
+            "--ies" => res.ies = Some(args.next().ok_or("--ies needs a file")?),
+            "--light-groups" => {
+                let l = args
+                    .next()
+                    .ok_or("--light-groups needs targets and groups")?;
+                res.light_groups = pairs(&l, target, |g| g.parse().ok())?;
+            }
+            "--light-mask" => {
+                let l = args
+                    .next()
+                    .ok_or("--light-mask needs object ids and masks")?;
+                res.light_mask = pairs(&l, |id| id.parse().ok(), |m| m.parse().ok())?;
+            }
+            "--shadow-mask" => {
+                let l = args
+                    .next()
+                    .ok_or("--shadow-mask needs object ids and masks")?;
+                res.shadow_mask = pairs(&l, |id| id.parse().ok(), |m| m.parse().ok())?;
+            }
+            "--shadow-intensity" => {
+                let l = args
+                    .next()
+                    .ok_or("--shadow-intensity needs targets and intensities")?;
+                res.shadow_intensity = pairs(&l, target, |x| {
+                    x.parse().ok().filter(|x: &f64| (0.0..=1.0).contains(x))
+                })?;
+            }
+            "--bias" => {
+                let d = args.next().ok_or("--bias needs a distance")?;
+                res.bias = Some(
+                    d.parse()
+                        .ok()
+                        .filter(|x: &f64| *x >= 0.0 && x.is_finite())
+                        .ok_or(format!("invalid distance: {}", d))?,
+                );
+            }
+            "--material-bias" => {
+                let f = args
+                    .next()
+                    .ok_or("--material-bias needs a material and a distance")?;
+                let parts: Vec<&str> = f.split(',').collect();
+
+                res.material_bias = match parts[..] {
+                    [name, dist] => Some((
+                        name.to_string(),
+                        dist.parse()
+                            .ok()
+                            .filter(|x: &f64| *x >= 0.0 && x.is_finite())
+                            .ok_or(format!("invalid distance: {}", dist))?,
+                    )),
+                    _ => {
+                        return Err(format!(
+                            "--material-bias needs a material and a distance: {}",
+                            f
+                        ))
+                    }
+                };
+            }
+            "--triplanar" => {
+                let s = args.next().ok_or("--triplanar needs a scale")?;
+                res.triplanar = Some(
+                    s.parse()
+                        .ok()
+                        .filter(|x: &f64| *x > 0.0 && x.is_finite())
+                        .ok_or(format!("invalid scale: {}", s))?,
+                );
+            }
+            "--env" => res.env = Some(args.next().ok_or("--env needs a file")?),
+            "--sky" => {
+                let t = args.next().ok_or("--sky needs a turbidity")?;
+                res.sky = Some(
+                    t.parse()
+                        .ok()
+                        .filter(|x: &f64| *x >= 1.0 && x.is_finite())
+                        .ok_or(format!("invalid turbidity: {}", t))?,
+                );
+            }
+            "--atmosphere" => {
+                let v = args
+                    .next()
+                    .ok_or("--atmosphere needs an altitude and a scale")?;
+                let values: Vec<f64> = v
+                    .split(',')
+                    .map(|x| x.parse().ok().filter(|x: &f64| *x >= 0.0 && x.is_finite()))
+                    .collect::<Option<_>>()
+                    .ok_or(format!("invalid altitude or scale: {}", v))?;
+                res.atmosphere =
+                    Some(values.try_into().map_err(|_| {
+                        format!("--atmosphere needs an altitude and a scale: {}", v)
+                    })?);
+            }
+            "--sun-elevation" => {
+                let a = args.next().ok_or("--sun-elevation needs a value")?;
+                res.sun_elevation = a
+                    .parse()
+                    .ok()
+                    .filter(|a: &f64| a.is_finite())
+                    .ok_or(format!("invalid elevation: {}", a))?;
+            }
+            "--fog" => {
+                let c = args.next().ok_or("--fog needs coefficients")?;
+                let coeffs: Vec<f64> = c
+                    .split(',')
+                    .map(|x| x.parse().ok().filter(|x: &f64| *x >= 0.0 && x.is_finite()))
+                    .collect::<Option<_>>()
+                    .ok_or(format!("invalid coefficients: {}", c))?;
+                res.fog = Some(
+                    coeffs
+                        .try_into()
+                        .map_err(|_| format!("--fog needs two coefficients: {}", c))?,
+                );
+            }
+            "--volume" => res.volume = Some(args.next().ok_or("--volume needs a file")?),
+            "--noise" => {
+                let n = args
+                    .next()
+                    .ok_or("--noise needs a pattern, frequency and threshold")?;
+                let parts: Vec<&str> = n.split(',').collect();
+                let number = |x: &str| x.parse().ok().filter(|x: &f64| *x >= 0.0 && x.is_finite());
+
+                res.fog_noise = match parts[..] {
+                    [pattern, frequency, threshold] => Some((
+                        Pattern::from_name(pattern)
+                            .ok_or(format!("unknown noise pattern: {}", pattern))?,
+                        number(frequency).ok_or(format!("invalid frequency: {}", frequency))?,
+                        number(threshold).ok_or(format!("invalid threshold: {}", threshold))?,
+                    )),
+                    _ => {
+                        return Err(format!(
+                            "--noise needs a pattern, frequency and threshold: {}",
+                            n
+                        ))
+                    }
+                };
+            }
+            "--asymmetry" => {
+                let g = args.next().ok_or("--asymmetry needs a value")?;
+                res.asymmetry = Some(
+                    g.parse()
+                        .ok()
+                        .filter(|g: &f64| g.abs() < 1.0)
+                        .ok_or(format!("invalid asymmetry (between -1 and 1): {}", g))?,
+                );
+            }
+            "--importance" => {
+                res.importance = Some(args.next().ok_or("--importance needs a file")?)
+            }
+            "--preview" => res.preview = Some(args.next().ok_or("--preview needs a file")?),
+            "--region" => {
+                let r = args.next().ok_or("--region needs a rectangle")?;
+                let values: Vec<u32> = r
+                    .split(',')
+                    .map(|x| x.parse().ok())
+                    .collect::<Option<_>>()
+                    .filter(|v: &Vec<u32>| v.len() != 4 || (v[2] > 0 && v[3] > 0))
+                    .ok_or(format!("invalid rectangle: {}", r))?;
+                res.region = Some(
+                    values
+                        .try_into()
+                        .map_err(|_| format!("--region needs X,Y,W,H: {}", r))?,
+                );
+            }
+            "--paths" => {
+                let n = args.next().ok_or("--paths needs a sample count")?;
+                res.paths = Some(
+                    n.parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or(format!("invalid sample count: {}", n))?,
+                );
+            }
+            "--ssaa" => {
+                let n = args.next().ok_or("--ssaa needs a grid size")?;
+                res.supersample = n
+                    .parse()
+                    .ok()
+                    .filter(|n| (1..=MAX_SUPERSAMPLE).contains(n))
+                    .ok_or(format!(
+                        "invalid grid size (between 1 and {}): {}",
+                        MAX_SUPERSAMPLE, n
+                    ))?;
+            }
+            "--filter" => {
+                let name = args.next().ok_or("--filter needs a name")?;
+                res.filter = Filter::from_name(&name).ok_or(format!("unknown filter: {}", name))?;
+            }
+            "--tonemap" => {
+                let name = args.next().ok_or("--tonemap needs a name")?;
+                res.tonemap =
+                    Tonemap::from_name(&name).ok_or(format!("unknown tone mapping: {}", name))?;
+            }
+            "--exposure" => {
+                let e = args.next().ok_or("--exposure needs a number of stops")?;
+                res.exposure = e
+                    .parse()
+                    .ok()
+                    .filter(|x: &f64| x.is_finite())
+                    .ok_or(format!("invalid exposure: {}", e))?;
+            }
+            "--linear" => res.linear = true,
+            "--bits" => {
+                let n = args.next().ok_or("--bits needs 8 or 16")?;
+                res.bits = Bits::from_name(&n).ok_or(format!("invalid bit depth: {}", n))?;
+            }
+            "--hdr" => {
+                let name = args.next().ok_or("--hdr needs a format")?;
+                res.hdr =
+                    Some(Hdr::from_name(&name).ok_or(format!("unknown HDR format: {}", name))?);
+            }
+            "--bidir" => res.bidir = true,
+            "--photons" => {
+                let n = args.next().ok_or("--photons needs a photon count")?;
+                res.photons = Some(
+                    n.parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or(format!("invalid photon count: {}", n))?,
+                );
+            }
+            "--ao" => {
+                let d = args.next().ok_or("--ao needs a distance")?;
+                res.ao = Some(
+                    d.parse()
+                        .ok()
+                        .filter(|d| *d > 0.0)
+                        .ok_or(format!("invalid distance: {}", d))?,
+                );
+            }
+            "--normals" => res.normals = true,
+            "--whitted" => res.whitted = true,
+            "--clamp" => {
+                let l = args.next().ok_or("--clamp needs limits")?;
+                res.clamp = l
+                    .split(',')
+                    .map(|m| m.parse().ok().filter(|m| *m > 0.0))
+                    .collect::<Option<_>>()
+                    .ok_or(format!("invalid limits: {}", l))?;
+            }
+            "--branching" => {
+                let l = args.next().ok_or("--branching needs limits")?;
+                res.branching = l
+                    .split(',')
+                    .map(|k| k.parse().ok().filter(|k| *k > 0))
+                    .collect::<Option<_>>()
+                    .ok_or(format!("invalid limits: {}", l))?;
+            }
+            "--frame-all" => res.frame_all = true,
+            "--anchor" => res.anchor = true,
+            "--checkerboard" => res.checkerboard = true,
+            "--no-cull" => res.cull = false,
+            "--shutter" => {
+                let t = args.next().ok_or("--shutter needs two times")?;
+                let values: Vec<f64> = t
+                    .split(',')
+                    .map(|x| x.parse().ok().filter(|x: &f64| x.is_finite()))
+                    .collect::<Option<_>>()
+                    .filter(|v: &Vec<f64>| v.len() != 2 || v[0] <= v[1])
+                    .ok_or(format!("invalid times: {}", t))?;
+                res.shutter = Some(
+                    values
+                        .try_into()
+                        .map_err(|_| format!("--shutter needs two times: {}", t))?,
+                );
+            }
+            "--velocity" => {
+                let v = args.next().ok_or("--velocity needs a vector")?;
+                let values: Vec<f64> = v
+                    .split(',')
+                    .map(|x| x.parse().ok().filter(|x: &f64| x.is_finite()))
+                    .collect::<Option<_>>()
+                    .ok_or(format!("invalid velocity: {}", v))?;
+                res.velocity = values
+                    .try_into()
+                    .map_err(|_| format!("--velocity needs three components: {}", v))?;
+            }
+            "--lens" => {
+                let l = args.next().ok_or("--lens needs a radius and a distance")?;
+                let values: Vec<f64> = l
+                    .split(',')
+                    .map(|x| x.parse().ok().filter(|x: &f64| *x >= 0.0 && x.is_finite()))
+                    .collect::<Option<_>>()
+                    .filter(|v: &Vec<f64>| v.get(1) != Some(&0.0))
+                    .ok_or(format!("invalid radius or distance: {}", l))?;
+                res.lens = Some(
+                    values
+                        .try_into()
+                        .map_err(|_| format!("--lens needs a radius and a distance: {}", l))?,
+                );
+            }
+            "--fisheye" => {
+                let f = args
+                    .next()
+                    .ok_or("--fisheye needs a projection and an angle")?;
+                let parts: Vec<&str> = f.split(',').collect();
+
+                res.fisheye = match parts[..] {
+                    [name, fov] => Some((
+                        Mapping::from_name(name)
+                            .ok_or(format!("unknown fisheye projection: {}", name))?,
+                        fov.parse()
+                            .ok()
+                            .filter(|x: &f64| *x > 0.0 && *x <= 360.0)
+                            .ok_or(format!("invalid angle of view: {}", fov))?,
+                    )),
+                    _ => return Err(format!("--fisheye needs a projection and an angle: {}", f)),
+                };
+            }
+            "--fov" | "--vfov" => {
+                let a = args.next().ok_or(format!("{} needs an angle", arg))?;
+                let fov = Some(
+                    a.parse()
+                        .ok()
+                        .filter(|x: &f64| *x > 0.0 && *x < 180.0)
+                        .ok_or(format!("invalid angle of view: {}", a))?,
+                );
+                if arg == "--fov" {
+                    res.fov = fov;
+                } else {
+                    res.vfov = fov;
+                }
+            }
+            "--ortho" => {
+                let w = args.next().ok_or("--ortho needs a width")?;
+                res.ortho = Some(
+                    w.parse()
+                        .ok()
+                        .filter(|x: &f64| *x > 0.0 && x.is_finite())
+                        .ok_or(format!("invalid width: {}", w))?,
+                );
+            }
+            "--spherical" => res.spherical = true,
+            "--stereo" => {
+                let f = args
+                    .next()
+                    .ok_or("--stereo needs a layout and a distance")?;
+                let parts: Vec<&str> = f.split(',').collect();
+
+                res.stereo = match parts[..] {
+                    [name, dist] => Some((
+                        Stereo::from_name(name)
+                            .ok_or(format!("unknown stereo layout: {}", name))?,
+                        dist.parse()
+                            .ok()
+                            .filter(|x: &f64| *x >= 0.0 && x.is_finite())
+                            .ok_or(format!("invalid distance: {}", dist))?,
+                    )),
+                    _ => return Err(format!("--stereo needs a layout and a distance: {}", f)),
+                };
+            }
+            "--progress-json" => res.progress = Progress::Json,
+            "--sampler" => {
+                let name = args.next().ok_or("--sampler needs a name")?;
+                res.sampler = *sampling::SAMPLERS
+                    .iter()
+                    .find(|s| s.name() == name)
+                    .ok_or(format!("unknown sampler: {}", name))?;
+            }
+            "--adaptive" => {
+                let n = args.next().ok_or("--adaptive needs a noise threshold")?;
+                res.noise = Some(
+                    n.parse()
+                        .ok()
+                        .filter(|n| *n > 0.0)
+                        .ok_or(format!("invalid noise threshold: {}", n))?,
+                );
+            }
+            "--max-samples" => {
+                let n = args.next().ok_or("--max-samples needs a sample count")?;
+                res.max_samples = Some(
+                    n.parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or(format!("invalid sample count: {}", n))?,
+                );
+            }
+            "--aovs" => res.aovs = true,
+            "--alpha" => res.alpha = true,
+            "--spectral" => res.spectral = true,
+            "--seed" => {
+                let n = args.next().ok_or("--seed needs a value")?;
+                res.seed = n.parse().map_err(|_| format!("invalid seed: {}", n))?;
+            }
+            "--rounds" => {
+                if !res.stress {
+                    return Err("--rounds needs stress".to_string());
+                }
+
+                let n = args.next().ok_or("--rounds needs a value")?;
+                res.rounds = n
+                    .parse()
+                    .map_err(|_| format!("invalid round count: {}", n))?;
+            }
+            // Already applied by `parse_with_presets`.
+            "--preset" => {
+                args.next().ok_or("--preset needs a name")?;
+            }
+            _ => return Err(format!("unknown argument: {}", arg)),
+        }
+    }
+
+    if [
+        res.env.is_some(),
+        res.sky.is_some(),
+        res.atmosphere.is_some(),
+    ]
+    .iter()
+    .filter(|x| **x)
+    .count()
+        > 1
+    {
+        return Err("--env, --sky and --atmosphere exclude each other".to_string());
+    }
+
+    if res.paths.is_some() && res.photons.is_some() {
+        return Err("--paths and --photons exclude each other".to_string());
+    }
+
+    // They scale the samples of each pixel.
+    if (res.importance.is_some() || res.preview.is_some() || res.region.is_some())
+        && res.paths.is_none()
+    {
+        return Err("--importance, --preview and --region need --paths".to_string());
+    }
+
+    if [
+        res.importance.is_some(),
+        res.preview.is_some(),
+        res.region.is_some(),
+    ]
+    .iter()
+    .filter(|x| **x)
+    .count()
+        > 1
+    {
+        return Err("--importance, --preview and --region exclude each other".to_string());
+    }
+
+    if res.bidir && res.paths.is_none() {
+        return Err("--bidir needs --paths".to_string());
+    }
+
+    let grid = res.supersample * res.supersample;
+    if res.paths.unwrap_or(1).checked_mul(grid).is_none() {
+        return Err("too many rays per pixel for --paths and --ssaa".to_string());
+    }
+
+    if res.max_samples.is_some() && res.noise.is_none() {
+        return Err("--max-samples needs --adaptive".to_string());
+    }
+
+    if res.max_samples.is_some_and(|max| max < res.rays()) {
+        return Err("--max-samples must be at least the samples of --paths and --ssaa".to_string());
+    }
+
+    if !res.clamp.is_empty() && (res.bidir || res.ao.is_some() || res.normals) {
+        return Err("--clamp doesn't apply to --bidir, --ao or --normals".to_string());
+    }
+
+    if res.fog.is_some() && (res.bidir || res.ao.is_some() || res.normals) {
+        return Err("--fog doesn't apply to --bidir, --ao or --normals".to_string());
+    }
+
+    if res.texture.is_some() && res.obj.is_none() {
+        return Err("--texture needs --obj".to_string());
+    }
+
+    let maps = res.roughness_map.is_some() || res.metalness_map.is_some();
+    if maps && res.obj.is_none() {
+        return Err("--roughness-map and --metalness-map need --obj".to_string());
+    }
+
+    if maps && res.texture.is_some() {
+        return Err("--roughness-map and --metalness-map exclude --texture".to_string());
+    }
+
+    // The demo scenes are small, and some have several cameras.
+    if res.anchor && res.obj.is_none() {
+        return Err("--anchor needs --obj".to_string());
+    }
+
+    if res.material_bias.is_some() && res.obj.is_none() {
+        return Err("--material-bias needs --obj".to_string());
+    }
+
+    let environment = res.env.is_some() || res.sky.is_some() || res.atmosphere.is_some();
+    if res.links_of(Target::Environment).is_some() && !environment {
+        return Err(
+            "--light-groups env and --shadow-intensity env need --env, --sky or --atmosphere"
+                .to_string(),
+        );
+    }
+
+    if res.links_of(Target::Lamp).is_some() && res.ies.is_none() {
+        return Err("--light-groups lamp and --shadow-intensity lamp need --ies".to_string());
+    }
+
+    if res.light_texture.is_some() && res.obj.is_none() {
+        return Err("--light-texture needs --obj".to_string());
+    }
+
+    // They stand in for the light.
+    if res.light_texture.is_some()
+        && (res.env.is_some() || res.sky.is_some() || res.atmosphere.is_some())
+    {
+        return Err("--light-texture excludes --env, --sky and --atmosphere".to_string());
+    }
+
+    if res.triplanar.is_some() && res.texture.is_none() {
+        return Err("--triplanar needs --texture".to_string());
+    }
+
+    if res.volume.is_some() && res.fog.is_none() {
+        return Err("--volume needs --fog".to_string());
+    }
+
+    if res.fog_noise.is_some() && res.fog.is_none() {
+        return Err("--noise needs --fog".to_string());
+    }
+
+    if res.fog_noise.is_some() && res.volume.is_some() {
+        return Err("--noise and --volume exclude each other".to_string());
+    }
+
+    let projections = [res.fisheye.is_some(), res.ortho.is_some(), res.spherical];
+    if projections.iter().filter(|p| **p).count() > 1 {
+        return Err("--fisheye, --ortho and --spherical exclude each other".to_string());
+    }
+
+    if projections.contains(&true) && (res.fov.is_some() || res.vfov.is_some()) {
+        return Err("--fov and --vfov apply to perspective cameras only".to_string());
+    }
+
+    if res.velocity != [0.0; 3] && res.shutter.is_none() {
+        return Err("--velocity needs --shutter".to_string());
+    }
+
+    if res.asymmetry.is_some() && res.fog.is_none() {
+        return Err("--asymmetry needs --fog".to_string());
+    }
+
+    // All others trace paths or no bounces at all.
+    let grid = !(res.paths.is_some()
+        || res.photons.is_some()
+        || res.ao.is_some()
+        || res.normals
+        || res.whitted);
+    if !res.branching.is_empty() && !grid {
+        return Err(
+            "--branching doesn't apply to --paths, --photons, --ao, --normals or --whitted"
+                .to_string(),
+        );
+    }
+
+    // Each picks the rendering algorithm, `--paths` just the samples per
+    // pixel for all but `--photons`.
+    let algorithms = [
+        res.photons.is_some(),
+        res.bidir,
+        res.ao.is_some(),
+        res.normals,
+        res.whitted,
+    ];
+    if algorithms.iter().filter(|a| **a).count() > 1 {
+        return Err(
+            "--photons, --bidir, --ao, --normals and --whitted exclude each other".to_string(),
+        );
+    }
+
+    return Ok(res);
+}
@@ -0,0 +1,274 @@
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr;
+
+use vecmath::traits::Float;
+
+use crate::geom::{Hit, Poly, Ray};
+
+type RTCDevice = *mut c_void;
+type RTCScene = *mut c_void;
+type RTCGeometry = *mut c_void;
+
+const RTC_GEOMETRY_TYPE_TRIANGLE: c_uint = 0;
+const RTC_BUFFER_TYPE_INDEX: c_uint = 0;
+const RTC_BUFFER_TYPE_VERTEX: c_uint = 1;
+const RTC_FORMAT_UINT3: c_uint = 0x5003;
+const RTC_FORMAT_FLOAT3: c_uint = 0x9003;
+const RTC_INVALID_GEOMETRY_ID: c_uint = c_uint::MAX;
+const RTC_SCENE_FLAG_ROBUST: c_uint = 1 << 2;
+
+#[repr(C)]
+struct RTCIntersectContext {
+    flags: c_uint,
+    filter: *const c_void,
+    inst_id: [c_uint; 1],
+}
+
+// Context of a query, passed on to `reject`: Embree's followed by ours.
+#[repr(C)]
+struct Context {
+    base: RTCIntersectContext,
+    rejected: *const Vec<c_uint>, // triangles to pass through
+}
+
+#[repr(C)]
+struct RTCFilterFunctionNArguments {
+    valid: *mut i32,
+    geometry_user_ptr: *mut c_void,
+    context: *const RTCIntersectContext,
+    ray: *mut c_void,
+    hit: *mut c_void, // RTCHitN: N of each field of RTCHit in turn
+    n: c_uint,
+}
+
+#[repr(C, align(16))]
+struct RTCRay {
+    org: [f32; 3],
+    tnear: f32,
+    dir: [f32; 3],
+    time: f32,
+    tfar: f32,
+    mask: c_uint,
+    id: c_uint,
+    flags: c_uint,
+}
+
+#[repr(C, align(16))]
+struct RTCHit {
+    ng: [f32; 3],
+    u: f32,
+    v: f32,
+    prim_id: c_uint,
+    geom_id: c_uint,
+    inst_id: [c_uint; 1],
+}
+
+#[repr(C, align(16))]
+struct RTCRayHit {
+    ray: RTCRay,
+    hit: RTCHit,
+}
+
+#[link(name = "embree3")]
+extern "C" {
+    fn rtcNewDevice(config: *const c_char) -> RTCDevice;
+    fn rtcReleaseDevice(device: RTCDevice);
+    fn rtcNewScene(device: RTCDevice) -> RTCScene;
+    fn rtcReleaseScene(scene: RTCScene);
+    fn rtcSetSceneFlags(scene: RTCScene, flags: c_uint);
+    fn rtcCommitScene(scene: RTCScene);
+    fn rtcNewGeometry(device: RTCDevice, kind: c_uint) -> RTCGeometry;
+    fn rtcSetNewGeometryBuffer(
+        geometry: RTCGeometry,
+        kind: c_uint,
+        slot: c_uint,
+        format: c_uint,
+        byte_stride: usize,
+        item_count: usize,
+    ) -> *mut c_void;
+    fn rtcSetGeometryIntersectFilterFunction(
+        geometry: RTCGeometry,
+        filter: extern "C" fn(*const RTCFilterFunctionNArguments),
+    );
+    fn rtcCommitGeometry(geometry: RTCGeometry);
+    fn rtcAttachGeometry(scene: RTCScene, geometry: RTCGeometry) -> c_uint;
+    fn rtcReleaseGeometry(geometry: RTCGeometry);
+    fn rtcIntersect1(scene: RTCScene, context: *mut RTCIntersectContext, rayhit: *mut RTCRayHit);
+}
+
+// Intersection filter passing through the triangles of `Context::rejected`,
+// and only those: others at the same distance still count.
+extern "C" fn reject(args: *const RTCFilterFunctionNArguments) {
+    unsafe {
+        let args = &*args;
+        let rejected = &*(*(args.context as *const Context)).rejected;
+        let n = args.n as usize;
+        // Past the normals and the barycentrics.
+        let prim_ids = (args.hit as *const c_uint).add(5 * n);
+
+        for k in 0..n {
+            if *args.valid.add(k) != 0 && rejected.contains(&*prim_ids.add(k)) {
+                *args.valid.add(k) = 0;
+            }
+        }
+    }
+}
+
+/// An Embree scene holding one triangle per poly. Triangle ids are the
+/// indices of the polys it was built from.
+pub struct Accel<T> {
+    device: RTCDevice,
+    scene: RTCScene,
+    to_f32: fn(T) -> f32,
+}
+
+// Embree allows concurrent queries on a committed scene.
+unsafe impl<T> Send for Accel<T> {}
+unsafe impl<T> Sync for Accel<T> {}
+
+fn to_f32<T: Into<f64>>(x: T) -> f32 {
+    return x.into() as f32;
+}
+
+impl<T: Float> Accel<T> {
+    pub fn new<S>(polys: &[Poly<T, S>]) -> Accel<T>
+    where
+        T: Into<f64>,
+    {
+        unsafe {
+            let device = rtcNewDevice(ptr::null());
+            assert!(!device.is_null(), "failed to create Embree device");
+
+            let scene = rtcNewScene(device);
+            let geometry = rtcNewGeometry(device, RTC_GEOMETRY_TYPE_TRIANGLE);
+
+            let vertices = rtcSetNewGeometryBuffer(
+                geometry,
+                RTC_BUFFER_TYPE_VERTEX,
+                0,
+                RTC_FORMAT_FLOAT3,
+                3 * std::mem::size_of::<f32>(),
+                3 * polys.len(),
+            ) as *mut [f32; 3];
+
+            let indices = rtcSetNewGeometryBuffer(
+                geometry,
+                RTC_BUFFER_TYPE_INDEX,
+                0,
+                RTC_FORMAT_UINT3,
+                3 * std::mem::size_of::<c_uint>(),
+                polys.len(),
+            ) as *mut [c_uint; 3];
+
+            for (i, p) in polys.iter().enumerate() {
+                for (j, point) in p.points().iter().enumerate() {
+                    *vertices.add(3 * i + j) = point.map(to_f32);
+                }
+
+                let base = (3 * i) as c_uint;
+                *indices.add(i) = [base, base + 1, base + 2];
+            }
+
+            rtcSetGeometryIntersectFilterFunction(geometry, reject);
+            rtcCommitGeometry(geometry);
+            rtcAttachGeometry(scene, geometry);
+            rtcReleaseGeometry(geometry);
+            // Watertight, like `Poly::hit`: rays through shared edges and
+            // corners don't slip between the triangles.
+            rtcSetSceneFlags(scene, RTC_SCENE_FLAG_ROBUST);
+            rtcCommitScene(scene);
+
+            return Accel {
+                device,
+                scene,
+                to_f32: to_f32::<T>,
+            };
+        }
+    }
+
+    /// Returns the index of the closest of `polys` (those it was built
+    /// from) hit by `ray`, for which `skip`, given the index and the hit, is
+    /// false, and the hit.
+    pub fn shoot<S>(
+        &self,
+        polys: &[Poly<T, S>],
+        ray: &Ray<T>,
+        skip: &dyn Fn(usize, &Hit<T>) -> bool,
+    ) -> Option<(usize, Hit<T>)> {
+        // Like `geom::shoot`, only ask `skip` about the closest hit and
+        // shoot again without the poly if it is skipped.
+        let mut rejected: Vec<c_uint> = Vec::new();
+
+        let mut rayhit = RTCRayHit {
+            ray: RTCRay {
+                org: ray.orig.map(self.to_f32),
+                tnear: 0.0,
+                dir: ray.dir.map(self.to_f32),
+                time: 0.0,
+                tfar: f32::INFINITY,
+                mask: c_uint::MAX,
+                id: 0,
+                flags: 0,
+            },
+            hit: RTCHit {
+                ng: [0.0; 3],
+                u: 0.0,
+                v: 0.0,
+                prim_id: RTC_INVALID_GEOMETRY_ID,
+                geom_id: RTC_INVALID_GEOMETRY_ID,
+                inst_id: [RTC_INVALID_GEOMETRY_ID],
+            },
+        };
+
+        loop {
+            let mut context = Context {
+                base: RTCIntersectContext {
+                    flags: 0,
+                    filter: ptr::null(),
+                    inst_id: [RTC_INVALID_GEOMETRY_ID],
+                },
+                rejected: &rejected,
+            };
+
+            unsafe { rtcIntersect1(self.scene, &mut context.base, &mut rayhit) };
+
+            if rayhit.hit.geom_id == RTC_INVALID_GEOMETRY_ID {
+                return None;
+            }
+
+            // Embree only finds the poly, in f32: the hit is computed again
+            // in `T` from the poly, so it is as precise as without Embree.
+            // Should that miss the poly (the ray passes within f32 error
+            // of its edge), the point is still taken from the poly rather
+            // than along the ray with the f32 distance.
+            let i = rayhit.hit.prim_id as usize;
+            let hit = match polys[i].hit(ray) {
+                Some((_, hit)) => hit,
+                None => {
+                    let uv = [rayhit.hit.u, rayhit.hit.v].map(T::from_f32);
+                    Hit {
+                        point: polys[i].position(uv),
+                        uv,
+                    }
+                }
+            };
+
+            if !skip(i, &hit) {
+                return Some((i, hit));
+            }
+
+            rejected.push(rayhit.hit.prim_id);
+            rayhit.ray.tfar = f32::INFINITY;
+            rayhit.hit.geom_id = RTC_INVALID_GEOMETRY_ID;
+        }
+    }
+}
+
+impl<T> Drop for Accel<T> {
+    fn drop(&mut self) {
+        unsafe {
+            rtcReleaseScene(self.scene);
+            rtcReleaseDevice(self.device);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use image::Pixel;
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::geom::Poly;
+use crate::sampling::Rng;
+use crate::surface::{Black, Point, Surface};
+
+// Number of points spread over all emitters.
+const SAMPLES: usize = 16;
+
+/// Emissive polys of a scene, which light it through shadow rays towards
+/// points on them, like `lights`, instead of waiting for rays to hit them.
+///
+/// The points are spread over the polys in proportion to the light they
+/// emit (their area times the emission at their center), and are the same
+/// for every shading point, so shadows come out smooth rather than noisy.
+/// Polys too dim to get a point are left to be hit by rays.
+pub struct Emitters<T> {
+    // Index of the poly, the point on it and the area the point stands for.
+    samples: Vec<(usize, Vector3<T>, T)>,
+    density: Vec<T>, // of points per unit area, per poly
+    // Emitting polys and the light of them and all before, for `pick`.
+    cdf: Vec<(usize, T)>,
+}
+
+impl<T: Float> Emitters<T> {
+    pub fn new<S, P>(polys: &[Poly<T, S>]) -> Emitters<T>
+    where
+        S: Surface<T, P>,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        let mut cdf = Vec::new();
+        let mut total = T::zero();
+
+        for (i, poly) in polys.iter().enumerate() {
+            let power = power(poly);
+
+            if power > T::zero() {
+                total += power;
+                cdf.push((i, total));
+            }
+        }
+
+        if total >= T::from_f64(f64::INFINITY) {
+            cdf.clear();
+        }
+
+        // Stratified picks, counting repeats of a poly.
+        let mut picks: Vec<(usize, usize)> = Vec::new();
+
+        if !cdf.is_empty() {
+            for k in 0..SAMPLES {
+                let target = T::from_f64((k as f64 + 0.5) / SAMPLES as f64) * total;
+                let j = cdf
+                    .partition_point(|(_, c)| *c <= target)
+                    .min(cdf.len() - 1);
+
+                match picks.last_mut() {
+                    Some((l, count)) if *l == j => *count += 1,
+                    _ => picks.push((j, 1)),
+                }
+            }
+        }
+
+        let mut samples = Vec::new();
+        let mut density = vec![T::zero(); polys.len()];
+
+        for (j, count) in picks {
+            let (i, c) = cdf[j];
+            let power = if j == 0 { c } else { c - cdf[j - 1].1 };
+            let poly = &polys[i];
+
+            // The poly's area over the chance of picking a point on it.
+            let weight = area(poly) * total / power / T::from_f64(SAMPLES as f64);
+
+            for k in 0..count {
+                samples.push((i, point_on(poly, k, count), weight));
+            }
+            density[i] = T::one() / weight;
+        }
+
+        return Emitters {
+            samples,
+            density,
+            cdf,
+        };
+    }
+
+    /// Points to cast shadow rays towards: the index of the poly, the point
+    /// on it and the area it stands for.
+    pub fn samples(&self) -> &[(usize, Vector3<T>, T)] {
+        return &self.samples;
+    }
+
+    /// Whether the poly at index `i` is lit through shadow rays, so rays
+    /// hitting it must not count all of its light again.
+    pub fn sampled(&self, i: usize) -> bool {
+        return self.density[i] > T::zero();
+    }
+
+    /// Points per unit area on the poly at index `i`, on average over the
+    /// picks; 0 if it isn't sampled.
+    pub fn density(&self, i: usize) -> T {
+        return self.density[i];
+    }
+
+    /// Emitting poly for `u` in [0, 1), picked by the light it emits like
+    /// the points, as its index and the chance of the pick. `None` if no
+    /// poly emits.
+    pub fn pick(&self, u: f64) -> Option<(usize, T)> {
+        let total = self.cdf.last()?.1;
+        let j = self
+            .cdf
+            .partition_point(|(_, c)| *c <= T::from_f64(u) * total)
+            .min(self.cdf.len() - 1);
+
+        return Some((self.cdf[j].0, self.chance_at(j)));
+    }
+
+    /// Chance of `pick` picking the poly at index `i`.
+    pub fn chance(&self, i: usize) -> T {
+        return match self.cdf.binary_search_by_key(&i, |(k, _)| *k) {
+            Ok(j) => self.chance_at(j),
+            Err(_) => T::zero(),
+        };
+    }
+
+    fn chance_at(&self, j: usize) -> T {
+        let c = self.cdf[j].1;
+        let power = if j == 0 { c } else { c - self.cdf[j - 1].1 };
+        return power / self.cdf[self.cdf.len() - 1].1;
+    }
+}
+
+pub fn area<T: Float, S>(poly: &Poly<T, S>) -> T {
+    let [a, b, c] = *poly.points();
+    let n = vecmath::vec3_cross(vecmath::vec3_sub(b, a), vecmath::vec3_sub(c, a));
+    return vecmath::vec3_len(n) / T::from_f64(2.0);
+}
+
+/// Light `poly` emits, by the emission at its center (mean over the
+/// channels) times its area.
+pub fn power<T, S, P>(poly: &Poly<T, S>) -> T
+where
+    T: Float,
+    S: Surface<T, P>,
+    P: Pixel<Subpixel = T>,
+{
+    return area(poly) * emission(poly);
+}
+
+// Mean over the channels of the light leaving the center of `poly`.
+fn emission<T, S, P>(poly: &Poly<T, S>) -> T
+where
+    T: Float,
+    S: Surface<T, P>,
+    P: Pixel<Subpixel = T>,
+{
+    let [a, b, c] = *poly.points();
+    let center = vecmath::vec3_scale(
+        vecmath::vec3_add(a, vecmath::vec3_add(b, c)),
+        T::from_f64(1.0 / 3.0),
+    );
+
+    let e = poly.surface.emitted(Point {
+        pos: center,
+        uv: poly.uv_at(poly.barycentrics(center)),
+        n: *poly.n(),
+        tangent: poly.tangent(),
+        id: poly.id(),
+        dist: T::zero(),
+        footprint: T::zero(),
+        uv_footprint: T::zero(),
+        outside: T::one(),
+    });
+
+    let channels = e.channels();
+    let sum = channels.iter().fold(T::zero(), |s, x| s + *x);
+
+    return sum / T::from_f64(channels.len() as f64);
+}
+
+// Point `k` of `count` evenly spread over `poly`.
+fn point_on<T: Float, S>(poly: &Poly<T, S>, k: usize, count: usize) -> Vector3<T> {
+    // Golden ratio conjugate.
+    const G: f64 = 0.618_033_988_749_895;
+
+    let u = (k as f64 + 0.5) / count as f64;
+    let v = (0.5 + k as f64 * G).fract();
+
+    return uniform_on(poly, u, v);
+}
+
+/// Random point on `poly`, uniform over its area.
+pub fn random_point<T: Float, S>(poly: &Poly<T, S>, rng: &mut Rng) -> Vector3<T> {
+    return uniform_on(poly, rng.unit(), rng.unit());
+}
+
+// Point on `poly` for (`u`, `v`) in [0, 1)², uniform over the triangle for
+// uniform (`u`, `v`).
+fn uniform_on<T: Float, S>(poly: &Poly<T, S>, u: f64, v: f64) -> Vector3<T> {
+    let s = u.sqrt();
+    let [b0, b1, b2] = [1.0 - s, s * (1.0 - v), s * v].map(T::from_f64);
+
+    let [p0, p1, p2] = *poly.points();
+
+    return vecmath::vec3_add(
+        vecmath::vec3_scale(p0, b0),
+        vecmath::vec3_add(vecmath::vec3_scale(p1, b1), vecmath::vec3_scale(p2, b2)),
+    );
+}
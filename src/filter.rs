@@ -0,0 +1,80 @@
+/// How the samples of the pixels around are weighted into a pixel, by
+/// their offset from its center (in pixels). Wider filters than `Box`
+/// blend across pixel borders, which smooths edges.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Filter {
+    /// Just the pixel's own samples, equally. Sharp, but edges step.
+    Box,
+    /// Falling off linearly to a pixel away.
+    Tent,
+    /// Gaussian falling off to 1.5 pixels away. Smooth, slightly soft.
+    Gaussian,
+    /// Mitchell–Netravali (B = C = 1/3) to 2 pixels away, with negative
+    /// lobes that keep edges crisp.
+    Mitchell,
+}
+
+// Standard deviation of `Filter::Gaussian`, in pixels.
+const SIGMA: f64 = 0.5;
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        return match name {
+            "box" => Some(Filter::Box),
+            "tent" => Some(Filter::Tent),
+            "gaussian" => Some(Filter::Gaussian),
+            "mitchell" => Some(Filter::Mitchell),
+            _ => None,
+        };
+    }
+
+    /// Distance from the center, in pixels, beyond which the weight is 0.
+    pub fn radius(self) -> f64 {
+        return match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0,
+        };
+    }
+
+    /// Weight of a sample `dx`, `dy` away from the center of a pixel.
+    pub fn weight(self, dx: f64, dy: f64) -> f64 {
+        return self.weight_1d(dx) * self.weight_1d(dy);
+    }
+
+    fn weight_1d(self, d: f64) -> f64 {
+        let r = self.radius();
+
+        return match self {
+            // Half open, so every sample falls into exactly one pixel.
+            Filter::Box if -r <= d && d < r => 1.0,
+            Filter::Box => 0.0,
+            Filter::Tent => (r - d.abs()).max(0.0),
+            // Shifted down to reach 0 at the radius.
+            Filter::Gaussian => {
+                let g = |x: f64| (-x * x / (2.0 * SIGMA * SIGMA)).exp();
+                (g(d) - g(r)).max(0.0)
+            }
+            Filter::Mitchell => mitchell(d.abs(), 1.0 / 3.0, 1.0 / 3.0),
+        };
+    }
+}
+
+// The Mitchell–Netravali cubic at `x` ≥ 0.
+fn mitchell(x: f64, b: f64, c: f64) -> f64 {
+    let res = if x < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * x * x * x
+            + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+            + (6.0 - 2.0 * b)
+    } else if x < 2.0 {
+        (-b - 6.0 * c) * x * x * x
+            + (6.0 * b + 30.0 * c) * x * x
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    } else {
+        0.0
+    };
+
+    return res / 6.0;
+}
@@ -0,0 +1,227 @@
+use image::Pixel;
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::geom::{self, Poly};
+use crate::lights::{Environment, Light};
+use crate::sampling::Rng;
+use crate::surface::{Black, Point, Surface};
+
+/// Stable 64-bit FNV-1a hash.
+///
+/// Unlike `std::hash`, the result does not change between runs, platforms
+/// or Rust versions, so it can be used to key data on disk.
+pub struct Fingerprint {
+    state: u64,
+}
+
+impl Default for Fingerprint {
+    fn default() -> Fingerprint {
+        return Fingerprint::new();
+    }
+}
+
+impl Fingerprint {
+    pub fn new() -> Fingerprint {
+        return Fingerprint {
+            state: 0xcbf2_9ce4_8422_2325,
+        };
+    }
+
+    pub fn finish(&self) -> u64 {
+        return self.state;
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.state ^= *b as u64;
+            self.state = self.state.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.write_bytes(&v.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, v: u64) {
+        self.write_bytes(&v.to_le_bytes());
+    }
+
+    pub fn write_f64<T: Into<f64>>(&mut self, v: T) {
+        self.write_bytes(&v.into().to_bits().to_le_bytes());
+    }
+
+    pub fn write_vec3<T: Into<f64> + Copy>(&mut self, v: Vector3<T>) {
+        for x in v.iter() {
+            self.write_f64(*x);
+        }
+    }
+
+    pub fn write_pixel<T: Into<f64> + Copy, P: Pixel<Subpixel = T>>(&mut self, p: &P) {
+        for x in p.channels() {
+            self.write_f64(*x);
+        }
+    }
+
+    /// Hashes the geometry of `poly` and the behaviour of its surface.
+    pub fn write_poly<T, P, S>(&mut self, poly: &Poly<T, S>)
+    where
+        T: Float + Into<f64>,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+        S: Surface<T, P>,
+    {
+        for p in poly.points().iter() {
+            self.write_vec3(*p);
+        }
+
+        self.write_f64(poly.bias());
+        self.write_u32(poly.light_groups());
+        self.write_u32(poly.light_mask());
+        self.write_u32(poly.shadow_mask());
+        self.write_f64(poly.shadow_intensity());
+        self.write_u32(poly.sides() as u32);
+        self.write_u32(poly.id());
+        self.write_u32(poly.material());
+        for uv in poly.uvs().iter() {
+            self.write_f64(uv[0]);
+            self.write_f64(uv[1]);
+        }
+        self.write_surface(&poly.surface);
+    }
+
+    /// Surfaces are opaque, so we hash their response to a fixed set of
+    /// directions instead of their parameters, and the directions they
+    /// sample with a fixed seed.
+    pub fn write_surface<T, P, S>(&mut self, surface: &S)
+    where
+        T: Float + Into<f64>,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+        S: Surface<T, P> + ?Sized,
+    {
+        let v = |x: f64, y: f64, z: f64| {
+            vecmath::vec3_normalized([T::from_f64(x), T::from_f64(y), T::from_f64(z)])
+        };
+
+        let n = v(0.0, 1.0, 0.0);
+        let point = |pos, u: f64, w: f64, dist: f64| Point {
+            pos,
+            uv: [T::from_f64(u), T::from_f64(w)],
+            n,
+            tangent: geom::basis(n)[0],
+            id: 0,
+            dist: T::from_f64(dist),
+            footprint: T::zero(),
+            uv_footprint: T::zero(),
+            outside: T::one(),
+        };
+        let points = [
+            point([T::zero(); 3], 0.0, 0.0, 0.0),
+            point(v(0.3, 0.7, -1.9), 0.3, 0.8, 0.5),
+            point(v(-2.6, 1.1, 0.4), 0.9, 0.1, 2.0),
+        ];
+        let outs = [v(0.3, -1.0, 0.2), v(-0.9, -0.1, 0.4), v(0.2, 0.8, -0.5)];
+        let ins = [v(0.0, 1.0, 0.0), v(0.6, 0.5, -0.6), v(-0.3, -0.9, 0.1)];
+
+        for p in points.iter() {
+            self.write_pixel(&surface.emitted(*p));
+            self.write_u32(surface.opaque(*p) as u32);
+        }
+
+        if let Some(bias) = surface.bias() {
+            self.write_f64(bias);
+        }
+
+        if let Some(indirect) = surface.indirect() {
+            self.write_surface(indirect);
+        }
+
+        if let Some(absorption) = surface.interior() {
+            self.write_pixel(&absorption);
+        }
+
+        if let Some(ior) = surface.ior() {
+            self.write_f64(ior);
+        }
+        self.write_u32(surface.priority());
+
+        for o in outs.iter() {
+            for i in ins.iter() {
+                for p in points.iter() {
+                    self.write_pixel(&surface.reflected(*p, *i, *o));
+                }
+            }
+
+            for (dir, weight) in surface.specular(points[0], *o) {
+                self.write_vec3(dir);
+                self.write_pixel(&weight);
+            }
+
+            let mut rng = Rng::new(1);
+            for p in points.iter() {
+                if let Some((dir, pdf, weight)) = surface.sample(*p, *o, &mut rng) {
+                    self.write_vec3(dir);
+                    self.write_f64(pdf);
+                    self.write_pixel(&weight);
+                }
+
+                for i in ins.iter() {
+                    self.write_f64(surface.pdf(*p, *o, *i));
+                }
+            }
+        }
+    }
+
+    /// Like surfaces, lights are hashed by the light they cast on a fixed
+    /// set of points: a grid, so lights shining into a narrow cone reach
+    /// some of them.
+    pub fn write_light<T, P, L>(&mut self, light: &L)
+    where
+        T: Float + Into<f64>,
+        P: Pixel<Subpixel = T>,
+        L: Light<T, P> + ?Sized,
+    {
+        self.write_u32(light.light_groups());
+        self.write_f64(light.shadow_intensity());
+
+        let steps = [-20.3, -10.1, 0.2, 9.8, 19.7];
+
+        for x in steps.iter() {
+            for y in steps.iter() {
+                for z in steps.iter() {
+                    let p = [*x, *y, *z].map(T::from_f64);
+
+                    for (dir, dist, irradiance) in light.incident(p) {
+                        self.write_vec3(dir);
+                        self.write_f64(dist);
+                        self.write_pixel(&irradiance);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hashes the shadow rays of the environment and the radiance rays
+    /// missing the scene see in a few directions.
+    pub fn write_environment<T, P, E>(&mut self, env: &E)
+    where
+        T: Float + Into<f64>,
+        P: Pixel<Subpixel = T>,
+        E: Environment<T, P> + ?Sized,
+    {
+        self.write_light(env);
+
+        let dirs = [[0.0, 1.0, 0.0], [0.6, -0.5, 0.6], [-0.3, 0.1, -0.9]];
+        for d in dirs.iter() {
+            self.write_pixel(&env.radiance(d.map(T::from_f64)));
+        }
+
+        let orig = [1.0, 2.0, 3.0].map(T::from_f64);
+        if let Some((through, haze)) =
+            env.aerial(orig, dirs[1].map(T::from_f64), T::from_f64(100.0))
+        {
+            self.write_pixel(&through);
+            self.write_pixel(&haze);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::geom::Poly;
+
+/// Returns the polys that may be seen from `orig` in the view spanned by
+/// the directions `corners` (given in order around the frame).
+///
+/// A poly is dropped if all its points are outside one of the planes
+/// bounding the view, so the result is conservative.
+pub fn cull<T: Float, S>(
+    polys: &[Poly<T, S>],
+    orig: Vector3<T>,
+    corners: [Vector3<T>; 4],
+) -> Vec<&Poly<T, S>> {
+    let center = corners
+        .iter()
+        .fold([T::zero(), T::zero(), T::zero()], |a, c| {
+            vecmath::vec3_add(a, *c)
+        });
+
+    let mut planes = Vec::with_capacity(5);
+
+    for i in 0..4 {
+        let n = vecmath::vec3_cross(corners[i], corners[(i + 1) % 4]);
+
+        // Point the normal inwards.
+        if vecmath::vec3_dot(n, center) < T::zero() {
+            planes.push(vecmath::vec3_neg(n));
+        } else {
+            planes.push(n);
+        }
+    }
+
+    // Nothing behind the camera is visible.
+    planes.push(center);
+
+    return polys
+        .iter()
+        .filter(|p| {
+            !planes.iter().any(|n| {
+                p.points()
+                    .iter()
+                    .all(|x| vecmath::vec3_dot(vecmath::vec3_sub(*x, orig), *n) < T::zero())
+            })
+        })
+        .collect();
+}
@@ -0,0 +1,163 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use image::{Pixel, Rgb};
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::surface::{self, Black, Point, Surface};
+
+// Quadrature steps over the polar angle, twice as many go around.
+const STEPS: u32 = 90;
+
+// Slack for the quadrature error.
+const TOLERANCE: f64 = 0.01;
+
+/// Fraction of the light arriving from every direction alike that
+/// `surface` sends along `-o` at point `p`, per channel: `reflected`
+/// integrated over the sphere (with the cosine) plus the `specular` weights.
+///
+/// Above 1 the surface creates energy, i.e. it glows in a white furnace,
+/// a scene lit uniformly from all sides.
+pub fn albedo<T, P, S>(surface: &S, p: Point<T>, o: Vector3<T>) -> P
+where
+    T: Float,
+    P: Pixel<Subpixel = T> + Black,
+    S: ?Sized + Surface<T, P>,
+{
+    let mut res = P::black();
+
+    for a in 0..STEPS {
+        let theta = (a as f64 + 0.5) / STEPS as f64 * PI;
+
+        for b in 0..2 * STEPS {
+            let phi = (b as f64 + 0.5) / STEPS as f64 * PI;
+
+            let d = [
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            ];
+            let i = local(p, d);
+
+            // Solid angle of the step, times the cosine to the normal, over
+            // pi for the albedo convention of `reflected`.
+            let w = theta.sin() * theta.cos().abs() * (PI / STEPS as f64).powi(2) / PI;
+            let w = T::from_f64(w);
+
+            let refl = surface.reflected(p, i, o);
+            res = res.map2(&refl, |x, y| x + y * w);
+        }
+    }
+
+    for (_, weight) in surface.specular(p, o) {
+        res = res.map2(&weight, |x, y| x + y);
+    }
+
+    return res;
+}
+
+// `d` given with y along the normal of `p` in its world space.
+fn local<T: Float>(p: Point<T>, d: [f64; 3]) -> Vector3<T> {
+    let b = vecmath::vec3_cross(p.n, p.tangent);
+    let [x, y, z] = d.map(T::from_f64);
+
+    return vecmath::vec3_add(
+        vecmath::vec3_scale(p.tangent, x),
+        vecmath::vec3_add(vecmath::vec3_scale(p.n, y), vecmath::vec3_scale(b, z)),
+    );
+}
+
+/// Largest `albedo` channel of `surface` for rays arriving at various
+/// angles to the normal, and the angle (in degrees) it occurs at.
+pub fn max_albedo<S: ?Sized + Surface<f64, Rgb<f64>>>(surface: &S) -> (f64, f64) {
+    let p = Point {
+        pos: [0.0, 0.0, 0.0],
+        uv: [0.5, 0.5],
+        n: [0.0, 1.0, 0.0],
+        tangent: [1.0, 0.0, 0.0],
+        id: 0,
+        dist: 1.0,
+        footprint: 0.0,
+        uv_footprint: 0.0,
+        outside: 1.0,
+    };
+
+    let mut res = (0.0, 0.0);
+
+    for angle in [0.0, 30.0, 60.0, 85.0].iter() {
+        let a: f64 = angle * PI / 180.0;
+        let o = [a.sin(), -a.cos(), 0.0];
+
+        for c in albedo(surface, p, o).channels() {
+            if *c > res.0 {
+                res = (*c, *angle);
+            }
+        }
+    }
+
+    return res;
+}
+
+// Name of a surface and the surface.
+type Case = (&'static str, Arc<dyn Surface<f64, Rgb<f64>>>);
+
+/// Runs the white furnace test on the built in surfaces, and reports those
+/// reflecting more light than they receive. Returns whether none did.
+pub fn run() -> bool {
+    let white = Rgb([1.0, 1.0, 1.0]);
+
+    let surfaces: Vec<Case> = vec![
+        ("matt", surface::matt(white)),
+        ("phong", surface::phong(Rgb([0.5; 3]), Rgb([0.5; 3]), 20.0)),
+        (
+            "blinn_phong",
+            surface::blinn_phong(Rgb([0.5; 3]), Rgb([0.5; 3]), 20.0),
+        ),
+        ("mirror", surface::mirror(white)),
+        ("glass", surface::glass(1.5, white)),
+        (
+            "dispersive_glass",
+            surface::dispersive_glass(surface::cauchy(1.5, 0.01), white),
+        ),
+        ("subsurface", surface::subsurface(white, Rgb([1.0; 3]))),
+        ("ggx", surface::ggx(white, 0.5)),
+        ("ggx_aniso", surface::ggx_aniso(white, 0.2, 0.6)),
+        ("metal", surface::metal(surface::Metal::Silver, 0.3)),
+        ("principled", surface::principled(white, 0.0, 0.5, 0.5, 0.5)),
+        ("toon", surface::toon(white, 3, 0.0)),
+        ("glossy", surface::glossy(white, 0.3)),
+        (
+            "mix",
+            surface::mix(surface::matt(white), surface::mirror(white), 0.5),
+        ),
+        ("clearcoat", surface::clearcoat(surface::matt(white), 1.5)),
+        (
+            "thin_film",
+            surface::thin_film(surface::matt(white), 300.0, 1.4),
+        ),
+    ];
+
+    let mut failed = 0;
+
+    for (name, s) in surfaces.iter() {
+        let (albedo, angle) = max_albedo(&**s);
+        let gains = albedo > 1.0 + TOLERANCE;
+
+        println!(
+            "{}: albedo {:.3} at {}°{}",
+            name,
+            albedo,
+            angle,
+            if gains { ", gains energy" } else { "" }
+        );
+
+        if gains {
+            failed += 1;
+        }
+    }
+
+    println!("{}", if failed == 0 { "PASS" } else { "FAIL" });
+
+    return failed == 0;
+}
@@ -6,98 +6,554 @@ use vecmath::Vector3;
 pub struct Ray<T> {
     pub orig: Vector3<T>,
     pub dir: Vector3<T>,
+    // Cone around the ray approximating the area it stands for: its width
+    // at `orig` and its growth per unit of distance.
+    pub width: T,
+    pub spread: T,
+    // Towards the rays of the neighbouring pixels, where known. More
+    // precise than the cone, which they take over from.
+    pub diffs: Option<[Differential<T>; 2]>,
+    // When it was shot, within the shutter interval of the camera. The rays
+    // of a path from the camera share it, all others are at 0.
+    pub time: T,
+}
+
+/// Change of a ray towards the ray through the next pixel along one axis
+/// of the image: of its origin and of its direction (Igehy, "Tracing Ray
+/// Differentials").
+#[derive(Clone, Copy)]
+pub struct Differential<T> {
+    pub orig: Vector3<T>,
+    pub dir: Vector3<T>,
+}
+
+impl<T: Float> Ray<T> {
+    /// Ray leaving a surface with normal `n` at `point` into `dir`. The
+    /// origin is moved by `bias` off the surface, to the side `dir` points to.
+    pub fn from_surface(point: Vector3<T>, n: Vector3<T>, dir: Vector3<T>, bias: T) -> Ray<T> {
+        let side = vecmath::vec3_dot(dir, n).signum();
+
+        return Ray {
+            orig: vecmath::vec3_add(point, vecmath::vec3_scale(n, bias * side)),
+            dir,
+            width: T::zero(),
+            spread: T::zero(),
+            diffs: None,
+            time: T::zero(),
+        };
+    }
+
+    /// Width of the ray cone at distance `dist` from the origin.
+    pub fn footprint(&self, dist: T) -> T {
+        return self.width + self.spread * dist;
+    }
+
+    /// Offsets from the hit at `dist` on a plane with normal `n` to where
+    /// the neighbouring rays hit that plane, if the ray has differentials
+    /// and doesn't run along the plane.
+    pub fn offsets(&self, dist: T, n: Vector3<T>) -> Option<[Vector3<T>; 2]> {
+        let diffs = self.diffs?;
+
+        let cos = vecmath::vec3_dot(self.dir, n);
+        if cos == T::zero() {
+            return None;
+        }
+
+        // Moved along the ray, so the offset stays in the plane.
+        return Some(diffs.map(|d| {
+            let off = vecmath::vec3_add(d.orig, vecmath::vec3_scale(d.dir, dist));
+            let along = -vecmath::vec3_dot(off, n) / cos;
+            vecmath::vec3_add(off, vecmath::vec3_scale(self.dir, along))
+        }));
+    }
+
+    /// Differentials of the ray leaving the hit at `dist` on a plane with
+    /// normal `n`: mirrored if it is the mirror image of this one, else
+    /// (lenses count as flat, as for the cone) going on unbent.
+    pub fn bounced(&self, dist: T, n: Vector3<T>, mirror: bool) -> Option<[Differential<T>; 2]> {
+        let offsets = self.offsets(dist, n)?;
+        let diffs = self.diffs?;
+
+        let two = T::from_f64(2.0);
+
+        return Some([0, 1].map(|k| {
+            let dir = diffs[k].dir;
+
+            Differential {
+                orig: offsets[k],
+                dir: if mirror {
+                    vecmath::vec3_sub(dir, vecmath::vec3_scale(n, two * vecmath::vec3_dot(dir, n)))
+                } else {
+                    dir
+                },
+            }
+        }));
+    }
+}
+
+/// How the back of a poly (the side its normal points away from) behaves.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sides {
+    /// Both sides are shaded by the surface.
+    Double,
+    /// The back is black, but still blocks rays.
+    BackBlack,
+    /// Rays hitting the back pass through.
+    BackCull,
+}
+
+/// Two unit vectors that form an orthonormal basis with the unit vector `n`.
+pub fn basis<T: Float>(n: Vector3<T>) -> [Vector3<T>; 2] {
+    // Start from the axis least aligned with `n`.
+    let a = if n[0] * n[0] < T::from_f64(0.5) {
+        [T::one(), T::zero(), T::zero()]
+    } else {
+        [T::zero(), T::one(), T::zero()]
+    };
+
+    let t = vecmath::vec3_normalized(vecmath::vec3_cross(n, a));
+    let b = vecmath::vec3_cross(n, t);
+
+    return [t, b];
 }
 
 pub struct Poly<T, S> {
     points: [Vector3<T>; 3],
-    plane: Plane<T>,
+    // Edges from the first point, precomputed for intersection.
+    e1: Vector3<T>,
+    e2: Vector3<T>,
+    n: Vector3<T>,
+    // Offset of secondary ray origins along the normal.
+    bias: T,
+    // Light linking: groups this poly emits light for, and groups whose
+    // light it receives. Bit masks, all set by default.
+    light_groups: u32,
+    light_mask: u32,
+    // Shadow linking: groups of lights this poly casts shadows for, and how
+    // strong the shadows of this poly (as an emitter) are, in [0, 1].
+    shadow_mask: u32,
+    shadow_intensity: T,
+    sides: Sides,
+    // Texture coordinates of the points.
+    uvs: [[T; 2]; 3],
+    // Object the poly belongs to, for shading and AOVs. 0 by default.
+    id: u32,
+    // Material of the poly, for AOVs. 0 by default.
+    material: u32,
     pub surface: S,
 }
 
-struct Plane<T> {
-    n: Vector3<T>,
-    d: T,
+/// Point on a poly, hit by a ray.
+#[derive(Clone, Copy)]
+pub struct Hit<T> {
+    pub point: Vector3<T>,
+    // Barycentric coordinates of the point w.r.t. points[1] and points[2],
+    // see `Poly::uv_at`.
+    pub uv: [T; 2],
 }
 
-struct Hit<T> {
-    point: Vector3<T>,
-    dist: T,
-}
+/// Whether a hit of a poly counts, or the ray passes through.
+pub type Keep<'a, T, S> = dyn 'a + Fn(&Poly<T, S>, &Hit<T>) -> bool;
 
-pub fn shoot<'a, T: Float, S, I: Iterator<Item = &'a Poly<T, S>>>(
+/// Closest hit of `ray` with `polys` (and the poly hit) that `keep`,
+/// given the poly and the hit, accepts.
+pub fn shoot<'a, T: Float, S: 'a, I: Clone + Iterator<Item = &'a Poly<T, S>>>(
     polys: I,
     ray: &Ray<T>,
-) -> Option<(Vector3<T>, &'a Poly<T, S>)> {
-    let mut closest: Option<(Hit<T>, &Poly<T, S>)> = None;
+    keep: &Keep<T, S>,
+) -> Option<(Hit<T>, &'a Poly<T, S>)> {
+    // Rejected hits are rare, so rather than asking `keep` about every hit,
+    // only ask about the closest one and shoot again if it is rejected.
+    let mut rejected: Vec<&Poly<T, S>> = Vec::new();
 
-    for p in polys {
-        if let Some(h) = p.hit(ray) {
-            if closest.as_ref().map_or(true, |c| c.0.dist > h.dist) {
-                closest = Some((h, &p));
+    loop {
+        let mut closest: Option<(T, Hit<T>, &Poly<T, S>)> = None;
+
+        for p in polys.clone() {
+            if rejected.iter().any(|r| std::ptr::eq(*r, p)) {
+                continue;
+            }
+
+            if let Some((d, h)) = p.hit(ray) {
+                if closest.as_ref().is_none_or(|c| c.0 > d) {
+                    closest = Some((d, h, p));
+                }
             }
         }
+
+        match closest {
+            None => return None,
+            Some((_, h, p)) if keep(p, &h) => return Some((h, p)),
+            Some((_, _, p)) => rejected.push(p),
+        }
     }
+}
+
+/// Axis aligned bounding box (min and max corner) of `polys`, `None` if
+/// there are none.
+pub fn bounds<'a, T: Float, S: 'a, I: Iterator<Item = &'a Poly<T, S>>>(
+    polys: I,
+) -> Option<[Vector3<T>; 2]> {
+    let mut res: Option<[Vector3<T>; 2]> = None;
+
+    for p in polys.flat_map(|p| p.points.iter()) {
+        res = Some(match res {
+            None => [*p, *p],
+            Some([lo, hi]) => [
+                [lo[0].min(p[0]), lo[1].min(p[1]), lo[2].min(p[2])],
+                [hi[0].max(p[0]), hi[1].max(p[1]), hi[2].max(p[2])],
+            ],
+        });
+    }
+
+    return res;
+}
+
+/// All hits of `ray` with `polys`, closest first, with their distances.
+pub fn hits<'a, T: Float, S, I: Iterator<Item = &'a Poly<T, S>>>(
+    polys: I,
+    ray: &Ray<T>,
+) -> Vec<(T, Hit<T>, &'a Poly<T, S>)> {
+    let mut res: Vec<_> = polys
+        .filter_map(|p| p.hit(ray).map(|(d, h)| (d, h, p)))
+        .collect();
+
+    // Distances are never NaN for a hit.
+    res.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
 
-    return closest.map(|x| (x.0.point, x.1));
+    return res;
 }
 
 impl<T: Float, S> Poly<T, S> {
     pub fn new(points: [Vector3<T>; 3], surface: S) -> Poly<T, S> {
-        let plane = {
-            // Surface normal.
-            let n = vecmath::vec3_normalized(vecmath::vec3_cross(
-                vecmath::vec3_sub(points[1], points[0]),
-                vecmath::vec3_sub(points[2], points[0]),
-            ));
+        let e1 = vecmath::vec3_sub(points[1], points[0]);
+        let e2 = vecmath::vec3_sub(points[2], points[0]);
 
-            // Surface to origin distance.
-            let d = vecmath::vec3_dot(n, points[0]);
+        // Surface normal.
+        let n = vecmath::vec3_normalized(vecmath::vec3_cross(e1, e2));
 
-            Plane { n, d }
+        // Default bias is relative to the size of the poly, so huge floors
+        // and tiny details both get a sensible offset.
+        let bias = {
+            let e3 = vecmath::vec3_sub(points[2], points[1]);
+            let size = vecmath::vec3_square_len(e1)
+                .max(vecmath::vec3_square_len(e2))
+                .max(vecmath::vec3_square_len(e3))
+                .sqrt();
+
+            size * T::from_f64(1e-6)
         };
 
         return Poly {
             points,
-            plane,
+            e1,
+            e2,
+            n,
+            bias,
+            light_groups: !0,
+            light_mask: !0,
+            shadow_mask: !0,
+            shadow_intensity: T::one(),
+            sides: Sides::Double,
+            uvs: [
+                [T::zero(), T::zero()],
+                [T::one(), T::zero()],
+                [T::zero(), T::one()],
+            ],
+            id: 0,
+            material: 0,
             surface,
         };
     }
 
-    pub fn n(&self) -> &Vector3<T> {
-        return &self.plane.n;
+    pub fn translate(&mut self, offset: Vector3<T>) {
+        for p in self.points.iter_mut() {
+            *p = vecmath::vec3_add(*p, offset);
+        }
     }
 
-    fn hit(&self, ray: &Ray<T>) -> Option<Hit<T>> {
-        let n = self.plane.n;
+    pub fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
 
-        let dot = vecmath::vec3_dot(n, ray.dir);
+    pub fn id(&self) -> u32 {
+        return self.id;
+    }
 
-        if dot == T::zero() {
-            // Ray is parallel to plane.
+    pub fn set_material(&mut self, material: u32) {
+        self.material = material;
+    }
+
+    pub fn material(&self) -> u32 {
+        return self.material;
+    }
+
+    pub fn set_uvs(&mut self, uvs: [[T; 2]; 3]) {
+        self.uvs = uvs;
+    }
+
+    pub fn uvs(&self) -> [[T; 2]; 3] {
+        return self.uvs;
+    }
+
+    /// Direction of increasing u in the plane of the poly, or an arbitrary
+    /// one in the plane if the texture coordinates are degenerate.
+    pub fn tangent(&self) -> Vector3<T> {
+        let [t0, t1, t2] = self.uvs;
+        let (du1, dv1) = (t1[0] - t0[0], t1[1] - t0[1]);
+        let (du2, dv2) = (t2[0] - t0[0], t2[1] - t0[1]);
+
+        // e1 = du1 * t + dv1 * b and e2 = du2 * t + dv2 * b, solved for t.
+        let det = du1 * dv2 - du2 * dv1;
+        let t = vecmath::vec3_sub(
+            vecmath::vec3_scale(self.e1, dv2),
+            vecmath::vec3_scale(self.e2, dv1),
+        );
+
+        if det == T::zero() || vecmath::vec3_len(t) == T::zero() {
+            return basis(self.n)[0];
+        }
+
+        let t = if det < T::zero() {
+            vecmath::vec3_neg(t)
+        } else {
+            t
+        };
+
+        return vecmath::vec3_normalized(t);
+    }
+
+    /// Texture coordinate units per world space unit (on average).
+    pub fn uv_density(&self) -> T {
+        let [t0, t1, t2] = self.uvs;
+        let (a, b) = (
+            [t1[0] - t0[0], t1[1] - t0[1]],
+            [t2[0] - t0[0], t2[1] - t0[1]],
+        );
+
+        let uv_area = a[0] * b[1] - a[1] * b[0];
+        let area = vecmath::vec3_len(vecmath::vec3_cross(self.e1, self.e2));
+
+        if area == T::zero() {
+            return T::zero();
+        }
+
+        let ratio = uv_area / area;
+        return if ratio < T::zero() { -ratio } else { ratio }.sqrt();
+    }
+
+    /// Barycentric coordinates of `point` (projected into the plane of the
+    /// poly), as for `Hit::uv`, for points not hit by a ray.
+    pub fn barycentrics(&self, point: Vector3<T>) -> [T; 2] {
+        let v = vecmath::vec3_sub(point, self.points[0]);
+
+        let d00 = vecmath::vec3_dot(self.e1, self.e1);
+        let d01 = vecmath::vec3_dot(self.e1, self.e2);
+        let d11 = vecmath::vec3_dot(self.e2, self.e2);
+        let d20 = vecmath::vec3_dot(v, self.e1);
+        let d21 = vecmath::vec3_dot(v, self.e2);
+
+        let denom = d00 * d11 - d01 * d01;
+        let b1 = (d11 * d20 - d01 * d21) / denom;
+        let b2 = (d00 * d21 - d01 * d20) / denom;
+
+        return [b1, b2];
+    }
+
+    /// The hit at `point` (in the plane of the poly), see `barycentrics`.
+    pub fn hit_at(&self, point: Vector3<T>) -> Hit<T> {
+        return Hit {
+            point,
+            uv: self.barycentrics(point),
+        };
+    }
+
+    /// Point at the barycentric coordinates `uv`, computed from the poly
+    /// rather than from a ray: it stays precise even if the ray origin is
+    /// far away.
+    pub fn position(&self, uv: [T; 2]) -> Vector3<T> {
+        return vecmath::vec3_add(
+            self.points[0],
+            vecmath::vec3_add(
+                vecmath::vec3_scale(self.e1, uv[0]),
+                vecmath::vec3_scale(self.e2, uv[1]),
+            ),
+        );
+    }
+
+    /// Texture coordinates at the barycentric coordinates `uv` (see
+    /// `Hit::uv`), interpolated from those of the points.
+    pub fn uv_at(&self, uv: [T; 2]) -> [T; 2] {
+        let [b1, b2] = uv;
+        let b0 = T::one() - b1 - b2;
+
+        let [t0, t1, t2] = self.uvs;
+
+        return [
+            t0[0] * b0 + t1[0] * b1 + t2[0] * b2,
+            t0[1] * b0 + t1[1] * b1 + t2[1] * b2,
+        ];
+    }
+
+    /// Restricts the light emitted by this poly to receivers in `groups`.
+    pub fn set_light_groups(&mut self, groups: u32) {
+        self.light_groups = groups;
+    }
+
+    /// Restricts the light this poly receives to emitters in `mask`.
+    pub fn set_light_mask(&mut self, mask: u32) {
+        self.light_mask = mask;
+    }
+
+    /// Whether light emitted from a poly in `groups` directly lights this
+    /// poly.
+    pub fn lit_by(&self, groups: u32) -> bool {
+        return self.light_mask & groups != 0;
+    }
+
+    pub fn light_groups(&self) -> u32 {
+        return self.light_groups;
+    }
+
+    pub fn light_mask(&self) -> u32 {
+        return self.light_mask;
+    }
+
+    /// Restricts the shadows this poly casts to emitters in `mask`.
+    pub fn set_shadow_mask(&mut self, mask: u32) {
+        self.shadow_mask = mask;
+    }
+
+    /// Scales the shadows cast on light emitted by this poly: 0 means light
+    /// passes through occluders unhindered, 1 (the default) fully blocks it.
+    pub fn set_shadow_intensity(&mut self, intensity: T) {
+        self.shadow_intensity = intensity;
+    }
+
+    pub fn shadow_mask(&self) -> u32 {
+        return self.shadow_mask;
+    }
+
+    pub fn shadow_intensity(&self) -> T {
+        return self.shadow_intensity;
+    }
+
+    /// How much light this poly lets through of an emitter with `links`,
+    /// its light groups and shadow intensity.
+    pub fn transmittance(&self, (groups, intensity): (u32, T)) -> T {
+        if self.shadow_mask & groups == 0 {
+            return T::one();
+        }
+
+        return T::one() - intensity;
+    }
+
+    pub fn set_sides(&mut self, sides: Sides) {
+        self.sides = sides;
+    }
+
+    pub fn sides(&self) -> Sides {
+        return self.sides;
+    }
+
+    /// Whether `dir` points at the back of the poly.
+    pub fn is_back(&self, dir: Vector3<T>) -> bool {
+        return vecmath::vec3_dot(dir, self.n) > T::zero();
+    }
+
+    pub fn bias(&self) -> T {
+        return self.bias;
+    }
+
+    pub fn set_bias(&mut self, bias: T) {
+        self.bias = bias;
+    }
+
+    pub fn points(&self) -> &[Vector3<T>; 3] {
+        return &self.points;
+    }
+
+    pub fn n(&self) -> &Vector3<T> {
+        return &self.n;
+    }
+
+    /// Watertight intersection (Woop, Benthin and Wald, "Watertight
+    /// Ray/Triangle Intersection"): the points are moved into a space where
+    /// the ray runs along the z axis from the origin, so the edge tests
+    /// compute the same values for polys sharing an edge and a ray through
+    /// it hits (at least) one of them. The distance of the hit and the hit.
+    pub fn hit(&self, ray: &Ray<T>) -> Option<(T, Hit<T>)> {
+        if self.sides == Sides::BackCull && self.is_back(ray.dir) {
+            // Ray hits the back.
             return None;
         }
 
-        // Distance of ray to hit point of the plane.
-        let d = (-vecmath::vec3_dot(n, ray.orig) + self.plane.d) / dot;
+        let abs = |x: T| if x < T::zero() { -x } else { x };
+
+        // The axis the ray runs along the most becomes z, the others are
+        // swapped if need be to keep the winding of the points.
+        let d = ray.dir;
+        let kz = if abs(d[0]) > abs(d[1]) {
+            if abs(d[0]) > abs(d[2]) {
+                0
+            } else {
+                2
+            }
+        } else if abs(d[1]) > abs(d[2]) {
+            1
+        } else {
+            2
+        };
+        let (kx, ky) = if d[kz] < T::zero() {
+            ((kz + 2) % 3, (kz + 1) % 3)
+        } else {
+            ((kz + 1) % 3, (kz + 2) % 3)
+        };
+
+        // Shear and scale of the points along the ray.
+        let sz = T::one() / d[kz];
+        let (sx, sy) = (d[kx] * sz, d[ky] * sz);
+
+        let [a, b, c] = self.points.map(|p| vecmath::vec3_sub(p, ray.orig));
+        let (ax, ay) = (a[kx] - sx * a[kz], a[ky] - sy * a[kz]);
+        let (bx, by) = (b[kx] - sx * b[kz], b[ky] - sy * b[kz]);
+        let (cx, cy) = (c[kx] - sx * c[kz], c[ky] - sy * c[kz]);
 
-        if d <= T::zero() {
-            // Plane is behind the ray.
+        // Scaled barycentric coordinates, by the edge opposite each point.
+        let u = cx * by - cy * bx;
+        let v = ax * cy - ay * cx;
+        let w = bx * ay - by * ax;
+
+        // Edges are tested inclusively.
+        if (u < T::zero() || v < T::zero() || w < T::zero())
+            && (u > T::zero() || v > T::zero() || w > T::zero())
+        {
             return None;
         }
 
-        // Hit point on the plane.
-        let p = vecmath::vec3_add(ray.orig, vecmath::vec3_scale(ray.dir, d));
+        let det = u + v + w;
+
+        if det == T::zero() {
+            // Ray is parallel to plane.
+            return None;
+        }
 
-        for i in 0..3 {
-            let edge = vecmath::vec3_sub(self.points[(i + 1) % 3], self.points[i]);
-            let c = vecmath::vec3_sub(p, self.points[i]);
+        // Distance of ray to hit point.
+        let t = (u * a[kz] + v * b[kz] + w * c[kz]) * sz / det;
 
-            if vecmath::vec3_dot(n, vecmath::vec3_cross(edge, c)) < T::zero() {
-                // Point is on wrong side of edge.
-                return None;
-            }
+        if t <= T::zero() {
+            // Poly is behind the ray.
+            return None;
         }
 
-        return Some(Hit { point: p, dist: d });
+        let uv = [v / det, w / det];
+
+        return Some((
+            t,
+            Hit {
+                point: self.position(uv),
+                uv,
+            },
+        ));
     }
 }
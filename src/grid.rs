@@ -0,0 +1,219 @@
+use std::convert::TryInto;
+
+use image::Pixel;
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::fingerprint::Fingerprint;
+use crate::geom::{Hit, Poly, Ray};
+use crate::integrator::Integrator;
+use crate::medium::Stack;
+use crate::passes::{Lobe, Passes};
+use crate::sampling::Rng;
+use crate::surface::{Black, Surface};
+use crate::tracer::Tracer;
+use crate::{crossed, Scene};
+
+/// Ray tracer branching into a fixed grid of directions at every bounce,
+/// besides shadow rays and mirrors and lenses, up to `max_depth` bounces.
+/// The directions add up their light unweighted, so the same scene comes
+/// out brighter than with the integrators weighing light properly.
+pub struct Grid<T> {
+    all_dirs: Vec<Vector3<T>>,
+    max_depth: u32,
+    // See `Tracer::clamped`.
+    clamp: Vec<T>,
+    // See `with_branching`.
+    branching: Vec<u32>,
+}
+
+impl<T: Float + Into<f64>> Grid<T> {
+    /// Branching into `rays`² directions, evenly spaced in angle.
+    pub fn new(rays: u32, max_depth: u32) -> Grid<T> {
+        let step = T::_360() / T::from_u32(rays);
+
+        let u = [T::one(), T::zero(), T::zero()];
+
+        let mut all_dirs = Vec::with_capacity((rays * rays).try_into().unwrap());
+
+        for x in 0..rays {
+            for y in 0..rays {
+                let q = quaternion::euler_angles(
+                    T::from_u32(x) * step,
+                    T::from_u32(y) * step,
+                    T::zero(),
+                );
+                all_dirs.push(quaternion::rotate_vector(q, u));
+            }
+        }
+
+        return Grid {
+            all_dirs,
+            max_depth,
+            clamp: Vec::new(),
+            branching: Vec::new(),
+        };
+    }
+
+    /// Limits the light rays bring to the camera from each bounce on, see
+    /// `Tracer::clamped`.
+    pub fn with_clamp(mut self, clamp: Vec<T>) -> Grid<T> {
+        self.clamp = clamp;
+        return self;
+    }
+
+    /// Limits how many of `all_dirs` each bounce branches into: the first
+    /// limit applies to the first hit, the last to all deeper ones as well
+    /// (none branches into all). The directions are spread evenly over
+    /// `all_dirs` from a random one on and weighted up for the others, so
+    /// light stays at its level, while the cost no longer grows by all of
+    /// `all_dirs` per bounce. Limits must be positive.
+    pub fn with_branching(mut self, branching: Vec<u32>) -> Grid<T> {
+        self.branching = branching;
+        return self;
+    }
+}
+
+impl<T: Float + Into<f64>> Tracer<T> for Grid<T> {
+    fn max_depth(&self) -> u32 {
+        return self.max_depth;
+    }
+
+    fn clamp(&self) -> &[T] {
+        return &self.clamp;
+    }
+
+    /// The directions add up the light of each without weights, as if
+    /// every steradian counted `all_dirs.len() / (4 pi)` times. Sampled
+    /// light is weighted alike (times pi, which `shade` divides by), so it
+    /// looks the same. Without directions light is weighted properly.
+    fn sampled_scale(&self) -> T {
+        if self.all_dirs.is_empty() {
+            return T::one();
+        }
+
+        return T::from_f64(self.all_dirs.len() as f64 / 4.0);
+    }
+
+    fn shade<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Hit<T>,
+        poly: &'a Poly<T, S>,
+        from: Option<&'a Poly<T, S>>,
+        depth: u32,
+        diffuse: Option<T>,
+        throughput: C,
+        media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let (surface, at, emitted) =
+            match self.shaded(scene, ray, hit, poly, from, depth, diffuse, media) {
+                Some(s) => s,
+                None => return Passes::black(),
+            };
+
+        let mut all_light = self.direct(
+            emitted, scene, ray, hit.point, poly, surface, at, depth, false, rng,
+        );
+
+        let bias = surface.bias().unwrap_or_else(|| poly.bias());
+        let footprint = ray.footprint(at.dist);
+
+        // Of `all_dirs`, every `n / k`th from `start` on, see
+        // `with_branching`.
+        let n = self.all_dirs.len();
+        let k = self
+            .branching
+            .get(depth as usize)
+            .or(self.branching.last())
+            .map_or(n, |k| n.min(*k as usize));
+        let start = if k < n {
+            (rng.next() % n as u64) as usize
+        } else {
+            0
+        };
+        let share = T::from_f64(n as f64 / k.max(1) as f64);
+
+        for j in 0..k {
+            let dir = &self.all_dirs[(start + j * n / k) % n];
+            let refl = surface.reflected(at, *dir, ray.dir);
+
+            if refl == C::black() {
+                continue;
+            }
+
+            let v = vecmath::vec3_dot(*dir, *poly.n());
+            let through = (v > T::zero()) == (vecmath::vec3_dot(ray.dir, *poly.n()) > T::zero());
+
+            let r = Ray {
+                width: footprint,
+                spread: ray.spread,
+                time: ray.time,
+                ..Ray::from_surface(hit.point, *poly.n(), *dir, bias)
+            };
+
+            let lambert = {
+                if v < T::zero() {
+                    -v
+                } else {
+                    v
+                }
+            } * share;
+
+            let t = throughput.map2(&refl, |a, b| a * b * lambert);
+            let media = crossed(media, poly, ray.dir, through);
+            let light = self.trace(
+                scene,
+                &r,
+                Some(poly),
+                depth + 1,
+                Some(T::zero()),
+                t,
+                &media,
+                rng,
+            );
+            let light = self
+                .clamped(light, &t, depth + 1)
+                .tagged(Lobe::Diffuse)
+                .map(|x| x.map2(&refl, |a, b| a * b));
+
+            all_light = all_light.zip(&light, |x, y| x.map2(y, |a, b| a + b * lambert));
+        }
+
+        return self.specular(
+            all_light, scene, ray, hit.point, poly, surface, at, depth, throughput, media, rng,
+        );
+    }
+}
+
+impl<T, S, C> Integrator<T, S, C> for Grid<T>
+where
+    T: Float + Into<f64> + Sync,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black + PartialEq,
+{
+    fn light(
+        &self,
+        scene: &Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Option<(Hit<T>, &Poly<T, S>)>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        return self.primary(scene, ray, hit, rng);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"grid");
+        for d in self.all_dirs.iter() {
+            h.write_vec3(*d);
+        }
+        self.write_limits(h);
+        h.write_u32(self.branching.len() as u32);
+        for k in self.branching.iter() {
+            h.write_u32(*k);
+        }
+    }
+}
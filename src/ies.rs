@@ -0,0 +1,185 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind};
+use std::path::Path;
+
+/// Light distribution of a luminaire, as measured in an IES (IESNA LM-63)
+/// photometric file: the luminous intensity in candela towards each
+/// direction.
+///
+/// Directions are given by the vertical angle to the nadir (the direction
+/// the luminaire points to, 0 to 180°) and the horizontal angle around it
+/// (0 to 360°), both in degrees.
+pub struct Profile {
+    vertical: Vec<f64>,
+    horizontal: Vec<f64>,
+    candela: Vec<f64>, // by horizontal, then vertical angle
+    max: f64,
+}
+
+impl Profile {
+    /// Intensity towards the `vertical` and `horizontal` angle, interpolated
+    /// between the measured ones. Outside of the measured vertical range
+    /// the luminaire is dark.
+    pub fn candela(&self, vertical: f64, horizontal: f64) -> f64 {
+        let v = match segment(&self.vertical, vertical) {
+            None => return 0.0,
+            Some(v) => v,
+        };
+
+        // The measured horizontal angles only cover a part of the circle if
+        // the distribution is symmetric, the rest mirrors it.
+        let last = self.horizontal[self.horizontal.len() - 1];
+        let h = horizontal.rem_euclid(360.0);
+        let h = if last <= 0.0 {
+            0.0
+        } else if last <= 90.0 {
+            let h = if h > 180.0 { 360.0 - h } else { h };
+            if h > 90.0 {
+                180.0 - h
+            } else {
+                h
+            }
+        } else if last <= 180.0 && h > 180.0 {
+            360.0 - h
+        } else {
+            h
+        };
+
+        let h = segment(&self.horizontal, h).unwrap_or((0, 0.0));
+
+        let at = |i: usize, j: usize| {
+            let i = i.min(self.horizontal.len() - 1);
+            let j = j.min(self.vertical.len() - 1);
+            self.candela[i * self.vertical.len() + j]
+        };
+
+        let lerp = |i: usize| at(i, v.0) * (1.0 - v.1) + at(i, v.0 + 1) * v.1;
+
+        return lerp(h.0) * (1.0 - h.1) + lerp(h.0 + 1) * h.1;
+    }
+
+    /// Intensity in the brightest measured direction.
+    pub fn max(&self) -> f64 {
+        return self.max;
+    }
+}
+
+// Index of the last of the ascending `angles` not above `x`, and how far
+// `x` is on the way to the next one, or `None` if `x` is out of range.
+fn segment(angles: &[f64], x: f64) -> Option<(usize, f64)> {
+    let first = angles[0];
+    let last = angles[angles.len() - 1];
+
+    if !(first..=last).contains(&x) {
+        return None;
+    }
+
+    let i = angles.partition_point(|a| *a <= x).max(1) - 1;
+
+    return match angles.get(i + 1) {
+        Some(next) if *next > angles[i] => Some((i, (x - angles[i]) / (next - angles[i]))),
+        _ => Some((i, 0.0)),
+    };
+}
+
+/// Loads an IES photometric file.
+pub fn load_file<P: AsRef<Path>>(path: P) -> io::Result<Profile> {
+    return load(BufReader::new(File::open(path)?));
+}
+
+/// Loads an IES photometric file from a stream.
+///
+/// Only type C photometry (that of almost all luminaires for buildings) is
+/// supported. Intensities are scaled by the candela multiplier and the
+/// ballast factor of the file. Tilt data, for luminaires whose output
+/// depends on how they are mounted, is ignored.
+pub fn load<R: BufRead>(mut reader: R) -> io::Result<Profile> {
+    let err = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+
+    // Keywords and other text up to the tilt line.
+    let mut line = String::new();
+    let tilt = loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(err("missing TILT line"));
+        }
+
+        if let Some(tilt) = line.trim().strip_prefix("TILT=") {
+            break tilt.trim().to_string();
+        }
+    };
+
+    // The rest is numbers, separated by white space or commas.
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest)?;
+
+    let mut numbers = rest
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|w| !w.is_empty())
+        .map(|w| w.parse::<f64>().map_err(|_| err("invalid number")));
+
+    let mut next = || {
+        numbers
+            .next()
+            .unwrap_or_else(|| Err(err("unexpected end of file")))
+    };
+
+    if tilt == "INCLUDE" {
+        // Lamp geometry, then pairs of angles and factors.
+        next()?;
+        let pairs = next()? as usize;
+        for _ in 0..pairs.saturating_mul(2) {
+            next()?;
+        }
+    }
+
+    // Lamp count, lumens per lamp.
+    next()?;
+    next()?;
+    let multiplier = next()?;
+    let count_vertical = next()? as usize;
+    let count_horizontal = next()? as usize;
+    let photometric_type = next()?;
+    // Units, width, length and height of the luminous opening.
+    for _ in 0..4 {
+        next()?;
+    }
+    let ballast = next()?;
+    // Future use (formerly the ballast-lamp photometric factor), watts.
+    next()?;
+    next()?;
+
+    if photometric_type != 1.0 {
+        return Err(err("only type C photometry is supported"));
+    }
+
+    if count_vertical == 0 || count_horizontal == 0 {
+        return Err(err("no angles"));
+    }
+
+    let mut read = |count: usize| (0..count).map(|_| next()).collect::<io::Result<Vec<f64>>>();
+
+    let vertical = read(count_vertical)?;
+    let horizontal = read(count_horizontal)?;
+    let count = count_vertical
+        .checked_mul(count_horizontal)
+        .ok_or_else(|| err("too many angles"))?;
+    let candela: Vec<f64> = read(count)?
+        .into_iter()
+        .map(|c| c * multiplier * ballast)
+        .collect();
+
+    let ascending = |a: &[f64]| a.windows(2).all(|w| w[0] <= w[1]);
+    if !ascending(&vertical) || !ascending(&horizontal) {
+        return Err(err("angles not in ascending order"));
+    }
+
+    let max = candela.iter().cloned().fold(0.0, f64::max);
+
+    return Ok(Profile {
+        vertical,
+        horizontal,
+        candela,
+        max,
+    });
+}
@@ -0,0 +1,194 @@
+use image::{Pixel, Rgb};
+
+use vecmath::traits::Float;
+
+use crate::fingerprint::Fingerprint;
+use crate::geom::{Hit, Poly, Ray};
+use crate::passes::Passes;
+use crate::sampling::{self, Rng};
+use crate::spectrum::FromRgb;
+use crate::surface::{self, Black, Surface};
+use crate::{opaque, point_at, Scene};
+
+// Rays per hit of `AmbientOcclusion`.
+const AO_RAYS: u32 = 16;
+
+/// Rendering algorithm: what camera rays see. `render` shoots the rays and
+/// finds their first hits, the integrator takes it from there.
+pub trait Integrator<T, S, C>: Sync {
+    /// Light arriving along the camera ray `ray`, which first hits `hit`
+    /// (the hit and the poly), if anything. `rng` makes random choices.
+    fn light(
+        &self,
+        scene: &Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Option<(Hit<T>, &Poly<T, S>)>,
+        rng: &mut Rng,
+    ) -> Passes<C>;
+
+    /// Adds everything that determines the light to `h`, see `fingerprint`.
+    fn fingerprint(&self, h: &mut Fingerprint);
+}
+
+/// Ambient occlusion: the share of rays over the hemisphere facing the
+/// viewer that hit nothing within a distance, in gray, ignoring surfaces
+/// and lights. Quick to render, shows off the shape of the geometry and
+/// makes a pass to composite over others.
+pub struct AmbientOcclusion<T> {
+    dist: T,
+}
+
+impl<T> AmbientOcclusion<T> {
+    pub fn new(dist: T) -> AmbientOcclusion<T> {
+        return AmbientOcclusion { dist };
+    }
+}
+
+impl<T, S, C> Integrator<T, S, C> for AmbientOcclusion<T>
+where
+    T: Float + Into<f64> + Sync,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black,
+{
+    fn light(
+        &self,
+        scene: &Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Option<(Hit<T>, &Poly<T, S>)>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let (hit, poly) = match hit {
+            Some(hit) => hit,
+            None => return Passes::black(),
+        };
+
+        let open = (0..AO_RAYS)
+            .filter(|_| {
+                let dir = surface::cosine_dir(*poly.n(), ray.dir, rng);
+                let r = Ray::from_surface(hit.point, *poly.n(), dir, poly.bias());
+
+                let hit = scene.shoot(&r, Some(poly), &opaque::<T, C, S>(&r));
+                hit.is_none_or(|(h, _)| {
+                    vecmath::vec3_len(vecmath::vec3_sub(h.point, r.orig)) >= self.dist
+                })
+            })
+            .count();
+
+        let gray = T::from_f64(255.0 * open as f64 / AO_RAYS as f64);
+        return Passes::emitted(C::black().map(|_| gray), 0);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"ao");
+        h.write_f64(self.dist);
+    }
+}
+
+/// Shows the normal of the side of the first hit facing the camera as a
+/// color, each axis from -1 to 1 mapped to a channel, to check geometry
+/// and its orientation.
+pub struct DebugNormals;
+
+impl<T, S, C> Integrator<T, S, C> for DebugNormals
+where
+    T: Float + image::Primitive,
+    S: Surface<T, C>,
+    C: FromRgb<T> + Pixel<Subpixel = T> + Black,
+{
+    fn light(
+        &self,
+        _scene: &Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Option<(Hit<T>, &Poly<T, S>)>,
+        _rng: &mut Rng,
+    ) -> Passes<C> {
+        let poly = match hit {
+            Some((_, poly)) => poly,
+            None => return Passes::black(),
+        };
+
+        let n = if poly.is_back(ray.dir) {
+            vecmath::vec3_neg(*poly.n())
+        } else {
+            *poly.n()
+        };
+
+        let half = T::from_f64(127.5);
+        return Passes::emitted(C::from_rgb(Rgb(n.map(|x| x * half + half))), 0);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"normals");
+    }
+}
+
+/// Adds what camera rays first hit to the AOVs of the light `integrator`
+/// gives, see `Passes::with_first_hit`.
+pub struct FirstHit<T, S, C> {
+    integrator: Box<dyn Integrator<T, S, C>>,
+}
+
+impl<T, S, C> FirstHit<T, S, C> {
+    pub fn new(integrator: Box<dyn Integrator<T, S, C>>) -> FirstHit<T, S, C> {
+        return FirstHit { integrator };
+    }
+}
+
+impl<T, S, C> Integrator<T, S, C> for FirstHit<T, S, C>
+where
+    T: Float + image::Primitive,
+    S: Surface<T, C>,
+    C: FromRgb<T> + Pixel<Subpixel = T> + Black + PartialEq,
+{
+    fn light(
+        &self,
+        scene: &Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Option<(Hit<T>, &Poly<T, S>)>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let light = self.integrator.light(scene, ray, hit, rng);
+
+        let (hit, poly) = match hit {
+            Some(hit) => hit,
+            None => return light,
+        };
+
+        let white = T::from_f64(255.0);
+        let at = point_at(ray, &hit, poly);
+
+        let n = if poly.is_back(ray.dir) {
+            vecmath::vec3_neg(at.n)
+        } else {
+            at.n
+        };
+
+        // The throughput towards one direction, the samples of a pixel
+        // average many. Picked with a copy of `rng`, leaving the choices of
+        // the next samples as they were.
+        let albedo = match poly.surface.sample(at, ray.dir, &mut rng.clone()) {
+            Some((_, _, weight)) => weight.map(|x| x * white),
+            None => C::black(),
+        };
+
+        // Scattered so neighbouring ids stand apart, black for 0 (none).
+        let color = |id: u32| {
+            let bits = sampling::mix(id as u64);
+            C::from_rgb(Rgb(
+                [0, 8, 16].map(|s| T::from_f64((bits >> s & 0xff) as f64))
+            ))
+        };
+
+        return light.with_first_hit([
+            C::from_rgb(Rgb(n.map(|x| x * white))),
+            C::black().map(|_| at.dist * white),
+            albedo,
+            color(poly.id()),
+            color(poly.material()),
+        ]);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        self.integrator.fingerprint(h);
+    }
+}
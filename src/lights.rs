@@ -0,0 +1,781 @@
+use image::codecs::hdr::HdrDecoder;
+use image::{ImageBuffer, ImageResult, Pixel, Primitive, Rgb};
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::geom;
+use crate::ies;
+use crate::sampling::{self, Rng};
+use crate::spectrum::FromRgb;
+
+/// Light source without geometry. Secondary rays can't find it, instead
+/// every hit casts a shadow ray towards it.
+pub trait Light<T, P>: Send + Sync {
+    /// Light arriving at `pos`, as the shadow rays to cast: the direction
+    /// towards the light, the distance to it (shadow rays stop there) and the
+    /// irradiance on a plane facing the light. Area lights split it into
+    /// several rays.
+    fn incident(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)>;
+
+    /// Random photon leaving the light, for photon mapping: where it
+    /// starts, its direction and its power (the light's radiant flux over
+    /// the chance of the photon). Lights infinitely far away send none.
+    fn photon(&self, _rng: &mut Rng) -> Option<(Vector3<T>, Vector3<T>, P)> {
+        return None;
+    }
+
+    /// The point the light shines from, if it is one.
+    fn position(&self) -> Option<Vector3<T>> {
+        return None;
+    }
+
+    /// Groups the light is in, as for `Poly::set_light_groups`: it only
+    /// lights polys whose mask has one of them, see `linked`.
+    fn light_groups(&self) -> u32 {
+        return !0;
+    }
+
+    /// How much the shadows of polys (with a shadow mask of one of its
+    /// groups) block the light, as for `Poly::set_shadow_intensity`.
+    fn shadow_intensity(&self) -> T
+    where
+        T: Float,
+    {
+        return T::one();
+    }
+}
+
+/// Light emitted from `pos` equally into all directions. The irradiance
+/// falls off with the squared distance, `intensity` is that at distance 1.
+pub fn point<'a, T: 'a + Float, P: 'a + Pixel<Subpixel = T> + Send + Sync>(
+    pos: Vector3<T>,
+    intensity: P,
+) -> Arc<dyn 'a + Light<T, P>> {
+    Arc::new(PointLight { pos, intensity })
+}
+
+struct PointLight<T, P> {
+    pos: Vector3<T>,
+    intensity: P,
+}
+
+impl<T: Float, P: Pixel<Subpixel = T> + Send + Sync> Light<T, P> for PointLight<T, P> {
+    fn incident(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        let d = vecmath::vec3_sub(self.pos, pos);
+        let dist2 = vecmath::vec3_dot(d, d);
+
+        if dist2 == T::zero() {
+            return Vec::new();
+        }
+
+        let dist = dist2.sqrt();
+
+        return vec![(
+            vecmath::vec3_scale(d, T::one() / dist),
+            dist,
+            self.intensity.map(|x| x / dist2),
+        )];
+    }
+
+    fn photon(&self, rng: &mut Rng) -> Option<(Vector3<T>, Vector3<T>, P)> {
+        let dir = sampling::uniform_cone([T::one(), T::zero(), T::zero()], -T::one(), rng);
+        let sphere = T::from_f64(4.0 * PI);
+
+        return Some((self.pos, dir, self.intensity.map(|x| x * sphere)));
+    }
+
+    fn position(&self) -> Option<Vector3<T>> {
+        return Some(self.pos);
+    }
+}
+
+/// Light from infinitely far away shining along `dir`, like the sun.
+/// `irradiance` is that on a plane facing it. If `radius` (the angular
+/// radius of the light's disc, in radians) is not 0, shadows soften with
+/// the distance to their caster.
+pub fn directional<'a, T: 'a + Float, P: 'a + Pixel<Subpixel = T> + Send + Sync>(
+    dir: Vector3<T>,
+    irradiance: P,
+    radius: T,
+) -> Arc<dyn 'a + Light<T, P>> {
+    // Directions towards the light, spread over its disc in rings.
+    const RINGS: u32 = 2;
+    const PER_RING: u32 = 6;
+
+    let to_light = vecmath::vec3_normalized(vecmath::vec3_neg(dir));
+    let mut dirs = vec![to_light];
+
+    if radius != T::zero() {
+        let [t, b] = geom::basis(to_light);
+
+        for ring in 1..=RINGS {
+            let theta = radius * T::from_u32(ring) / T::from_u32(RINGS);
+            let count = PER_RING * ring;
+
+            for k in 0..count {
+                // Offset every other ring, so rays don't line up.
+                let phi = T::_360().deg_to_rad()
+                    * (T::from_u32(k) + T::from_u32(ring % 2) / T::from_u32(2))
+                    / T::from_u32(count);
+
+                dirs.push(vecmath::vec3_add(
+                    vecmath::vec3_scale(to_light, theta.cos()),
+                    vecmath::vec3_add(
+                        vecmath::vec3_scale(t, theta.sin() * phi.cos()),
+                        vecmath::vec3_scale(b, theta.sin() * phi.sin()),
+                    ),
+                ));
+            }
+        }
+    }
+
+    let share = T::one() / T::from_f64(dirs.len() as f64);
+
+    Arc::new(Directional {
+        dirs,
+        irradiance: irradiance.map(|x| x * share),
+    })
+}
+
+struct Directional<T, P> {
+    dirs: Vec<Vector3<T>>,
+    irradiance: P, // per direction
+}
+
+impl<T: Float, P: Pixel<Subpixel = T> + Send + Sync> Light<T, P> for Directional<T, P> {
+    fn incident(&self, _pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        let infinity = T::from_f64(f64::INFINITY);
+
+        return self
+            .dirs
+            .iter()
+            .map(|d| (*d, infinity, self.irradiance))
+            .collect();
+    }
+}
+
+/// Point light at `pos` that only shines into a cone around `dir`, like a
+/// stage light. Inside `inner` (the angle to `dir`, in radians) it shines
+/// with full `intensity`, which fades smoothly to nothing at `outer`.
+pub fn spot<'a, T: 'a + Float, P: 'a + Pixel<Subpixel = T> + Send + Sync>(
+    pos: Vector3<T>,
+    dir: Vector3<T>,
+    intensity: P,
+    inner: T,
+    outer: T,
+) -> Arc<dyn 'a + Light<T, P>> {
+    Arc::new(Spot {
+        point: PointLight { pos, intensity },
+        dir: vecmath::vec3_normalized(dir),
+        cos_inner: inner.cos(),
+        cos_outer: outer.cos(),
+    })
+}
+
+struct Spot<T, P> {
+    point: PointLight<T, P>,
+    dir: Vector3<T>,
+    cos_inner: T,
+    cos_outer: T,
+}
+
+impl<T: Float, P> Spot<T, P> {
+    // Share of the intensity shining at `cos` to `dir`.
+    fn fade(&self, cos: T) -> T {
+        if cos <= self.cos_outer {
+            return T::zero();
+        }
+
+        if cos >= self.cos_inner {
+            return T::one();
+        }
+
+        // Smoothstep between the cones.
+        let t = (cos - self.cos_outer) / (self.cos_inner - self.cos_outer);
+        return t * t * (T::from_f64(3.0) - (t + t));
+    }
+}
+
+impl<T: Float, P: Pixel<Subpixel = T> + Send + Sync> Light<T, P> for Spot<T, P> {
+    fn incident(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        let mut res = self.point.incident(pos);
+
+        res.retain(|(to_light, _, _)| -vecmath::vec3_dot(self.dir, *to_light) > self.cos_outer);
+
+        for (to_light, _, irradiance) in res.iter_mut() {
+            let fade = self.fade(-vecmath::vec3_dot(self.dir, *to_light));
+            *irradiance = irradiance.map(|x| x * fade);
+        }
+
+        return res;
+    }
+
+    fn photon(&self, rng: &mut Rng) -> Option<(Vector3<T>, Vector3<T>, P)> {
+        // Only into the outer cone.
+        let dir = sampling::uniform_cone(self.dir, self.cos_outer, rng);
+        let cone = T::from_f64(2.0 * PI) * (T::one() - self.cos_outer);
+        let scale = self.fade(vecmath::vec3_dot(self.dir, dir)) * cone;
+
+        return Some((self.point.pos, dir, self.point.intensity.map(|x| x * scale)));
+    }
+
+    fn position(&self) -> Option<Vector3<T>> {
+        return Some(self.point.pos);
+    }
+}
+
+/// `light`, a point or spot light, with its intensity distributed over the
+/// directions like that of the luminaire measured in `profile`. The
+/// profile's nadir points along `down`, its horizontal angle 0 towards
+/// `front`. The intensity of `light` is that in the brightest direction.
+pub fn profiled<'a, T, P>(
+    light: Arc<dyn 'a + Light<T, P>>,
+    down: Vector3<T>,
+    front: Vector3<T>,
+    profile: ies::Profile,
+) -> Arc<dyn 'a + Light<T, P>>
+where
+    T: 'a + Float + Into<f64>,
+    P: 'a + Pixel<Subpixel = T> + Send + Sync,
+{
+    let down = vecmath::vec3_normalized(down);
+
+    // Without the part along `down`.
+    let front = vecmath::vec3_sub(
+        front,
+        vecmath::vec3_scale(down, vecmath::vec3_dot(front, down)),
+    );
+    let front = if vecmath::vec3_len(front) > T::zero() {
+        vecmath::vec3_normalized(front)
+    } else {
+        geom::basis(down)[0]
+    };
+
+    // Horizontal angles count counterclockwise seen from above.
+    let side = vecmath::vec3_cross(front, down);
+
+    Arc::new(Profiled {
+        light,
+        frame: [down, front, side],
+        profile,
+    })
+}
+
+struct Profiled<'a, T, P> {
+    light: Arc<dyn 'a + Light<T, P>>,
+    frame: [Vector3<T>; 3], // down, front, side
+    profile: ies::Profile,
+}
+
+impl<'a, T: Float + Into<f64>, P> Profiled<'a, T, P> {
+    // Share of the intensity of `light` arriving from `to_light`.
+    fn scale(&self, to_light: Vector3<T>) -> T {
+        if self.profile.max() <= 0.0 {
+            return T::zero();
+        }
+
+        let [d, f, s] = self.frame.map(|a| (-vecmath::vec3_dot(a, to_light)).into());
+
+        let vertical = d.clamp(-1.0, 1.0).acos().to_degrees();
+        let horizontal = s.atan2(f).to_degrees();
+
+        return T::from_f64(self.profile.candela(vertical, horizontal) / self.profile.max());
+    }
+}
+
+impl<'a, T, P> Light<T, P> for Profiled<'a, T, P>
+where
+    T: Float + Into<f64>,
+    P: Pixel<Subpixel = T> + Send + Sync,
+{
+    fn incident(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        let mut res = self.light.incident(pos);
+
+        if self.profile.max() <= 0.0 {
+            res.clear();
+        }
+
+        for (to_light, _, irradiance) in res.iter_mut() {
+            let scale = self.scale(*to_light);
+            *irradiance = irradiance.map(|x| x * scale);
+        }
+
+        return res;
+    }
+
+    fn photon(&self, rng: &mut Rng) -> Option<(Vector3<T>, Vector3<T>, P)> {
+        let (pos, dir, power) = self.light.photon(rng)?;
+        let scale = self.scale(vecmath::vec3_neg(dir));
+
+        return Some((pos, dir, power.map(|x| x * scale)));
+    }
+
+    fn position(&self) -> Option<Vector3<T>> {
+        return self.light.position();
+    }
+
+    fn light_groups(&self) -> u32 {
+        return self.light.light_groups();
+    }
+
+    fn shadow_intensity(&self) -> T {
+        return self.light.shadow_intensity();
+    }
+}
+
+/// Light arriving from infinitely far away, from all directions rays can
+/// leave the scene into. As a `Light` it casts the shadow rays that light
+/// surfaces with it; rays missing all polys see its `radiance`.
+pub trait Environment<T, P>: Light<T, P> {
+    /// Radiance arriving from direction `dir` (pointing out of the scene).
+    fn radiance(&self, dir: Vector3<T>) -> P;
+
+    /// The part of `incident` coming from spots too small to find by
+    /// looking up `radiance` across the environment, like the sun's disc.
+    /// `portals` still casts these shadow rays.
+    fn spots(&self, _pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        return Vec::new();
+    }
+
+    /// `radiance` apart from the `spots`.
+    fn radiance_besides_spots(&self, dir: Vector3<T>) -> P {
+        return self.radiance(dir);
+    }
+
+    /// Shadow rays per steradian that `incident` casts from `pos` around
+    /// direction `dir`, infinite towards the `spots`. Light arriving from
+    /// there is shared between them and rays picked otherwise by how densely
+    /// each covers it.
+    fn density(&self, pos: Vector3<T>, dir: Vector3<T>) -> T;
+
+    /// Share of the light getting through the first `dist` of the ray from
+    /// `orig` along `dir` and the radiance the air along it adds towards
+    /// `orig`, for environments with an atmosphere that haze hides distant
+    /// things in. `None` for clear air.
+    fn aerial(&self, _orig: Vector3<T>, _dir: Vector3<T>, _dist: T) -> Option<(P, P)> {
+        return None;
+    }
+}
+
+/// `light` lighting only the polys whose mask has one of `groups` and
+/// blocked by shadows with `shadow_intensity`, as emitting polys do with
+/// `Poly::set_light_groups` and `Poly::set_shadow_intensity`.
+pub fn linked<'a, T, P>(
+    light: Arc<dyn 'a + Light<T, P>>,
+    groups: u32,
+    shadow_intensity: T,
+) -> Arc<dyn 'a + Light<T, P>>
+where
+    T: 'a + Float,
+    P: 'a + Pixel<Subpixel = T> + Send + Sync,
+{
+    Arc::new(Linked {
+        light,
+        groups,
+        shadow_intensity,
+    })
+}
+
+/// `linked` for an environment.
+pub fn linked_environment<'a, T, P>(
+    environment: Arc<dyn 'a + Environment<T, P>>,
+    groups: u32,
+    shadow_intensity: T,
+) -> Arc<dyn 'a + Environment<T, P>>
+where
+    T: 'a + Float,
+    P: 'a + Pixel<Subpixel = T> + Send + Sync,
+{
+    Arc::new(Linked {
+        light: environment,
+        groups,
+        shadow_intensity,
+    })
+}
+
+struct Linked<T, L: ?Sized> {
+    light: Arc<L>,
+    groups: u32,
+    shadow_intensity: T,
+}
+
+impl<T, P, L> Light<T, P> for Linked<T, L>
+where
+    T: Float,
+    P: Pixel<Subpixel = T> + Send + Sync,
+    L: ?Sized + Light<T, P>,
+{
+    fn incident(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        return self.light.incident(pos);
+    }
+
+    fn photon(&self, rng: &mut Rng) -> Option<(Vector3<T>, Vector3<T>, P)> {
+        return self.light.photon(rng);
+    }
+
+    fn position(&self) -> Option<Vector3<T>> {
+        return self.light.position();
+    }
+
+    fn light_groups(&self) -> u32 {
+        return self.groups;
+    }
+
+    fn shadow_intensity(&self) -> T {
+        return self.shadow_intensity;
+    }
+}
+
+impl<T, P, E> Environment<T, P> for Linked<T, E>
+where
+    T: Float,
+    P: Pixel<Subpixel = T> + Send + Sync,
+    E: ?Sized + Environment<T, P>,
+{
+    fn radiance(&self, dir: Vector3<T>) -> P {
+        return self.light.radiance(dir);
+    }
+
+    fn spots(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        return self.light.spots(pos);
+    }
+
+    fn radiance_besides_spots(&self, dir: Vector3<T>) -> P {
+        return self.light.radiance_besides_spots(dir);
+    }
+
+    fn density(&self, pos: Vector3<T>, dir: Vector3<T>) -> T {
+        return self.light.density(pos, dir);
+    }
+
+    fn aerial(&self, orig: Vector3<T>, dir: Vector3<T>, dist: T) -> Option<(P, P)> {
+        return self.light.aerial(orig, dir, dist);
+    }
+}
+
+/// `environment` lighting an interior only through `portals`, its openings
+/// (e.g. windows), each a parallelogram given by a corner and its two sides.
+///
+/// Shadow rays go towards points spread evenly over the portals instead of
+/// across the whole environment, most of which the walls would block; only
+/// the environment's `spots` are lit as before. Light coming in elsewhere is
+/// lost, unless rays sampling surfaces find it.
+pub fn portals<'a, T, P>(
+    environment: Arc<dyn 'a + Environment<T, P>>,
+    portals: Vec<[Vector3<T>; 3]>,
+) -> Arc<dyn 'a + Environment<T, P>>
+where
+    T: 'a + Float,
+    P: 'a + Pixel<Subpixel = T> + Send + Sync,
+{
+    Arc::new(Portals {
+        environment,
+        portals,
+    })
+}
+
+// Points per side of a portal.
+const PORTAL_STEPS: u32 = 4;
+
+struct Portals<'a, T, P> {
+    environment: Arc<dyn 'a + Environment<T, P>>,
+    portals: Vec<[Vector3<T>; 3]>,
+}
+
+impl<'a, T: Float, P: Pixel<Subpixel = T> + Send + Sync> Light<T, P> for Portals<'a, T, P> {
+    fn incident(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        let infinity = T::from_f64(f64::INFINITY);
+        let mut res = Vec::new();
+
+        for [corner, a, b] in self.portals.iter() {
+            let n = vecmath::vec3_cross(*a, *b);
+            let area = vecmath::vec3_len(n);
+
+            if area == T::zero() {
+                continue;
+            }
+
+            let n = vecmath::vec3_scale(n, T::one() / area);
+            let cell = area / T::from_u32(PORTAL_STEPS * PORTAL_STEPS);
+
+            for i in 0..PORTAL_STEPS {
+                for j in 0..PORTAL_STEPS {
+                    let s = (T::from_u32(i) + T::from_f64(0.5)) / T::from_u32(PORTAL_STEPS);
+                    let t = (T::from_u32(j) + T::from_f64(0.5)) / T::from_u32(PORTAL_STEPS);
+
+                    let q = vecmath::vec3_add(
+                        *corner,
+                        vecmath::vec3_add(vecmath::vec3_scale(*a, s), vecmath::vec3_scale(*b, t)),
+                    );
+                    let d = vecmath::vec3_sub(q, pos);
+                    let dist2 = vecmath::vec3_dot(d, d);
+
+                    if dist2 == T::zero() {
+                        continue;
+                    }
+
+                    let dir = vecmath::vec3_scale(d, T::one() / dist2.sqrt());
+
+                    // Solid angle of the cell around `q`.
+                    let cos = vecmath::vec3_dot(dir, n);
+                    let cos = if cos < T::zero() { -cos } else { cos };
+                    let w = cell * cos / dist2;
+
+                    // Past the portal, out of the scene.
+                    let radiance = self.environment.radiance_besides_spots(dir);
+                    res.push((dir, infinity, radiance.map(|x| x * w)));
+                }
+            }
+        }
+
+        res.extend(self.environment.spots(pos));
+        return res;
+    }
+
+    fn light_groups(&self) -> u32 {
+        return self.environment.light_groups();
+    }
+
+    fn shadow_intensity(&self) -> T {
+        return self.environment.shadow_intensity();
+    }
+}
+
+impl<'a, T: Float, P: Pixel<Subpixel = T> + Send + Sync> Environment<T, P> for Portals<'a, T, P> {
+    fn radiance(&self, dir: Vector3<T>) -> P {
+        return self.environment.radiance(dir);
+    }
+
+    fn spots(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        return self.environment.spots(pos);
+    }
+
+    fn radiance_besides_spots(&self, dir: Vector3<T>) -> P {
+        return self.environment.radiance_besides_spots(dir);
+    }
+
+    fn aerial(&self, orig: Vector3<T>, dir: Vector3<T>, dist: T) -> Option<(P, P)> {
+        return self.environment.aerial(orig, dir, dist);
+    }
+
+    fn density(&self, pos: Vector3<T>, dir: Vector3<T>) -> T {
+        let infinity = T::from_f64(f64::INFINITY);
+
+        if self.environment.density(pos, dir) == infinity {
+            return infinity;
+        }
+
+        let mut res = T::zero();
+
+        for [corner, a, b] in self.portals.iter() {
+            let n = vecmath::vec3_cross(*a, *b);
+            let n2 = vecmath::vec3_dot(n, n);
+            let cos = vecmath::vec3_dot(dir, n);
+
+            if n2 == T::zero() || cos == T::zero() {
+                continue;
+            }
+
+            // Where the ray crosses the plane of the portal, and whether
+            // that's inside it.
+            let t = vecmath::vec3_dot(vecmath::vec3_sub(*corner, pos), n) / cos;
+            let q = vecmath::vec3_add(pos, vecmath::vec3_scale(dir, t));
+            let r = vecmath::vec3_sub(q, *corner);
+            let s = vecmath::vec3_dot(vecmath::vec3_cross(r, *b), n) / n2;
+            let u = vecmath::vec3_dot(vecmath::vec3_cross(*a, r), n) / n2;
+
+            let inside = |x: T| x >= T::zero() && x <= T::one();
+            if t <= T::zero() || !inside(s) || !inside(u) {
+                continue;
+            }
+
+            // The cells' points spread over the solid angle of the portal.
+            let cos = if cos < T::zero() { -cos } else { cos };
+            res += T::from_u32(PORTAL_STEPS * PORTAL_STEPS) * t * t / cos;
+        }
+
+        return res;
+    }
+}
+
+/// Linear RGB image, e.g. read from an HDR file.
+pub type HdrImage = ImageBuffer<Rgb<f32>, Vec<f32>>;
+
+/// Reads a Radiance HDR (.hdr) file.
+pub fn read_hdr<Q: AsRef<Path>>(path: Q) -> ImageResult<HdrImage> {
+    let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+    let meta = decoder.metadata();
+    let pixels = decoder.read_image_hdr()?;
+
+    return Ok(ImageBuffer::from_fn(meta.width, meta.height, |x, y| {
+        pixels[(y * meta.width + x) as usize]
+    }));
+}
+
+/// Environment from an equirectangular (latitude-longitude) `image` with
+/// +y at the top and -z at the center, scaled by `brightness`.
+///
+/// Shadow rays go towards texels picked in proportion to the light they
+/// contribute, so small bright spots like the sun are found reliably.
+pub fn environment<'a, T, P>(image: HdrImage, brightness: T) -> Arc<dyn 'a + Environment<T, P>>
+where
+    T: 'a + Float + Into<f64> + Primitive,
+    P: 'a + Pixel<Subpixel = T> + FromRgb<T> + Send + Sync,
+{
+    // Number of shadow rays (fewer if several pick the same texel).
+    const SAMPLES: usize = 64;
+
+    let (width, height) = image.dimensions();
+
+    // Light of each texel: its luminance times its solid angle, which
+    // shrinks towards the poles.
+    let mut cdf = Vec::with_capacity((width * height) as usize);
+    let mut total = 0.0;
+
+    for (x, y, c) in image.enumerate_pixels() {
+        let [r, g, b] = c.0.map(|v| v as f64);
+        let lum = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        total += lum.max(0.0) * texel_dir(x, y, width, height).1;
+        cdf.push(total);
+    }
+
+    // Stratified picks, merging repeats of a texel.
+    let mut picks: Vec<(usize, u32)> = Vec::new();
+
+    if total > 0.0 {
+        for k in 0..SAMPLES {
+            let target = (k as f64 + 0.5) / SAMPLES as f64 * total;
+            let i = cdf.partition_point(|c| *c <= target).min(cdf.len() - 1);
+
+            match picks.last_mut() {
+                Some((j, count)) if *j == i => *count += 1,
+                _ => picks.push((i, 1)),
+            }
+        }
+    }
+
+    let samples = picks
+        .into_iter()
+        .map(|(i, count)| {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            let (dir, sin) = texel_dir(x, y, width, height);
+
+            // The texel's radiance times its solid angle, over the chance
+            // of picking it.
+            let weight = if i == 0 { cdf[0] } else { cdf[i] - cdf[i - 1] };
+            let solid_angle = 2.0 * PI * PI * sin / (width as f64 * height as f64);
+            let scale = solid_angle * total / weight * count as f64 / SAMPLES as f64;
+
+            (x, y, dir.map(T::from_f64), T::from_f64(scale))
+        })
+        .collect();
+
+    // Picks of a texel per steradian are its share of `total` over its
+    // solid angle, in which its sine cancels out.
+    let density = if total > 0.0 {
+        SAMPLES as f64 * (width * height) as f64 / (2.0 * PI * PI * total)
+    } else {
+        0.0
+    };
+
+    Arc::new(EnvMap {
+        image,
+        brightness,
+        samples,
+        density: T::from_f64(density),
+    })
+}
+
+/// Direction towards the center of texel (`x`, `y`) of an equirectangular
+/// image (see `environment`), and the sine of its angle to +y.
+pub fn texel_dir(x: u32, y: u32, width: u32, height: u32) -> (Vector3<f64>, f64) {
+    let theta = (y as f64 + 0.5) / height as f64 * PI;
+    let phi = ((x as f64 + 0.5) / width as f64 - 0.5) * 2.0 * PI;
+
+    let dir = [
+        theta.sin() * phi.sin(),
+        theta.cos(),
+        -theta.sin() * phi.cos(),
+    ];
+
+    return (dir, theta.sin());
+}
+
+struct EnvMap<T> {
+    image: HdrImage,
+    brightness: T,
+    // Texels shadow rays go towards, the direction to them and the factor
+    // their radiance is scaled by.
+    samples: Vec<(u32, u32, Vector3<T>, T)>,
+    density: T, // of shadow rays per steradian, per unit of luminance
+}
+
+impl<T: Float + Primitive> EnvMap<T> {
+    fn texel<P: FromRgb<T>>(&self, x: u32, y: u32, scale: T) -> P {
+        let c = self.image.get_pixel(x, y).0;
+        return P::from_rgb(Rgb(c.map(|v| T::from_f64(v as f64) * scale)));
+    }
+
+    // The texel seen in direction `dir`.
+    fn texel_at(&self, dir: Vector3<T>) -> (u32, u32)
+    where
+        T: Into<f64>,
+    {
+        let (width, height) = self.image.dimensions();
+        let [x, y, z] = vecmath::vec3_normalized(dir).map(|v| v.into());
+
+        let theta = y.clamp(-1.0, 1.0).acos();
+        let phi = x.atan2(-z);
+
+        let u = phi / (2.0 * PI) + 0.5;
+        let v = theta / PI;
+
+        let tx = ((u * width as f64) as u32).min(width - 1);
+        let ty = ((v * height as f64) as u32).min(height - 1);
+
+        return (tx, ty);
+    }
+}
+
+impl<T, P> Light<T, P> for EnvMap<T>
+where
+    T: Float + Primitive,
+    P: Pixel<Subpixel = T> + FromRgb<T> + Send + Sync,
+{
+    fn incident(&self, _pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        let infinity = T::from_f64(f64::INFINITY);
+
+        return self
+            .samples
+            .iter()
+            .map(|(x, y, dir, scale)| {
+                (*dir, infinity, self.texel(*x, *y, *scale * self.brightness))
+            })
+            .collect();
+    }
+}
+
+impl<T, P> Environment<T, P> for EnvMap<T>
+where
+    T: Float + Into<f64> + Primitive,
+    P: Pixel<Subpixel = T> + FromRgb<T> + Send + Sync,
+{
+    fn radiance(&self, dir: Vector3<T>) -> P {
+        let (x, y) = self.texel_at(dir);
+        return self.texel(x, y, self.brightness);
+    }
+
+    fn density(&self, _pos: Vector3<T>, dir: Vector3<T>) -> T {
+        let (x, y) = self.texel_at(dir);
+        let [r, g, b] = self.image.get_pixel(x, y).0.map(|v| v as f64);
+        let lum = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        return T::from_f64(lum.max(0.0)) * self.density;
+    }
+}
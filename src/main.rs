@@ -1,131 +1,779 @@
+#![allow(clippy::needless_return)]
+
 extern crate image;
 extern crate quaternion;
 extern crate same;
 extern crate vecmath;
 
+mod atmosphere;
+mod bidir;
+mod camera;
+mod checkerboard;
+mod cli;
+#[cfg(feature = "embree")]
+mod embree;
+mod emitters;
+mod filter;
+mod fingerprint;
+mod frustum;
+mod furnace;
 mod geom;
+mod grid;
+mod ies;
+mod integrator;
+mod lights;
+mod medium;
+mod microfacet;
+mod noise;
+mod obj;
+mod output;
+mod passes;
+mod paths;
+mod phase;
+mod photons;
+mod ply;
+mod progress;
+mod sampling;
 mod shapes;
+mod sky;
+mod spectrum;
+mod stress;
 mod surface;
+mod texture;
+mod tonemap;
+mod tracer;
+mod volume;
+mod whitted;
 
-use image::{GenericImage, Pixel, Rgb, RgbImage};
+use image::{DynamicImage, GenericImage, GrayImage, ImageBuffer, Pixel, Rgb, SubImage};
 use vecmath::traits::Float;
 use vecmath::Vector3;
 
-use std::convert::TryInto;
 use std::option::Option;
-use std::sync::Arc;
-
-use geom::{Poly, Ray};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use bidir::Bidir;
+use camera::{Camera, Fisheye, Ortho, Pinhole, Spherical, Stereo, ThinLens};
+use checkerboard::Checkerboard;
+use emitters::Emitters;
+use filter::Filter;
+use fingerprint::Fingerprint;
+use geom::{Differential, Hit, Poly, Ray, Sides};
+use grid::Grid;
+use integrator::{AmbientOcclusion, DebugNormals, FirstHit, Integrator};
+use lights::{Environment, Light};
+use medium::{Emission, Fog, Stack};
+use output::HdrImage;
+use passes::Passes;
+use paths::PathTracer;
+use phase::HenyeyGreenstein;
+use photons::{Photon, PhotonMap, PhotonMapper};
+use progress::Progress;
+#[cfg(not(feature = "embree"))]
 use same::Same;
+use sampling::{Adaptive, Rng, Sampler};
+use spectrum::{FromRgb, Spectrum};
 use surface::{Black, Surface};
+use whitted::Whitted;
+
+/// Moves `polys` so that `camera` ends up at the origin.
+///
+/// Coordinates far from the origin (e.g. large terrains) lose precision,
+/// especially with `f32`. Anchoring keeps the coordinates of the geometry
+/// close to the camera small. Note that this also moves world space
+/// textures (e.g. `surface::checker`) relative to the geometry.
+fn anchor<T: Float, S>(polys: &mut [Poly<T, S>], camera: &mut dyn Camera<T>) {
+    let offset = vecmath::vec3_neg(camera.pose().orig);
+
+    for p in polys.iter_mut() {
+        p.translate(offset);
+    }
 
-struct Camera<T> {
-    orig: Vector3<T>,
-    dir: Vector3<T>,
-    up: Vector3<T>,
-    aperture: T, // aperture angle in radians
+    camera.pose_mut().orig = [T::zero(), T::zero(), T::zero()];
 }
 
-struct Scene<T, S> {
-    polys: Vec<Poly<T, S>>,
+/// Numbers the shapes of a demo scene: runs of `polys` sharing a surface
+/// get object ids counting up from 1, their surfaces material ids in the
+/// order they first appear.
+fn number_shapes<T: Float, S: ?Sized>(polys: &mut [Poly<T, Arc<S>>]) {
+    let mut materials: Vec<Arc<S>> = Vec::new();
+    let mut object = 0;
+
+    for k in 0..polys.len() {
+        if k == 0 || !Arc::ptr_eq(&polys[k].surface, &polys[k - 1].surface) {
+            object += 1;
+        }
+
+        let material = match materials
+            .iter()
+            .position(|m| Arc::ptr_eq(m, &polys[k].surface))
+        {
+            Some(i) => i,
+            None => {
+                materials.push(polys[k].surface.clone());
+                materials.len() - 1
+            }
+        };
+
+        polys[k].set_id(object);
+        polys[k].set_material(material as u32 + 1);
+    }
 }
 
-struct Tracer<T> {
-    all_dirs: Vec<Vector3<T>>,
-    max_depth: u32,
+/// Overrides the offset of secondary rays off each of `polys` by `--bias`,
+/// if given.
+fn set_bias<S>(args: &cli::Args, polys: &mut [Poly<f64, S>]) {
+    if let Some(bias) = args.bias {
+        for poly in polys.iter_mut() {
+            poly.set_bias(bias);
+        }
+    }
 }
 
-impl<T: Float> Tracer<T> {
-    fn new(rays: u32, max_depth: u32) -> Tracer<T> {
-        let step = T::_360() / T::from_u32(rays);
+/// Links the objects of `polys` to the lights as `--light-groups`,
+/// `--light-mask`, `--shadow-mask` and `--shadow-intensity` say.
+fn set_links<S>(args: &cli::Args, polys: &mut [Poly<f64, S>]) {
+    let find = |masks: &[(u32, u32)], id| masks.iter().rev().find(|(i, _)| *i == id).map(|m| m.1);
 
-        let u = [T::one(), T::zero(), T::zero()];
+    for poly in polys.iter_mut() {
+        if let Some(groups) = args.light_groups_of(cli::Target::Object(poly.id())) {
+            poly.set_light_groups(groups);
+        }
 
-        let mut all_dirs = Vec::with_capacity((rays * rays).try_into().unwrap());
+        if let Some(intensity) = args.shadow_intensity_of(cli::Target::Object(poly.id())) {
+            poly.set_shadow_intensity(intensity);
+        }
 
-        for x in 0..rays {
-            for y in 0..rays {
-                let q = quaternion::euler_angles(
-                    T::from_u32(x) * step,
-                    T::from_u32(y) * step,
-                    T::zero(),
-                );
-                all_dirs.push(quaternion::rotate_vector(q, u));
-            }
+        if let Some(mask) = find(&args.light_mask, poly.id()) {
+            poly.set_light_mask(mask);
+        }
+
+        if let Some(mask) = find(&args.shadow_mask, poly.id()) {
+            poly.set_shadow_mask(mask);
         }
+    }
+}
 
-        return Tracer {
-            all_dirs,
-            max_depth,
+/// Moves `camera` back along its direction (or widens its view, see
+/// `Camera::frame`) until the bounding sphere of `polys` fits into it (with
+/// some margin).
+fn frame<T: Float, S>(camera: &mut dyn Camera<T>, polys: &[Poly<T, S>]) {
+    let [lo, hi] = match geom::bounds(polys.iter()) {
+        None => return,
+        Some(b) => b,
+    };
+
+    let half = T::from_f64(0.5);
+    let center = vecmath::vec3_scale(vecmath::vec3_add(lo, hi), half);
+    let radius = vecmath::vec3_len(vecmath::vec3_sub(hi, lo)) * half;
+
+    let margin = T::from_f64(1.1);
+    camera.frame(center, radius * margin);
+}
+
+/// `camera` with the angles of view of `--fov` and `--vfov`, the
+/// projection of `--fisheye`, `--ortho` or `--spherical` (keeping its pose
+/// and aspect), the lens of `--lens` and the shutter and velocity of
+/// `--shutter` and `--velocity`, if given.
+fn optics<T: Float + Into<f64> + Sync + 'static>(
+    mut camera: Pinhole<T>,
+    args: &cli::Args,
+) -> Box<dyn Camera<T>> {
+    let aspect = camera.aspect();
+    let mut pose = camera.pose;
+
+    let radians = |deg: Option<f64>| deg.map(|d| T::from_f64(d.to_radians()));
+    camera.set_fov(radians(args.fov), radians(args.vfov));
+
+    if let Some(shutter) = args.shutter {
+        pose.shutter = shutter.map(T::from_f64);
+        pose.velocity = args.velocity.map(T::from_f64);
+    }
+
+    let mut camera: Box<dyn Camera<T>> = if let Some((mapping, fov)) = args.fisheye {
+        Box::new(Fisheye::new(
+            pose,
+            mapping,
+            T::from_f64(fov.to_radians()),
+            aspect,
+        ))
+    } else if let Some(width) = args.ortho {
+        let width = T::from_f64(width);
+        Box::new(Ortho {
+            pose,
+            size: [width, width / aspect],
+        })
+    } else if args.spherical {
+        Box::new(Spherical { pose })
+    } else {
+        Box::new(Pinhole { pose, ..camera })
+    };
+
+    if let Some([radius, focus]) = args.lens {
+        camera = Box::new(ThinLens {
+            camera,
+            lens: T::from_f64(radius),
+            focus: T::from_f64(focus),
+        });
+    }
+
+    return camera;
+}
+
+// Light leaving an emitting poly, see `Scene::emit`.
+type Emitted<'a, T, S, C> = (&'a Poly<T, S>, Vector3<T>, Vector3<T>, C, T, T);
+
+struct Scene<T, S, C> {
+    polys: Vec<Poly<T, S>>,
+    lights: Vec<Arc<dyn Light<T, C>>>,
+    environment: Option<Arc<dyn Environment<T, C>>>,
+    emitters: Emitters<T>,
+    photons: Option<PhotonMap<T, C>>, // see `scatter_photons`
+    fog: Option<Fog<C>>,
+    // Whether any poly or light uses shadow linking or shadow intensity.
+    shadow_links: bool,
+    #[cfg(feature = "embree")]
+    accel: embree::Accel<T>,
+}
+
+impl<T: Float, S, C> Scene<T, S, C> {
+    #[cfg(not(feature = "embree"))]
+    fn new(polys: Vec<Poly<T, S>>) -> Scene<T, S, C>
+    where
+        S: Surface<T, C>,
+        C: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        let shadow_links = Scene::<T, S, C>::has_shadow_links(&polys);
+        return Scene {
+            emitters: Emitters::new(&polys),
+            photons: None,
+            fog: None,
+            polys,
+            lights: Vec::new(),
+            environment: None,
+            shadow_links,
         };
     }
 
-    fn trace<C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
-        &self,
-        scene: &Scene<T, S>,
-        ray: &Ray<T>,
-        exclude: Option<&Poly<T, S>>,
-        depth: u32,
-    ) -> C {
-        if depth > self.max_depth {
-            return C::black();
+    /// Adds a light source without geometry, see `lights`.
+    fn add_light(&mut self, light: Arc<dyn Light<T, C>>) {
+        self.shadow_links |= light.shadow_intensity() != T::one();
+        self.lights.push(light);
+    }
+
+    /// Sets the light around the scene, which rays missing all polys see.
+    fn set_environment(&mut self, environment: Arc<dyn Environment<T, C>>) {
+        self.shadow_links |= environment.shadow_intensity() != T::one();
+        self.environment = Some(environment);
+    }
+
+    fn set_fog(&mut self, fog: Fog<C>) {
+        self.fog = Some(fog);
+    }
+
+    /// Scatters `count` photons from the emitting polys and the lights
+    /// through the scene, for `PhotonMapper`, making random choices from
+    /// `seed`. Lights infinitely far away, like the environment, send none.
+    fn scatter_photons(&mut self, count: u32, seed: u64)
+    where
+        T: Into<f64>,
+        S: Surface<T, C>,
+        C: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        // Bounces of a photon before it is dropped for certain.
+        const MAX_BOUNCES: u32 = 16;
+
+        let mut rng = Rng::new(seed);
+
+        let lights: Vec<_> = self
+            .lights
+            .iter()
+            .filter(|l| l.photon(&mut rng).is_some())
+            .collect();
+
+        // The lights and the emitting polys (together) get equal shares of
+        // the photons.
+        let emits = self.emitters.pick(0.0).is_some();
+        let sources = lights.len() + usize::from(emits);
+        if sources == 0 {
+            self.photons = Some(PhotonMap::new(Vec::new()));
+            return;
         }
 
-        let maybe_hit = match exclude {
-            Some(that_poly) => {
-                let filtered = scene.polys.iter().filter(|x| !that_poly.same(x));
-                geom::shoot(filtered, ray)
+        let share = T::from_f64(sources as f64 / count as f64);
+
+        let mut stored = Vec::new();
+
+        for _ in 0..count {
+            let k = ((rng.unit() * sources as f64) as usize).min(sources - 1);
+
+            // The first ray and the power and poly it leaves from, whether
+            // shadow rays already bring its light, and the light groups of
+            // its source, which it only lights the polys of directly.
+            let (mut ray, power, mut from, mut direct, groups) = match lights.get(k) {
+                Some(light) => match light.photon(&mut rng) {
+                    Some((orig, dir, power)) => {
+                        let ray = Ray {
+                            orig,
+                            dir,
+                            width: T::zero(),
+                            spread: T::zero(),
+                            diffs: None,
+                            time: T::zero(),
+                        };
+                        (ray, power, None, true, light.light_groups())
+                    }
+                    None => continue,
+                },
+                None => match self.emit(&mut rng) {
+                    Some((poly, q, dir, power, _, _)) => {
+                        let ray = Ray::from_surface(q, *poly.n(), dir, poly.bias());
+                        let direct = self.emitters.sampled(self.index(poly));
+                        (ray, power, Some(poly), direct, poly.light_groups())
+                    }
+                    None => continue,
+                },
+            };
+
+            let mut power = power.map(|x| x * share);
+
+            for bounce in 0..MAX_BOUNCES {
+                let (hit, poly) = match self.shoot(&ray, from, &opaque::<T, C, S>(&ray)) {
+                    Some(hit) => hit,
+                    None => break,
+                };
+
+                if poly.sides() == Sides::BackBlack && poly.is_back(ray.dir) {
+                    break;
+                }
+
+                if bounce == 0 && !poly.lit_by(groups) {
+                    break;
+                }
+
+                if let Some(through) = interior(&ray, hit.point, poly) {
+                    power = power.map2(&through, |a, b| a * b);
+                }
+
+                if let Some(fog) = &self.fog {
+                    let dist = vecmath::vec3_len(vecmath::vec3_sub(hit.point, ray.orig));
+                    let through = fog.transmittance_along(ray.orig, ray.dir, dist, &mut rng);
+                    power = power.map2(&through, |a, b| a * b);
+                }
+
+                let at = point_at(&ray, &hit, poly);
+
+                // Only surfaces reflecting diffusely keep photons, mirrors
+                // and lenses pass them on.
+                let n = *poly.n();
+                let back = if poly.is_back(ray.dir) {
+                    n
+                } else {
+                    vecmath::vec3_neg(n)
+                };
+                let diffuse = poly.surface.reflected(at, vecmath::vec3_neg(ray.dir), back);
+
+                if !direct && diffuse != C::black() {
+                    stored.push(Photon {
+                        pos: hit.point,
+                        dir: ray.dir,
+                        power,
+                    });
+                }
+
+                let (dir, _, weight) = match poly.surface.sample(at, ray.dir, &mut rng) {
+                    Some(s) => s,
+                    None => break,
+                };
+
+                // Russian roulette: go on with the chance of the strongest
+                // channel of `weight`, making up for the photons dropped.
+                let keep = weight
+                    .channels()
+                    .iter()
+                    .fold(T::zero(), |m, x| m.max(*x))
+                    .min(T::one());
+
+                if T::from_f64(rng.unit()) >= keep {
+                    break;
+                }
+
+                power = power.map2(&weight, |a, b| a * b / keep);
+
+                let bias = poly.surface.bias().unwrap_or_else(|| poly.bias());
+                ray = Ray::from_surface(hit.point, n, dir, bias);
+                from = Some(poly);
+                direct = false;
             }
-            None => geom::shoot(scene.polys.iter(), ray),
+        }
+
+        self.photons = Some(PhotonMap::new(stored));
+    }
+
+    /// Random ray of light leaving an emitting poly, picked by
+    /// `Emitters::pick`: the poly, the point on it and the direction, the
+    /// light it carries over the density of both, and those densities (per
+    /// unit area and per steradian).
+    fn emit(&self, rng: &mut Rng) -> Option<Emitted<'_, T, S, C>>
+    where
+        S: Surface<T, C>,
+        C: Pixel<Subpixel = T>,
+    {
+        let (i, chance) = self.emitters.pick(rng.unit())?;
+
+        let poly = &self.polys[i];
+        let n = *poly.n();
+        let q = emitters::random_point(poly, rng);
+
+        // Out of the front, or a random side if both emit.
+        let (side, sides) = match poly.sides() {
+            Sides::Double if rng.unit() < 0.5 => (vecmath::vec3_neg(n), 2.0),
+            Sides::Double => (n, 2.0),
+            _ => (n, 1.0),
+        };
+        let dir = surface::cosine_dir(side, vecmath::vec3_neg(side), rng);
+
+        let towards = Ray {
+            orig: vecmath::vec3_add(q, dir),
+            dir: vecmath::vec3_neg(dir),
+            width: T::zero(),
+            spread: T::zero(),
+            diffs: None,
+            time: T::zero(),
+        };
+        let emitted = poly
+            .surface
+            .emitted(point_at(&towards, &poly.hit_at(q), poly));
+
+        let area_density = chance / emitters::area(poly);
+        let cos = vecmath::vec3_dot(dir, n);
+        let cos = if cos < T::zero() { -cos } else { cos };
+        let dir_density = cos / T::from_f64(std::f64::consts::PI * sides);
+
+        // Radiance times the cosine over both densities.
+        let scale = T::from_f64(std::f64::consts::PI * sides) / area_density;
+
+        return Some((
+            poly,
+            q,
+            dir,
+            emitted.map(|x| x * scale),
+            area_density,
+            dir_density,
+        ));
+    }
+
+    /// Light arriving along `ray` if it misses all polys.
+    fn background(&self, ray: &Ray<T>) -> C
+    where
+        C: Black,
+    {
+        return match &self.environment {
+            Some(env) => env.radiance(ray.dir),
+            None => C::black(),
+        };
+    }
+
+    /// Whether the environment, if any, lights `poly` directly, see
+    /// `Light::light_groups`.
+    fn environment_lights(&self, poly: &Poly<T, S>) -> bool {
+        return match &self.environment {
+            Some(env) => poly.lit_by(env.light_groups()),
+            None => true,
         };
+    }
 
-        let (hit_point, poly) = match maybe_hit {
+    /// `background` for a `ray` picked with density `pdf` (see
+    /// `Surface::pdf`), less the share of the environment's shadow rays.
+    fn sampled_background(&self, ray: &Ray<T>, pdf: T) -> C
+    where
+        C: Pixel<Subpixel = T> + Black,
+    {
+        let env = match &self.environment {
+            Some(env) => env,
             None => return C::black(),
-            Some(hit) => hit,
         };
 
-        let mut all_light = poly.surface.emitted();
+        let share = sampling::power_heuristic(pdf, env.density(ray.orig, ray.dir));
 
-        for dir in self.all_dirs.iter() {
-            let refl = poly.surface.reflected(*poly.n(), *dir, ray.dir);
+        if share == T::zero() {
+            return C::black();
+        }
 
-            if refl == C::black() {
-                continue;
-            }
+        return env.radiance_besides_spots(ray.dir).map(|x| x * share);
+    }
 
-            let v = vecmath::vec3_dot(*dir, *poly.n());
+    /// Share of the light of `emitter`, hit `dist` along `ray`, that counts
+    /// for a ray picked with density `pdf`, less that of the emitters'
+    /// shadow rays.
+    fn emitter_share(&self, ray: &Ray<T>, dist: T, emitter: &Poly<T, S>, pdf: T) -> T {
+        let i = self.index(emitter);
 
-            let r = Ray {
-                orig: hit_point,
-                dir: *dir,
-            };
+        if !self.emitters.sampled(i) {
+            return T::one();
+        }
 
-            let lambert = {
-                if v < T::zero() {
-                    -v
-                } else {
-                    v
-                }
+        // Points per steradian, as seen from the origin of `ray`.
+        let cos = vecmath::vec3_dot(ray.dir, *emitter.n());
+        let cos = if cos < T::zero() { -cos } else { cos };
+        let density = self.emitters.density(i) * dist * dist / cos;
+
+        return sampling::power_heuristic(pdf, density);
+    }
+
+    /// Index of `poly`, which must be one of the scene's, in `polys`.
+    fn index(&self, poly: &Poly<T, S>) -> usize {
+        return (poly as *const Poly<T, S> as usize - self.polys.as_ptr() as usize)
+            / std::mem::size_of::<Poly<T, S>>();
+    }
+
+    fn has_shadow_links(polys: &[Poly<T, S>]) -> bool {
+        return polys
+            .iter()
+            .any(|p| p.shadow_mask() != !0 || p.shadow_intensity() != T::one());
+    }
+
+    /// All hits of `ray` except with `exclude`, closest first.
+    fn hits(&self, ray: &Ray<T>, exclude: Option<&Poly<T, S>>) -> Vec<(T, Hit<T>, &Poly<T, S>)> {
+        let filtered = self
+            .polys
+            .iter()
+            .filter(|x| !exclude.is_some_and(|e| std::ptr::eq(e, *x)));
+        return geom::hits(filtered, ray);
+    }
+
+    /// Closest hit of `ray` except with `exclude`, skipping hits `keep`
+    /// rejects (e.g. in holes of the surface).
+    fn shoot(
+        &self,
+        ray: &Ray<T>,
+        exclude: Option<&Poly<T, S>>,
+        keep: &geom::Keep<T, S>,
+    ) -> Option<(Hit<T>, &Poly<T, S>)> {
+        #[cfg(feature = "embree")]
+        {
+            let exclude = exclude.map(|p| self.index(p));
+
+            let skip = |i: usize, hit: &Hit<T>| {
+                let p = &self.polys[i];
+                Some(i) == exclude
+                    || (p.sides() == Sides::BackCull && p.is_back(ray.dir))
+                    || !keep(p, hit)
             };
 
-            let light = self
-                .trace(scene, &r, Some(poly), depth + 1)
-                .map2(&refl, |x, y| x * y);
+            return self
+                .accel
+                .shoot(&self.polys, ray, &skip)
+                .map(|(i, hit)| (hit, &self.polys[i]));
+        }
 
-            all_light = all_light.map2(&light, |x, y| x + y * lambert);
+        #[cfg(not(feature = "embree"))]
+        match exclude {
+            Some(that_poly) => {
+                let filtered = self.polys.iter().filter(|x| !that_poly.same(x));
+                return geom::shoot(filtered, ray, keep);
+            }
+            None => return geom::shoot(self.polys.iter(), ray, keep),
         }
+    }
+}
 
-        return all_light;
+#[cfg(feature = "embree")]
+impl<T: Float + Into<f64>, S, C> Scene<T, S, C> {
+    fn new(polys: Vec<Poly<T, S>>) -> Scene<T, S, C>
+    where
+        S: Surface<T, C>,
+        C: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        let accel = embree::Accel::new(&polys);
+        let shadow_links = Scene::<T, S, C>::has_shadow_links(&polys);
+        return Scene {
+            emitters: Emitters::new(&polys),
+            photons: None,
+            fog: None,
+            polys,
+            lights: Vec::new(),
+            environment: None,
+            shadow_links,
+            accel,
+        };
     }
 }
 
+struct RenderOptions {
+    checkerboard: Checkerboard,
+    samples: u32,                  // samples per pixel
+    supersample: u32,              // each spread over an N×N grid of subpixels
+    filter: Filter,                // weighs samples into the pixels around
+    importance: Option<GrayImage>, // scales `samples` per pixel of `frame`
+    frame: [u32; 2],               // size of the image the rendered views are parts of
+    cull: bool,                    // skip polys outside the view for primary rays
+    threads: usize,                // 0 means one per core
+    progress: Progress,
+    sampler: &'static dyn Sampler, // places the samples of a pixel
+    adaptive: Option<Adaptive>,    // more samples for noisy pixels
+    seed: u64,                     // of the random choices, see `Rng::for_pixel`
+    stereo: Option<(Stereo, f64)>, // render a pair of eyes this far apart
+}
+
+/// Where on `poly` light leaves towards the origin of `ray`, which hit it at
+/// `hit`.
+fn point_at<T: Float, S>(ray: &Ray<T>, hit: &Hit<T>, poly: &Poly<T, S>) -> surface::Point<T> {
+    let hit_point = hit.point;
+    let dist = vecmath::vec3_len(vecmath::vec3_sub(hit_point, ray.orig));
+    let uv = poly.uv_at(hit.uv);
+
+    // At grazing angles the area covers a long stretch of the surface.
+    // Textures filter over all of it, blurring rather than aliasing.
+    let (footprint, uv_footprint) = match ray.offsets(dist, *poly.n()) {
+        // Its longer side, in space and in texture coordinates.
+        Some(offsets) => offsets.iter().fold((T::zero(), T::zero()), |(w, t), off| {
+            let [u, v] = poly.uv_at(poly.barycentrics(vecmath::vec3_add(hit_point, *off)));
+            let (du, dv) = (u - uv[0], v - uv[1]);
+            (
+                w.max(vecmath::vec3_len(*off)),
+                t.max((du * du + dv * dv).sqrt()),
+            )
+        }),
+        None => {
+            let cos = vecmath::vec3_dot(ray.dir, *poly.n());
+            let cos = if cos < T::zero() { -cos } else { cos };
+            let stretched = ray.footprint(dist) / cos.max(T::from_f64(1e-3));
+            (stretched, stretched * poly.uv_density())
+        }
+    };
+
+    return surface::Point {
+        pos: hit_point,
+        uv,
+        n: *poly.n(),
+        tangent: poly.tangent(),
+        id: poly.id(),
+        dist,
+        footprint,
+        uv_footprint,
+        outside: T::one(),
+    };
+}
+
+/// Share of the light from `hit_point` on `poly` that gets to the origin of
+/// `ray` through the medium inside the object `poly` bounds, if the ray
+/// runs inside it (hitting the back of `poly`) and it isn't clear.
+fn interior<T: Float + Into<f64>, C: Pixel<Subpixel = T>, S: Surface<T, C>>(
+    ray: &Ray<T>,
+    hit_point: Vector3<T>,
+    poly: &Poly<T, S>,
+) -> Option<C> {
+    if !poly.is_back(ray.dir) {
+        return None;
+    }
+
+    let absorption = poly.surface.interior()?;
+    let dist = vecmath::vec3_len(vecmath::vec3_sub(hit_point, ray.orig));
+    return Some(medium::beer(&absorption, dist));
+}
+
+/// Whether `poly` bounds an object filled with a medium, which paths keep
+/// track of in a `Stack`.
+fn bounds_medium<T, C, S: Surface<T, C>>(poly: &Poly<T, S>) -> bool {
+    return poly.surface.interior().is_some() || poly.surface.ior().is_some();
+}
+
+/// `media` after a path along `dir` went on from `poly`, `through` it or
+/// back.
+fn crossed<'a, T: Float, C, S: Surface<T, C>>(
+    media: &Stack<'a, T, S>,
+    poly: &'a Poly<T, S>,
+    dir: Vector3<T>,
+    through: bool,
+) -> Stack<'a, T, S> {
+    if !through || !bounds_medium(poly) {
+        return media.clone();
+    }
+
+    let priority = poly.surface.priority();
+    return match poly.is_back(dir) {
+        true => media.leaving(poly, priority),
+        false => media.entering(poly, priority),
+    };
+}
+
+/// Index of refraction of the medium on the other side of `poly` than its
+/// object, for a path along `dir` inside `media`.
+fn outside<T: Float, C, S: Surface<T, C>>(
+    media: &Stack<T, S>,
+    poly: &Poly<T, S>,
+    dir: Vector3<T>,
+) -> T {
+    let beyond = match poly.is_back(dir) {
+        true => media.leaving(poly, poly.surface.priority()).top(),
+        false => media.top(),
+    };
+    return beyond.and_then(|p| p.surface.ior()).unwrap_or_else(T::one);
+}
+
+/// Whether hits of `ray` stop at the surface rather than passing through a
+/// hole in it.
+fn opaque<'a, T: Float, C, S: Surface<T, C>>(
+    ray: &'a Ray<T>,
+) -> impl 'a + Fn(&Poly<T, S>, &Hit<T>) -> bool {
+    return move |poly, hit| poly.surface.opaque(point_at(ray, hit, poly));
+}
+
 fn main() {
-    draw_color_polys();
-    //draw_box();
+    let args = cli::parse_with_presets(std::env::args().skip(1).collect()).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, cli::USAGE);
+        std::process::exit(2);
+    });
+
+    if args.low_priority {
+        lower_priority();
+    }
+
+    if let Some(dir) = &args.output_dir {
+        std::fs::create_dir_all(dir).unwrap_or_else(|e| {
+            eprintln!("{}: {}", dir.display(), e);
+            std::process::exit(1);
+        });
+    }
+
+    if args.stress {
+        let ok = stress::run(args.seed, args.rounds, args.threads);
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    if args.furnace {
+        let ok = furnace::run();
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    match &args.obj {
+        Some(path) if args.spectral => draw_obj::<Spectrum<f64>>(&args, path),
+        Some(path) => draw_obj::<Rgb<f64>>(&args, path),
+        None if args.spectral => draw_color_polys::<Spectrum<f64>>(&args),
+        None => draw_color_polys::<Rgb<f64>>(&args),
+    }
+    //draw_box(&args);
+}
+
+/// Lowers the scheduling priority of the process (and all threads it
+/// spawns afterwards), so long renders don't hog the machine.
+fn lower_priority() {
+    #[cfg(unix)]
+    unsafe {
+        // Only fails if the priority is already at the minimum.
+        libc::nice(19);
+    }
+
+    #[cfg(not(unix))]
+    eprintln!("--low-priority is not supported on this platform");
 }
 
-fn draw_box() {
-    let mut img = RgbImage::new(500, 300);
+// Toggled by (un)commenting its call in `main`.
+#[allow(dead_code)]
+fn draw_box(args: &cli::Args) {
+    let mut img = HdrImage::new(500, 300);
 
     let mut polys = Vec::<Poly<f64, Arc<dyn Surface<f64, Rgb<f64>>>>>::new();
 
@@ -194,189 +842,1164 @@ fn draw_box() {
         &mut polys,
     );
 
-    let scene = Scene { polys: polys };
+    number_shapes(&mut polys);
+    set_bias(args, &mut polys);
+    set_links(args, &mut polys);
+    let mut scene = Scene::new(polys);
+    if let Some(fog) = load_fog(args, &scene.polys) {
+        scene.set_fog(fog);
+    }
+
+    let start = Instant::now();
+    args.progress
+        .start("box.png", args.views(1), scene.polys.len());
 
-    let cam = Camera {
-        orig: [5.0, 5.0, -0.2],
-        dir: [0.0, 0.0, -1.0],
-        up: [0.0, 1.0, 0.0],
-        aperture: 30.0 / 180.0 * std::f64::consts::PI, // deg
-    };
+    let cam = Pinhole::look_at(
+        [5.0, 5.0, -0.2],
+        [5.0, 5.0, -1.2],
+        [0.0, 1.0, 0.0],
+        30.0 / 180.0 * std::f64::consts::PI, // deg
+        500.0 / 300.0,
+    )
+    .unwrap();
 
-    let tracer = Tracer::<f64>::new(6, 4);
+    let mut cam = optics(cam, args);
 
-    let gamma = |c: Rgb<f64>| -> Rgb<u8> {
-        *Rgb::from_slice(&c.channels().iter().map(|x| (*x) as u8).collect::<Vec<u8>>())
+    if args.frame_all {
+        frame(&mut *cam, &scene.polys);
+    }
+
+    let integrator = integrator(args, &mut scene, 6, 4);
+
+    let gamma = |c: Rgb<f64>| c;
+
+    let opts = RenderOptions {
+        checkerboard: args.pixels(),
+        samples: args.paths.unwrap_or(1),
+        supersample: args.supersample,
+        filter: args.filter,
+        importance: load_importance(args, img.width(), img.height()),
+        frame: [img.width(), img.height()],
+        cull: args.cull,
+        threads: args.threads,
+        progress: args.progress,
+        sampler: args.sampler,
+        adaptive: args.adaptive(),
+        seed: args.seed,
+        stereo: args.stereo,
     };
 
-    render(&tracer, &scene, &cam, gamma, &mut img);
+    let mut aovs = aov_images(args, 500, 300);
+
+    render(
+        &*integrator,
+        &scene,
+        &*cam,
+        &opts,
+        gamma,
+        [0, 0],
+        &mut img,
+        &mut aovs,
+    );
 
-    img.save("box.png").unwrap();
+    save(args, "box", &img, &aovs);
+
+    args.progress.saved(
+        "box.png",
+        fingerprint(args, &*integrator, &scene, &[&*cam], &opts),
+        start.elapsed().as_secs_f64(),
+    );
 }
 
-fn draw_color_polys() {
-    let mut img = RgbImage::new(1001, 601);
+fn draw_obj<C>(args: &cli::Args, path: &str)
+where
+    C: 'static + FromRgb<f64> + Pixel<Subpixel = f64> + Black + PartialEq + Send + Sync,
+{
+    let mut img = HdrImage::new(500, 300);
+
+    let grey = C::from_rgb(Rgb([0.8, 0.8, 0.8]));
+
+    let surface = match (&args.texture, args.triplanar) {
+        (Some(path), Some(scale)) => surface::triplanar(load_image(path), scale),
+        (Some(path), None) => surface::image_texture(load_image(path)),
+        (None, _) if args.roughness_map.is_some() || args.metalness_map.is_some() => {
+            surface::principled_map(
+                grey,
+                load_map(&args.metalness_map, 0.0),
+                load_map(&args.roughness_map, 0.5),
+                0.5,
+                0.0,
+            )
+        }
+        (None, _) => surface::matt(grey),
+    };
 
-    let mut polys = Vec::<Poly<f64, Arc<dyn Surface<f64, Rgb<f64>>>>>::new();
+    // Overrides the bias of the material `--material-bias` names, even if
+    // it falls back to `surface`.
+    let named = |name: &str| match &args.material_bias {
+        Some((biased, bias)) if biased == name => Some(surface::biased(
+            material(name).unwrap_or_else(|| surface.clone()),
+            *bias,
+        )),
+        _ => material(name),
+    };
+
+    // PLY files have no materials.
+    let loaded = if path.to_lowercase().ends_with(".ply") {
+        ply::load_file::<f64, _, _>(path, surface.clone(), args.progress)
+    } else {
+        obj::load_file::<f64, _, _>(path, surface.clone(), &named, args.progress)
+    };
+
+    let mut polys = loaded.unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+
+    let [lo, hi] = match geom::bounds(polys.iter()) {
+        None => {
+            eprintln!("{}: no faces", path);
+            std::process::exit(1);
+        }
+        Some(b) => b,
+    };
+
+    let cam = Pinhole::look_at(
+        [0.0, 0.0, 10.0],
+        [0.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0],
+        30.0 / 180.0 * std::f64::consts::PI, // deg
+        500.0 / 300.0,
+    )
+    .unwrap();
+
+    let mut cam = optics(cam, args);
+
+    // Frame the mesh only, not the sky.
+    if args.frame_all {
+        frame(&mut *cam, &polys);
+    }
+
+    let env = load_environment(args);
+    let size = vecmath::vec3_len(vecmath::vec3_sub(hi, lo)).max(1.0);
+
+    // Sky, well above and around the mesh, unless the environment stands in
+    // for it.
+    if env.is_none() {
+        shapes::add_par(
+            [lo[0] - 5.0 * size, hi[1] + size, lo[2] - 5.0 * size],
+            [11.0 * size, 0.0, 0.0],
+            [0.0, 0.0, 11.0 * size],
+            match &args.light_texture {
+                Some(path) => surface::image_light(load_image(path), 255.0),
+                None => surface::light(C::from_rgb(Rgb([255.0, 255.0, 255.0]))),
+            },
+            &mut polys,
+        );
+    }
+
+    // Lamp above the mesh, as high as the sky.
+    let center = vecmath::vec3_scale(vecmath::vec3_add(lo, hi), 0.5);
+    let mut lamp = [center[0], hi[1] + size, center[2]];
+
+    if args.anchor {
+        lamp = vecmath::vec3_sub(lamp, cam.pose().orig);
+        anchor(&mut polys, &mut *cam);
+    }
+
+    set_bias(args, &mut polys);
+    set_links(args, &mut polys);
+    let mut scene = Scene::new(polys);
+    if let Some(env) = env {
+        scene.set_environment(env);
+    }
+    if let Some(lamp) = load_ies(args, lamp, size) {
+        scene.add_light(lamp);
+    }
+    if let Some(fog) = load_fog(args, &scene.polys) {
+        scene.set_fog(fog);
+    }
+
+    let start = Instant::now();
+    args.progress
+        .start("obj.png", args.views(1), scene.polys.len());
+
+    let integrator = integrator(args, &mut scene, 6, 2);
+
+    let opts = RenderOptions {
+        checkerboard: args.pixels(),
+        samples: args.paths.unwrap_or(1),
+        supersample: args.supersample,
+        filter: args.filter,
+        importance: load_importance(args, img.width(), img.height()),
+        frame: [img.width(), img.height()],
+        cull: args.cull,
+        threads: args.threads,
+        progress: args.progress,
+        sampler: args.sampler,
+        adaptive: args.adaptive(),
+        seed: args.seed,
+        stereo: args.stereo,
+    };
 
-    polys.push(Poly::new(
-        [[2.0, 1.0, -8.0], [0.0, 0.0, -10.0], [-1.0, 1.0, -9.0]],
-        surface::matt(Rgb([0.5, 0.02, 0.02])),
-    ));
-    polys.push(Poly::new(
-        [[1.0, 1.0, -12.0], [0.0, 3.0, -8.0], [-3.0, -3.0, -8.0]],
-        surface::matt(Rgb([0.02, 0.02, 0.5])),
-    ));
-    polys.push(Poly::new(
-        [[2.0, 0.0, -8.0], [2.0, 0.0, -15.0], [1.5, -3.0, -15.0]],
-        surface::matt(Rgb([0.02, 0.5, 0.02])),
-    ));
-    polys.push(Poly::new(
-        [[-2.0, -1.0, -2.0], [-1.0, 2.0, -12.0], [1.5, -2.0, -5.0]],
-        surface::matt(Rgb([0.4, 0.4, 0.02])),
-    ));
+    let gamma = |c: C| c.to_rgb();
+
+    let mut aovs = aov_images(args, 500, 300);
+
+    render(
+        &*integrator,
+        &scene,
+        &*cam,
+        &opts,
+        gamma,
+        [0, 0],
+        &mut img,
+        &mut aovs,
+    );
+
+    save(args, "obj", &img, &aovs);
+
+    args.progress.saved(
+        "obj.png",
+        fingerprint(args, &*integrator, &scene, &[&*cam], &opts),
+        start.elapsed().as_secs_f64(),
+    );
+}
+
+fn draw_color_polys<C>(args: &cli::Args)
+where
+    C: 'static + FromRgb<f64> + Pixel<Subpixel = f64> + Black + PartialEq + Send + Sync,
+{
+    let mut img = HdrImage::new(1001, 601);
+
+    let mut polys = vec![
+        Poly::new(
+            [[2.0, 1.0, -8.0], [0.0, 0.0, -10.0], [-1.0, 1.0, -9.0]],
+            surface::matt(C::from_rgb(Rgb([0.5, 0.02, 0.02]))),
+        ),
+        Poly::new(
+            [[1.0, 1.0, -12.0], [0.0, 3.0, -8.0], [-3.0, -3.0, -8.0]],
+            surface::matt(C::from_rgb(Rgb([0.02, 0.02, 0.5]))),
+        ),
+        Poly::new(
+            [[2.0, 0.0, -8.0], [2.0, 0.0, -15.0], [1.5, -3.0, -15.0]],
+            surface::matt(C::from_rgb(Rgb([0.02, 0.5, 0.02]))),
+        ),
+        Poly::new(
+            [[-2.0, -1.0, -2.0], [-1.0, 2.0, -12.0], [1.5, -2.0, -5.0]],
+            surface::matt(C::from_rgb(Rgb([0.4, 0.4, 0.02]))),
+        ),
+    ];
 
     // Floor.
     shapes::add_par(
         [-50.0, -5.0, -50.0],
         [100.0, 0.0, 0.0],
         [0.0, 0.0, 100.0],
-        surface::matt(Rgb([0.4, 0.4, 0.4])),
+        surface::matt(C::from_rgb(Rgb([0.4, 0.4, 0.4]))),
         &mut polys,
     );
 
-    // Sky.
-    shapes::add_par(
-        [-50.0, 40.0, -50.0],
-        [100.0, 0.0, 0.0],
-        [0.0, 0.0, 100.0],
-        surface::light(Rgb([80.0, 80.0, 80.0])),
-        &mut polys,
+    let env = load_environment(args);
+
+    // Sky and sun, unless the environment stands in for them.
+    if env.is_none() {
+        shapes::add_par(
+            [-50.0, 40.0, -50.0],
+            [100.0, 0.0, 0.0],
+            [0.0, 0.0, 100.0],
+            surface::light(C::from_rgb(Rgb([80.0, 80.0, 80.0]))),
+            &mut polys,
+        );
+
+        shapes::add_par(
+            [50.0, 40.0, -50.0],
+            [2.0, 0.0, 0.0],
+            [0.0, 0.0, -2.0],
+            surface::light(C::from_rgb(Rgb([255.0, 255.0, 255.0]))),
+            &mut polys,
+        );
+    }
+
+    number_shapes(&mut polys);
+    set_bias(args, &mut polys);
+    set_links(args, &mut polys);
+    let mut scene = Scene::new(polys);
+    if let Some(env) = env {
+        scene.set_environment(env);
+    }
+    // Above the shapes, which are about 13 away from it.
+    if let Some(lamp) = load_ies(args, [0.0, 8.0, -10.0], 13.0) {
+        scene.add_light(lamp);
+    }
+    if let Some(fog) = load_fog(args, &scene.polys) {
+        scene.set_fog(fog);
+    }
+
+    let start = Instant::now();
+    args.progress
+        .start("test.png", args.views(4), scene.polys.len());
+
+    let front = Pinhole::look_at(
+        [0.0, 0.0, 10.0],
+        [0.0, 0.0, -10.0],
+        [0.0, 1.0, 0.0],
+        30.0 / 180.0 * std::f64::consts::PI, // deg
+        500.0 / 300.0,
+    )
+    .unwrap();
+
+    let back = Pinhole::look_at(
+        [0.0, 0.0, -25.0],
+        [0.0, 0.0, -10.0],
+        [0.0, 1.0, 0.0],
+        30.0 / 180.0 * std::f64::consts::PI, // deg
+        500.0 / 300.0,
+    )
+    .unwrap();
+
+    let right = Pinhole::look_at(
+        [20.0, 0.0, -10.0],
+        [0.0, 0.0, -10.0],
+        [0.0, 1.0, 0.0],
+        30.0 / 180.0 * std::f64::consts::PI, // deg
+        500.0 / 300.0,
+    )
+    .unwrap();
+
+    let left = Pinhole::look_at(
+        [-20.0, 0.0, -10.0],
+        [0.0, 0.0, -10.0],
+        [0.0, 1.0, 0.0],
+        30.0 / 180.0 * std::f64::consts::PI, // deg
+        500.0 / 300.0,
+    )
+    .unwrap();
+
+    let [front, back, right, left] = [front, back, right, left].map(|cam| {
+        let mut cam = optics(cam, args);
+
+        if args.frame_all {
+            frame(&mut *cam, &scene.polys);
+        }
+
+        return cam;
+    });
+
+    let integrator = integrator(args, &mut scene, 6, 3);
+
+    let mut opts = RenderOptions {
+        checkerboard: args.pixels(),
+        samples: args.paths.unwrap_or(1),
+        supersample: args.supersample,
+        filter: args.filter,
+        importance: load_importance(args, img.width(), img.height()),
+        frame: [img.width(), img.height()],
+        cull: args.cull,
+        threads: args.threads,
+        progress: args.progress,
+        sampler: args.sampler,
+        adaptive: args.adaptive(),
+        seed: args.seed,
+        stereo: args.stereo,
+    };
+
+    let gamma = |c: C| c.to_rgb();
+
+    let mut aovs = aov_images(args, 1001, 601);
+
+    let views: [(&dyn Camera<f64>, u32, u32); 4] = [
+        (&*front, 0, 0),
+        (&*back, 0, 301),
+        (&*right, 501, 0),
+        (&*left, 501, 301),
+    ];
+
+    // The checkerboard alternates from view to view, ending up where it
+    // started for the fingerprint.
+    for (camera, x, y) in views {
+        render(
+            &*integrator,
+            &scene,
+            camera,
+            &opts,
+            gamma,
+            [x, y],
+            &mut img.sub_image(x, y, 500, 300),
+            &mut sub_images(&mut aovs, x, y, 500, 300),
+        );
+        opts.checkerboard = opts.checkerboard.next();
+    }
+
+    for img in std::iter::once(&mut img).chain(aovs.iter_mut()) {
+        for i in 0..1001 {
+            img.put_pixel(i, 300, Rgb([255.0, 255.0, 255.0]));
+        }
+
+        for i in 0..601 {
+            img.put_pixel(500, i, Rgb([255.0, 255.0, 255.0]));
+        }
+    }
+
+    save(args, "test", &img, &aovs);
+
+    args.progress.saved(
+        "test.png",
+        fingerprint(
+            args,
+            &*integrator,
+            &scene,
+            &[&*front, &*back, &*right, &*left],
+            &opts,
+        ),
+        start.elapsed().as_secs_f64(),
     );
+}
 
-    // Sun.
-    shapes::add_par(
-        [50.0, 40.0, -50.0],
-        [2.0, 0.0, 0.0],
-        [0.0, 0.0, -2.0],
-        surface::light(Rgb([255.0, 255.0, 255.0])),
-        &mut polys,
+/// Integrator following `--ao`, `--normals`, `--whitted`, `--paths` or
+/// `--photons` (scattering them through `scene`), or else branching into
+/// `rays`² directions. With `--aovs`, it adds the first hits as well.
+fn integrator<S, C>(
+    args: &cli::Args,
+    scene: &mut Scene<f64, S, C>,
+    rays: u32,
+    max_depth: u32,
+) -> Box<dyn Integrator<f64, S, C>>
+where
+    S: 'static + Surface<f64, C>,
+    C: 'static + FromRgb<f64> + Pixel<Subpixel = f64> + Black + PartialEq,
+{
+    let integrator: Box<dyn Integrator<f64, S, C>> = if let Some(dist) = args.ao {
+        Box::new(AmbientOcclusion::new(dist))
+    } else if args.normals {
+        Box::new(DebugNormals)
+    } else {
+        let clamp = args.clamp.clone();
+
+        if args.whitted {
+            Box::new(Whitted::new(max_depth).with_clamp(clamp))
+        } else if let Some(count) = args.photons {
+            scene.scatter_photons(count, args.seed);
+            Box::new(PhotonMapper::new(max_depth).with_clamp(clamp))
+        } else {
+            match args.paths {
+                Some(_) if args.bidir => Box::new(Bidir::new(max_depth)),
+                Some(_) => Box::new(PathTracer::new(max_depth).with_clamp(clamp)),
+                None => Box::new(
+                    Grid::new(rays, max_depth)
+                        .with_clamp(clamp)
+                        .with_branching(args.branching.clone()),
+                ),
+            }
+        }
+    };
+
+    if args.aovs {
+        return Box::new(FirstHit::new(integrator));
+    }
+
+    return integrator;
+}
+
+/// The fog of `--fog`, if any, only in the grid of `--volume` and glowing as
+/// that says, or as dense as the noise of `--noise` across `polys`, and
+/// scattering with the asymmetry of `--asymmetry` if given.
+fn load_fog<C, S>(args: &cli::Args, polys: &[Poly<f64, S>]) -> Option<Fog<C>>
+where
+    C: FromRgb<f64> + Pixel<Subpixel = f64>,
+{
+    let [absorption, scattering] = args.fog?;
+    let mut fog = Fog::new(
+        C::from_rgb(Rgb([absorption; 3])),
+        C::from_rgb(Rgb([scattering; 3])),
     );
 
-    let scene = Scene { polys: polys };
+    if let Some(g) = args.asymmetry {
+        fog = fog.with_phase(Box::new(HenyeyGreenstein { g }));
+    }
+
+    if let Some((pattern, frequency, threshold)) = args.fog_noise {
+        let [min, max] = geom::bounds(polys.iter())?;
+        let noise = volume::Noise::new(min, max, pattern, frequency, 4, threshold);
+        return Some(fog.with_density(Box::new(noise)));
+    }
 
-    let front = Camera {
-        orig: [0.0, 0.0, 10.0],
-        dir: [0.0, 0.0, -1.0],
-        up: [0.0, 1.0, 0.0],
-        aperture: 30.0 / 180.0 * std::f64::consts::PI, // deg
+    let path = match &args.volume {
+        Some(path) => path,
+        None => return Some(fog),
     };
 
-    let back = Camera {
-        orig: [0.0, 0.0, -25.0],
-        dir: [0.0, 0.0, 1.0],
-        up: [0.0, 1.0, 0.0],
-        aperture: 30.0 / 180.0 * std::f64::consts::PI, // deg
+    let volume = volume::load_file(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+
+    if let Some((grid, scale)) = volume.emission {
+        fog = fog.with_emission(Emission::gray(grid, scale));
+    }
+    if let Some((grid, scale)) = volume.temperature {
+        fog = fog.with_emission(Emission::blackbody(grid, scale));
+    }
+
+    return Some(fog.with_density(Box::new(volume.density)));
+}
+
+/// The environment map given with `--env`, the sky of `--sky` or that of
+/// `--atmosphere`, if any, linked by `--light-groups env` and
+/// `--shadow-intensity env`.
+fn load_environment<C>(args: &cli::Args) -> Option<Arc<dyn Environment<f64, C>>>
+where
+    C: 'static + FromRgb<f64> + Pixel<Subpixel = f64> + Send + Sync,
+{
+    let environment = load_sky(args)?;
+
+    return Some(match args.links_of(cli::Target::Environment) {
+        Some((groups, intensity)) => lights::linked_environment(environment, groups, intensity),
+        None => environment,
+    });
+}
+
+/// The light around the scene of `load_environment`, not yet linked.
+fn load_sky<C>(args: &cli::Args) -> Option<Arc<dyn Environment<f64, C>>>
+where
+    C: 'static + FromRgb<f64> + Pixel<Subpixel = f64> + Send + Sync,
+{
+    // From where the demo scene's sun is. A clear noon lights white
+    // surfaces to about 255.
+    let e = args.sun_elevation.to_radians();
+    let sun = [-e.cos() / 2f64.sqrt(), -e.sin(), e.cos() / 2f64.sqrt()];
+    let brightness = 255.0 * std::f64::consts::PI / 100.0;
+
+    if let Some(turbidity) = args.sky {
+        return Some(sky::sky(sun, turbidity, brightness));
+    }
+
+    if let Some([altitude, scale]) = args.atmosphere {
+        return Some(atmosphere::atmosphere(sun, altitude, scale, brightness));
+    }
+
+    let path = args.env.as_ref()?;
+
+    let image = lights::read_hdr(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+
+    // HDR files use 1 for white, renders 255.
+    return Some(lights::environment(image, 255.0));
+}
+
+/// Lamp at `pos` shining down with the profile of --ies if given, bright
+/// enough to light white surfaces `dist` below it to about 255, linked by
+/// `--light-groups lamp` and `--shadow-intensity lamp`.
+fn load_ies<C>(args: &cli::Args, pos: Vector3<f64>, dist: f64) -> Option<Arc<dyn Light<f64, C>>>
+where
+    C: 'static + FromRgb<f64> + Pixel<Subpixel = f64> + Send + Sync,
+{
+    let path = args.ies.as_ref()?;
+
+    let profile = ies::load_file(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+
+    let light = lights::point(pos, C::from_rgb(Rgb([255.0 * dist * dist; 3])));
+
+    let lamp = lights::profiled(light, [0.0, -1.0, 0.0], [0.0, 0.0, 1.0], profile);
+
+    return Some(match args.links_of(cli::Target::Lamp) {
+        Some((groups, intensity)) => lights::linked(lamp, groups, intensity),
+        None => lamp,
+    });
+}
+
+/// The image at `path`, exiting if it can't be read.
+fn load_image(path: &str) -> DynamicImage {
+    return image::open(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+}
+
+/// Surface parameter read from the map at `path` if given, else `value`
+/// all over, see `surface::image_map`.
+fn load_map(
+    path: &Option<String>,
+    value: f64,
+) -> Box<dyn Fn(surface::Point<f64>) -> f64 + Send + Sync> {
+    return match path {
+        Some(path) => Box::new(surface::image_map(load_image(path))),
+        None => Box::new(move |_| value),
     };
+}
+
+/// Built in surface for the OBJ material `name`, if there is one: `gold`,
+/// `copper`, `aluminum` and `silver` are brushed metals, `marble`, `wood`
+/// and `stone` procedural textures, a few features per unit.
+fn material<C>(name: &str) -> Option<Arc<dyn Surface<f64, C>>>
+where
+    C: 'static + FromRgb<f64> + Pixel<Subpixel = f64> + Black + Send + Sync,
+{
+    if let Some(metal) = surface::Metal::from_name(name) {
+        return Some(surface::metal(metal, 0.3));
+    }
 
-    let right = Camera {
-        orig: [20.0, 0.0, -10.0],
-        dir: [-1.0, 0.0, 0.0],
-        up: [0.0, 1.0, 0.0],
-        aperture: 30.0 / 180.0 * std::f64::consts::PI, // deg
+    let rgb = |c| C::from_rgb(Rgb(c));
+
+    let (a, b) = match name {
+        "marble" => ([0.85, 0.85, 0.82], [0.25, 0.25, 0.3]),
+        "wood" => ([0.6, 0.4, 0.2], [0.35, 0.2, 0.1]),
+        "stone" => ([0.5, 0.48, 0.45], [0.2, 0.2, 0.2]),
+        _ => return None,
     };
 
-    let left = Camera {
-        orig: [-20.0, 0.0, -10.0],
-        dir: [1.0, 0.0, 0.0],
-        up: [0.0, 1.0, 0.0],
-        aperture: 30.0 / 180.0 * std::f64::consts::PI, // deg
+    let surface = match name {
+        "marble" => surface::marble(rgb(a), rgb(b), 1.0, 5),
+        "wood" => surface::wood(rgb(a), rgb(b), 4.0, 3),
+        _ => surface::turbulence(rgb(a), rgb(b), 2.0, 6),
     };
 
-    let tracer = Tracer::<f64>::new(6, 3);
+    // Noise is costly to evaluate at every bounce, so indirect hits shade
+    // with the mean of the two colors instead.
+    let mean = rgb([0, 1, 2].map(|c| (a[c] + b[c]) / 2.0));
+
+    return Some(surface::with_indirect(surface, surface::matt(mean)));
+}
+
+/// Importance map of `--importance`, `--preview` or `--region` for a
+/// `width`×`height` image, if any: outside of the region, pixels get one
+/// sample.
+fn load_importance(args: &cli::Args, width: u32, height: u32) -> Option<GrayImage> {
+    if let Some(path) = &args.importance {
+        return Some(load_image(path).into_luma8());
+    }
+
+    if let Some(path) = &args.preview {
+        return Some(sampling::importance_from(&load_image(path).into_rgb8()));
+    }
+
+    return args.region.map(|[x, y, w, h]| {
+        if x >= width || y >= height {
+            eprintln!("--region is outside of the {}×{} image", width, height);
+            std::process::exit(1);
+        }
+
+        return sampling::region(width, height, x, y, w, h, 0);
+    });
+}
 
-    let gamma = |c: Rgb<f64>| -> Rgb<u8> {
-        *Rgb::from_slice(&c.channels().iter().map(|x| (*x) as u8).collect::<Vec<u8>>())
+/// Blank images for the AOVs of a draw, none unless requested: all of them
+/// for `--aovs`, the alpha for `--alpha`.
+fn aov_images(args: &cli::Args, width: u32, height: u32) -> Vec<HdrImage> {
+    let count = if args.aovs {
+        passes::COUNT
+    } else if args.alpha {
+        passes::ALPHA + 1
+    } else {
+        0
     };
 
-    render(
-        &tracer,
-        &scene,
-        &front,
-        gamma,
-        &mut img.sub_image(0, 0, 500, 300),
-    );
-    render(
-        &tracer,
-        &scene,
-        &back,
-        gamma,
-        &mut img.sub_image(0, 301, 500, 300),
-    );
-    render(
-        &tracer,
-        &scene,
-        &right,
-        gamma,
-        &mut img.sub_image(501, 0, 500, 300),
-    );
-    render(
-        &tracer,
-        &scene,
-        &left,
-        gamma,
-        &mut img.sub_image(501, 301, 500, 300),
-    );
+    return (0..count).map(|_| HdrImage::new(width, height)).collect();
+}
+
+/// The same region of each of `imgs`.
+fn sub_images(
+    imgs: &mut [HdrImage],
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> Vec<SubImage<&mut HdrImage>> {
+    return imgs
+        .iter_mut()
+        .map(|img| img.sub_image(x, y, width, height))
+        .collect();
+}
+
+/// Saves the draw `img` as `stem`.png (see `output::save`), transparent
+/// where it shows nothing if `--alpha`, and its AOVs as `stem`.<aov>.png if
+/// `--aovs`. The alpha and those of the first hit hold data, not light.
+fn save(args: &cli::Args, stem: &str, img: &HdrImage, aovs: &[HdrImage]) {
+    let alpha = aovs.get(passes::ALPHA).filter(|_| args.alpha);
+    output::save(args, stem, img, alpha);
 
-    for i in 0..1001 {
-        img.put_pixel(i, 300, Rgb([255, 255, 255]));
+    if !args.aovs {
+        return;
     }
 
-    for i in 0..601 {
-        img.put_pixel(500, i, Rgb([255, 255, 255]));
+    for (k, (img, name)) in aovs.iter().zip(passes::NAMES.iter()).enumerate() {
+        let stem = format!("{}.{}", stem, name);
+        if k == passes::ALPHA || k >= passes::FIRST_HIT {
+            output::save_data(args, &stem, img);
+        } else {
+            output::save(args, &stem, img, None);
+        }
     }
+}
 
-    img.save("test.png").unwrap();
+/// Stable hash of everything that determines a render: geometry,
+/// materials, integrator and render settings and the cameras.
+fn fingerprint<F, S, C>(
+    args: &cli::Args,
+    integrator: &dyn Integrator<F, S, C>,
+    scene: &Scene<F, S, C>,
+    cameras: &[&dyn Camera<F>],
+    opts: &RenderOptions,
+) -> u64
+where
+    F: Float + Into<f64>,
+    S: Surface<F, C>,
+    C: Pixel<Subpixel = F> + Black + PartialEq,
+{
+    let mut h = Fingerprint::new();
+
+    for p in scene.polys.iter() {
+        h.write_poly(p);
+    }
+
+    for l in scene.lights.iter() {
+        h.write_light(&**l);
+    }
+    if let Some(env) = &scene.environment {
+        h.write_environment(&**env);
+    }
+    if let Some(fog) = &scene.fog {
+        h.write_pixel(&fog.absorption);
+        h.write_pixel(&fog.scattering);
+        fog.phase.fingerprint(&mut h);
+        if let Some(grid) = &fog.density {
+            grid.fingerprint(&mut h);
+        }
+        if let Some(emission) = &fog.emission {
+            emission.grid.fingerprint(&mut h);
+            for c in emission.ramp.iter() {
+                h.write_pixel(c);
+            }
+        }
+    }
+
+    integrator.fingerprint(&mut h);
+    h.write_u32(scene.photons.as_ref().map_or(0, |m| m.len() as u32));
+
+    for c in cameras {
+        c.fingerprint(&mut h);
+    }
+
+    h.write_u32(opts.checkerboard as u32);
+    h.write_u32(opts.samples);
+    h.write_u32(opts.supersample);
+    h.write_u32(opts.filter as u32);
+    h.write_u32(opts.cull as u32);
+    h.write_bytes(opts.sampler.name().as_bytes());
+    if let Some(adaptive) = &opts.adaptive {
+        h.write_f64(adaptive.noise);
+        h.write_u32(adaptive.max_samples);
+    }
+    h.write_u64(opts.seed);
+    if let Some((stereo, dist)) = opts.stereo {
+        h.write_u32(stereo as u32);
+        h.write_f64(dist);
+    }
+    if let Some(importance) = &opts.importance {
+        h.write_u32(importance.width());
+        h.write_u32(importance.height());
+        h.write_bytes(importance.as_raw());
+    }
+
+    output::fingerprint(args, &mut h);
+
+    return h.finish();
 }
 
+/// Renders what `camera` sees into `img`, which is at `at` in `opts.frame`,
+/// as one view or a stereo pair.
+#[allow(clippy::too_many_arguments)]
 fn render<
-    F: Float,
+    F: Float + Into<f64>,
     S: Surface<F, C>,
-    C: Pixel<Subpixel = F> + Black + PartialEq,
+    C: Pixel<Subpixel = F> + Black + PartialEq + Send + Sync,
     I: GenericImage,
     G: Fn(C) -> I::Pixel,
 >(
-    tracer: &Tracer<F>,
-    scene: &Scene<F, S>,
-    camera: &Camera<F>,
+    integrator: &dyn Integrator<F, S, C>,
+    scene: &Scene<F, S, C>,
+    camera: &dyn Camera<F>,
+    opts: &RenderOptions,
     gamma: G,
+    at: [u32; 2],
     img: &mut I,
+    aovs: &mut [I],
+) where
+    I::Pixel: 'static,
+{
+    let (dist, stereo) = match opts.stereo {
+        None => {
+            return render_eye(
+                integrator,
+                scene,
+                camera,
+                F::zero(),
+                opts.checkerboard,
+                opts,
+                &gamma,
+                at,
+                img,
+                aovs,
+            )
+        }
+        Some((stereo, dist)) => (F::from_f64(dist * 0.5), stereo),
+    };
+
+    let (width, height) = img.dimensions();
+
+    match stereo {
+        Stereo::SideBySide => {
+            let half = width / 2;
+
+            let eyes = [
+                (0, -dist, opts.checkerboard),
+                (half, dist, opts.checkerboard.next()),
+            ];
+            for (x, eye, checkerboard) in eyes {
+                let mut aovs: Vec<_> = aovs
+                    .iter_mut()
+                    .map(|aov| aov.sub_image(x, 0, half, height))
+                    .collect();
+                let mut img = img.sub_image(x, 0, half, height);
+                render_eye(
+                    integrator,
+                    scene,
+                    camera,
+                    eye,
+                    checkerboard,
+                    opts,
+                    &gamma,
+                    [at[0] + x, at[1]],
+                    &mut img,
+                    &mut aovs,
+                );
+            }
+        }
+        Stereo::Anaglyph => {
+            let eyes = [(-dist, opts.checkerboard), (dist, opts.checkerboard.next())];
+            let [left, right] = eyes.map(|(eye, checkerboard)| {
+                let blank = || ImageBuffer::<I::Pixel, Vec<_>>::new(width, height);
+                let mut img = blank();
+                let mut aovs: Vec<_> = aovs.iter().map(|_| blank()).collect();
+                render_eye(
+                    integrator,
+                    scene,
+                    camera,
+                    eye,
+                    checkerboard,
+                    opts,
+                    &gamma,
+                    at,
+                    &mut img,
+                    &mut aovs,
+                );
+                return std::iter::once(img).chain(aovs).collect::<Vec<_>>();
+            });
+
+            // Red from the left eye, green and blue from the right.
+            let outputs = std::iter::once(img).chain(aovs.iter_mut());
+            for (to, (left, right)) in outputs.zip(left.iter().zip(right.iter())) {
+                for (x, y, p) in right.enumerate_pixels() {
+                    let mut p = *p;
+                    p.channels_mut()[0] = left.get_pixel(x, y).channels()[0];
+                    to.put_pixel(x, y, p);
+                }
+            }
+        }
+    }
+}
+
+/// Renders what `camera` sees from `eye` to its right (or left, if
+/// negative), tracing the pixels of `checkerboard`, into `img` at `at` in
+/// `opts.frame`, see `render`.
+#[allow(clippy::too_many_arguments)]
+fn render_eye<
+    F: Float + Into<f64>,
+    S: Surface<F, C>,
+    C: Pixel<Subpixel = F> + Black + PartialEq + Send + Sync,
+    I: GenericImage,
+    G: Fn(C) -> I::Pixel,
+>(
+    integrator: &dyn Integrator<F, S, C>,
+    scene: &Scene<F, S, C>,
+    camera: &dyn Camera<F>,
+    eye: F,
+    checkerboard: Checkerboard,
+    opts: &RenderOptions,
+    gamma: G,
+    at: [u32; 2],
+    img: &mut I,
+    aovs: &mut [I],
 ) {
     let (width, height) = img.dimensions();
+    let shift = vecmath::vec3_scale(camera.pose().basis()[0], eye);
     let center = vecmath::vec2_scale([F::from_u32(width), F::from_u32(height)], F::from_f64(0.5));
-    let pix_ang = camera.aperture / F::from_u32(width);
 
-    let xrot = camera.up;
-    let yrot = vecmath::vec3_normalized(vecmath::vec3_cross(camera.dir, camera.up));
+    let size = (width * height) as usize;
+
+    // Each sample becomes a ray through every cell of the subpixel grid, in
+    // turn. No pixel's budget exceeds the samples, so this bounds them all.
+    let grid = opts.supersample;
+    if grid
+        .checked_mul(grid)
+        .and_then(|g| g.checked_mul(opts.samples))
+        .is_none()
+    {
+        eprintln!(
+            "too many rays per pixel: {} samples of {}×{}",
+            opts.samples, grid, grid
+        );
+        std::process::exit(1);
+    }
 
-    for x in 0..width {
-        for y in 0..height {
-            let pos = [F::from_u32(x), F::from_u32(y)];
-            let angles = vecmath::vec2_scale(vecmath::vec2_sub(pos, center), pix_ang);
+    opts.progress.view(width, height);
+
+    // Fisheyes leave the pixels beyond what they see black. The rays
+    // through the next pixels make the same random choices, so a ray
+    // through a lens goes towards them in focus.
+    let primary_ray = |x: F, y: F, sample: &mut Rng| -> Option<Ray<F>> {
+        let generate = |x: F, y: F, sample: &mut Rng| {
+            let [px, py] = [(x - center[0]) / center[0], (y - center[1]) / center[1]];
+            let r = camera.generate_ray(px, py, sample)?;
+            return Some(Ray {
+                orig: vecmath::vec3_add(r.orig, shift),
+                ..r
+            });
+        };
 
-            let q = quaternion::mul(
-                quaternion::axis_angle(xrot, -angles[0]),
-                quaternion::axis_angle(yrot, -angles[1]),
-            );
+        let start = sample.clone();
+        let r = generate(x, y, sample)?;
 
-            let r = Ray {
-                orig: camera.orig,
-                dir: quaternion::rotate_vector(q, camera.dir),
-            };
+        let towards = |x: F, y: F| -> Option<Differential<F>> {
+            let other = generate(x, y, &mut start.clone())?;
+            return Some(Differential {
+                orig: vecmath::vec3_sub(other.orig, r.orig),
+                dir: vecmath::vec3_sub(other.dir, r.dir),
+            });
+        };
+        let diffs = [towards(x + F::one(), y), towards(x, y + F::one())];
+
+        // Grows by about a pixel per pixel, as the differentials.
+        let cone = diffs[0].or(diffs[1]);
+
+        return Some(Ray {
+            width: cone.map_or(F::zero(), |d| vecmath::vec3_len(d.orig)),
+            spread: cone.map_or(F::zero(), |d| vecmath::vec3_len(d.dir)),
+            diffs: match diffs {
+                [Some(dx), Some(dy)] => Some([dx, dy]),
+                _ => None,
+            },
+            ..r
+        });
+    };
 
-            let light = tracer.trace(scene, &r, None, 0);
+    // Polys primary rays are tested against, if all leave from the camera
+    // within a frustum. Embree's BVH skips the others at least as quickly
+    // as shooting at the visible ones one by one would.
+    let visible = if opts.cull && camera.pinhole() && !cfg!(feature = "embree") {
+        let (w, h) = (F::from_u32(width), F::from_u32(height));
+        let corner = |x: F, y: F| primary_ray(x, y, &mut Rng::new(0)).unwrap().dir;
+
+        // Leave room for subpixel offsets.
+        let corners = [
+            corner(-F::one(), -F::one()),
+            corner(w + F::one(), -F::one()),
+            corner(w + F::one(), h + F::one()),
+            corner(-F::one(), h + F::one()),
+        ];
+
+        let orig = vecmath::vec3_add(camera.pose().orig, shift);
+        Some(frustum::cull(&scene.polys, orig, corners))
+    } else {
+        None
+    };
+
+    let primary_hit = |r: &Ray<F>| match &visible {
+        Some(polys) => geom::shoot(polys.iter().copied(), r, &opaque::<F, C, S>(r)),
+        None => scene.shoot(r, None, &opaque::<F, C, S>(r)),
+    };
+
+    let opaque = C::black().map(|_| F::from_f64(255.0));
+    let trace_primary = |r: &Ray<F>, rng: &mut Rng| -> Passes<C> {
+        let hit = primary_hit(r);
+        let light = integrator.light(scene, r, hit, rng);
+
+        return match hit {
+            Some(_) => light.with_alpha(opaque),
+            None => light,
+        };
+    };
+
+    let threads = if opts.threads == 0 {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        opts.threads
+    };
 
-            img.put_pixel(x, y, gamma(light));
+    // Light of the samples around each pixel, weighted by `opts.filter`,
+    // and the weights.
+    let splats = Mutex::new(vec![(Passes::black(), F::zero()); size]);
+    let mut gbuf = vec![None; size];
+
+    // Pixels around a pixel its samples reach, each way.
+    let reach = opts.filter.radius().ceil() as u32;
+
+    // Rows are handed out to the workers one at a time.
+    let rows = Mutex::new(gbuf.chunks_mut(width as usize).enumerate());
+
+    let rows_done = Mutex::new(0);
+
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| loop {
+                let next = rows.lock().unwrap().next();
+
+                let (y, grow) = match next {
+                    None => break,
+                    Some(row) => row,
+                };
+
+                let y = y as u32;
+
+                // The rows the samples of this one reach, added to `splats`
+                // in one go.
+                let top = y.saturating_sub(reach);
+                let bottom = (y + reach).min(height - 1);
+                let mut local =
+                    vec![(Passes::black(), F::zero()); ((bottom - top + 1) * width) as usize];
+
+                for x in 0..width {
+                    if checkerboard != Checkerboard::Off {
+                        // Primary hits are cheap compared to a full trace, so
+                        // we get them for every pixel to guide the
+                        // reconstruction.
+                        let r = primary_ray(F::from_u32(x), F::from_u32(y), &mut Rng::new(0));
+                        grow[x as usize] = r.and_then(|r| {
+                            primary_hit(&r).map(|(hit, poly)| {
+                                (
+                                    vecmath::vec3_len(vecmath::vec3_sub(hit.point, r.orig)),
+                                    *poly.n(),
+                                )
+                            })
+                        });
+                    }
+
+                    if !checkerboard.traced(x, y) {
+                        continue;
+                    }
+
+                    let n = sampling::budget(
+                        opts.samples,
+                        opts.importance.as_ref(),
+                        at[0] + x,
+                        at[1] + y,
+                        opts.frame,
+                    );
+
+                    let n = n * grid * grid;
+
+                    // The rays spread over the pixel (within their cells)
+                    // and their first bounces over the hemisphere, seeded by
+                    // where it is in the frame so other views differ.
+                    let mut rng = Rng::for_pixel(opts.seed, at[0] + x, at[1] + y);
+                    let scramble = [rng.next(), rng.next(), rng.next(), rng.next()];
+
+                    // Of the samples' brightness and its square, for
+                    // `opts.adaptive`.
+                    let mut moments = (0.0, 0.0);
+
+                    let mut i = 0;
+                    while i < n
+                        || opts
+                            .adaptive
+                            .is_some_and(|a| !a.converged(i, moments.0, moments.1))
+                    {
+                        let [dx, dy, u, v] = opts.sampler.point(i, scramble);
+                        let cell = i % (grid * grid);
+                        let px = x as f64 + (dx + (cell % grid) as f64) / grid as f64;
+                        let py = y as f64 + (dy + (cell / grid) as f64) / grid as f64;
+                        let r = primary_ray(F::from_f64(px), F::from_f64(py), &mut rng);
+                        rng.stratify([u, v]);
+                        let light = match r {
+                            Some(r) => trace_primary(&r, &mut rng),
+                            None => Passes::black(),
+                        };
+
+                        for ny in top..=bottom {
+                            for nx in x.saturating_sub(reach)..=(x + reach).min(width - 1) {
+                                let w = opts
+                                    .filter
+                                    .weight(px - nx as f64 - 0.5, py - ny as f64 - 0.5);
+
+                                if w == 0.0 || !checkerboard.traced(nx, ny) {
+                                    continue;
+                                }
+
+                                let w = F::from_f64(w);
+                                let (sum, weight) = &mut local[((ny - top) * width + nx) as usize];
+                                *sum = sum.zip(&light, |x, y| x.map2(y, |a, b| a + b * w));
+                                *weight += w;
+                            }
+                        }
+
+                        let channels = light.beauty.channels();
+                        let b = channels.iter().map(|c| (*c).into()).sum::<f64>()
+                            / channels.len() as f64;
+                        moments = (moments.0 + b, moments.1 + b * b);
+
+                        i += 1;
+                    }
+                }
+
+                let mut splats = splats.lock().unwrap();
+                let start = (top * width) as usize;
+                for (to, from) in splats[start..].iter_mut().zip(local.iter()) {
+                    to.0 = to.0.zip(&from.0, |x, y| x.map2(y, |a, b| a + b));
+                    to.1 += from.1;
+                }
+                drop(splats);
+
+                // Report under the lock, so counts arrive in order.
+                let mut done = rows_done.lock().unwrap();
+                *done += 1;
+                opts.progress.rows(*done, height);
+            });
         }
+    });
+
+    let buf: Vec<Passes<C>> = splats
+        .into_inner()
+        .unwrap()
+        .iter()
+        .map(|(sum, weight)| {
+            if *weight == F::zero() {
+                Passes::black()
+            } else {
+                sum.map(|x| x.map(|v| v / *weight))
+            }
+        })
+        .collect();
+
+    let write = |pass: &dyn Fn(&Passes<C>) -> C, img: &mut I| {
+        let mut pbuf: Vec<C> = buf.iter().map(pass).collect();
+
+        checkerboard::reconstruct(checkerboard, width, height, &gbuf, &mut pbuf);
+
+        for y in 0..height {
+            for x in 0..width {
+                img.put_pixel(x, y, gamma(pbuf[(y * width + x) as usize]));
+            }
+        }
+    };
+
+    write(&|p| p.beauty, img);
+
+    for (k, aov) in aovs.iter_mut().enumerate() {
+        write(&|p| p.aovs[k], aov);
     }
 }
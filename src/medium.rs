@@ -0,0 +1,448 @@
+use image::{Pixel, Primitive, Rgb};
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::geom::Poly;
+use crate::phase::{Isotropic, PhaseFunction};
+use crate::sampling::Rng;
+use crate::spectrum::{self, FromRgb};
+use crate::volume::{self, Density, Grid};
+
+/// Fog filling all of space evenly, or, with a `density` (e.g. a grid), as
+/// dense as that (e.g. smoke or a cloud) and clear outside its box. Per unit of distance,
+/// it absorbs the share `absorption` of the light travelling through it and
+/// scatters the share `scattering` to other directions (each per channel,
+/// at density 1), so distant things fade and light crossing it shows, e.g.
+/// as shafts through gaps. Light from infinitely far away (the environment
+/// and directional lights) doesn't get through even fog. It scatters by
+/// `phase`, evenly unless given, and may glow by `emission`.
+pub struct Fog<C> {
+    pub absorption: C,
+    pub scattering: C,
+    pub density: Option<Box<dyn Density>>,
+    pub phase: Box<dyn PhaseFunction>,
+    pub emission: Option<Emission<C>>,
+}
+
+impl<C> Fog<C> {
+    pub fn new(absorption: C, scattering: C) -> Fog<C> {
+        return Fog {
+            absorption,
+            scattering,
+            density: None,
+            phase: Box::new(Isotropic),
+            emission: None,
+        };
+    }
+
+    /// The fog, only where `density` has it and as dense.
+    pub fn with_density(mut self, density: Box<dyn Density>) -> Fog<C> {
+        self.density = Some(density);
+        return self;
+    }
+
+    /// The fog, scattering by `phase`.
+    pub fn with_phase(mut self, phase: Box<dyn PhaseFunction>) -> Fog<C> {
+        self.phase = phase;
+        return self;
+    }
+
+    /// The fog, giving off the light of `emission`.
+    pub fn with_emission(mut self, emission: Emission<C>) -> Fog<C> {
+        self.emission = Some(emission);
+        return self;
+    }
+}
+
+impl<T: Float + Into<f64>, C: Pixel<Subpixel = T>> Fog<C> {
+    /// Share of the light lost per unit of distance, absorbed or scattered,
+    /// at density 1.
+    pub fn extinction(&self) -> C {
+        return self.absorption.map2(&self.scattering, |a, b| a + b);
+    }
+
+    /// Density at `p`, 1 everywhere for even fog.
+    pub fn density_at(&self, p: Vector3<T>) -> T {
+        return match &self.density {
+            Some(density) => T::from_f64(density.at(p.map(|x| x.into()))),
+            None => T::one(),
+        };
+    }
+
+    /// Part of the first `dist` of the ray from `orig` along `dir` where
+    /// there is fog, as distances, `None` if there is none.
+    pub fn span(&self, orig: Vector3<T>, dir: Vector3<T>, dist: T) -> Option<(T, T)> {
+        return match &self.density {
+            Some(density) => volume::span(density.bounds(), orig, dir, dist),
+            None if dist > T::zero() => Some((T::zero(), dist)),
+            None => None,
+        };
+    }
+
+    /// Share of the light getting through `dist` of even fog.
+    pub fn transmittance(&self, dist: T) -> C {
+        return beer(&self.extinction(), dist);
+    }
+
+    /// Share of the light getting through the first `dist` of the ray from
+    /// `orig` along `dir`. Uneven fog estimates it by ratio tracking, which
+    /// is exact on average.
+    pub fn transmittance_along(
+        &self,
+        orig: Vector3<T>,
+        dir: Vector3<T>,
+        dist: T,
+        rng: &mut Rng,
+    ) -> C {
+        let white = self.absorption.map(|_| T::one());
+
+        if self.density.is_none() {
+            return self.transmittance(dist);
+        }
+
+        let (mut t, end) = match self.span(orig, dir, dist) {
+            Some(span) => span,
+            None => return white,
+        };
+
+        let majorant = self.majorant();
+        if majorant == T::zero() {
+            return white;
+        }
+
+        let extinction = self.extinction();
+        let mut res = white;
+
+        // Steps between tentative collisions, each passed by the share of
+        // the majorant that isn't real.
+        loop {
+            t += T::from_f64(-(1.0 - rng.unit()).ln()) / majorant;
+
+            if t >= end || res.channels().iter().all(|x| *x == T::zero()) {
+                return res;
+            }
+
+            let d = self.density_at(vecmath::vec3_add(orig, vecmath::vec3_scale(dir, t)));
+            res = res.map2(&extinction, |r, e| r * (T::one() - e * d / majorant));
+        }
+    }
+
+    /// Where a path along the first `dist` of the ray from `orig` along
+    /// `dir` scatters, if before `dist`, with its weight: the scattering
+    /// there times the light getting there, over the density of the pick.
+    /// Otherwise the weight is the light getting through over the chance of
+    /// that, black if the path is dropped. Even fog picks the distance by
+    /// how much light gets there for a random channel, uneven fog by delta
+    /// tracking with every channel.
+    pub fn sample(
+        &self,
+        orig: Vector3<T>,
+        dir: Vector3<T>,
+        dist: T,
+        rng: &mut Rng,
+    ) -> (Option<T>, C) {
+        let extinction = self.extinction();
+        let channels = extinction.channels();
+        let n = T::from_f64(channels.len() as f64);
+        let sum = |c: C| c.channels().iter().fold(T::zero(), |s, x| s + *x);
+
+        if self.density.is_none() {
+            let e = channels[(rng.next() % channels.len() as u64) as usize];
+            let t = if e == T::zero() {
+                T::from_f64(f64::INFINITY)
+            } else {
+                T::from_f64(-(1.0 - rng.unit()).ln()) / e
+            };
+
+            if t < dist {
+                let through = self.transmittance(t);
+
+                // Density of `t`, averaged over the channels.
+                let pdf = sum(extinction.map2(&through, |a, b| a * b)) / n;
+                return (Some(t), self.scattering.map2(&through, |a, b| a * b / pdf));
+            }
+
+            let through = self.transmittance(dist);
+
+            // Chance that `t` is past `dist`, averaged over the channels.
+            let chance = sum(through) / n;
+            if chance == T::zero() {
+                return (None, through);
+            }
+
+            return (None, through.map(|x| x / chance));
+        }
+
+        let mut weight = self.absorption.map(|_| T::one());
+
+        let (mut t, end) = match self.span(orig, dir, dist) {
+            Some(span) => span,
+            None => return (None, weight),
+        };
+
+        let majorant = self.majorant();
+        if majorant == T::zero() {
+            return (None, weight);
+        }
+
+        // At each tentative collision, the path scatters or goes on (as
+        // through a null collision), more likely the more light either
+        // carries. Absorbed paths would bring nothing.
+        loop {
+            t += T::from_f64(-(1.0 - rng.unit()).ln()) / majorant;
+
+            if t >= end {
+                return (None, weight);
+            }
+
+            let d = self.density_at(vecmath::vec3_add(orig, vecmath::vec3_scale(dir, t)));
+            let scattering = self.scattering.map(|s| s * d);
+            let null = extinction.map(|e| majorant - e * d);
+
+            let ps = sum(scattering.map2(&weight, |a, b| a * b));
+            let pn = sum(null.map2(&weight, |a, b| a * b));
+
+            if ps + pn == T::zero() {
+                return (None, weight.map(|_| T::zero()));
+            }
+
+            if T::from_f64(rng.unit()) * (ps + pn) < ps {
+                let p = ps / (ps + pn);
+                return (
+                    Some(t),
+                    weight.map2(&scattering, |w, s| w * s / (majorant * p)),
+                );
+            }
+
+            let p = pn / (ps + pn);
+            weight = weight.map2(&null, |w, x| w * x / (majorant * p));
+        }
+    }
+
+    /// Light the fog gives off along the first `dist` of the ray from
+    /// `orig` along `dir` that gets to `orig`, estimated at one random
+    /// point. Black if it doesn't glow.
+    pub fn emitted_along(&self, orig: Vector3<T>, dir: Vector3<T>, dist: T, rng: &mut Rng) -> C {
+        let black = self.absorption.map(|_| T::zero());
+
+        let emission = match &self.emission {
+            Some(emission) => emission,
+            None => return black,
+        };
+
+        let (from, to) = match emission.grid.span(orig, dir, dist) {
+            Some(span) => span,
+            None => return black,
+        };
+
+        let t = from + (to - from) * T::from_f64(rng.unit());
+        let p = vecmath::vec3_add(orig, vecmath::vec3_scale(dir, t));
+        let through = self.transmittance_along(orig, dir, t, rng);
+
+        return emission.at(p).map2(&through, |a, b| a * b * (to - from));
+    }
+
+    /// `span`, up to where hardly any light scattered there would get back
+    /// in even fog.
+    pub fn range(&self, orig: Vector3<T>, dir: Vector3<T>, dist: T) -> Option<(T, T)> {
+        let (from, to) = self.span(orig, dir, dist)?;
+        return Some((from, to.min(from + self.reach(1e-3))));
+    }
+
+    /// Random point of the `range` of the first `dist` of the ray from
+    /// `orig` along `dir`, as its distance, with the density of the pick.
+    /// Even fog picks evenly, uneven fog by how much light would get there
+    /// through fog as dense as its densest part. `None` if there is none.
+    pub fn pick(
+        &self,
+        orig: Vector3<T>,
+        dir: Vector3<T>,
+        dist: T,
+        rng: &mut Rng,
+    ) -> Option<(T, T)> {
+        let (from, to) = self.range(orig, dir, dist)?;
+        let u = T::from_f64(rng.unit());
+
+        let majorant = self.majorant();
+        if self.density.is_none() || majorant == T::zero() {
+            return Some((from + (to - from) * u, T::one() / (to - from)));
+        }
+
+        // Cut off at `to`.
+        let len: f64 = (to - from).into();
+        let norm = T::from_f64(1.0 - (-(majorant.into() * len)).exp());
+        let t = T::from_f64(-(T::one() - u * norm).into().ln()) / majorant;
+        let pdf = majorant * T::from_f64((-(majorant * t).into()).exp()) / norm;
+
+        return Some((from + t, pdf));
+    }
+
+    /// Distance after which less than `share` of the light gets through in
+    /// every channel the fog dims, infinite for clear fog and uneven fog,
+    /// which may be clearer anywhere.
+    pub fn reach(&self, share: f64) -> T {
+        if self.density.is_some() {
+            return T::from_f64(f64::INFINITY);
+        }
+
+        let thinnest = self.extinction().channels().iter().fold(T::zero(), |m, e| {
+            if *e > T::zero() && (m == T::zero() || *e < m) {
+                *e
+            } else {
+                m
+            }
+        });
+
+        return T::from_f64(-share.ln()) / thinnest;
+    }
+
+    // Extinction bounding that of every channel everywhere.
+    fn majorant(&self) -> T {
+        let peak = match &self.density {
+            Some(density) => T::from_f64(density.peak()),
+            None => T::one(),
+        };
+
+        return self
+            .extinction()
+            .channels()
+            .iter()
+            .fold(T::zero(), |m, e| m.max(*e))
+            * peak;
+    }
+}
+
+/// Light a fog gives off per unit of distance (e.g. fire or a glowing
+/// nebula), by the values of `grid`: the color of `ramp` at each, with the
+/// ramp's colors spread evenly from 0 to the highest value and blended.
+pub struct Emission<C> {
+    pub grid: Grid,
+    pub ramp: Vec<C>,
+}
+
+impl<T: Float + Primitive, C: Pixel<Subpixel = T> + FromRgb<T>> Emission<C> {
+    /// Gray light, as bright as the values times `scale`.
+    pub fn gray(grid: Grid, scale: f64) -> Emission<C> {
+        let top = T::from_f64(grid.peak() * scale);
+
+        return Emission {
+            grid,
+            ramp: vec![
+                C::from_rgb(Rgb([T::from_f64(0.0); 3])),
+                C::from_rgb(Rgb([top; 3])),
+            ],
+        };
+    }
+
+    /// Light of black bodies at the values as temperatures (in K) times
+    /// `scale`, see `spectrum::blackbody`.
+    pub fn blackbody(grid: Grid, scale: f64) -> Emission<C> {
+        // Fine enough to follow how steeply the light of cool bodies rises.
+        const STEPS: usize = 256;
+
+        let ramp = (0..STEPS)
+            .map(|i| {
+                let kelvin = grid.peak() * i as f64 / (STEPS - 1) as f64;
+                let rgb = spectrum::blackbody(kelvin).map(|c| T::from_f64(c * scale));
+                C::from_rgb(Rgb(rgb))
+            })
+            .collect();
+
+        return Emission { grid, ramp };
+    }
+}
+
+impl<T: Float + Into<f64>, C: Pixel<Subpixel = T>> Emission<C> {
+    /// Light given off per unit of distance at `p`.
+    pub fn at(&self, p: Vector3<T>) -> C {
+        let peak = self.grid.peak();
+        if peak == 0.0 {
+            return self.ramp[0];
+        }
+
+        let x = self.grid.density::<f64>(p.map(|c| c.into())) / peak * (self.ramp.len() - 1) as f64;
+        let i = (x as usize).min(self.ramp.len() - 2);
+        let f = T::from_f64(x - i as f64);
+
+        return self.ramp[i].map2(&self.ramp[i + 1], |a, b| a * (T::one() - f) + b * f);
+    }
+}
+
+/// Share of the light getting through `dist` of a medium that absorbs (or
+/// scatters away) the share `extinction` of it per unit of distance, by the
+/// Beer–Lambert law.
+pub fn beer<T: Float + Into<f64>, C: Pixel<Subpixel = T>>(extinction: &C, dist: T) -> C {
+    return extinction.map(|e| {
+        if e == T::zero() {
+            // Even at infinite distances.
+            T::one()
+        } else {
+            T::from_f64((-(e * dist).into()).exp())
+        }
+    });
+}
+
+/// The objects filled with a medium (with a `Surface::interior` or `ior`) a
+/// path is inside, e.g. a glass, the water in it and an ice cube floating
+/// in the water, by the polys it entered them through and their
+/// `Surface::priority`. Where objects overlap, the one of the highest
+/// priority fills the overlap (Schmidt and Budge, "Simple Nested
+/// Dielectrics in Ray Traced Images", 2002).
+pub struct Stack<'a, T, S> {
+    entered: Vec<(&'a Poly<T, S>, u32)>,
+}
+
+impl<'a, T, S> Clone for Stack<'a, T, S> {
+    fn clone(&self) -> Stack<'a, T, S> {
+        return Stack {
+            entered: self.entered.clone(),
+        };
+    }
+}
+
+/// Outside of all objects.
+impl<'a, T, S> Default for Stack<'a, T, S> {
+    fn default() -> Stack<'a, T, S> {
+        return Stack {
+            entered: Vec::new(),
+        };
+    }
+}
+
+impl<'a, T: Float, S> Stack<'a, T, S> {
+    /// A poly of the object the path runs in: the one of the highest
+    /// priority, and of those the one entered last.
+    pub fn top(&self) -> Option<&'a Poly<T, S>> {
+        return self.entered.iter().max_by_key(|(_, k)| *k).map(|(p, _)| *p);
+    }
+
+    /// Whether the surface of an object of `priority` lies within an object
+    /// of a higher one, so paths pass it as if it wasn't there.
+    pub fn skips(&self, priority: u32) -> bool {
+        return self.entered.iter().any(|(_, k)| *k > priority);
+    }
+
+    /// After entering the object of `poly`, of `priority`.
+    pub fn entering(&self, poly: &'a Poly<T, S>, priority: u32) -> Stack<'a, T, S> {
+        let mut entered = self.entered.clone();
+        entered.push((poly, priority));
+        return Stack { entered };
+    }
+
+    /// After leaving the object of `poly`, of `priority`. Objects are told
+    /// apart by their priority and object id, so where objects overlap they
+    /// must differ in either.
+    pub fn leaving(&self, poly: &Poly<T, S>, priority: u32) -> Stack<'a, T, S> {
+        let mut entered = self.entered.clone();
+
+        if let Some(i) = entered
+            .iter()
+            .rposition(|(p, k)| *k == priority && p.id() == poly.id())
+        {
+            entered.remove(i);
+        }
+
+        return Stack { entered };
+    }
+}
@@ -0,0 +1,156 @@
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+/// Cosines between the surface and the light direction `i`, the view
+/// direction (`-o`) and their half vector, as `[nl, nv, nh, vh]`. The
+/// normal is turned towards the viewer. `None` if `i` is not on the side of
+/// the viewer.
+pub fn cosines<T: Float>(n: Vector3<T>, i: Vector3<T>, o: Vector3<T>) -> Option<[T; 4]> {
+    let v = vecmath::vec3_neg(o);
+
+    let n = if vecmath::vec3_dot(n, v) < T::zero() {
+        vecmath::vec3_neg(n)
+    } else {
+        n
+    };
+
+    let nl = vecmath::vec3_dot(n, i);
+    let nv = vecmath::vec3_dot(n, v);
+
+    if nl <= T::zero() || nv <= T::zero() {
+        return None;
+    }
+
+    let h = vecmath::vec3_normalized(vecmath::vec3_add(i, v));
+
+    return Some([nl, nv, vecmath::vec3_dot(n, h), vecmath::vec3_dot(v, h)]);
+}
+
+/// GGX (Trowbridge-Reitz) normal distribution for a microfacet normal at
+/// cosine `nh` to the surface normal.
+pub fn ggx_d<T: Float>(nh: T, alpha: T) -> T {
+    let a2 = alpha * alpha;
+    let d = nh * nh * (a2 - T::one()) + T::one();
+
+    return a2 / (T::from_f64(std::f64::consts::PI) * d * d);
+}
+
+/// Smith masking term for a direction at cosine `nx` to the normal.
+pub fn smith_g1<T: Float>(nx: T, alpha: T) -> T {
+    let a2 = alpha * alpha;
+    let two = T::one() + T::one();
+
+    return two * nx / (nx + (a2 + (T::one() - a2) * nx * nx).sqrt());
+}
+
+/// Anisotropic GGX distribution with roughness `ax` along the tangent and
+/// `ay` along the bitangent, for a microfacet normal with coordinates `ht`,
+/// `hb` and `nh` in the tangent frame.
+pub fn ggx_d_aniso<T: Float>(ht: T, hb: T, nh: T, ax: T, ay: T) -> T {
+    let d = ht * ht / (ax * ax) + hb * hb / (ay * ay) + nh * nh;
+
+    return T::one() / (T::from_f64(std::f64::consts::PI) * ax * ay * d * d);
+}
+
+/// Anisotropic Smith masking term for a direction with coordinates `xt`,
+/// `xb` and `nx` in the tangent frame.
+pub fn smith_g1_aniso<T: Float>(xt: T, xb: T, nx: T, ax: T, ay: T) -> T {
+    let a2 = (xt * xt * ax * ax + xb * xb * ay * ay) / (nx * nx);
+    let two = T::one() + T::one();
+
+    return two / (T::one() + (T::one() + a2).sqrt());
+}
+
+/// Schlick's approximation of the Fresnel reflectance for normal incidence
+/// reflectance `f0`.
+pub fn schlick<T: Float>(f0: T, cos: T) -> T {
+    let c = T::one() - cos;
+    return f0 + (T::one() - f0) * c * c * c * c * c;
+}
+
+/// Cook-Torrance specular term `D * G / (4 * nl * nv)` without Fresnel.
+pub fn specular<T: Float>(nl: T, nv: T, nh: T, alpha: T) -> T {
+    let g = smith_g1(nl, alpha) * smith_g1(nv, alpha);
+    let four = T::from_u32(4);
+
+    return ggx_d(nh, alpha) * g / (four * nl * nv);
+}
+
+/// Fresnel reflectance of a conductor with complex index of refraction
+/// `eta + k i` for light at cosine `cos` to the normal.
+pub fn conductor<T: Float>(eta: T, k: T, cos: T) -> T {
+    let two = T::one() + T::one();
+
+    let cos2 = cos * cos;
+    let sin2 = T::one() - cos2;
+
+    let t0 = eta * eta - k * k - sin2;
+    let a2b2 = (t0 * t0 + two * two * eta * eta * k * k).sqrt();
+    let a = ((a2b2 + t0) / two).sqrt();
+
+    let t1 = a2b2 + cos2;
+    let t2 = two * a * cos;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    return (rs + rp) / two;
+}
+
+/// Anisotropic version of `specular` for light direction `i` and ray
+/// direction `o`, with roughness `ax` along the tangent `t` and `ay` along
+/// the bitangent `b`. `nl`, `nv` and `nh` are as given by `cosines`.
+pub fn specular_aniso<T: Float>(
+    [t, b]: [Vector3<T>; 2],
+    i: Vector3<T>,
+    o: Vector3<T>,
+    [nl, nv, nh]: [T; 3],
+    ax: T,
+    ay: T,
+) -> T {
+    let v = vecmath::vec3_neg(o);
+    let h = vecmath::vec3_normalized(vecmath::vec3_add(i, v));
+    let dot = vecmath::vec3_dot;
+
+    let g = smith_g1_aniso(dot(t, i), dot(b, i), nl, ax, ay)
+        * smith_g1_aniso(dot(t, v), dot(b, v), nv, ax, ay);
+    let four = T::from_u32(4);
+
+    return ggx_d_aniso(dot(t, h), dot(b, h), nh, ax, ay) * g / (four * nl * nv);
+}
+
+/// Reflectance of a thin film with index of refraction `film` and
+/// `thickness` between air and a medium with index `base`, for light of
+/// `wavelength` (in the unit of `thickness`) at cosine `cos` to the normal.
+/// Light reflected at the top and the bottom of the film interferes, which
+/// makes the result vary with wavelength (Airy summation, averaged over
+/// both polarizations).
+pub fn thin_film<T: Float>(film: T, base: T, thickness: T, wavelength: T, cos: T) -> T {
+    let one = T::one();
+    let two = one + one;
+
+    let sin2 = one - cos * cos;
+    let cos_film = (one - sin2 / (film * film)).sqrt();
+    let cos_base = (one - sin2 / (base * base)).max(T::zero()).sqrt();
+
+    let phase =
+        two * T::from_f64(std::f64::consts::PI) * two * film * thickness * cos_film / wavelength;
+
+    let airy = |r12: T, r23: T| {
+        let c = two * r12 * r23 * phase.cos();
+        (r12 * r12 + r23 * r23 + c) / (one + r12 * r12 * r23 * r23 + c)
+    };
+
+    let s = airy(
+        (cos - film * cos_film) / (cos + film * cos_film),
+        (film * cos_film - base * cos_base) / (film * cos_film + base * cos_base),
+    );
+    let p = airy(
+        (film * cos - cos_film) / (film * cos + cos_film),
+        (base * cos_film - film * cos_base) / (base * cos_film + film * cos_base),
+    );
+
+    return (s + p) / two;
+}
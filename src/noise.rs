@@ -0,0 +1,118 @@
+/// Improved Perlin noise at `p`, roughly in [-1, 1].
+///
+/// Gradients come from a hash of the lattice coordinates instead of a
+/// permutation table, so the noise doesn't repeat.
+pub fn perlin(p: [f64; 3]) -> f64 {
+    let cell = [p[0].floor(), p[1].floor(), p[2].floor()];
+    let f = [p[0] - cell[0], p[1] - cell[1], p[2] - cell[2]];
+    let c = [cell[0] as i64, cell[1] as i64, cell[2] as i64];
+
+    let grad = |dx: i64, dy: i64, dz: i64| {
+        let h = hash(c[0] + dx, c[1] + dy, c[2] + dz);
+        let (x, y, z) = (f[0] - dx as f64, f[1] - dy as f64, f[2] - dz as f64);
+
+        // One of the 12 edge directions of a cube (plus 4 repeats).
+        let u = if h & 15 < 8 { x } else { y };
+        let v = match h & 15 {
+            0..=3 => y,
+            12 | 14 => x,
+            _ => z,
+        };
+
+        return (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v });
+    };
+
+    let [u, v, w] = [fade(f[0]), fade(f[1]), fade(f[2])];
+
+    let x00 = lerp(u, grad(0, 0, 0), grad(1, 0, 0));
+    let x10 = lerp(u, grad(0, 1, 0), grad(1, 1, 0));
+    let x01 = lerp(u, grad(0, 0, 1), grad(1, 0, 1));
+    let x11 = lerp(u, grad(0, 1, 1), grad(1, 1, 1));
+
+    return lerp(w, lerp(v, x00, x10), lerp(v, x01, x11));
+}
+
+/// Fractal sum of `octaves` layers of noise, each at double the frequency
+/// and half the amplitude of the previous one. Roughly in [-1, 1].
+pub fn fbm(p: [f64; 3], octaves: u32) -> f64 {
+    return octave_sum(p, octaves, perlin);
+}
+
+/// Like `fbm`, but sums the absolute value of each layer, giving the sharp
+/// creases of turbulent flow. In [0, 1].
+pub fn turbulence(p: [f64; 3], octaves: u32) -> f64 {
+    return octave_sum(p, octaves, |q| perlin(q).abs()).min(1.0);
+}
+
+/// Worley (cellular) noise: the distance from `p` to the nearest of points
+/// scattered one per lattice cell, summed over `octaves` layers as for
+/// `fbm`. In [0, 1], 0 at the points, so `1 - worley` gives round puffs.
+pub fn worley(p: [f64; 3], octaves: u32) -> f64 {
+    return octave_sum(p, octaves, nearest).min(1.0);
+}
+
+// Distance to the nearest point of `worley`'s first layer.
+fn nearest(p: [f64; 3]) -> f64 {
+    let cell = [p[0].floor(), p[1].floor(), p[2].floor()];
+    let unit = |h: u64| (h & 0x1f_ffff) as f64 / (1u64 << 21) as f64;
+
+    let mut best = f64::INFINITY;
+
+    for dz in -1..=1 {
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let c = [
+                    cell[0] + dx as f64,
+                    cell[1] + dy as f64,
+                    cell[2] + dz as f64,
+                ];
+                let h = hash(c[0] as i64, c[1] as i64, c[2] as i64);
+                let q = [c[0] + unit(h), c[1] + unit(h >> 21), c[2] + unit(h >> 42)];
+
+                let d = [p[0] - q[0], p[1] - q[1], p[2] - q[2]];
+                best = best.min(d[0] * d[0] + d[1] * d[1] + d[2] * d[2]);
+            }
+        }
+    }
+
+    return best.sqrt();
+}
+
+fn octave_sum(p: [f64; 3], octaves: u32, layer: impl Fn([f64; 3]) -> f64) -> f64 {
+    let mut sum = 0.0;
+    let mut norm = 0.0;
+    let mut amp = 1.0;
+    let mut freq = 1.0;
+
+    for _ in 0..octaves.max(1) {
+        sum += amp * layer([p[0] * freq, p[1] * freq, p[2] * freq]);
+        norm += amp;
+        amp *= 0.5;
+        freq *= 2.0;
+    }
+
+    return sum / norm;
+}
+
+fn fade(t: f64) -> f64 {
+    return t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    return a + t * (b - a);
+}
+
+fn hash(x: i64, y: i64, z: i64) -> u64 {
+    let mut h = (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ (y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f)
+        ^ (z as u64).wrapping_mul(0x1656_67b1_9e37_79f9);
+
+    // Final mix of splitmix64.
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^= h >> 31;
+
+    return h;
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind};
+use std::path::Path;
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::geom::Poly;
+use crate::progress::Progress;
+
+/// Loads the faces of a Wavefront OBJ file as polys with `surface`, or
+/// the surface `named` gives for their material, see `load`.
+///
+/// Reports loading progress to `progress`.
+pub fn load_file<T: Float, S: Clone, P: AsRef<Path>>(
+    path: P,
+    surface: S,
+    named: &dyn Fn(&str) -> Option<S>,
+    progress: Progress,
+) -> io::Result<Vec<Poly<T, S>>> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let total = file.metadata()?.len().max(1);
+
+    let mut last = None;
+
+    let res = load(BufReader::new(file), surface, named, &mut |read| {
+        let pct = read * 100 / total;
+        if last != Some(pct) {
+            progress.loading(path, read, total);
+            last = Some(pct);
+        }
+    })?;
+
+    progress.loaded(path, res.len());
+
+    return Ok(res);
+}
+
+/// Loads the faces of a Wavefront OBJ stream as polys with `surface`, or
+/// the surface `named` gives for the name of their material, if any.
+///
+/// The input is parsed line by line, so only the vertex positions and the
+/// resulting polys are held in memory. `progress` is called with the number
+/// of bytes read so far. Faces with more than three vertices are split into
+/// a fan of triangles. Texture coordinates are applied if every vertex of a
+/// face has them. Every `o` statement starts a new object id, counting up
+/// from 1. Every material named by `usemtl` gets a material id, counting up
+/// from 1 in the order they first appear.
+pub fn load<T: Float, S: Clone, R: BufRead>(
+    mut reader: R,
+    surface: S,
+    named: &dyn Fn(&str) -> Option<S>,
+    progress: &mut dyn FnMut(u64),
+) -> io::Result<Vec<Poly<T, S>>> {
+    let mut vertices: Vec<Vector3<T>> = Vec::new();
+    let mut uvs: Vec<[T; 2]> = Vec::new();
+    let mut polys = Vec::new();
+    let mut object = 0;
+    let mut materials = HashMap::new();
+    let mut material = 0;
+    let mut current = surface.clone();
+
+    let mut line = String::new();
+    let mut read = 0;
+    let mut lineno = 0;
+
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        if n == 0 {
+            break;
+        }
+
+        read += n as u64;
+        lineno += 1;
+
+        let err =
+            |msg: &str| Error::new(ErrorKind::InvalidData, format!("line {}: {}", lineno, msg));
+
+        let mut words = line.split_whitespace();
+
+        match words.next() {
+            Some("v") => {
+                let mut v = [T::zero(); 3];
+                for x in v.iter_mut() {
+                    let w = words.next().ok_or_else(|| err("missing coordinate"))?;
+                    let f: f64 = w.parse().map_err(|_| err("invalid coordinate"))?;
+                    *x = T::from_f64(f);
+                }
+                vertices.push(v);
+            }
+            Some("vt") => {
+                let mut uv = [T::zero(); 2];
+                for x in uv.iter_mut() {
+                    // Missing coordinates default to 0.
+                    if let Some(w) = words.next() {
+                        let f: f64 = w.parse().map_err(|_| err("invalid texture coordinate"))?;
+                        *x = T::from_f64(f);
+                    }
+                }
+                uvs.push(uv);
+            }
+            Some("o") => object += 1,
+            Some("usemtl") => {
+                let name = words.next().ok_or_else(|| err("missing material name"))?;
+                let next = materials.len() as u32 + 1;
+                let (id, s) = materials
+                    .entry(name.to_string())
+                    .or_insert_with(|| (next, named(name).unwrap_or_else(|| surface.clone())));
+                material = *id;
+                current = s.clone();
+            }
+            Some("f") => {
+                let mut face = Vec::with_capacity(4);
+                let mut face_uvs = Vec::with_capacity(4);
+
+                // Indices are 1-based, negative ones are relative to the end.
+                let lookup = |idx: &str, len: usize| -> io::Result<usize> {
+                    let i: isize = idx.parse().map_err(|_| err("invalid index"))?;
+                    let i = if i < 0 { len as isize + i } else { i - 1 };
+
+                    if i < 0 || i as usize >= len {
+                        return Err(err("index out of range"));
+                    }
+
+                    return Ok(i as usize);
+                };
+
+                for w in words {
+                    // Position, then optionally texture coordinate (and normal).
+                    let mut idx = w.split('/');
+
+                    face.push(vertices[lookup(idx.next().unwrap_or(""), vertices.len())?]);
+
+                    match idx.next() {
+                        Some(t) if !t.is_empty() => face_uvs.push(uvs[lookup(t, uvs.len())?]),
+                        _ => {}
+                    }
+                }
+
+                if face.len() < 3 {
+                    return Err(err("face with less than 3 vertices"));
+                }
+
+                for k in 1..face.len() - 1 {
+                    let mut poly = Poly::new([face[0], face[k], face[k + 1]], current.clone());
+                    poly.set_id(object);
+                    poly.set_material(material);
+                    if face_uvs.len() == face.len() {
+                        poly.set_uvs([face_uvs[0], face_uvs[k], face_uvs[k + 1]]);
+                    }
+                    polys.push(poly);
+                }
+            }
+            // Normals, groups, material libraries, etc.
+            _ => {}
+        }
+
+        if lineno % 4096 == 0 {
+            progress(read);
+        }
+    }
+
+    progress(read);
+
+    return Ok(polys);
+}
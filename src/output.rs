@@ -0,0 +1,256 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use image::{ImageBuffer, Rgb, RgbImage, Rgba, RgbaImage};
+
+use crate::cli;
+use crate::fingerprint::Fingerprint;
+use crate::tonemap;
+
+/// Light of each pixel as rendered, 255 for white.
+pub type HdrImage = ImageBuffer<Rgb<f64>, Vec<f64>>;
+
+type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
+type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+/// Depth of the channels of PNGs: 16 bits keep smooth gradients through
+/// grading, without the size of the HDR formats.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Bits {
+    Eight,
+    Sixteen,
+}
+
+impl Bits {
+    pub fn from_name(name: &str) -> Option<Bits> {
+        return match name {
+            "8" => Some(Bits::Eight),
+            "16" => Some(Bits::Sixteen),
+            _ => None,
+        };
+    }
+}
+
+/// File format keeping the whole range of the light, for grading and
+/// compositing elsewhere.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Hdr {
+    /// OpenEXR, uncompressed 32-bit floats.
+    Exr,
+    /// Portable float map.
+    Pfm,
+}
+
+impl Hdr {
+    pub fn from_name(name: &str) -> Option<Hdr> {
+        return match name {
+            "exr" => Some(Hdr::Exr),
+            "pfm" => Some(Hdr::Pfm),
+            _ => None,
+        };
+    }
+
+    fn extension(self) -> &'static str {
+        return match self {
+            Hdr::Exr => "exr",
+            Hdr::Pfm => "pfm",
+        };
+    }
+}
+
+/// Saves `img` as `stem`.png, with 8 or 16 bits per channel as of `--bits`,
+/// and in the format of `--hdr` if given. With the coverage `alpha` (white
+/// where covered), the PNG is RGBA, with the color of what covers each
+/// pixel, and the EXR has an alpha channel, with the color as is: PNGs take
+/// straight alpha, EXRs premultiplied. PFMs have no alpha.
+pub fn save(args: &cli::Args, stem: &str, img: &HdrImage, alpha: Option<&HdrImage>) {
+    write(args, stem, img, alpha, |c| quantize(args, c));
+}
+
+/// Saves `img`, which holds data rather than light (like normals), as
+/// `save` does, but its PNG as is: neither tone mapped nor encoded. Only
+/// the HDR formats keep values out of 0 to 255.
+pub fn save_data(args: &cli::Args, stem: &str, img: &HdrImage) {
+    write(args, stem, img, None, |c| {
+        Rgb(c.0.map(|x| x.clamp(0.0, 255.0)))
+    });
+}
+
+// Saves `img` as `save` does, with `png` bringing its pixels into 0 to 255.
+fn write<P: Fn(Rgb<f64>) -> Rgb<f64>>(
+    args: &cli::Args,
+    stem: &str,
+    img: &HdrImage,
+    alpha: Option<&HdrImage>,
+    png: P,
+) {
+    let path = args.output(&format!("{}.png", stem));
+    let (width, height) = img.dimensions();
+
+    // The color, undoing the blend with the black of the uncovered share.
+    let pixel = |x, y| {
+        let c = *img.get_pixel(x, y);
+        return match alpha.map(|alpha| coverage(alpha, x, y)) {
+            Some(a) if a > 0.0 => png(Rgb(c.0.map(|c| c / a))),
+            _ => png(c),
+        };
+    };
+
+    let a = |x, y| alpha.map_or(1.0, |alpha| coverage(alpha, x, y));
+
+    let res = match (args.bits, alpha) {
+        (Bits::Eight, None) => {
+            RgbImage::from_fn(width, height, |x, y| Rgb(pixel(x, y).0.map(|c| c as u8))).save(path)
+        }
+        (Bits::Eight, Some(_)) => RgbaImage::from_fn(width, height, |x, y| {
+            let [r, g, b] = pixel(x, y).0.map(|c| c as u8);
+            Rgba([r, g, b, (a(x, y) * 255.0) as u8])
+        })
+        .save(path),
+        (Bits::Sixteen, None) => Rgb16Image::from_fn(width, height, |x, y| {
+            Rgb(pixel(x, y).0.map(|c| (c * 257.0) as u16))
+        })
+        .save(path),
+        (Bits::Sixteen, Some(_)) => Rgba16Image::from_fn(width, height, |x, y| {
+            let [r, g, b] = pixel(x, y).0.map(|c| (c * 257.0) as u16);
+            Rgba([r, g, b, (a(x, y) * 65535.0) as u16])
+        })
+        .save(path),
+    };
+    res.unwrap();
+
+    if let Some(hdr) = args.hdr {
+        let path = args.output(&format!("{}.{}", stem, hdr.extension()));
+        let res = match hdr {
+            Hdr::Exr => write_exr(&path, img, alpha),
+            Hdr::Pfm => write_pfm(&path, img),
+        };
+        res.unwrap();
+    }
+}
+
+/// Adds everything `save` and `save_data` take from `args` to `h`, see
+/// `fingerprint`.
+pub fn fingerprint(args: &cli::Args, h: &mut Fingerprint) {
+    h.write_u32(args.tonemap as u32);
+    h.write_f64(args.exposure);
+    h.write_u32(args.linear as u32);
+    h.write_u32(args.bits as u32);
+    h.write_u32(args.hdr.map_or(0, |hdr| hdr as u32 + 1));
+    h.write_u32(args.alpha as u32);
+    h.write_u32(args.aovs as u32);
+}
+
+/// The light `c` tone mapped, exposed and (unless `--linear`) sRGB encoded
+/// as requested, in 0 to 255.
+fn quantize(args: &cli::Args, c: Rgb<f64>) -> Rgb<f64> {
+    let c = args.tonemap.apply(c, args.exposure);
+    let encode = |x: f64| if args.linear { x } else { tonemap::srgb(x) };
+    return Rgb(c.0.map(encode));
+}
+
+// The light of `img` at (`x`, `y`) with white at 1, as HDR formats have it.
+fn linear(img: &HdrImage, x: u32, y: u32) -> [f32; 3] {
+    return img.get_pixel(x, y).0.map(|c| (c / 255.0) as f32);
+}
+
+// The share of pixel (`x`, `y`) covered, in 0 to 1, of the coverage `alpha`.
+fn coverage(alpha: &HdrImage, x: u32, y: u32) -> f64 {
+    return (alpha.get_pixel(x, y)[0] / 255.0).clamp(0.0, 1.0);
+}
+
+/// Writes `img` to `path` as a scanline OpenEXR file, uncompressed, with
+/// 32-bit float channels, and the coverage `alpha` as its alpha channel if
+/// given.
+pub fn write_exr(path: &Path, img: &HdrImage, alpha: Option<&HdrImage>) -> io::Result<()> {
+    let (width, height) = img.dimensions();
+
+    // Magic number and version 2, single part scanlines.
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(kind.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    };
+
+    // In alphabetical order, as are the channels of the pixels, each a
+    // function of the pixel.
+    type Plane<'a> = Box<dyn 'a + Fn(u32, u32) -> f32>;
+    let mut planes: Vec<(&str, Plane)> = Vec::new();
+    if let Some(alpha) = alpha {
+        planes.push(("A", Box::new(move |x, y| coverage(alpha, x, y) as f32)));
+    }
+    for (name, c) in [("B", 2), ("G", 1), ("R", 0)] {
+        planes.push((name, Box::new(move |x, y| linear(img, x, y)[c])));
+    }
+
+    // 32-bit floats, linear, not subsampled.
+    let mut channels = Vec::new();
+    for (name, _) in planes.iter() {
+        channels.extend_from_slice(name.as_bytes());
+        channels.extend_from_slice(&[0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+    }
+    channels.push(0);
+
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+
+    attribute("channels", "chlist", &channels);
+    attribute("compression", "compression", &[0]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    attribute("lineOrder", "lineOrder", &[0]);
+    attribute("pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0; 8]);
+    attribute("screenWindowWidth", "float", &1f32.to_le_bytes());
+    header.push(0);
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&header)?;
+
+    // Offsets of the scanlines, each its y, its size and its channels.
+    let size = planes.len() as u32 * 4 * width;
+    let first = header.len() as u64 + 8 * height as u64;
+    for y in 0..height as u64 {
+        out.write_all(&(first + y * (8 + size as u64)).to_le_bytes())?;
+    }
+
+    for y in 0..height {
+        out.write_all(&(y as i32).to_le_bytes())?;
+        out.write_all(&(size as i32).to_le_bytes())?;
+
+        for (_, plane) in planes.iter() {
+            for x in 0..width {
+                out.write_all(&plane(x, y).to_le_bytes())?;
+            }
+        }
+    }
+
+    return out.flush();
+}
+
+/// Writes `img` to `path` as a portable float map, little-endian.
+pub fn write_pfm(path: &Path, img: &HdrImage) -> io::Result<()> {
+    let (width, height) = img.dimensions();
+    let mut out = BufWriter::new(File::create(path)?);
+
+    write!(out, "PF\n{} {}\n-1.0\n", width, height)?;
+
+    // From the bottom row up.
+    for y in (0..height).rev() {
+        for x in 0..width {
+            for c in linear(img, x, y) {
+                out.write_all(&c.to_le_bytes())?;
+            }
+        }
+    }
+
+    return out.flush();
+}
@@ -0,0 +1,144 @@
+use image::Pixel;
+
+use crate::surface::Black;
+
+/// Number of AOVs (arbitrary output variables) and their names, used as
+/// suffixes of their image files.
+pub const COUNT: usize = 13;
+pub const NAMES: [&str; COUNT] = [
+    "alpha",
+    "direct",
+    "indirect",
+    "deep",
+    "emission",
+    "diffuse",
+    "specular",
+    "transmission",
+    "normal",
+    "depth",
+    "albedo",
+    "object",
+    "material",
+];
+
+/// Index of the AOV of the coverage, first so it can go without the others.
+pub const ALPHA: usize = 0;
+
+// Index of the first bounce AOV.
+const BOUNCES: usize = 1;
+
+// Index of the first lobe AOV.
+const LOBES: usize = 4;
+
+/// Index of the first AOV of the first hit, after those of the light.
+pub const FIRST_HIT: usize = 8;
+
+/// How light leaves the first surface it hits on its way to the camera.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Lobe {
+    Emission,
+    Diffuse,      // everything `Surface::reflected` gives
+    Specular,     // explicit directions on the side of the viewer
+    Transmission, // explicit directions through the surface
+}
+
+/// Light arriving along a ray, in total and split into AOVs.
+///
+/// The `alpha` AOV is white where the ray hits anything, see `with_alpha`,
+/// so the pixels' share of it is their coverage.
+///
+/// The next AOVs split the light twice, each split adds up to the total:
+///
+/// - By the number of bounces it took on its way from the emitter:
+///   `direct` light reached the camera straight away or after one bounce,
+///   `indirect` after two and `deep` after more.
+/// - By the `Lobe` it left the first surface in.
+///
+/// The last AOVs hold what the camera ray first hit rather than light, for
+/// denoisers and compositing, see `with_first_hit`. Integrators leave them
+/// black.
+#[derive(Clone, Copy)]
+pub struct Passes<C> {
+    pub beauty: C,
+    pub aovs: [C; COUNT],
+}
+
+impl<C: Pixel + Black> Passes<C> {
+    pub fn black() -> Passes<C> {
+        return Passes {
+            beauty: C::black(),
+            aovs: [C::black(); COUNT],
+        };
+    }
+
+    /// Light `c` emitted at a path vertex `depth` bounces from the camera.
+    pub fn emitted(c: C, depth: u32) -> Passes<C> {
+        let mut res = Passes::black();
+
+        res.beauty = c;
+        res.aovs[BOUNCES + (depth.max(1) - 1).min(2) as usize] = c;
+        res.aovs[LOBES + Lobe::Emission as usize] = c;
+
+        return res;
+    }
+
+    /// The same light, all of it leaving its surface in `lobe`.
+    ///
+    /// Every path vertex tags the light it reflects, so the tag of the
+    /// first surface, which is applied last, sticks.
+    pub fn tagged(&self, lobe: Lobe) -> Passes<C> {
+        let mut res = *self;
+
+        for a in res.aovs[LOBES..FIRST_HIT].iter_mut() {
+            *a = C::black();
+        }
+        res.aovs[LOBES + lobe as usize] = self.beauty;
+
+        return res;
+    }
+
+    /// The same light, from a ray that hit something: `opaque` is white.
+    pub fn with_alpha(&self, opaque: C) -> Passes<C> {
+        let mut res = *self;
+        res.aovs[ALPHA] = opaque;
+        return res;
+    }
+
+    /// The same light, with the `normal` (of the side facing the camera),
+    /// `depth` (distance from the camera) and `albedo` of the first hit,
+    /// each in units of 255 like light: 255 for a normal pointing along an
+    /// axis, a distance of 1 or white. Then the colors of its `object` and
+    /// `material` ids, as mattes: where several share a pixel, they blend
+    /// by coverage.
+    pub fn with_first_hit(&self, first_hit: [C; 5]) -> Passes<C> {
+        let mut res = *self;
+        res.aovs[FIRST_HIT..].copy_from_slice(&first_hit);
+        return res;
+    }
+
+    /// Applies `f` to the total and every AOV.
+    pub fn map<F: Fn(&C) -> C>(&self, f: F) -> Passes<C> {
+        let mut aovs = self.aovs;
+        for a in aovs.iter_mut() {
+            *a = f(a);
+        }
+
+        return Passes {
+            beauty: f(&self.beauty),
+            aovs,
+        };
+    }
+
+    /// Combines the total and every AOV with those of `other`.
+    pub fn zip<F: Fn(&C, &C) -> C>(&self, other: &Passes<C>, f: F) -> Passes<C> {
+        let mut aovs = self.aovs;
+        for (a, b) in aovs.iter_mut().zip(other.aovs.iter()) {
+            *a = f(a, b);
+        }
+
+        return Passes {
+            beauty: f(&self.beauty, &other.beauty),
+            aovs,
+        };
+    }
+}
@@ -0,0 +1,309 @@
+use image::Pixel;
+
+use vecmath::traits::Float;
+
+use crate::fingerprint::Fingerprint;
+use crate::geom::{Hit, Poly, Ray};
+use crate::integrator::Integrator;
+use crate::medium::{Fog, Stack};
+use crate::passes::{Lobe, Passes};
+use crate::phase;
+use crate::sampling::{self, Rng};
+use crate::surface::{Black, Surface};
+use crate::tracer::{Tracer, MAX_PATH_DEPTH};
+use crate::{crossed, Scene};
+
+/// Path tracer: unbiased, but noisy unless many samples per pixel are
+/// averaged. Light comes out at its true level, while `Grid` adds up its
+/// directions unweighted, so scenes set up for that look darker. Past
+/// `max_depth` bounces paths end at random, the more likely the less light
+/// they carry (Russian roulette), so no light is cut off. In fog, paths
+/// scatter on their way as well, so light bounced around in it any number
+/// of times shows; the others only scatter light once into the rays from
+/// the camera and the bounces.
+pub struct PathTracer<T> {
+    max_depth: u32,
+    // See `Tracer::clamped`.
+    clamp: Vec<T>,
+}
+
+impl<T: Float + Into<f64>> PathTracer<T> {
+    pub fn new(max_depth: u32) -> PathTracer<T> {
+        return PathTracer {
+            max_depth,
+            clamp: Vec::new(),
+        };
+    }
+
+    /// Limits the light paths bring to the camera from each bounce on, see
+    /// `Tracer::clamped`.
+    pub fn with_clamp(mut self, clamp: Vec<T>) -> PathTracer<T> {
+        self.clamp = clamp;
+        return self;
+    }
+
+    /// Light `fog` scatters at `t` along `ray` into its direction, as
+    /// `shade` does on a surface: of the lights, the environment and the
+    /// sampled emitters by shadow rays, shared with a path on into a
+    /// direction picked by the fog's phase function, inside `media`.
+    #[allow(clippy::too_many_arguments)]
+    fn scattered<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        fog: &Fog<C>,
+        ray: &Ray<T>,
+        t: T,
+        depth: u32,
+        throughput: C,
+        media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let p = vecmath::vec3_add(ray.orig, vecmath::vec3_scale(ray.dir, t));
+        let infinity = T::from_f64(f64::INFINITY);
+
+        let mut all_light = Passes::black();
+
+        if depth < self.limit() {
+            let incident = scene
+                .lights
+                .iter()
+                .flat_map(|l| {
+                    let links = (l.light_groups(), l.shadow_intensity());
+                    l.incident(p)
+                        .into_iter()
+                        .map(move |(dir, dist, irradiance)| {
+                            (dir, dist, irradiance, None, links, infinity)
+                        })
+                })
+                .chain(scene.environment.iter().flat_map(|e| {
+                    let links = (e.light_groups(), e.shadow_intensity());
+                    e.incident(p)
+                        .into_iter()
+                        .map(move |(dir, dist, irradiance)| {
+                            (dir, dist, irradiance, None, links, e.density(p, dir))
+                        })
+                }))
+                .chain(self.emitted_at(scene, ray, p, None));
+
+            for (dir, dist, irradiance, emitter, links, density) in incident {
+                let through = fog.transmittance_along(p, dir, dist, rng);
+
+                if through == C::black() {
+                    continue;
+                }
+
+                let pass = self.visible(scene, p, dir, dist, emitter, links, ray.time);
+
+                if pass == T::zero() {
+                    continue;
+                }
+
+                // The path may find the light too.
+                let phase = phase::eval(&*fog.phase, ray.dir, dir);
+                let w = sampling::power_heuristic(density, phase) * phase * pass;
+                let light = irradiance.map2(&through, |a, b| a * b * w);
+
+                let light = Passes::emitted(light, depth + 1).tagged(Lobe::Diffuse);
+                all_light = all_light.zip(&light, |x, y| x.map2(y, |a, b| a + b));
+            }
+        }
+
+        // Russian roulette, as in `shade`.
+        let keep = self.keep(depth, &throughput);
+
+        if T::from_f64(rng.unit()) >= keep {
+            return all_light;
+        }
+
+        let throughput = throughput.map(|x| x / keep);
+
+        // Picked by the phase function, which it thus cancels.
+        let (dir, phase) = phase::sample(&*fog.phase, ray.dir, rng);
+        let r = Ray {
+            orig: p,
+            dir,
+            width: ray.footprint(t),
+            spread: ray.spread,
+            diffs: None,
+            time: ray.time,
+        };
+
+        let light = self.trace(
+            scene,
+            &r,
+            None,
+            depth + 1,
+            Some(phase),
+            throughput,
+            media,
+            rng,
+        );
+        let light = self
+            .clamped(light, &throughput, depth + 1)
+            .tagged(Lobe::Diffuse)
+            .map(|x| x.map(|a| a / keep));
+
+        return all_light.zip(&light, |x, y| x.map2(y, |a, b| a + b));
+    }
+
+    // Chance of a path carrying `throughput` to go on at `depth`: past
+    // `max_depth` bounces, that of the strongest channel of its light,
+    // making up for the paths dropped (Russian roulette).
+    fn keep<C: Pixel<Subpixel = T>>(&self, depth: u32, throughput: &C) -> T {
+        if depth < self.max_depth {
+            return T::one();
+        }
+
+        return throughput
+            .channels()
+            .iter()
+            .fold(T::zero(), |m, x| m.max(*x))
+            .min(T::one());
+    }
+}
+
+impl<T: Float + Into<f64>> Tracer<T> for PathTracer<T> {
+    fn max_depth(&self) -> u32 {
+        return self.max_depth;
+    }
+
+    fn clamp(&self) -> &[T] {
+        return &self.clamp;
+    }
+
+    fn limit(&self) -> u32 {
+        return MAX_PATH_DEPTH;
+    }
+
+    /// The light of the lights and the sampled emitters, shared with one
+    /// path on into a direction picked by `Surface::sample`, leaving the
+    /// rest to the samples per pixel.
+    fn shade<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Hit<T>,
+        poly: &'a Poly<T, S>,
+        from: Option<&'a Poly<T, S>>,
+        depth: u32,
+        diffuse: Option<T>,
+        throughput: C,
+        media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let (surface, at, emitted) =
+            match self.shaded(scene, ray, hit, poly, from, depth, diffuse, media) {
+                Some(s) => s,
+                None => return Passes::black(),
+            };
+
+        let all_light = self.direct(
+            emitted, scene, ray, hit.point, poly, surface, at, depth, true, rng,
+        );
+
+        let (dir, pdf, weight) = match surface.sample(at, ray.dir, rng) {
+            Some(s) => s,
+            None => return all_light,
+        };
+
+        let throughput = throughput.map2(&weight, |a, b| a * b);
+        let keep = self.keep(depth, &throughput);
+
+        if T::from_f64(rng.unit()) >= keep {
+            return all_light;
+        }
+
+        let weight = weight.map(|x| x / keep);
+        let throughput = throughput.map(|x| x / keep);
+
+        // Directions out of a discrete set escape light sampling.
+        let discrete = pdf == T::from_f64(f64::INFINITY);
+
+        let through = (vecmath::vec3_dot(dir, *poly.n()) > T::zero())
+            == (vecmath::vec3_dot(ray.dir, *poly.n()) > T::zero());
+
+        // Differentials only follow mirrors and lenses.
+        let bias = surface.bias().unwrap_or_else(|| poly.bias());
+        let r = Ray {
+            width: ray.footprint(at.dist),
+            spread: ray.spread,
+            diffs: ray
+                .bounced(at.dist, *poly.n(), !through)
+                .filter(|_| discrete),
+            time: ray.time,
+            ..Ray::from_surface(hit.point, *poly.n(), dir, bias)
+        };
+        let lobe = match (discrete, through) {
+            (false, _) => Lobe::Diffuse,
+            (true, true) => Lobe::Transmission,
+            (true, false) => Lobe::Specular,
+        };
+
+        let light = self.trace(
+            scene,
+            &r,
+            Some(poly),
+            depth + 1,
+            Some(pdf).filter(|_| !discrete),
+            throughput,
+            &crossed(media, poly, ray.dir, through),
+            rng,
+        );
+        let light = self
+            .clamped(light, &throughput, depth + 1)
+            .tagged(lobe)
+            .map(|x| x.map2(&weight, |a, b| a * b));
+
+        return all_light.zip(&light, |x, y| x.map2(y, |a, b| a + b));
+    }
+
+    /// The path scatters in the fog or goes on to the surface.
+    fn lit_fog<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        fog: &Fog<C>,
+        ray: &Ray<T>,
+        dist: T,
+        depth: u32,
+        throughput: C,
+        surface: impl FnOnce(C, &mut Rng) -> Passes<C>,
+        media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let (t, weight) = fog.sample(ray.orig, ray.dir, dist, rng);
+
+        if weight == C::black() {
+            return Passes::black();
+        }
+
+        let throughput = throughput.map2(&weight, |a, b| a * b);
+        let light = match t {
+            Some(t) => self.scattered(scene, fog, ray, t, depth, throughput, media, rng),
+            None => surface(throughput, rng),
+        };
+
+        return light.map(|x| x.map2(&weight, |a, b| a * b));
+    }
+}
+
+impl<T, S, C> Integrator<T, S, C> for PathTracer<T>
+where
+    T: Float + Into<f64> + Sync,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black + PartialEq,
+{
+    fn light(
+        &self,
+        scene: &Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Option<(Hit<T>, &Poly<T, S>)>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        return self.primary(scene, ray, hit, rng);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"paths");
+        self.write_limits(h);
+    }
+}
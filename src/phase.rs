@@ -0,0 +1,121 @@
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use std::f64::consts::PI;
+
+use crate::fingerprint::Fingerprint;
+use crate::geom;
+use crate::sampling::Rng;
+
+/// How a medium (e.g. fog) scatters light, by the angle it turns it by.
+pub trait PhaseFunction: Send + Sync {
+    /// Share of the light scattered per steradian, for the cosine `cos` of
+    /// the angle between the directions it travels in before and after.
+    /// Sums to 1 over the sphere.
+    fn eval(&self, cos: f64) -> f64;
+
+    /// Cosine of an angle picked by `eval`, for `u` in [0, 1).
+    fn sample_cos(&self, u: f64) -> f64;
+
+    fn fingerprint(&self, h: &mut Fingerprint);
+}
+
+/// Scattering evenly into all directions.
+pub struct Isotropic;
+
+impl PhaseFunction for Isotropic {
+    fn eval(&self, _cos: f64) -> f64 {
+        return 1.0 / (4.0 * PI);
+    }
+
+    fn sample_cos(&self, u: f64) -> f64 {
+        return 1.0 - 2.0 * u;
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_u32(0);
+    }
+}
+
+/// The Henyey–Greenstein phase function: scattering mostly forwards for an
+/// asymmetry `g` towards 1 (e.g. 0.7 to 0.9 for haze and clouds), backwards
+/// for `g` towards -1, evenly for 0. `g` is the mean cosine of the angle.
+pub struct HenyeyGreenstein {
+    pub g: f64,
+}
+
+impl PhaseFunction for HenyeyGreenstein {
+    fn eval(&self, cos: f64) -> f64 {
+        let g = self.g;
+        let denom = 1.0 + g * g - 2.0 * g * cos;
+        return (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt());
+    }
+
+    fn sample_cos(&self, u: f64) -> f64 {
+        let g = self.g;
+
+        if g.abs() < 1e-3 {
+            return 1.0 - 2.0 * u;
+        }
+
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+        return ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_u32(1);
+        h.write_f64(self.g);
+    }
+}
+
+/// Scattering by particles much smaller than the wavelength, like the
+/// molecules of the air: as much forwards as backwards, less sideways.
+pub struct Rayleigh;
+
+impl PhaseFunction for Rayleigh {
+    fn eval(&self, cos: f64) -> f64 {
+        return 3.0 / (16.0 * PI) * (1.0 + cos * cos);
+    }
+
+    fn sample_cos(&self, u: f64) -> f64 {
+        // The root of c³ + 3c = 8u - 4, after Cardano.
+        let q = 4.0 * u - 2.0;
+        let r = (q * q + 1.0).sqrt();
+        return ((q + r).cbrt() + (q - r).cbrt()).clamp(-1.0, 1.0);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_u32(2);
+    }
+}
+
+/// Share of the light arriving from direction `i` that `phase` scatters on
+/// along `o`, the direction of a ray towards it, per steradian.
+pub fn eval<T: Float + Into<f64>>(phase: &dyn PhaseFunction, o: Vector3<T>, i: Vector3<T>) -> T {
+    return T::from_f64(phase.eval(vecmath::vec3_dot(o, i).into()));
+}
+
+/// Direction to continue a ray travelling along `o` into, picked by how
+/// much light arriving from there `phase` scatters on along it, with the
+/// density of the pick (which is `eval`).
+pub fn sample<T: Float + Into<f64>>(
+    phase: &dyn PhaseFunction,
+    o: Vector3<T>,
+    rng: &mut Rng,
+) -> (Vector3<T>, T) {
+    let [t, b] = geom::basis(o);
+
+    let z = T::from_f64(phase.sample_cos(rng.unit()));
+    let r = (T::one() - z * z).max(T::zero()).sqrt();
+    let phi = T::from_f64(2.0 * PI * rng.unit());
+
+    let i = vecmath::vec3_add(
+        vecmath::vec3_scale(o, z),
+        vecmath::vec3_add(
+            vecmath::vec3_scale(t, r * phi.cos()),
+            vecmath::vec3_scale(b, r * phi.sin()),
+        ),
+    );
+
+    return (i, T::from_f64(phase.eval(z.into())));
+}
@@ -0,0 +1,250 @@
+use image::Pixel;
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use std::cmp::Ordering;
+
+use crate::fingerprint::Fingerprint;
+use crate::geom::{Hit, Poly, Ray};
+use crate::integrator::Integrator;
+use crate::medium::Stack;
+use crate::passes::{Lobe, Passes};
+use crate::sampling::Rng;
+use crate::surface::{Black, Point, Surface};
+use crate::tracer::Tracer;
+use crate::Scene;
+
+// Number of photons the light at a point is estimated from.
+const NEAREST: usize = 50;
+
+/// Photon that arrived at `pos` on a surface travelling along `dir`,
+/// carrying `power` (radiant flux).
+pub struct Photon<T, P> {
+    pub pos: Vector3<T>,
+    pub dir: Vector3<T>,
+    pub power: P,
+}
+
+/// Photons scattered from the lights through a scene, which estimate the
+/// light arriving at a point by how densely they lie around it (Jensen,
+/// "Global Illumination using Photon Maps").
+pub struct PhotonMap<T, P> {
+    // A kd-tree: every range of photons is split at its middle one, along
+    // the axis `axes` holds for that.
+    photons: Vec<Photon<T, P>>,
+    axes: Vec<u8>,
+}
+
+impl<T: Float + Into<f64>, P> PhotonMap<T, P> {
+    pub fn new(mut photons: Vec<Photon<T, P>>) -> PhotonMap<T, P> {
+        photons.retain(|p| p.pos.iter().all(|x| (*x).into().is_finite()));
+
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+
+        return PhotonMap { photons, axes };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.photons.len();
+    }
+}
+
+impl<T: Float, P: Pixel<Subpixel = T> + Black> PhotonMap<T, P> {
+    /// Light `surface` reflects at `p` towards a ray coming from `o`, of the
+    /// light the photons around `p` brought.
+    pub fn reflected<S: ?Sized + Surface<T, P>>(
+        &self,
+        surface: &S,
+        p: Point<T>,
+        o: Vector3<T>,
+    ) -> P {
+        // Squared distance and index, closest first.
+        let mut nearest = Vec::with_capacity(NEAREST + 1);
+        self.find(0, self.photons.len(), p.pos, &mut nearest);
+
+        let r2 = match nearest.last() {
+            Some((r2, _)) if *r2 > T::zero() => *r2,
+            _ => return P::black(),
+        };
+
+        // Their power spread over the disc they cover. `reflected` is the
+        // BRDF scaled by pi.
+        let scale = T::one() / (T::from_f64(std::f64::consts::PI * std::f64::consts::PI) * r2);
+
+        return nearest.iter().fold(P::black(), |sum, (_, i)| {
+            let photon = &self.photons[*i];
+            let refl = surface.reflected(p, vecmath::vec3_neg(photon.dir), o);
+            let light = refl.map2(&photon.power, |a, b| a * b * scale);
+            sum.map2(&light, |a, b| a + b)
+        });
+    }
+
+    // Adds the photons in `lo..hi` closer to `pos` than the farthest of
+    // `nearest` to it, keeping the `NEAREST` closest.
+    fn find(&self, lo: usize, hi: usize, pos: Vector3<T>, nearest: &mut Vec<(T, usize)>) {
+        if lo >= hi {
+            return;
+        }
+
+        let mid = lo + (hi - lo) / 2;
+        let photon = &self.photons[mid];
+        let axis = self.axes[mid] as usize;
+        let d = pos[axis] - photon.pos[axis];
+
+        let (near, far) = if d < T::zero() {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+
+        self.find(near.0, near.1, pos, nearest);
+
+        let v = vecmath::vec3_sub(pos, photon.pos);
+        let d2 = vecmath::vec3_dot(v, v);
+
+        if nearest.len() < NEAREST || d2 < nearest[nearest.len() - 1].0 {
+            let k = nearest.partition_point(|(x, _)| *x <= d2);
+            nearest.insert(k, (d2, mid));
+            nearest.truncate(NEAREST);
+        }
+
+        // The other side can only hold closer photons if the splitting
+        // plane is.
+        if nearest.len() < NEAREST || d * d < nearest[nearest.len() - 1].0 {
+            self.find(far.0, far.1, pos, nearest);
+        }
+    }
+}
+
+/// Photon mapper: shadow rays and mirrors and lenses as with `Grid`, the
+/// rest of the light (bounced off other surfaces, or focused by glass into
+/// caustics) from the photons of `Scene::scatter_photons`. Smooth but
+/// blurry, and leaves out light bounced from the environment. Light comes
+/// out at its true level, as with `PathTracer`.
+pub struct PhotonMapper<T> {
+    max_depth: u32,
+    // See `Tracer::clamped`.
+    clamp: Vec<T>,
+}
+
+impl<T: Float + Into<f64>> PhotonMapper<T> {
+    pub fn new(max_depth: u32) -> PhotonMapper<T> {
+        return PhotonMapper {
+            max_depth,
+            clamp: Vec::new(),
+        };
+    }
+
+    /// Limits the light rays bring to the camera from each bounce on, see
+    /// `Tracer::clamped`.
+    pub fn with_clamp(mut self, clamp: Vec<T>) -> PhotonMapper<T> {
+        self.clamp = clamp;
+        return self;
+    }
+}
+
+impl<T: Float + Into<f64>> Tracer<T> for PhotonMapper<T> {
+    fn max_depth(&self) -> u32 {
+        return self.max_depth;
+    }
+
+    fn clamp(&self) -> &[T] {
+        return &self.clamp;
+    }
+
+    fn shade<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Hit<T>,
+        poly: &'a Poly<T, S>,
+        from: Option<&'a Poly<T, S>>,
+        depth: u32,
+        diffuse: Option<T>,
+        throughput: C,
+        media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let (surface, at, emitted) =
+            match self.shaded(scene, ray, hit, poly, from, depth, diffuse, media) {
+                Some(s) => s,
+                None => return Passes::black(),
+            };
+
+        let mut all_light = self.direct(
+            emitted, scene, ray, hit.point, poly, surface, at, depth, false, rng,
+        );
+
+        if let (Some(map), true) = (&scene.photons, depth < self.limit()) {
+            let light = Passes::emitted(map.reflected(surface, at, ray.dir), depth + 1)
+                .tagged(Lobe::Diffuse);
+
+            all_light = all_light.zip(&light, |x, y| x.map2(y, |a, b| a + b));
+        }
+
+        return self.specular(
+            all_light, scene, ray, hit.point, poly, surface, at, depth, throughput, media, rng,
+        );
+    }
+}
+
+impl<T, S, C> Integrator<T, S, C> for PhotonMapper<T>
+where
+    T: Float + Into<f64> + Sync,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black + PartialEq,
+{
+    fn light(
+        &self,
+        scene: &Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Option<(Hit<T>, &Poly<T, S>)>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        return self.primary(scene, ray, hit, rng);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"photons");
+        self.write_limits(h);
+    }
+}
+
+// Sorts `photons` into a kd-tree, split along the axis they spread the most
+// in, with the axes for `PhotonMap`.
+fn build<T: Float + Into<f64>, P>(photons: &mut [Photon<T, P>], axes: &mut [u8]) {
+    if photons.len() <= 1 {
+        return;
+    }
+
+    let mut lo = photons[0].pos;
+    let mut hi = lo;
+    for p in photons.iter() {
+        for a in 0..3 {
+            lo[a] = lo[a].min(p.pos[a]);
+            hi[a] = hi[a].max(p.pos[a]);
+        }
+    }
+
+    let axis = (0..3)
+        .max_by(|a, b| {
+            (hi[*a] - lo[*a])
+                .partial_cmp(&(hi[*b] - lo[*b]))
+                .unwrap_or(Ordering::Equal)
+        })
+        .unwrap_or(0);
+
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| {
+        a.pos[axis].into().total_cmp(&b.pos[axis].into())
+    });
+    axes[mid] = axis as u8;
+
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
@@ -0,0 +1,416 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind};
+use std::path::Path;
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::geom::Poly;
+use crate::progress::Progress;
+
+// Names of the texture coordinates of vertices.
+const U: [&str; 3] = ["u", "s", "texture_u"];
+const V: [&str; 3] = ["v", "t", "texture_v"];
+
+// Type of a property value.
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Scalar> {
+        return match name {
+            "char" | "int8" => Some(Scalar::I8),
+            "uchar" | "uint8" => Some(Scalar::U8),
+            "short" | "int16" => Some(Scalar::I16),
+            "ushort" | "uint16" => Some(Scalar::U16),
+            "int" | "int32" => Some(Scalar::I32),
+            "uint" | "uint32" => Some(Scalar::U32),
+            "float" | "float32" => Some(Scalar::F32),
+            "double" | "float64" => Some(Scalar::F64),
+            _ => None,
+        };
+    }
+
+    // Bytes a value takes in binary files.
+    fn size(self) -> usize {
+        return match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        };
+    }
+}
+
+enum Property {
+    Scalar(Scalar),
+    List(Scalar, Scalar), // type of the length, of the items
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<(String, Property)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+/// Loads the faces of a PLY file as polys with `surface`, see `load`.
+///
+/// Reports loading progress to `progress`.
+pub fn load_file<T: Float, S: Clone, P: AsRef<Path>>(
+    path: P,
+    surface: S,
+    progress: Progress,
+) -> io::Result<Vec<Poly<T, S>>> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let total = file.metadata()?.len().max(1);
+
+    let mut last = None;
+
+    let res = load(BufReader::new(file), surface, &mut |read| {
+        let pct = read * 100 / total;
+        if last != Some(pct) {
+            progress.loading(path, read, total);
+            last = Some(pct);
+        }
+    })?;
+
+    progress.loaded(path, res.len());
+
+    return Ok(res);
+}
+
+/// Loads the faces of a PLY stream, ASCII or binary (either byte order), as
+/// polys with `surface`.
+///
+/// The input is parsed element by element, so only the vertex positions
+/// and the resulting polys are held in memory. `progress` is called with
+/// the number of bytes read so far. Faces with more than three vertices are
+/// split into a fan of triangles. Texture coordinates are applied if the
+/// vertices have them (as `u` and `v`, `s` and `t`, or `texture_u` and
+/// `texture_v`). Elements other than vertices and faces are skipped.
+pub fn load<T: Float, S: Clone, R: BufRead>(
+    mut reader: R,
+    surface: S,
+    progress: &mut dyn FnMut(u64),
+) -> io::Result<Vec<Poly<T, S>>> {
+    let (format, elements, read) = header(&mut reader)?;
+
+    let mut body = Body {
+        reader,
+        format,
+        line: String::new(),
+        pos: 0,
+        read,
+    };
+
+    let mut vertices: Vec<Vector3<T>> = Vec::new();
+    let mut uvs: Vec<[T; 2]> = Vec::new();
+    let mut polys = Vec::new();
+    let mut face = Vec::new();
+    let mut done = 0u64;
+
+    for element in elements.iter() {
+        let has_uvs = element.name == "vertex"
+            && [U, V].iter().all(|names| {
+                element
+                    .properties
+                    .iter()
+                    .any(|(name, _)| names.contains(&name.as_str()))
+            });
+
+        for i in 0..element.count {
+            let err = |msg: &str| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} {}: {}", element.name, i, msg),
+                )
+            };
+
+            match element.name.as_str() {
+                "vertex" => {
+                    let mut v = [T::zero(); 3];
+                    let mut uv = [T::zero(); 2];
+
+                    for (name, property) in element.properties.iter() {
+                        let x = match property {
+                            Property::Scalar(ty) => T::from_f64(body.value(*ty, &err)?),
+                            Property::List(len, ty) => {
+                                body.skip_list(*len, *ty, &err)?;
+                                continue;
+                            }
+                        };
+
+                        match name.as_str() {
+                            "x" => v[0] = x,
+                            "y" => v[1] = x,
+                            "z" => v[2] = x,
+                            n if U.contains(&n) => uv[0] = x,
+                            n if V.contains(&n) => uv[1] = x,
+                            // Normals, colors, etc.
+                            _ => {}
+                        }
+                    }
+
+                    vertices.push(v);
+                    if has_uvs {
+                        uvs.push(uv);
+                    }
+                }
+                "face" => {
+                    face.clear();
+
+                    for (name, property) in element.properties.iter() {
+                        match property {
+                            Property::List(len, ty)
+                                if name == "vertex_indices" || name == "vertex_index" =>
+                            {
+                                let n = body.len(*len, &err)?;
+                                for _ in 0..n {
+                                    let idx = body.value(*ty, &err)?;
+                                    if !idx.is_finite()
+                                        || idx.fract() != 0.0
+                                        || idx < 0.0
+                                        || idx >= vertices.len() as f64
+                                    {
+                                        return Err(err("index out of range"));
+                                    }
+                                    face.push(idx as usize);
+                                }
+                            }
+                            Property::List(len, ty) => body.skip_list(*len, *ty, &err)?,
+                            Property::Scalar(ty) => {
+                                body.value(*ty, &err)?;
+                            }
+                        }
+                    }
+
+                    if face.len() < 3 {
+                        return Err(err("face with less than 3 vertices"));
+                    }
+
+                    for k in 1..face.len() - 1 {
+                        let idx = [face[0], face[k], face[k + 1]];
+                        let mut poly = Poly::new(idx.map(|i| vertices[i]), surface.clone());
+                        if !uvs.is_empty() {
+                            poly.set_uvs(idx.map(|i| uvs[i]));
+                        }
+                        polys.push(poly);
+                    }
+                }
+                _ => {
+                    for (_, property) in element.properties.iter() {
+                        match property {
+                            Property::List(len, ty) => body.skip_list(*len, *ty, &err)?,
+                            Property::Scalar(ty) => {
+                                body.value(*ty, &err)?;
+                            }
+                        }
+                    }
+                }
+            }
+
+            done += 1;
+            if done.is_multiple_of(4096) {
+                progress(body.read);
+            }
+        }
+    }
+
+    progress(body.read);
+
+    return Ok(polys);
+}
+
+// Reads the header up to `end_header`: the format of the body, its elements
+// in order and the number of bytes read.
+fn header<R: BufRead>(reader: &mut R) -> io::Result<(Format, Vec<Element>, u64)> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+
+    let mut line = String::new();
+    let mut read = 0;
+    let mut lineno = 0;
+
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        if n == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "missing end_header"));
+        }
+
+        read += n as u64;
+        lineno += 1;
+
+        let err =
+            |msg: &str| Error::new(ErrorKind::InvalidData, format!("line {}: {}", lineno, msg));
+
+        let mut words = line.split_whitespace();
+        let word = words.next();
+
+        if lineno == 1 {
+            if word != Some("ply") {
+                return Err(err("not a PLY file"));
+            }
+            continue;
+        }
+
+        match word {
+            Some("format") => {
+                format = Some(match words.next() {
+                    Some("ascii") => Format::Ascii,
+                    Some("binary_little_endian") => Format::LittleEndian,
+                    Some("binary_big_endian") => Format::BigEndian,
+                    _ => return Err(err("unknown format")),
+                });
+            }
+            Some("element") => {
+                let name = words.next().ok_or_else(|| err("missing element name"))?;
+                let count = words
+                    .next()
+                    .and_then(|w| w.parse().ok())
+                    .ok_or_else(|| err("invalid element count"))?;
+                elements.push(Element {
+                    name: name.to_string(),
+                    count,
+                    properties: Vec::new(),
+                });
+            }
+            Some("property") => {
+                let scalar = |w: Option<&str>| {
+                    w.and_then(Scalar::parse)
+                        .ok_or_else(|| err("unknown property type"))
+                };
+
+                let property = match words.next() {
+                    Some("list") => {
+                        let len = scalar(words.next())?;
+                        Property::List(len, scalar(words.next())?)
+                    }
+                    w => Property::Scalar(scalar(w)?),
+                };
+                let name = words.next().ok_or_else(|| err("missing property name"))?;
+
+                elements
+                    .last_mut()
+                    .ok_or_else(|| err("property outside of an element"))?
+                    .properties
+                    .push((name.to_string(), property));
+            }
+            Some("end_header") => break,
+            // Comments, obj_info, etc.
+            _ => {}
+        }
+    }
+
+    let format = format.ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing format"))?;
+
+    return Ok((format, elements, read));
+}
+
+// The body of a PLY stream, read one value at a time.
+struct Body<R> {
+    reader: R,
+    format: Format,
+    // Current line of an ASCII body, and how much of it is read.
+    line: String,
+    pos: usize,
+    read: u64, // bytes, including the header
+}
+
+impl<R: BufRead> Body<R> {
+    fn value(&mut self, ty: Scalar, err: &dyn Fn(&str) -> Error) -> io::Result<f64> {
+        if self.format == Format::Ascii {
+            return self.word(err)?.parse().map_err(|_| err("invalid value"));
+        }
+
+        let mut buf = [0; 8];
+        let buf = &mut buf[..ty.size()];
+        self.reader.read_exact(buf).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => err("unexpected end of file"),
+            _ => e,
+        })?;
+        self.read += buf.len() as u64;
+
+        if self.format == Format::BigEndian {
+            buf.reverse();
+        }
+
+        return Ok(match ty {
+            Scalar::I8 => buf[0] as i8 as f64,
+            Scalar::U8 => buf[0] as f64,
+            Scalar::I16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+            Scalar::U16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+            Scalar::I32 => i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            Scalar::U32 => u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            Scalar::F32 => f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            Scalar::F64 => f64::from_le_bytes([
+                buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7],
+            ]),
+        });
+    }
+
+    // Length of a list, of type `ty`.
+    fn len(&mut self, ty: Scalar, err: &dyn Fn(&str) -> Error) -> io::Result<usize> {
+        let n = self.value(ty, err)?;
+        if n < 0.0 || n.fract() != 0.0 {
+            return Err(err("invalid list length"));
+        }
+
+        return Ok(n as usize);
+    }
+
+    fn skip_list(
+        &mut self,
+        len: Scalar,
+        ty: Scalar,
+        err: &dyn Fn(&str) -> Error,
+    ) -> io::Result<()> {
+        for _ in 0..self.len(len, err)? {
+            self.value(ty, err)?;
+        }
+
+        return Ok(());
+    }
+
+    // Next word of an ASCII body. Elements usually take a line each, but
+    // line breaks are only whitespace to PLY.
+    fn word(&mut self, err: &dyn Fn(&str) -> Error) -> io::Result<&str> {
+        loop {
+            let rest = &self.line[self.pos..];
+            if let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+                let start = self.pos + start;
+                let end = self.line[start..]
+                    .find(char::is_whitespace)
+                    .map_or(self.line.len(), |n| start + n);
+                self.pos = end;
+                return Ok(&self.line[start..end]);
+            }
+
+            self.line.clear();
+            self.pos = 0;
+
+            let n = self.reader.read_line(&mut self.line)?;
+            if n == 0 {
+                return Err(err("unexpected end of file"));
+            }
+            self.read += n as u64;
+        }
+    }
+}
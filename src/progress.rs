@@ -0,0 +1,111 @@
+use std::path::Path;
+
+/// How progress of loading and rendering is reported.
+///
+/// In `Json` mode every event is a single line of JSON on stdout with an
+/// `"event"` field naming it, and nothing else is written to stdout:
+///
+/// ```text
+/// {"event":"loading","file":"bunny.obj","read":65536,"total":3145728}
+/// {"event":"loaded","file":"bunny.obj","polys":69451}
+/// {"event":"start","image":"obj.png","views":1,"polys":69453}
+/// {"event":"view","width":500,"height":300}
+/// {"event":"rows","done":1,"total":300}
+/// {"event":"saved","image":"obj.png","fingerprint":"9f0c...","seconds":12.5}
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Progress {
+    Human,
+    Json,
+}
+
+impl Progress {
+    /// `read` of `total` bytes of `path` have been loaded.
+    pub fn loading(self, path: &Path, read: u64, total: u64) {
+        match self {
+            Progress::Human => eprint!("\rloading {}: {}%", path.display(), read * 100 / total),
+            Progress::Json => println!(
+                "{{\"event\":\"loading\",\"file\":{},\"read\":{},\"total\":{}}}",
+                json_str(&path.to_string_lossy()),
+                read,
+                total
+            ),
+        }
+    }
+
+    pub fn loaded(self, path: &Path, polys: usize) {
+        match self {
+            Progress::Human => eprintln!(),
+            Progress::Json => println!(
+                "{{\"event\":\"loaded\",\"file\":{},\"polys\":{}}}",
+                json_str(&path.to_string_lossy()),
+                polys
+            ),
+        }
+    }
+
+    /// Rendering of `image` starts; it is made of `views` renders.
+    pub fn start(self, image: &str, views: u32, polys: usize) {
+        if self == Progress::Json {
+            println!(
+                "{{\"event\":\"start\",\"image\":{},\"views\":{},\"polys\":{}}}",
+                json_str(image),
+                views,
+                polys
+            );
+        }
+    }
+
+    /// The next view of the current image starts.
+    pub fn view(self, width: u32, height: u32) {
+        if self == Progress::Json {
+            println!(
+                "{{\"event\":\"view\",\"width\":{},\"height\":{}}}",
+                width, height
+            );
+        }
+    }
+
+    /// `done` of the `total` rows of the current view are finished.
+    pub fn rows(self, done: u32, total: u32) {
+        if self == Progress::Json {
+            println!(
+                "{{\"event\":\"rows\",\"done\":{},\"total\":{}}}",
+                done, total
+            );
+        }
+    }
+
+    pub fn saved(self, image: &str, fingerprint: u64, seconds: f64) {
+        match self {
+            Progress::Human => println!("{}: {:016x}", image, fingerprint),
+            Progress::Json => println!(
+                "{{\"event\":\"saved\",\"image\":{},\"fingerprint\":\"{:016x}\",\"seconds\":{:.3}}}",
+                json_str(image),
+                fingerprint,
+                seconds
+            ),
+        }
+    }
+}
+
+/// `s` as a quoted JSON string.
+fn json_str(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+
+    return res;
+}
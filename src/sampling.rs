@@ -0,0 +1,436 @@
+use image::{GrayImage, Luma, Pixel, RgbImage};
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::geom;
+
+/// Small, fast, seedable PRNG (xorshift64*), so random choices can be
+/// reproduced.
+#[derive(Clone)]
+pub struct Rng {
+    state: u64,
+    stratum: Option<[f64; 2]>, // see `stratify`
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // The state must not be 0.
+        return Rng {
+            state: seed ^ 0x9e37_79b9_7f4a_7c15,
+            stratum: None,
+        };
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        return self.state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+    }
+
+    /// Generator for pixel (`x`, `y`) of a render with `seed`, so a render
+    /// comes out the same no matter which thread traces the pixel, and
+    /// another seed gives another (equally valid) one.
+    pub fn for_pixel(seed: u64, x: u32, y: u32) -> Rng {
+        // Mixed so neighbours (and seeds) don't start off alike.
+        return Rng::new(mix(((y as u64) << 32 | x as u64) ^ mix(seed)));
+    }
+
+    /// Uniform in [0, 1).
+    pub fn unit(&mut self) -> f64 {
+        return to_unit(self.next());
+    }
+
+    /// Uniform in [0, 1)², or the point last passed to `stratify` if no
+    /// call took it yet.
+    pub fn square(&mut self) -> [f64; 2] {
+        return match self.stratum.take() {
+            Some(p) => p,
+            None => [self.unit(), self.unit()],
+        };
+    }
+
+    /// Makes the next `square` return `point`, e.g. to spread the first
+    /// bounces of the samples of a pixel evenly over the hemisphere.
+    pub fn stratify(&mut self, point: [f64; 2]) {
+        self.stratum = Some(point);
+    }
+}
+
+/// Number of samples to take for pixel (x, y) of a `frame`, e.g. the whole
+/// output image of a view rendered into part of it.
+///
+/// `samples` is the full budget. If an importance map is given, it is
+/// stretched over the frame and its intensity scales the budget (white gets
+/// all samples). Every pixel gets at least one sample.
+pub fn budget(
+    samples: u32,
+    importance: Option<&GrayImage>,
+    x: u32,
+    y: u32,
+    frame: [u32; 2],
+) -> u32 {
+    let [width, height] = frame;
+    let n = match importance {
+        None => samples,
+        Some(map) => {
+            let mx = (x as u64 * map.width() as u64 / width as u64) as u32;
+            let my = (y as u64 * map.height() as u64 / height as u64) as u32;
+            let v = map.get_pixel(mx, my)[0] as u64;
+
+            ((samples as u64 * v + 127) / 255) as u32
+        }
+    };
+
+    return n.max(1);
+}
+
+/// Adaptive sampling: once a pixel has its `budget`, it keeps taking
+/// samples until the standard error of their mean brightness drops below
+/// `noise` times that brightness, or it has `max_samples`. Uniform areas
+/// stop early, noisy ones get the samples.
+#[derive(Clone, Copy)]
+pub struct Adaptive {
+    pub noise: f64,
+    pub max_samples: u32,
+}
+
+impl Adaptive {
+    /// Whether a pixel with `n` samples, whose brightnesses add up to `sum`
+    /// and their squares to `sum_sq`, needs no more.
+    pub fn converged(&self, n: u32, sum: f64, sum_sq: f64) -> bool {
+        if n >= self.max_samples {
+            return true;
+        }
+
+        if n < 2 {
+            return false;
+        }
+
+        let n = n as f64;
+        let mean = sum / n;
+        let variance = (sum_sq - sum * mean).max(0.0) / (n - 1.0);
+
+        // Below a single output level the noise can't show, so dark pixels
+        // are held to that instead.
+        let limit = self.noise * mean.max(1.0);
+        return variance / n <= limit * limit;
+    }
+}
+
+/// Offset of the i-th sample inside a pixel, in [0, 1)².
+///
+/// Uses the R2 low-discrepancy sequence, so any prefix of the sequence
+/// covers the pixel evenly. The first sample is at the pixel origin.
+pub fn subpixel(i: u32) -> [f64; 2] {
+    // 1/g and 1/g² for the plastic number g.
+    const A1: f64 = 0.754_877_666_246_692_8;
+    const A2: f64 = 0.569_840_290_998_053_3;
+
+    let i = i as f64;
+
+    return [(i * A1).fract(), (i * A2).fract()];
+}
+
+/// Point `i` of a sequence spreading over [0, 1)² as evenly as `subpixel`,
+/// shifted by `shift` (wrapping around), to stratify another choice of a
+/// pixel's samples. A random shift keeps every point uniformly random.
+///
+/// Uses the last two dimensions of the R4 sequence, so the points don't
+/// line up with the subpixel offsets.
+pub fn stratum(i: u32, shift: [f64; 2]) -> [f64; 2] {
+    // 1/g³ and 1/g⁴ for the root g of x⁵ = x + 1.
+    const A3: f64 = 0.628_706_721_037_808_6;
+    const A4: f64 = 0.538_597_257_223_610_1;
+
+    let i = i as f64;
+
+    return [(i * A3 + shift[0]).fract(), (i * A4 + shift[1]).fract()];
+}
+
+/// Where the samples of a pixel go: each takes a point in [0, 1)⁴, its
+/// offset in the pixel and its first bounce (see `Rng::stratify`).
+/// Low-discrepancy points cover both more evenly than random ones, so
+/// renders converge faster.
+pub trait Sampler: Sync {
+    /// Name to pick it by, see `SAMPLERS`.
+    fn name(&self) -> &'static str;
+
+    /// Point of sample `i` of a pixel. `scramble` holds random bits drawn
+    /// for the pixel, a word per dimension, so pixels don't repeat each
+    /// other's pattern.
+    fn point(&self, i: u32, scramble: [u64; 4]) -> [f64; 4];
+}
+
+/// All samplers, `R2` first as the default.
+pub const SAMPLERS: [&dyn Sampler; 3] = [&R2, &Halton, &Sobol];
+
+/// `subpixel` and `stratum`: pixels share their offsets, the first of which
+/// is at the pixel origin.
+pub struct R2;
+
+impl Sampler for R2 {
+    fn name(&self) -> &'static str {
+        return "r2";
+    }
+
+    fn point(&self, i: u32, scramble: [u64; 4]) -> [f64; 4] {
+        let [x, y] = subpixel(i);
+        let [u, v] = stratum(i, [to_unit(scramble[2]), to_unit(scramble[3])]);
+        return [x, y, u, v];
+    }
+}
+
+/// The Halton sequence in bases 2, 3, 5 and 7, shifted at random per pixel
+/// (wrapping around).
+pub struct Halton;
+
+impl Sampler for Halton {
+    fn name(&self) -> &'static str {
+        return "halton";
+    }
+
+    fn point(&self, i: u32, scramble: [u64; 4]) -> [f64; 4] {
+        let mut res = [0.0; 4];
+
+        for (d, base) in [2, 3, 5, 7].iter().enumerate() {
+            res[d] = (radical_inverse(*base, i) + to_unit(scramble[d])).fract();
+        }
+
+        return res;
+    }
+}
+
+// `i` with its digits in `base` mirrored at the decimal point.
+fn radical_inverse(base: u32, mut i: u32) -> f64 {
+    let inv = 1.0 / base as f64;
+    let mut scale = inv;
+    let mut res = 0.0;
+
+    while i > 0 {
+        res += (i % base) as f64 * scale;
+        i /= base;
+        scale *= inv;
+    }
+
+    return res;
+}
+
+/// The first four dimensions of the Sobol sequence, with random bits
+/// flipped per pixel (a digital shift), which keeps it evenly spread.
+pub struct Sobol;
+
+impl Sampler for Sobol {
+    fn name(&self) -> &'static str {
+        return "sobol";
+    }
+
+    fn point(&self, i: u32, scramble: [u64; 4]) -> [f64; 4] {
+        let mut res = [0.0; 4];
+
+        for (d, dirs) in SOBOL_DIRECTIONS.iter().enumerate() {
+            let mut x = (scramble[d] >> 32) as u32;
+            for (k, v) in dirs.iter().enumerate() {
+                if i >> k & 1 == 1 {
+                    x ^= v;
+                }
+            }
+            res[d] = x as f64 / (1u64 << 32) as f64;
+        }
+
+        return res;
+    }
+}
+
+// Direction numbers of the Sobol dimensions, from the degree, coefficients
+// and initial numbers of their primitive polynomials (Joe and Kuo, "Constructing
+// Sobol sequences with better two-dimensional projections"). The first
+// dimension has none.
+static SOBOL_DIRECTIONS: [[u32; 32]; 4] = [
+    sobol_directions(0, 0, [0; 3]),
+    sobol_directions(1, 0, [1, 0, 0]),
+    sobol_directions(2, 1, [1, 3, 0]),
+    sobol_directions(3, 1, [1, 3, 1]),
+];
+
+const fn sobol_directions(degree: usize, a: u32, m: [u32; 3]) -> [u32; 32] {
+    let mut v = [0; 32];
+
+    let mut k = 0;
+    while k < 32 {
+        v[k] = if degree == 0 {
+            1 << (31 - k)
+        } else if k < degree {
+            m[k] << (31 - k)
+        } else {
+            let mut x = v[k - degree] ^ (v[k - degree] >> degree);
+            let mut j = 1;
+            while j < degree {
+                if (a >> (degree - 1 - j)) & 1 == 1 {
+                    x ^= v[k - j];
+                }
+                j += 1;
+            }
+            x
+        };
+        k += 1;
+    }
+
+    return v;
+}
+
+/// The splitmix64 finalizer: scatters nearby numbers all over, keeping 0.
+pub fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    return z ^ (z >> 31);
+}
+
+// Random bits as a number in [0, 1).
+fn to_unit(bits: u64) -> f64 {
+    return (bits >> 11) as f64 / (1u64 << 53) as f64;
+}
+
+/// Derives an importance map from a previous (e.g. low sample) render.
+///
+/// Pixels with strong contrast to their neighbours, i.e. edges and noisy
+/// areas, are marked important.
+pub fn importance_from(preview: &RgbImage) -> GrayImage {
+    let (width, height) = preview.dimensions();
+
+    let lum = |x: u32, y: u32| preview.get_pixel(x, y).to_luma()[0] as i32;
+
+    let mut contrast = vec![0; (width * height) as usize];
+    let mut max = 0;
+
+    for y in 0..height {
+        for x in 0..width {
+            let l = lum(x, y);
+            let mut c = 0;
+
+            if x > 0 {
+                c = c.max((l - lum(x - 1, y)).abs());
+            }
+            if x + 1 < width {
+                c = c.max((l - lum(x + 1, y)).abs());
+            }
+            if y > 0 {
+                c = c.max((l - lum(x, y - 1)).abs());
+            }
+            if y + 1 < height {
+                c = c.max((l - lum(x, y + 1)).abs());
+            }
+
+            contrast[(y * width + x) as usize] = c;
+            max = max.max(c);
+        }
+    }
+
+    return GrayImage::from_fn(width, height, |x, y| {
+        let c = contrast[(y * width + x) as usize];
+        Luma([if max == 0 { 0 } else { (c * 255 / max) as u8 }])
+    });
+}
+
+/// Importance map that gives the full sample budget to the rectangle at
+/// (`x`, `y`) of size `w`×`h` and `rest` (out of 255) to the remaining
+/// frame, e.g. to inspect a problem area closely while the rest stays at
+/// preview quality.
+pub fn region(width: u32, height: u32, x: u32, y: u32, w: u32, h: u32, rest: u8) -> GrayImage {
+    return GrayImage::from_fn(width, height, |px, py| {
+        let inside = px >= x && px - x < w && py >= y && py - y < h;
+        Luma([if inside { 255 } else { rest }])
+    });
+}
+
+/// Random direction at most `acos(cos_max)` away from `axis`, uniform over
+/// the solid angle (all directions for a `cos_max` of -1).
+pub fn uniform_cone<T: Float>(axis: Vector3<T>, cos_max: T, rng: &mut Rng) -> Vector3<T> {
+    let [t, b] = geom::basis(axis);
+
+    let z = T::one() - T::from_f64(rng.unit()) * (T::one() - cos_max);
+    let r = (T::one() - z * z).max(T::zero()).sqrt();
+    let phi = T::from_f64(2.0 * std::f64::consts::PI * rng.unit());
+
+    return vecmath::vec3_add(
+        vecmath::vec3_scale(axis, z),
+        vecmath::vec3_add(
+            vecmath::vec3_scale(t, r * phi.cos()),
+            vecmath::vec3_scale(b, r * phi.sin()),
+        ),
+    );
+}
+
+/// Distance between `from` and `to` along the ray from `orig` along `dir`
+/// for `u` in [0, 1), picked with a density falling off with the squared
+/// distance to `center` (equiangular sampling), as light from there does.
+/// Evenly if `center` is on the ray's line.
+pub fn equiangular<T: Float>(
+    orig: Vector3<T>,
+    dir: Vector3<T>,
+    center: Vector3<T>,
+    from: T,
+    to: T,
+    u: T,
+) -> T {
+    let (along, off) = across(orig, dir, center);
+
+    if off == T::zero() {
+        return from + (to - from) * u;
+    }
+
+    let a = (from - along).atan2(off);
+    let b = (to - along).atan2(off);
+
+    return (along + off * (a + (b - a) * u).tan()).max(from).min(to);
+}
+
+/// Density of `t` for `equiangular`.
+pub fn equiangular_density<T: Float>(
+    orig: Vector3<T>,
+    dir: Vector3<T>,
+    center: Vector3<T>,
+    from: T,
+    to: T,
+    t: T,
+) -> T {
+    let (along, off) = across(orig, dir, center);
+
+    if off == T::zero() {
+        return T::one() / (to - from);
+    }
+
+    let a = (from - along).atan2(off);
+    let b = (to - along).atan2(off);
+    let d = t - along;
+
+    return off / ((b - a) * (off * off + d * d));
+}
+
+// Distance along the ray from `orig` along `dir` to the point closest to
+// `p`, and the distance between them.
+fn across<T: Float>(orig: Vector3<T>, dir: Vector3<T>, p: Vector3<T>) -> (T, T) {
+    let d = vecmath::vec3_sub(p, orig);
+    let along = vecmath::vec3_dot(d, dir);
+    let off = vecmath::vec3_sub(d, vecmath::vec3_scale(dir, along));
+
+    return (along, vecmath::vec3_len(off));
+}
+
+/// Share of light found both by a strategy with `density` and by another
+/// with `other` (e.g. shadow rays and surface sampling) that the first
+/// should count, by Veach's power heuristic. Each covers what the other
+/// misses, and an infinite density (as of a point light) takes it all.
+pub fn power_heuristic<T: Float>(density: T, other: T) -> T {
+    if density == T::zero() {
+        return T::zero();
+    }
+
+    if density == T::from_f64(f64::INFINITY) {
+        return T::one();
+    }
+
+    // As a ratio, so huge densities don't overflow.
+    let r = other / density;
+    return T::one() / (T::one() + r * r);
+}
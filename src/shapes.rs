@@ -16,6 +16,14 @@ pub fn add_par<T: Float, S: Clone>(
     let c = vecmath::vec3_add(a, c_side);
     let d = vecmath::vec3_add(b, c_side);
 
-    trg.push(Poly::new([a, b, d], surface.clone()));
-    trg.push(Poly::new([a, c, d], surface));
+    // Texture coordinates span [0, 1]² over the parallelogram.
+    let (zero, one) = (T::zero(), T::one());
+
+    let mut abd = Poly::new([a, b, d], surface.clone());
+    abd.set_uvs([[zero, zero], [one, zero], [one, one]]);
+    trg.push(abd);
+
+    let mut acd = Poly::new([a, c, d], surface);
+    acd.set_uvs([[zero, zero], [zero, one], [one, one]]);
+    trg.push(acd);
 }
@@ -0,0 +1,243 @@
+use image::{Pixel, Primitive, Rgb};
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use crate::lights::{self, Environment, HdrImage, Light};
+use crate::spectrum::FromRgb;
+
+// Angular radius of the sun, in radians.
+const SUN_RADIUS: f64 = 0.00465;
+
+// Illuminance of the sun above the atmosphere, in klx.
+const SUN_ILLUMINANCE: f64 = 128.0;
+
+/// Clear sky after Preetham, Shirley and Smits, "A Practical Analytic
+/// Model for Daylight" (1999), with the sun shining along `sun` (as for
+/// `lights::directional`).
+///
+/// `turbidity` is the haziness of the air, from 2 (very clear) to 10
+/// (hazy). Radiance comes in kcd/m² and the sun's irradiance in klx, both
+/// scaled by `brightness`; a clear noon sky and sun put around 100 klx on
+/// the ground. Below the horizon the sky is black, scenes are expected to
+/// have ground.
+pub fn sky<'a, T, P>(
+    sun: Vector3<T>,
+    turbidity: T,
+    brightness: T,
+) -> Arc<dyn 'a + Environment<T, P>>
+where
+    T: 'a + Float + Into<f64> + Primitive,
+    P: 'a + Pixel<Subpixel = T> + FromRgb<T> + Send + Sync,
+{
+    // Size of the image the shadow rays are picked from.
+    const WIDTH: u32 = 128;
+    const HEIGHT: u32 = 64;
+
+    let to_sun = vecmath::vec3_normalized(vecmath::vec3_neg(sun.map(|x| x.into())));
+    let model = Preetham::new(to_sun, turbidity.into());
+
+    let image = HdrImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        let (dir, _) = lights::texel_dir(x, y, WIDTH, HEIGHT);
+        return Rgb(model.rgb(dir).map(|c| c as f32));
+    });
+
+    // Sunlight: what is left after scattering (Rayleigh) and aerosols take
+    // their share along the way through the air, per channel at about its
+    // wavelength (in µm). After the appendix of the paper.
+    let theta = to_sun[1].clamp(0.0, 1.0).acos();
+    let mass = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+    let beta = 0.04608 * turbidity.into() - 0.04586;
+
+    let sun_irradiance = [0.65, 0.57, 0.475].map(|l: f64| {
+        if to_sun[1] <= 0.0 {
+            return 0.0;
+        }
+
+        let rayleigh = (-0.008735 * l.powf(-4.08) * mass).exp();
+        let aerosol = (-beta * l.powf(-1.3) * mass).exp();
+        SUN_ILLUMINANCE * rayleigh * aerosol
+    });
+
+    let rgb = |c: [f64; 3]| P::from_rgb(Rgb(c.map(|x| T::from_f64(x) * brightness)));
+
+    Arc::new(Sky {
+        sky: lights::environment(image, brightness),
+        sun: lights::directional(sun, rgb(sun_irradiance), T::from_f64(SUN_RADIUS)),
+        model,
+        cos_sun: SUN_RADIUS.cos(),
+        // A little wider: the sun's outer ring of shadow rays lies right on
+        // the rim of its disc.
+        cos_spot: (SUN_RADIUS * 1.001).cos(),
+        sun_radiance: rgb(sun_irradiance.map(|x| x / (PI * SUN_RADIUS * SUN_RADIUS))),
+        brightness,
+    })
+}
+
+struct Sky<'a, T, P> {
+    // The sky without the sun, to pick shadow rays from.
+    sky: Arc<dyn 'a + Environment<T, P>>,
+    sun: Arc<dyn 'a + Light<T, P>>,
+    model: Preetham,
+    cos_sun: f64,
+    cos_spot: f64, // towards which `sun` casts the shadow rays
+    sun_radiance: P,
+    brightness: T,
+}
+
+impl<'a, T, P> Light<T, P> for Sky<'a, T, P>
+where
+    T: Float + Into<f64> + Primitive,
+    P: Pixel<Subpixel = T> + FromRgb<T> + Send + Sync,
+{
+    fn incident(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        let mut res = self.sky.incident(pos);
+        res.extend(self.sun.incident(pos));
+        return res;
+    }
+}
+
+impl<'a, T, P> Environment<T, P> for Sky<'a, T, P>
+where
+    T: Float + Into<f64> + Primitive,
+    P: Pixel<Subpixel = T> + FromRgb<T> + Send + Sync,
+{
+    fn radiance(&self, dir: Vector3<T>) -> P {
+        let d = vecmath::vec3_normalized(dir.map(|x| x.into()));
+
+        if vecmath::vec3_dot(d, self.model.to_sun) > self.cos_sun {
+            return self.sun_radiance;
+        }
+
+        return self.radiance_besides_spots(dir);
+    }
+
+    fn spots(&self, pos: Vector3<T>) -> Vec<(Vector3<T>, T, P)> {
+        return self.sun.incident(pos);
+    }
+
+    fn radiance_besides_spots(&self, dir: Vector3<T>) -> P {
+        let dir = vecmath::vec3_normalized(dir.map(|x| x.into()));
+        let c = self.model.rgb(dir);
+        return P::from_rgb(Rgb(c.map(|x| T::from_f64(x) * self.brightness)));
+    }
+
+    fn density(&self, pos: Vector3<T>, dir: Vector3<T>) -> T {
+        let d = vecmath::vec3_normalized(dir.map(|x| x.into()));
+
+        if vecmath::vec3_dot(d, self.model.to_sun) > self.cos_spot {
+            return T::from_f64(f64::INFINITY);
+        }
+
+        return self.sky.density(pos, dir);
+    }
+}
+
+// Coefficients A to E of Perez' sky luminance distribution.
+type Perez = [f64; 5];
+
+/// The sky model for one position of the sun, for luminance Y and the
+/// chromaticity x and y.
+struct Preetham {
+    to_sun: Vector3<f64>,
+    zenith: [f64; 3], // Y, x and y towards the zenith
+    perez: [Perez; 3],
+    norm: [f64; 3], // of `perez` towards the zenith
+}
+
+impl Preetham {
+    fn new(to_sun: Vector3<f64>, t: f64) -> Preetham {
+        // Angle of the sun to the zenith. The model only holds for the sun
+        // above the horizon, lower it's taken as at the horizon.
+        let theta = to_sun[1].clamp(0.0, 1.0).acos();
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let lum = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+
+        let poly = |k: [[f64; 4]; 3]| {
+            let [a, b, c] =
+                k.map(|k| k[0] * theta.powi(3) + k[1] * theta.powi(2) + k[2] * theta + k[3]);
+            t * t * a + t * b + c
+        };
+
+        let x = poly([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let y = poly([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        return Preetham {
+            to_sun,
+            zenith: [lum, x, y],
+            norm: perez.map(|c| perez_f(c, 1.0, theta)),
+            perez,
+        };
+    }
+
+    /// Linear sRGB radiance from direction `dir` (normalized), in kcd/m².
+    fn rgb(&self, dir: Vector3<f64>) -> [f64; 3] {
+        if dir[1] <= 0.0 {
+            return [0.0; 3];
+        }
+
+        let gamma = vecmath::vec3_dot(dir, self.to_sun).clamp(-1.0, 1.0).acos();
+
+        let mut v = [0.0; 3];
+        for (i, v) in v.iter_mut().enumerate() {
+            *v = self.zenith[i] * perez_f(self.perez[i], dir[1], gamma) / self.norm[i];
+        }
+
+        let [lum, x, y] = v;
+        if y <= 0.0 {
+            return [0.0; 3];
+        }
+
+        let (cx, cz) = (x / y * lum, (1.0 - x - y) / y * lum);
+
+        return [
+            3.2406 * cx - 1.5372 * lum - 0.4986 * cz,
+            -0.9689 * cx + 1.8758 * lum + 0.0415 * cz,
+            0.0557 * cx - 0.2040 * lum + 1.0570 * cz,
+        ]
+        .map(|c| c.max(0.0));
+    }
+}
+
+// Perez' distribution, for the cosine of the angle to the zenith and the
+// angle to the sun.
+fn perez_f([a, b, c, d, e]: Perez, cos_theta: f64, gamma: f64) -> f64 {
+    return (1.0 + a * (b / cos_theta).exp())
+        * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2));
+}
@@ -0,0 +1,231 @@
+use image::{Bgr, Bgra, ColorType, Luma, LumaA, Pixel, Primitive, Rgb, Rgba};
+
+use vecmath::traits::Float;
+
+use crate::surface::Black;
+
+/// Number of wavelengths a `Spectrum` is sampled at, and the range they
+/// evenly cover (in nm). Each sample stands for the light in a band of
+/// `(LAMBDA_MAX - LAMBDA_MIN) / SAMPLES` around it.
+pub const SAMPLES: usize = 16;
+const LAMBDA_MIN: f64 = 380.0;
+const LAMBDA_MAX: f64 = 700.0;
+
+/// Light (or reflectance) as samples over the visible wavelengths, to
+/// render with in place of `Rgb`.
+///
+/// Surfaces act on every sample independently, so effects that depend on
+/// the wavelength, like dispersion, are resolved per band instead of per
+/// color channel. `Pixel::to_rgb` converts to linear sRGB through CIE XYZ,
+/// scaled so that a flat spectrum is white.
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Spectrum<T>(pub [T; SAMPLES]);
+
+/// Center wavelength of sample `i`, in nm.
+pub fn wavelength(i: usize) -> f64 {
+    return LAMBDA_MIN + (i as f64 + 0.5) * (LAMBDA_MAX - LAMBDA_MIN) / SAMPLES as f64;
+}
+
+/// Colors scenes are described in, converted to whatever the renderer
+/// carries light as.
+pub trait FromRgb<T: Primitive> {
+    fn from_rgb(rgb: Rgb<T>) -> Self;
+}
+
+impl<T: Primitive> FromRgb<T> for Rgb<T> {
+    fn from_rgb(rgb: Rgb<T>) -> Rgb<T> {
+        return rgb;
+    }
+}
+
+impl<T: Primitive + Float> FromRgb<T> for Spectrum<T> {
+    /// Smooth enough for reflectances and light colors: every channel
+    /// covers one third of the range, so gray stays flat (and white stays
+    /// white), at the cost of some saturation.
+    fn from_rgb(rgb: Rgb<T>) -> Spectrum<T> {
+        let mut res = [rgb[0]; SAMPLES];
+
+        for (i, s) in res.iter_mut().enumerate() {
+            let lambda = wavelength(i);
+            if lambda < 490.0 {
+                *s = rgb[2];
+            } else if lambda < 590.0 {
+                *s = rgb[1];
+            }
+        }
+
+        return Spectrum(res);
+    }
+}
+
+// Piecewise Gaussian fits of the CIE 1931 color matching functions, after
+// Wyman, Sloan and Shirley, "Simple Analytic Approximations to the CIE XYZ
+// Color Matching Functions" (2013).
+fn cie_xyz(lambda: f64) -> [f64; 3] {
+    let g = |mu: f64, s1: f64, s2: f64| {
+        let t = (lambda - mu) / if lambda < mu { s1 } else { s2 };
+        (-0.5 * t * t).exp()
+    };
+
+    return [
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    ];
+}
+
+// Linear sRGB of the samples `s`, not white balanced.
+fn xyz_rgb(s: &[f64; SAMPLES]) -> [f64; 3] {
+    let mut xyz = [0.0; 3];
+    for (i, v) in s.iter().enumerate() {
+        for (c, m) in xyz.iter_mut().zip(cie_xyz(wavelength(i)).iter()) {
+            *c += v * m;
+        }
+    }
+
+    let [x, y, z] = xyz;
+    return [
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    ];
+}
+
+/// Linear sRGB of the light of a black body at `kelvin`, relative to its
+/// peak over all wavelengths: dim and red when cool (red is about 10⁻² at
+/// 1500 K, 10⁻⁵ at 1000 K), bright and white towards 6500 K.
+pub fn blackbody(kelvin: f64) -> [f64; 3] {
+    // Planck's law, with λ in m.
+    let planck = |lambda: f64| {
+        let c2 = 1.4387769e-2; // hc/k, in m K
+        1.0 / (lambda.powi(5) * ((c2 / (lambda * kelvin)).exp() - 1.0))
+    };
+
+    if kelvin <= 0.0 {
+        return [0.0; 3];
+    }
+
+    // By Wien's displacement law.
+    let peak = planck(2.8977721e-3 / kelvin);
+
+    let white = xyz_rgb(&[1.0; SAMPLES]);
+    let rgb = xyz_rgb(&std::array::from_fn(|i| {
+        planck(wavelength(i) * 1e-9) / peak
+    }));
+    return [0, 1, 2].map(|c| (rgb[c] / white[c]).max(0.0));
+}
+
+impl<T: Float + Into<f64>> Spectrum<T> {
+    fn rgb(&self) -> [T; 3] {
+        let white = xyz_rgb(&[1.0; SAMPLES]);
+        let rgb = xyz_rgb(&self.0.map(|x| x.into()));
+
+        return [0, 1, 2].map(|c| T::from_f64(rgb[c] / white[c]));
+    }
+}
+
+impl<T: Primitive> Black for Spectrum<T> {
+    fn black() -> Spectrum<T> {
+        return Spectrum([T::zero(); SAMPLES]);
+    }
+}
+
+impl<T: Primitive + Float + Into<f64>> Pixel for Spectrum<T> {
+    type Subpixel = T;
+
+    const CHANNEL_COUNT: u8 = SAMPLES as u8;
+    const COLOR_MODEL: &'static str = "spectral";
+    // There are no spectral image formats, spectra are stored as RGB.
+    const COLOR_TYPE: ColorType = ColorType::Rgb8;
+
+    fn channels(&self) -> &[T] {
+        return &self.0;
+    }
+
+    fn channels_mut(&mut self) -> &mut [T] {
+        return &mut self.0;
+    }
+
+    fn channels4(&self) -> (T, T, T, T) {
+        let [r, g, b] = self.rgb();
+        return (r, g, b, T::from_f64(1.0));
+    }
+
+    fn from_channels(a: T, b: T, c: T, _: T) -> Spectrum<T> {
+        return Spectrum::from_rgb(Rgb([a, b, c]));
+    }
+
+    fn from_slice(slice: &[T]) -> &Spectrum<T> {
+        assert_eq!(slice.len(), SAMPLES);
+        // Sound, `Spectrum` is a transparent wrapper of an array.
+        return unsafe { &*(slice.as_ptr() as *const Spectrum<T>) };
+    }
+
+    fn from_slice_mut(slice: &mut [T]) -> &mut Spectrum<T> {
+        assert_eq!(slice.len(), SAMPLES);
+        return unsafe { &mut *(slice.as_mut_ptr() as *mut Spectrum<T>) };
+    }
+
+    fn to_rgb(&self) -> Rgb<T> {
+        return Rgb(self.rgb());
+    }
+
+    fn to_rgba(&self) -> Rgba<T> {
+        let [r, g, b] = self.rgb();
+        return Rgba([r, g, b, T::from_f64(1.0)]);
+    }
+
+    fn to_luma(&self) -> Luma<T> {
+        return self.to_rgb().to_luma();
+    }
+
+    fn to_luma_alpha(&self) -> LumaA<T> {
+        return self.to_rgb().to_luma_alpha();
+    }
+
+    fn to_bgr(&self) -> Bgr<T> {
+        return self.to_rgb().to_bgr();
+    }
+
+    fn to_bgra(&self) -> Bgra<T> {
+        return self.to_rgb().to_bgra();
+    }
+
+    fn map<F: FnMut(T) -> T>(&self, f: F) -> Spectrum<T> {
+        return Spectrum(self.0.map(f));
+    }
+
+    fn apply<F: FnMut(T) -> T>(&mut self, f: F) {
+        *self = self.map(f);
+    }
+
+    // Spectra have no alpha.
+    fn map_with_alpha<F: FnMut(T) -> T, G: FnMut(T) -> T>(&self, f: F, _: G) -> Spectrum<T> {
+        return self.map(f);
+    }
+
+    fn apply_with_alpha<F: FnMut(T) -> T, G: FnMut(T) -> T>(&mut self, f: F, _: G) {
+        self.apply(f);
+    }
+
+    fn map2<F: FnMut(T, T) -> T>(&self, other: &Spectrum<T>, f: F) -> Spectrum<T> {
+        let mut res = *self;
+        res.apply2(other, f);
+        return res;
+    }
+
+    fn apply2<F: FnMut(T, T) -> T>(&mut self, other: &Spectrum<T>, mut f: F) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a = f(*a, *b);
+        }
+    }
+
+    fn invert(&mut self) {
+        self.apply(|x| T::from_f64(1.0) - x);
+    }
+
+    fn blend(&mut self, other: &Spectrum<T>) {
+        *self = *other;
+    }
+}
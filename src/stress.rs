@@ -0,0 +1,585 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use image::{ImageBuffer, Rgb};
+use vecmath::Vector3;
+
+use crate::atmosphere;
+use crate::bidir::Bidir;
+use crate::camera::{Camera, Fisheye, Mapping, Ortho, Pinhole, Pose, Spherical, Stereo, ThinLens};
+use crate::checkerboard::Checkerboard;
+use crate::filter::Filter;
+use crate::geom::{Poly, Sides};
+use crate::grid;
+use crate::ies;
+use crate::integrator::{AmbientOcclusion, DebugNormals, Integrator};
+use crate::lights;
+use crate::medium::{Emission, Fog};
+use crate::paths::PathTracer;
+use crate::phase::{HenyeyGreenstein, Rayleigh};
+use crate::photons::PhotonMapper;
+use crate::progress::Progress;
+use crate::sampling::{self, Rng};
+use crate::sky;
+use crate::surface::{self, Surface};
+use crate::volume::{Grid, Noise, Pattern};
+use crate::whitted::Whitted;
+use crate::{render, RenderOptions, Scene};
+
+type Polys = Vec<Poly<f64, Arc<dyn Surface<f64, Rgb<f64>>>>>;
+
+/// A kind of pathological scene, generated from a random number source.
+struct Case {
+    name: &'static str,
+    generate: fn(&mut Rng) -> (Polys, Pose<f64>),
+}
+
+const CASES: [Case; 6] = [
+    Case {
+        name: "huge and tiny polys",
+        generate: huge_and_tiny,
+    },
+    Case {
+        name: "near-coplanar overlaps",
+        generate: coplanar,
+    },
+    Case {
+        name: "extreme coordinates",
+        generate: extreme,
+    },
+    Case {
+        name: "zero-area emitters",
+        generate: zero_area,
+    },
+    Case {
+        name: "degenerate polys",
+        generate: degenerate,
+    },
+    Case {
+        name: "random soup",
+        generate: soup,
+    },
+];
+
+/// Renders `rounds` random scenes of every case, starting at `seed`, and
+/// reports which of them panic or produce non-finite pixels. Returns
+/// whether all of them passed.
+pub fn run(seed: u64, rounds: u32, threads: usize) -> bool {
+    let grid = grid::Grid::<f64>::new(3, 2);
+    let paths = PathTracer::<f64>::new(2);
+    let photons = PhotonMapper::<f64>::new(2);
+    let bidir = Bidir::new(2);
+    let whitted = Whitted::<f64>::new(2);
+    let ao = AmbientOcclusion::new(1.0);
+
+    let mut opts = RenderOptions {
+        checkerboard: Checkerboard::Off,
+        samples: 2,
+        supersample: 1,
+        filter: Filter::Box,
+        importance: None,
+        frame: [32, 24],
+        cull: true,
+        threads,
+        progress: Progress::Human,
+        sampler: &sampling::R2,
+        adaptive: None,
+        seed,
+        stereo: None,
+    };
+
+    let mut failed = 0;
+
+    for case in CASES.iter() {
+        let mut passed = 0;
+
+        for round in 0..rounds {
+            let seed = seed.wrapping_add(round as u64);
+            let mut rng = Rng::new(seed);
+
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                let (polys, mut pose) = (case.generate)(&mut rng);
+                let mut scene = Scene::new(polys);
+
+                // Possibly open for no time at all, or at negative times.
+                if rng.next() & 1 == 0 {
+                    let open = rng.range(-1.0, 1.0);
+                    pose.shutter = [open, open + rng.range(-0.5, 1.0).max(0.0)];
+                    pose.velocity = rng.point([0.0, 0.0, 0.0], 5.0);
+                }
+
+                // Up to fisheyes seeing all around and more, or views of no
+                // width or height at all, with pixels far from square.
+                let mapping = [Mapping::Equidistant, Mapping::Equisolid];
+                let mut camera: Box<dyn Camera<f64>> = match rng.next() % 4 {
+                    0 => Box::new(Pinhole {
+                        pose,
+                        fov: [0, 1].map(|_| rng.range(0.0, std::f64::consts::PI)),
+                    }),
+                    1 => Box::new(Fisheye {
+                        pose,
+                        fov: [0, 1].map(|_| rng.range(0.0, 2.5 * std::f64::consts::PI)),
+                        mapping: mapping[(rng.next() & 1) as usize],
+                    }),
+                    2 => Box::new(Ortho {
+                        pose,
+                        size: [0, 1].map(|_| rng.range(0.0, 20.0)),
+                    }),
+                    _ => Box::new(Spherical { pose }),
+                };
+
+                // Possibly focused right at the camera.
+                if rng.next() & 1 == 0 {
+                    camera = Box::new(ThinLens {
+                        camera,
+                        lens: rng.range(0.0, 2.0),
+                        focus: rng.range(0.0, 20.0),
+                    });
+                }
+
+                // Possibly with the eyes in one place.
+                let stereo = [Stereo::SideBySide, Stereo::Anaglyph];
+                opts.stereo = match rng.next() % 3 {
+                    0 => None,
+                    k => Some((stereo[k as usize - 1], rng.range(0.0, 2.0))),
+                };
+
+                // Possibly inside or right on some poly.
+                let pos = rng.point(pose.orig, 5.0);
+                let dir = rng.point([0.0, -1.0, 0.0], 0.5);
+
+                let light = match rng.next() % 3 {
+                    0 => lights::point(pos, Rgb([1e3, 1e3, 1e3])),
+                    1 => lights::directional(dir, Rgb([10.0, 10.0, 10.0]), rng.range(0.0, 0.5)),
+                    _ => lights::spot(
+                        pos,
+                        dir,
+                        Rgb([1e3, 1e3, 1e3]),
+                        rng.range(0.0, 1.0),
+                        rng.range(0.0, 1.0),
+                    ),
+                };
+
+                let light = if rng.next() & 1 == 0 {
+                    let front = rng.point([0.0, 0.0, 0.0], 1.0);
+                    lights::profiled(light, dir, front, rng.profile())
+                } else {
+                    light
+                };
+
+                // Light linking and shadow linking, as for the polys.
+                if rng.next() & 3 == 0 {
+                    let groups = 1 << (rng.next() % 4);
+                    scene.add_light(lights::linked(light, groups, rng.range(0.0, 1.0)));
+                } else {
+                    scene.add_light(light);
+                }
+
+                let env = match rng.next() % 4 {
+                    0 => {
+                        // Small, possibly all black.
+                        let env = lights::HdrImage::from_fn(8, 4, |_, _| {
+                            Rgb([rng.range(-0.5, 2.0).max(0.0) as f32; 3])
+                        });
+                        Some(lights::environment(env, 255.0))
+                    }
+                    // Possibly with the sun below the horizon.
+                    1 => Some(sky::sky(
+                        rng.point([0.0, 0.0, 0.0], 1.0),
+                        rng.range(1.0, 12.0),
+                        8.0,
+                    )),
+                    // From the ground to space, with haze from none to
+                    // thick.
+                    2 => Some(atmosphere::atmosphere(
+                        rng.point([0.0, 0.0, 0.0], 1.0),
+                        rng.range(-1e3, 5e5).max(0.0),
+                        rng.range(0.0, 5e3),
+                        8.0,
+                    )),
+                    _ => None,
+                };
+
+                if let Some(mut env) = env {
+                    if rng.next() & 3 == 0 {
+                        let groups = 1 << (rng.next() % 4);
+                        env = lights::linked_environment(env, groups, rng.range(0.0, 1.0));
+                    }
+
+                    if rng.next() & 1 == 0 {
+                        // Possibly degenerate, or right at the camera.
+                        let portals = (0..rng.next() % 3)
+                            .map(|_| {
+                                let scale = rng.range(0.0, 5.0);
+                                [
+                                    rng.point(pose.orig, 5.0),
+                                    rng.point([0.0, 0.0, 0.0], scale),
+                                    rng.point([0.0, 0.0, 0.0], scale),
+                                ]
+                            })
+                            .collect();
+                        scene.set_environment(lights::portals(env, portals));
+                    } else {
+                        scene.set_environment(env);
+                    }
+                }
+
+                if rng.next() & 3 == 0 {
+                    // Possibly clear, or absorbing only.
+                    let fog = Fog::new(
+                        Rgb([rng.range(0.0, 0.5); 3]),
+                        Rgb([rng.range(-0.5, 0.5).max(0.0); 3]),
+                    );
+
+                    // Up to scattering almost only forwards or backwards.
+                    let fog = match rng.next() % 3 {
+                        0 => fog.with_phase(Box::new(HenyeyGreenstein {
+                            g: rng.range(-0.999, 0.999),
+                        })),
+                        1 => fog.with_phase(Box::new(Rayleigh)),
+                        _ => fog,
+                    };
+
+                    if rng.next() & 1 == 0 {
+                        // Possibly around the camera, partly empty or
+                        // empty.
+                        let min = rng.point(pose.orig, 5.0);
+                        let max = vecmath::vec3_add(min, [rng.range(1e-3, 10.0); 3]);
+                        let values = (0..27).map(|_| rng.range(-1.0, 5.0).max(0.0)).collect();
+                        let fog = if rng.next() & 1 == 0 {
+                            fog.with_density(Box::new(Grid::new(min, max, [3, 3, 3], values)))
+                        } else {
+                            let pattern =
+                                [Pattern::Fbm, Pattern::Worley][(rng.next() & 1) as usize];
+                            let noise = Noise::new(
+                                min,
+                                max,
+                                pattern,
+                                rng.range(0.0, 5.0),
+                                (rng.next() % 5) as u32,
+                                rng.range(0.0, 1.2),
+                            );
+                            fog.with_density(Box::new(noise))
+                        };
+
+                        // Possibly dark, or only where there is no fog.
+                        let glow = (0..27).map(|_| rng.range(-1e3, 6e3).max(0.0)).collect();
+                        let glow = Grid::new(min, max, [3, 3, 3], glow);
+                        scene.set_fog(match rng.next() % 3 {
+                            0 => fog.with_emission(Emission::gray(glow, rng.range(0.0, 0.1))),
+                            1 => fog.with_emission(Emission::blackbody(glow, rng.range(0.0, 1e3))),
+                            _ => fog,
+                        });
+                    } else {
+                        scene.set_fog(fog);
+                    }
+                }
+
+                let mut img = ImageBuffer::<Rgb<f64>, Vec<f64>>::new(32, 24);
+                let integrator: &dyn Integrator<_, _, _> = match rng.next() % 7 {
+                    0 => &grid,
+                    1 => &paths,
+                    2 => &bidir,
+                    3 => &ao,
+                    4 => &DebugNormals,
+                    5 => &whitted,
+                    _ => {
+                        scene.scatter_photons(200, seed);
+                        &photons
+                    }
+                };
+                render(
+                    integrator,
+                    &scene,
+                    &*camera,
+                    &opts,
+                    |c| c,
+                    [0, 0],
+                    &mut img,
+                    &mut [],
+                );
+
+                return img.pixels().flat_map(|p| p.0.iter()).all(|x| x.is_finite());
+            }));
+
+            match res {
+                Ok(true) => passed += 1,
+                Ok(false) => println!("{}, seed {}: non-finite pixels", case.name, seed),
+                Err(_) => println!("{}, seed {}: panicked", case.name, seed),
+            }
+        }
+
+        println!("{}: {}/{} passed", case.name, passed, rounds);
+        failed += rounds - passed;
+    }
+
+    println!("{}", if failed == 0 { "PASS" } else { "FAIL" });
+
+    return failed == 0;
+}
+
+impl Rng {
+    /// Uniform in [lo, hi).
+    fn range(&mut self, lo: f64, hi: f64) -> f64 {
+        return lo + (hi - lo) * self.unit();
+    }
+
+    fn point(&mut self, center: Vector3<f64>, radius: f64) -> Vector3<f64> {
+        return [
+            center[0] + self.range(-radius, radius),
+            center[1] + self.range(-radius, radius),
+            center[2] + self.range(-radius, radius),
+        ];
+    }
+
+    fn surface(&mut self) -> Arc<dyn Surface<f64, Rgb<f64>>> {
+        let c = Rgb([
+            self.range(0.0, 1.0),
+            self.range(0.0, 1.0),
+            self.range(0.0, 1.0),
+        ]);
+
+        return match self.next() % 13 {
+            0 => surface::light(Rgb(c.0.map(|x| x * 255.0))),
+            1 => surface::mirror(c),
+            2 => {
+                // Possibly clear inside.
+                let absorption = Rgb([
+                    self.range(-1.0, 2.0).max(0.0),
+                    self.range(-1.0, 2.0).max(0.0),
+                    self.range(-1.0, 2.0).max(0.0),
+                ]);
+                let glass =
+                    surface::with_interior(surface::glass(self.range(1.0, 3.0), c), absorption);
+                surface::with_priority(glass, (self.next() % 3) as u32)
+            }
+            3 => surface::ggx(c, self.range(0.0, 1.0)),
+            4 => surface::glossy(c, self.range(0.0, 1.0)),
+            5 => surface::principled(
+                c,
+                self.range(0.0, 1.0),
+                self.range(0.0, 1.0),
+                self.range(0.0, 1.0),
+                self.range(0.0, 1.0),
+            ),
+            6 => surface::checker(c, Rgb([0.5, 0.5, 0.5]), self.range(0.0, 2.0)),
+            7 => surface::subsurface(c, Rgb([self.range(0.0, 5.0); 3])),
+            8 => surface::thin_film(
+                surface::matt(c),
+                self.range(0.0, 1000.0),
+                self.range(1.0, 2.0),
+            ),
+            9 => surface::clearcoat(surface::matt(c), self.range(1.0, 3.0)),
+            10 => {
+                let freq = self.range(0.0, 20.0);
+                surface::cutout(surface::matt(c), move |p: surface::Point<f64>| {
+                    (p.uv[0] * freq).sin()
+                })
+            }
+            11 => surface::dispersive_glass(
+                Rgb([
+                    self.range(1.0, 3.0),
+                    self.range(1.0, 3.0),
+                    self.range(1.0, 3.0),
+                ]),
+                c,
+            ),
+            _ => surface::matt(c),
+        };
+    }
+
+    /// Luminaire of random intensities, over a random part of the angles.
+    fn profile(&mut self) -> ies::Profile {
+        let vertical = [0.0, 30.0, 60.0, 90.0, 135.0, 180.0];
+        let vertical = &vertical[..1 + (self.next() % 6) as usize];
+
+        let horizontal: &[f64] = match self.next() % 4 {
+            0 => &[0.0],
+            1 => &[0.0, 45.0, 90.0],
+            2 => &[0.0, 90.0, 180.0],
+            _ => &[0.0, 90.0, 180.0, 270.0, 360.0],
+        };
+
+        let mut text = format!(
+            "IESNA:LM-63-2002\nTILT=NONE\n1 -1 1 {} {} 1 2 0 0 0\n1 1 100\n",
+            vertical.len(),
+            horizontal.len()
+        );
+        for a in vertical.iter().chain(horizontal.iter()) {
+            text += &format!("{} ", a);
+        }
+        for _ in 0..vertical.len() * horizontal.len() {
+            text += &format!("{} ", self.range(-100.0, 1000.0).max(0.0));
+        }
+
+        return ies::load(text.as_bytes()).unwrap();
+    }
+
+    fn poly(
+        &mut self,
+        center: Vector3<f64>,
+        radius: f64,
+    ) -> Poly<f64, Arc<dyn Surface<f64, Rgb<f64>>>> {
+        let points = [
+            self.point(center, radius),
+            self.point(center, radius),
+            self.point(center, radius),
+        ];
+        let mut poly = Poly::new(points, self.surface());
+
+        poly.set_sides(match self.next() % 3 {
+            0 => Sides::BackBlack,
+            1 => Sides::BackCull,
+            _ => Sides::Double,
+        });
+
+        // Light linking and shadow linking.
+        if self.next() & 3 == 0 {
+            poly.set_light_groups(1 << (self.next() % 4));
+            poly.set_light_mask(self.next() as u32 & 0xf);
+            poly.set_shadow_mask(self.next() as u32 & 0xf);
+            poly.set_shadow_intensity(self.range(0.0, 1.0));
+        }
+
+        return poly;
+    }
+}
+
+fn camera(orig: Vector3<f64>) -> Pose<f64> {
+    let target = vecmath::vec3_add(orig, [0.0, 0.0, -1.0]);
+    return Pose::look_at(orig, target, [0.0, 1.0, 0.0]).unwrap();
+}
+
+/// A light above the origin, so there is something to see.
+fn add_sky(polys: &mut Polys, center: Vector3<f64>, size: f64) {
+    let [x, y, z] = center;
+    polys.push(Poly::new(
+        [
+            [x - size, y + size, z - size],
+            [x + size, y + size, z - size],
+            [x, y + size, z + size],
+        ],
+        surface::light(Rgb([255.0, 255.0, 255.0])),
+    ));
+}
+
+fn huge_and_tiny(rng: &mut Rng) -> (Polys, Pose<f64>) {
+    let mut polys = Vec::new();
+
+    for _ in 0..20 {
+        let radius = 10f64.powf(rng.range(-7.0, 7.0));
+        polys.push(rng.poly([0.0, 0.0, -5.0], radius));
+    }
+    add_sky(&mut polys, [0.0, 0.0, 0.0], 1e3);
+
+    return (polys, camera([0.0, 0.0, 0.0]));
+}
+
+fn coplanar(rng: &mut Rng) -> (Polys, Pose<f64>) {
+    let mut polys = Vec::new();
+
+    for _ in 0..5 {
+        let base = rng.poly([0.0, 0.0, -5.0], 3.0);
+        let eps = 10f64.powf(rng.range(-12.0, -3.0));
+
+        let mut points = *base.points();
+        for p in points.iter_mut() {
+            p[2] += eps;
+        }
+
+        polys.push(Poly::new(points, rng.surface()));
+        polys.push(base);
+    }
+    add_sky(&mut polys, [0.0, 0.0, 0.0], 20.0);
+
+    return (polys, camera([0.0, 0.0, 0.0]));
+}
+
+fn extreme(rng: &mut Rng) -> (Polys, Pose<f64>) {
+    let center = [
+        rng.range(-1e12, 1e12),
+        rng.range(-1e12, 1e12),
+        rng.range(-1e12, 1e12),
+    ];
+
+    let mut polys = Vec::new();
+    for _ in 0..10 {
+        polys.push(rng.poly([center[0], center[1], center[2] - 5.0], 3.0));
+    }
+    add_sky(&mut polys, center, 20.0);
+
+    return (polys, camera(center));
+}
+
+fn zero_area(rng: &mut Rng) -> (Polys, Pose<f64>) {
+    let mut polys = Vec::new();
+
+    for _ in 0..10 {
+        polys.push(rng.poly([0.0, 0.0, -5.0], 3.0));
+    }
+
+    for _ in 0..10 {
+        let p = rng.point([0.0, 0.0, -5.0], 3.0);
+        let q = rng.point([0.0, 0.0, -5.0], 3.0);
+        let mid = [
+            (p[0] + q[0]) / 2.0,
+            (p[1] + q[1]) / 2.0,
+            (p[2] + q[2]) / 2.0,
+        ];
+
+        let points = if rng.next() & 1 == 0 {
+            [p, p, p]
+        } else {
+            [p, mid, q]
+        };
+        polys.push(Poly::new(
+            points,
+            surface::light(Rgb([255.0, 255.0, 255.0])),
+        ));
+    }
+
+    return (polys, camera([0.0, 0.0, 0.0]));
+}
+
+fn degenerate(rng: &mut Rng) -> (Polys, Pose<f64>) {
+    let mut polys = Vec::new();
+
+    for _ in 0..20 {
+        let p = rng.point([0.0, 0.0, -5.0], 3.0);
+        let q = rng.point([0.0, 0.0, -5.0], 3.0);
+
+        let points = match rng.next() % 3 {
+            0 => [p, p, p],
+            1 => [p, p, q],
+            _ => [
+                p,
+                q,
+                [2.0 * q[0] - p[0], 2.0 * q[1] - p[1], 2.0 * q[2] - p[2]],
+            ],
+        };
+        polys.push(Poly::new(points, rng.surface()));
+    }
+
+    for _ in 0..5 {
+        polys.push(rng.poly([0.0, 0.0, -5.0], 3.0));
+    }
+    add_sky(&mut polys, [0.0, 0.0, 0.0], 20.0);
+
+    return (polys, camera([0.0, 0.0, 0.0]));
+}
+
+fn soup(rng: &mut Rng) -> (Polys, Pose<f64>) {
+    let mut polys = Vec::new();
+
+    for _ in 0..40 {
+        let radius = rng.range(0.1, 5.0);
+        let center = rng.point([0.0, 0.0, -5.0], 5.0);
+        polys.push(rng.poly(center, radius));
+    }
+    add_sky(&mut polys, [0.0, 0.0, 0.0], 20.0);
+
+    // Possibly inside the soup.
+    let orig = rng.point([0.0, 0.0, 0.0], 5.0);
+
+    return (polys, camera(orig));
+}
@@ -1,10 +1,17 @@
-use image::Rgb;
+use image::{DynamicImage, Pixel, Rgb};
 
 use vecmath::traits::Float;
 use vecmath::Vector3;
 
 use std::sync::Arc;
 
+use crate::geom;
+use crate::microfacet;
+use crate::noise;
+use crate::sampling::{self, Rng};
+use crate::spectrum::FromRgb;
+use crate::texture::Texture;
+
 pub trait Black {
     fn black() -> Self;
 }
@@ -15,21 +22,152 @@ impl<T: image::Primitive> Black for Rgb<T> {
     }
 }
 
-pub trait Surface<T, P> {
-    fn emitted(&self) -> P;
-    fn reflected(&self, n: Vector3<T>, i: Vector3<T>, o: Vector3<T>) -> P;
+/// Where on a surface light is reflected, and what surfaces need to know
+/// about it to shade it.
+#[derive(Clone, Copy)]
+pub struct Point<T> {
+    pub pos: Vector3<T>,     // world space
+    pub uv: [T; 2],          // texture coordinates
+    pub n: Vector3<T>,       // shading normal
+    pub tangent: Vector3<T>, // direction of increasing u, orthogonal to n
+    pub dist: T,             // distance the ray traveled to the point
+    pub id: u32,             // object id of the poly, e.g. to vary noise per object
+    // Width of the area the ray stands for (e.g. the camera pixel) at the
+    // point, in world space and in texture coordinates. Textures average
+    // over it to avoid aliasing.
+    pub footprint: T,
+    pub uv_footprint: T,
+    // Index of refraction of the medium on the other side of the surface
+    // than the object it bounds, 1 in air.
+    pub outside: T,
+}
+
+/// Surfaces are shared between render threads.
+pub trait Surface<T, P>: Send + Sync {
+    fn emitted(&self, p: Point<T>) -> P;
+
+    /// Light reflected at point `p` into direction `i` for a ray coming from
+    /// direction `o`.
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P;
+
+    /// Explicit directions (and their weights) the surface reflects `o`
+    /// into at point `p`, in addition to what `reflected` gives for the tracer's grid.
+    fn specular(&self, _p: Point<T>, _o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        return Vec::new();
+    }
+
+    /// Picks a direction to continue a ray coming from `o` into at point
+    /// `p`, as `(direction, pdf, throughput)`. The throughput weights the
+    /// light arriving from the direction: the BRDF times the cosine over the
+    /// pdf. The pdf is infinite for directions out of a discrete set, like a
+    /// mirror's. `None` if the ray is absorbed.
+    ///
+    /// Defaults to a random pick between a cosine weighted direction for
+    /// `reflected` and the `specular` directions.
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return sample_lobes(self, p, o, rng);
+    }
+
+    /// Density (per steradian) of `sample` picking direction `i` for a ray
+    /// coming from `o` at point `p`, leaving out the discrete directions.
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return pdf_lobes(self, p, o, i);
+    }
+
+    /// Overrides the secondary ray offset of polys with this surface.
+    fn bias(&self) -> Option<T> {
+        return None;
+    }
+
+    /// Whether rays hitting point `p` stop there, rather than passing
+    /// through a hole cut into the surface.
+    fn opaque(&self, _p: Point<T>) -> bool {
+        return true;
+    }
+
+    /// Cheaper stand-in used to shade hits of secondary (non-camera) rays,
+    /// where detail is barely visible.
+    fn indirect(&self) -> Option<&dyn Surface<T, P>> {
+        return None;
+    }
+
+    /// Share of the light absorbed per unit of distance (per channel)
+    /// inside the closed object the surface bounds, with its normals
+    /// pointing out, if it isn't clear.
+    fn interior(&self) -> Option<P> {
+        return None;
+    }
+
+    /// Index of refraction of the medium inside the closed object the
+    /// surface bounds, if it refracts. Surfaces within it refract relative
+    /// to it (see `Point::outside`).
+    fn ior(&self) -> Option<T> {
+        return None;
+    }
+
+    /// Where objects with an `interior` or an `ior` overlap, the medium of
+    /// the one of the highest priority fills the overlap, and the others'
+    /// surfaces within it are skipped. 0 unless given by `with_priority`.
+    fn priority(&self) -> u32 {
+        return 0;
+    }
 }
 
-impl<T, P> Surface<T, P> for Arc<dyn Surface<T, P>> {
-    fn emitted(&self) -> P {
-        return (**self).emitted();
+impl<'a, T, P> Surface<T, P> for Arc<dyn 'a + Surface<T, P>> {
+    fn emitted(&self, p: Point<T>) -> P {
+        return (**self).emitted(p);
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        return (**self).reflected(p, i, o);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        return (**self).specular(p, o);
     }
-    fn reflected(&self, n: Vector3<T>, i: Vector3<T>, o: Vector3<T>) -> P {
-        return (**self).reflected(n, i, o);
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return (**self).sample(p, o, rng);
+    }
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return (**self).pdf(p, o, i);
+    }
+    fn bias(&self) -> Option<T> {
+        return (**self).bias();
+    }
+    fn opaque(&self, p: Point<T>) -> bool {
+        return (**self).opaque(p);
+    }
+    fn indirect(&self) -> Option<&dyn Surface<T, P>> {
+        return (**self).indirect();
+    }
+    fn interior(&self) -> Option<P> {
+        return (**self).interior();
+    }
+    fn ior(&self) -> Option<T> {
+        return (**self).ior();
+    }
+    fn priority(&self) -> u32 {
+        return (**self).priority();
     }
 }
 
-pub fn matt<'a, T: Float, P: 'a + Black + Copy>(color: P) -> Arc<dyn 'a + Surface<T, P>> {
+pub fn matt<'a, T: Float, P: 'a + Black + Copy + Send + Sync>(
+    color: P,
+) -> Arc<dyn 'a + Surface<T, P>> {
     Arc::new(Matt { color })
 }
 
@@ -37,40 +175,2095 @@ struct Matt<P> {
     color: P,
 }
 
-impl<T: Float, P: Copy + Black> Surface<T, P> for Matt<P> {
-    fn emitted(&self) -> P {
+impl<T: Float, P: Copy + Black + Send + Sync> Surface<T, P> for Matt<P> {
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        if !same_side(p.n, i, o) {
+            return P::black();
+        }
+
+        return self.color;
+    }
+}
+
+/// `Surface::sample` from `reflected` and `specular` alone.
+fn sample_lobes<T, P, S>(
+    s: &S,
+    p: Point<T>,
+    o: Vector3<T>,
+    rng: &mut Rng,
+) -> Option<(Vector3<T>, T, P)>
+where
+    T: Float,
+    P: Pixel<Subpixel = T> + Black + PartialEq,
+    S: ?Sized + Surface<T, P>,
+{
+    let mut lobes: Vec<_> = s
+        .specular(p, o)
+        .into_iter()
+        .map(|(dir, weight)| (dir, T::from_f64(f64::INFINITY), weight))
+        .collect();
+
+    let d = cosine_dir(p.n, o, rng);
+    let cos = vecmath::vec3_dot(d, p.n);
+    let cos = if cos < T::zero() { -cos } else { cos };
+    lobes.push((
+        d,
+        cos / T::from_f64(std::f64::consts::PI),
+        s.reflected(p, d, o),
+    ));
+
+    lobes.retain(|(_, _, weight)| *weight != P::black());
+    if lobes.is_empty() {
+        return None;
+    }
+
+    // Pick one uniformly, and make up for the ones not picked.
+    let k = ((rng.unit() * lobes.len() as f64) as usize).min(lobes.len() - 1);
+    let count = T::from_f64(lobes.len() as f64);
+    let (dir, pdf, weight) = lobes.swap_remove(k);
+
+    return Some((dir, pdf / count, weight.map(|x| x * count)));
+}
+
+/// `Surface::pdf` of `sample_lobes`.
+fn pdf_lobes<T, P, S>(s: &S, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+where
+    T: Float,
+    P: Pixel<Subpixel = T> + Black + PartialEq,
+    S: ?Sized + Surface<T, P>,
+{
+    // `cosine_dir` only picks the side `o` is reflected to.
+    let cos = vecmath::vec3_dot(i, p.n);
+    if cos * vecmath::vec3_dot(o, p.n) >= T::zero() || s.reflected(p, i, o) == P::black() {
+        return T::zero();
+    }
+
+    let count = 1 + s
+        .specular(p, o)
+        .iter()
+        .filter(|(_, weight)| *weight != P::black())
+        .count();
+
+    let cos = if cos < T::zero() { -cos } else { cos };
+    return cos / T::from_f64(std::f64::consts::PI * count as f64);
+}
+
+/// Random direction on the side of the surface with normal `n` that a ray
+/// coming from `o` is reflected to, with a density proportional to the
+/// cosine to the normal.
+pub fn cosine_dir<T: Float>(n: Vector3<T>, o: Vector3<T>, rng: &mut Rng) -> Vector3<T> {
+    let n = if vecmath::vec3_dot(o, n) > T::zero() {
+        vecmath::vec3_neg(n)
+    } else {
+        n
+    };
+    let [t, b] = geom::basis(n);
+
+    let [u, v] = rng.square();
+    let r = T::from_f64(u.sqrt());
+    let phi = T::from_f64(2.0 * std::f64::consts::PI * v);
+    let z = (T::one() - r * r).sqrt();
+
+    return vecmath::vec3_add(
+        vecmath::vec3_scale(n, z),
+        vecmath::vec3_add(
+            vecmath::vec3_scale(t, r * phi.cos()),
+            vecmath::vec3_scale(b, r * phi.sin()),
+        ),
+    );
+}
+
+/// Whether light leaving into `i` can reach a ray coming from `o`, i.e.
+/// both are on the same side of the surface.
+fn same_side<T: Float>(n: Vector3<T>, i: Vector3<T>, o: Vector3<T>) -> bool {
+    let v = vecmath::vec3_dot(i, n);
+
+    if v == T::zero() {
+        // Perpendicular to surface.
+        return false;
+    }
+
+    return vecmath::vec3_dot(o, n) / v <= T::zero();
+}
+
+pub fn phong<'a, T: Float, P: 'a + Pixel<Subpixel = T> + Black + Send + Sync>(
+    diffuse: P,
+    specular: P,
+    shininess: T,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    Arc::new(Phong {
+        diffuse,
+        specular,
+        shininess,
+        half_vector: false,
+    })
+}
+
+/// Like `phong`, but the highlight is computed from the half vector of the
+/// incoming and outgoing directions.
+pub fn blinn_phong<'a, T: Float, P: 'a + Pixel<Subpixel = T> + Black + Send + Sync>(
+    diffuse: P,
+    specular: P,
+    shininess: T,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    Arc::new(Phong {
+        diffuse,
+        specular,
+        shininess,
+        half_vector: true,
+    })
+}
+
+struct Phong<T, P> {
+    diffuse: P,
+    specular: P,
+    shininess: T, // exponent of the highlight lobe
+    half_vector: bool,
+}
+
+impl<T: Float, P: Pixel<Subpixel = T> + Black + Send + Sync> Surface<T, P> for Phong<T, P> {
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        if !same_side(p.n, i, o) {
+            return P::black();
+        }
+
+        let cos = if self.half_vector {
+            // Normal facing the viewer, against the half vector.
+            let n = if vecmath::vec3_dot(o, p.n) < T::zero() {
+                p.n
+            } else {
+                vecmath::vec3_neg(p.n)
+            };
+            let h = vecmath::vec3_normalized(vecmath::vec3_sub(i, o));
+            vecmath::vec3_dot(n, h)
+        } else {
+            // Highlight around the mirror direction.
+            vecmath::vec3_dot(reflect(p.n, o), i)
+        };
+
+        if cos <= T::zero() {
+            return self.diffuse;
+        }
+
+        let k = cos.powf(self.shininess);
+
+        return self.diffuse.map2(&self.specular, |d, s| d + s * k);
+    }
+}
+
+pub fn light<'a, T: 'a + Float, P: 'a + Copy + Black + Send + Sync>(
+    color: P,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    return light_map(move |_| color);
+}
+
+/// Like `light`, but the emitted color varies over the surface, e.g. with
+/// the texture coordinates (for screens) or the position (for gradients).
+pub fn light_map<'a, T, P, E>(emission: E) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float,
+    P: 'a + Black,
+    E: 'a + Fn(Point<T>) -> P + Send + Sync,
+{
+    Arc::new(Light { emission })
+}
+
+struct Light<E> {
+    emission: E,
+}
+
+impl<T: Float, P: Black, E: Fn(Point<T>) -> P + Send + Sync> Surface<T, P> for Light<E> {
+    fn emitted(&self, p: Point<T>) -> P {
+        return (self.emission)(p);
+    }
+    fn reflected(&self, _p: Point<T>, _i: Vector3<T>, _o: Vector3<T>) -> P {
+        return P::black();
+    }
+}
+
+pub fn mirror<'a, T: Float, P: 'a + Copy + Black + Send + Sync>(
+    color: P,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    Arc::new(Mirror { color })
+}
+
+struct Mirror<P> {
+    color: P,
+}
+
+impl<T: Float, P: Copy + Black + Send + Sync> Surface<T, P> for Mirror<P> {
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, _p: Point<T>, _i: Vector3<T>, _o: Vector3<T>) -> P {
+        return P::black();
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        return vec![(reflect(p.n, o), self.color)];
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, _rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return Some((reflect(p.n, o), T::from_f64(f64::INFINITY), self.color));
+    }
+}
+
+pub fn glass<'a, T: Float, P: 'a + Pixel<Subpixel = T> + Black + Send + Sync>(
+    ior: T,
+    tint: P,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    return dispersive_glass(tint.map(|_| ior), tint);
+}
+
+/// Like `glass`, but the index of refraction differs per color channel, so
+/// each channel is refracted into its own direction and e.g. prisms split
+/// white light into a rainbow. See `cauchy` for realistic values.
+pub fn dispersive_glass<'a, T: Float, P: 'a + Pixel<Subpixel = T> + Black + Send + Sync>(
+    ior: P,
+    tint: P,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    Arc::new(Glass { ior, tint })
+}
+
+/// Per channel indices of refraction following Cauchy's equation
+/// `a + b / λ²` (with λ in micrometers), e.g. `a = 1.5046, b = 0.0042` for
+/// borosilicate crown glass.
+pub fn cauchy<T: Float + image::Primitive>(a: T, b: T) -> Rgb<T> {
+    return Rgb(WAVELENGTHS.map(|w| a + b / T::from_f64(w * w * 1e-6)));
+}
+
+/// Wavelengths (in nanometers) standing for the red, green and blue
+/// channels.
+const WAVELENGTHS: [f64; 3] = [650.0, 532.0, 450.0];
+
+struct Glass<P> {
+    ior: P, // index of refraction, per channel
+    tint: P,
+}
+
+/// Fresnel reflectance and refracted direction of a ray along `o` hitting
+/// a dielectric with index of refraction `ior`; no direction on total
+/// internal reflection.
+fn refract<T: Float>(n: Vector3<T>, o: Vector3<T>, ior: T) -> (T, Option<Vector3<T>>) {
+    // Orient the normal against the ray and find the ratio of refraction
+    // indices depending on whether we enter or leave the medium.
+    let (n, eta) = if vecmath::vec3_dot(o, n) < T::zero() {
+        (n, T::one() / ior)
+    } else {
+        (vecmath::vec3_neg(n), ior)
+    };
+
+    let cos = -vecmath::vec3_dot(o, n);
+    let sin2 = eta * eta * (T::one() - cos * cos);
+
+    if sin2 > T::one() {
+        // Total internal reflection.
+        return (T::one(), None);
+    }
+
+    let cos_t = (T::one() - sin2).sqrt();
+
+    let refr = vecmath::vec3_add(
+        vecmath::vec3_scale(o, eta),
+        vecmath::vec3_scale(n, eta * cos - cos_t),
+    );
+
+    // Schlick's approximation of the Fresnel reflectance. Use the angle
+    // on the optically thinner side.
+    let r0 = (T::one() - ior) / (T::one() + ior);
+    let fresnel = microfacet::schlick(r0 * r0, if eta > T::one() { cos_t } else { cos });
+
+    return (fresnel, Some(refr));
+}
+
+impl<T: Float, P: Pixel<Subpixel = T> + Black + Send + Sync> Surface<T, P> for Glass<P> {
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, _p: Point<T>, _i: Vector3<T>, _o: Vector3<T>) -> P {
+        return P::black();
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        let refl = reflect(p.n, o);
+        let iors = self.ior.channels();
+
+        if iors.iter().all(|x| *x == iors[0]) {
+            return match refract(p.n, o, iors[0] / p.outside) {
+                (_, None) => vec![(refl, self.tint.map(|_| T::one()))],
+                (fresnel, Some(refr)) => vec![
+                    (refl, self.tint.map(|_| fresnel)),
+                    (refr, self.tint.map(|x| x * (T::one() - fresnel))),
+                ],
+            };
+        }
+
+        // Split the ray: every channel refracts into its own direction.
+        let mut refl_weight = P::black();
+        let mut res = Vec::new();
+
+        for (k, ior) in iors.iter().enumerate() {
+            let (fresnel, refr) = refract(p.n, o, *ior / p.outside);
+            refl_weight.channels_mut()[k] = fresnel;
+
+            if let Some(refr) = refr {
+                let mut weight = P::black();
+                weight.channels_mut()[k] = self.tint.channels()[k] * (T::one() - fresnel);
+                res.push((refr, weight));
+            }
+        }
+
+        res.push((refl, refl_weight));
+
+        return res;
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        let iors = self.ior.channels();
+
+        if iors.iter().any(|x| *x != iors[0]) {
+            return sample_lobes(self, p, o, rng);
+        }
+
+        // Reflect or refract in proportion to the Fresnel reflectance, which
+        // then cancels out of the throughput.
+        let infinity = T::from_f64(f64::INFINITY);
+        return match refract(p.n, o, iors[0] / p.outside) {
+            (fresnel, Some(refr)) if T::from_f64(rng.unit()) >= fresnel => {
+                Some((refr, infinity, self.tint))
+            }
+            _ => Some((reflect(p.n, o), infinity, self.tint.map(|_| T::one()))),
+        };
+    }
+    fn ior(&self) -> Option<T> {
+        let iors = self.ior.channels();
+        return Some(iors.iter().fold(T::zero(), |s, x| s + *x) / T::from_f64(iors.len() as f64));
+    }
+}
+
+/// Translucent material (wax, skin, marble) scattering light below its
+/// surface. Light enters diffusely, travels through the object and leaves
+/// it where it reaches the boundary again, tinted by `color` and attenuated
+/// by the distance it traveled inside relative to `radius` (the mean free
+/// path, per channel).
+///
+/// The polys must form a closed shape with outward facing normals and
+/// shade both sides: rays arriving at their back are inside the object.
+pub fn subsurface<'a, T: Float, P: 'a + Pixel<Subpixel = T> + Black + Send + Sync>(
+    color: P,
+    radius: P,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    Arc::new(Subsurface { color, radius })
+}
+
+struct Subsurface<P> {
+    color: P,
+    radius: P,
+}
+
+impl<P: Pixel> Subsurface<P> {
+    // Fraction of the entering light that scatters back out close to where
+    // it entered, approximated by diffuse reflection.
+    const NEAR: f64 = 0.5;
+
+    // Fraction of the light reaching the boundary from inside that is
+    // reflected back in, instead of leaving the object.
+    const INTERNAL: f64 = 0.5;
+
+    /// How much light is left after traveling `dist` inside.
+    fn attenuation<T: Float>(&self, dist: T) -> P
+    where
+        P: Pixel<Subpixel = T>,
+    {
+        return self.radius.map(|r| {
+            if r > T::zero() {
+                T::from_f64(std::f64::consts::E).powf(-dist / r)
+            } else {
+                T::zero()
+            }
+        });
+    }
+}
+
+impl<T: Float, P: Pixel<Subpixel = T> + Black + Send + Sync> Surface<T, P> for Subsurface<P> {
+    fn emitted(&self, _p: Point<T>) -> P {
         return P::black();
     }
-    fn reflected(&self, n: Vector3<T>, i: Vector3<T>, o: Vector3<T>) -> P {
-        let v = vecmath::vec3_dot(i, n);
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        if vecmath::vec3_dot(o, p.n) < T::zero() {
+            if !same_side(p.n, i, o) {
+                return P::black();
+            }
+
+            let near = T::from_f64(Self::NEAR);
+            return self.color.map(|c| c * near);
+        }
 
-        if v == T::zero() {
-            // Perpendicular to surface.
+        // Light from outside, gathered by rays inside the object.
+        if vecmath::vec3_dot(i, p.n) <= T::zero() {
             return P::black();
         }
 
-        if vecmath::vec3_dot(o, n) / v > T::zero() {
-            // Rays are not on the same side of the surfce.
+        let out = T::one() - T::from_f64(Self::INTERNAL);
+
+        return self
+            .attenuation(p.dist)
+            .map2(&self.color, |a, c| a * c * out);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        if vecmath::vec3_dot(o, p.n) > T::zero() {
+            // Inside, keep walking.
+            let weight = self
+                .attenuation(p.dist)
+                .map(|a| a * T::from_f64(Self::INTERNAL));
+            return vec![(reflect(p.n, o), weight)];
+        }
+
+        // Enter the object: straight in and a wide ring around it.
+        const RING: u32 = 4;
+        const SPREAD: f64 = 2.5;
+
+        let n = vecmath::vec3_neg(p.n);
+        let [t, b] = geom::basis(n);
+        let far = T::one() - T::from_f64(Self::NEAR);
+        let weight = self.color.map(|_| far / T::from_u32(RING + 1));
+
+        let mut dirs = vec![(n, weight)];
+        for k in 0..RING {
+            let phi = T::from_u32(k) * T::_360().deg_to_rad() / T::from_u32(RING);
+            let side = vecmath::vec3_add(
+                vecmath::vec3_scale(t, phi.cos() * T::from_f64(SPREAD)),
+                vecmath::vec3_scale(b, phi.sin() * T::from_f64(SPREAD)),
+            );
+            let d = vecmath::vec3_normalized(vecmath::vec3_add(n, side));
+            dirs.push((d, weight));
+        }
+
+        return dirs;
+    }
+}
+
+/// Rough specular surface with a GGX microfacet distribution. `color` is
+/// the reflectance at normal incidence, `roughness` is in [0, 1].
+pub fn ggx<'a, T: 'a + Float + Send + Sync, P: 'a + Pixel<Subpixel = T> + Black + Send + Sync>(
+    color: P,
+    roughness: T,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    return ggx_map(color, move |_| roughness);
+}
+
+/// Like `ggx`, but the roughness varies over the surface, e.g. with an
+/// `image_map`.
+pub fn ggx_map<'a, T, P, R>(color: P, roughness: R) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float,
+    P: 'a + Pixel<Subpixel = T> + Black + Send + Sync,
+    R: 'a + Fn(Point<T>) -> T + Send + Sync,
+{
+    return ggx_aniso_map(color, move |p| {
+        let r = roughness(p);
+        [r, r]
+    });
+}
+
+/// Like `ggx`, but with separate roughness along the tangent (the direction
+/// of increasing u) and the bitangent, e.g. for brushed metal.
+pub fn ggx_aniso<'a, T, P>(color: P, roughness_t: T, roughness_b: T) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Send + Sync,
+    P: 'a + Pixel<Subpixel = T> + Black + Send + Sync,
+{
+    return ggx_aniso_map(color, move |_| [roughness_t, roughness_b]);
+}
+
+/// Like `ggx_aniso`, but the roughness varies over the surface.
+pub fn ggx_aniso_map<'a, T, P, R>(color: P, roughness: R) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float,
+    P: 'a + Pixel<Subpixel = T> + Black + Send + Sync,
+    R: 'a + Fn(Point<T>) -> [T; 2] + Send + Sync,
+{
+    Arc::new(Ggx { color, roughness })
+}
+
+struct Ggx<P, R> {
+    color: P,
+    // Along the tangent and the bitangent.
+    roughness: R,
+}
+
+impl<T, P, R> Surface<T, P> for Ggx<P, R>
+where
+    T: Float,
+    P: Pixel<Subpixel = T> + Black + Send + Sync,
+    R: Fn(Point<T>) -> [T; 2] + Send + Sync,
+{
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        let [nl, nv, nh, vh] = match microfacet::cosines(p.n, i, o) {
+            None => return P::black(),
+            Some(c) => c,
+        };
+
+        // Avoid a degenerate distribution for perfectly smooth surfaces.
+        let [at, ab] = (self.roughness)(p).map(|r| (r * r).max(T::from_f64(1e-3)));
+
+        let spec = if at == ab {
+            microfacet::specular(nl, nv, nh, at)
+        } else {
+            let frame = [p.tangent, vecmath::vec3_cross(p.n, p.tangent)];
+            microfacet::specular_aniso(frame, i, o, [nl, nv, nh], at, ab)
+        };
+
+        // Scaled by pi, since `matt` returns its albedo as is.
+        let spec = spec * T::from_f64(std::f64::consts::PI);
+
+        return self.color.map(|f0| microfacet::schlick(f0, vh) * spec);
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        // Sample microfacet normals by the isotropic distribution of the mean
+        // roughness; `reflected` accounts for any anisotropy.
+        let alpha = self.alpha(p);
+
+        let n = if vecmath::vec3_dot(o, p.n) > T::zero() {
+            vecmath::vec3_neg(p.n)
+        } else {
+            p.n
+        };
+        let [t, b] = geom::basis(n);
+
+        // Density D(h) nh over the hemisphere.
+        let [u, v] = rng.square();
+        let u = T::from_f64(u);
+        let phi = T::from_f64(2.0 * std::f64::consts::PI * v);
+        let cos = (T::one() / (T::one() + alpha * alpha * u / (T::one() - u))).sqrt();
+        let sin = (T::one() - cos * cos).sqrt();
+
+        let h = vecmath::vec3_add(
+            vecmath::vec3_scale(n, cos),
+            vecmath::vec3_add(
+                vecmath::vec3_scale(t, sin * phi.cos()),
+                vecmath::vec3_scale(b, sin * phi.sin()),
+            ),
+        );
+        let i = reflect(h, o);
+
+        let [nl, _, nh, vh] = microfacet::cosines(p.n, i, o)?;
+        let pdf = microfacet::ggx_d(nh, alpha) * nh / (T::from_f64(4.0) * vh);
+
+        // `reflected` is the BRDF scaled by pi.
+        let weight = nl / (T::from_f64(std::f64::consts::PI) * pdf);
+        return Some((i, pdf, self.reflected(p, i, o).map(|x| x * weight)));
+    }
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return match microfacet::cosines(p.n, i, o) {
+            None => T::zero(),
+            Some([_, _, nh, vh]) => {
+                microfacet::ggx_d(nh, self.alpha(p)) * nh / (T::from_f64(4.0) * vh)
+            }
+        };
+    }
+}
+
+impl<P, R> Ggx<P, R> {
+    // Roughness of the distribution `sample` picks microfacet normals by.
+    fn alpha<T: Float>(&self, p: Point<T>) -> T
+    where
+        R: Fn(Point<T>) -> [T; 2],
+    {
+        let [at, ab] = (self.roughness)(p).map(|r| (r * r).max(T::from_f64(1e-3)));
+        return (at * ab).sqrt();
+    }
+}
+
+/// Metal with complex index of refraction `eta + k i` (per channel).
+/// Zero roughness gives a perfect reflection.
+pub fn conductor<'a, T: Float, P: 'a + Pixel<Subpixel = T> + Black + Send + Sync>(
+    eta: P,
+    k: P,
+    roughness: T,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    Arc::new(Conductor { eta, k, roughness })
+}
+
+#[derive(Clone, Copy)]
+pub enum Metal {
+    Gold,
+    Copper,
+    Aluminum,
+    Silver,
+}
+
+impl Metal {
+    pub fn from_name(name: &str) -> Option<Metal> {
+        return match name {
+            "gold" => Some(Metal::Gold),
+            "copper" => Some(Metal::Copper),
+            "aluminum" => Some(Metal::Aluminum),
+            "silver" => Some(Metal::Silver),
+            _ => None,
+        };
+    }
+}
+
+/// A `conductor` with measured (RGB) indices of refraction of `metal`.
+pub fn metal<'a, T, P>(metal: Metal, roughness: T) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + image::Primitive,
+    P: 'a + FromRgb<T> + Pixel<Subpixel = T> + Black + Send + Sync,
+{
+    let (eta, k) = match metal {
+        Metal::Gold => ([0.143, 0.374, 1.442], [3.983, 2.385, 1.603]),
+        Metal::Copper => ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+        Metal::Aluminum => ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+        Metal::Silver => ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+    };
+
+    let rgb = |c: [f64; 3]| P::from_rgb(Rgb(c.map(T::from_f64)));
+
+    return conductor(rgb(eta), rgb(k), roughness);
+}
+
+struct Conductor<T, P> {
+    eta: P,
+    k: P,
+    roughness: T,
+}
+
+impl<T: Float, P: Pixel<Subpixel = T> + Black + Send + Sync> Surface<T, P> for Conductor<T, P> {
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        if self.roughness == T::zero() {
             return P::black();
         }
 
-        return self.color;
+        let [nl, nv, nh, vh] = match microfacet::cosines(p.n, i, o) {
+            None => return P::black(),
+            Some(c) => c,
+        };
+
+        let alpha = self.roughness * self.roughness;
+
+        // Scaled by pi, since `matt` returns its albedo as is.
+        let pi = T::from_f64(std::f64::consts::PI);
+        let spec = microfacet::specular(nl, nv, nh, alpha) * pi;
+
+        return self
+            .eta
+            .map2(&self.k, |eta, k| microfacet::conductor(eta, k, vh) * spec);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        if self.roughness != T::zero() {
+            return Vec::new();
+        }
+
+        let cos = vecmath::vec3_dot(o, p.n);
+        let cos = if cos < T::zero() { -cos } else { cos };
+
+        let weight = self
+            .eta
+            .map2(&self.k, |eta, k| microfacet::conductor(eta, k, cos));
+
+        return vec![(reflect(p.n, o), weight)];
+    }
+}
+
+/// Disney-style "principled" surface blending a diffuse and a microfacet
+/// specular lobe. All parameters but `base` are in [0, 1]; `specular` is
+/// the amount of dielectric reflection (0.5 is a typical 4%).
+pub fn principled<'a, T, P>(
+    base: P,
+    metallic: T,
+    roughness: T,
+    specular: T,
+    sheen: T,
+) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Send + Sync,
+    P: 'a + Pixel<Subpixel = T> + Black + Send + Sync,
+{
+    return principled_map(base, move |_| metallic, move |_| roughness, specular, sheen);
+}
+
+/// Like `principled`, but metalness and roughness vary over the surface,
+/// so e.g. rusty and polished regions can be mixed.
+pub fn principled_map<'a, T, P, M, R>(
+    base: P,
+    metallic: M,
+    roughness: R,
+    specular: T,
+    sheen: T,
+) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Send + Sync,
+    P: 'a + Pixel<Subpixel = T> + Black + Send + Sync,
+    M: 'a + Fn(Point<T>) -> T + Send + Sync,
+    R: 'a + Fn(Point<T>) -> T + Send + Sync,
+{
+    Arc::new(Principled {
+        base,
+        metallic,
+        roughness,
+        specular,
+        sheen,
+    })
+}
+
+struct Principled<T, P, M, R> {
+    base: P,
+    metallic: M,
+    roughness: R,
+    specular: T,
+    sheen: T,
+}
+
+impl<T, P, M, R> Surface<T, P> for Principled<T, P, M, R>
+where
+    T: Float + Send + Sync,
+    P: Pixel<Subpixel = T> + Black + Send + Sync,
+    M: Fn(Point<T>) -> T + Send + Sync,
+    R: Fn(Point<T>) -> T + Send + Sync,
+{
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        let [nl, nv, nh, vh] = match microfacet::cosines(p.n, i, o) {
+            None => return P::black(),
+            Some(c) => c,
+        };
+
+        let metallic = (self.metallic)(p);
+        let roughness = (self.roughness)(p);
+
+        let one = T::one();
+        let pi = T::from_f64(std::f64::consts::PI);
+        let pow5 = |x: T| x * x * x * x * x;
+
+        // Burley diffuse with retro-reflection at grazing angles, normalized
+        // as in Frostbite so it doesn't reflect more than it receives.
+        let half = T::from_f64(0.5);
+        let fd90 = half * roughness + (roughness + roughness) * vh * vh;
+        let fd = (one + (fd90 - one) * pow5(one - nl))
+            * (one + (fd90 - one) * pow5(one - nv))
+            * (one - roughness + roughness / T::from_f64(1.51));
+
+        // Sheen brightens the diffuse towards white at grazing angles,
+        // rather than adding to it.
+        let sheen = (self.sheen * pow5(one - vh)).min(one);
+        let dielectric = one - metallic;
+
+        let alpha = (roughness * roughness).max(T::from_f64(1e-3));
+        let spec = microfacet::specular(nl, nv, nh, alpha) * pi;
+
+        // Dielectrics reflect white, metals tint with the base color.
+        let f0_dielectric = T::from_f64(0.08) * self.specular;
+
+        // What the dielectric specular lobe reflects doesn't enter the
+        // diffuse one.
+        let transmitted = one - microfacet::schlick(f0_dielectric, nv);
+
+        return self.base.map(|c| {
+            let f0 = f0_dielectric * dielectric + c * metallic;
+            let diffuse = (c * fd * (one - sheen) + sheen) * dielectric * transmitted;
+
+            diffuse + microfacet::schlick(f0, vh) * spec
+        });
     }
 }
 
-pub fn light<'a, T: Float, P: 'a + Copy + Black>(color: P) -> Arc<dyn 'a + Surface<T, P>> {
-    Arc::new(Light { color })
+/// Cel shaded surface for non-photorealistic renders: the light of every
+/// direction falls into one of `bands` flat brightness levels instead of
+/// fading with the angle, which gives hard edged shading with small lights
+/// such as the sun. Where the surface is seen at a grazing angle (the
+/// cosine to the view direction is below `outline`) it is drawn black,
+/// which outlines silhouettes; 0 disables that.
+pub fn toon<'a, T: Float + Into<f64>, P: 'a + Pixel<Subpixel = T> + Black + Send + Sync>(
+    color: P,
+    bands: u32,
+    outline: T,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    Arc::new(Toon {
+        color,
+        bands: bands.max(1),
+        outline,
+    })
 }
 
-struct Light<P> {
+struct Toon<P, T> {
     color: P,
+    bands: u32,
+    outline: T,
 }
 
-impl<T: Float, P: Copy + Black> Surface<T, P> for Light<P> {
-    fn emitted(&self) -> P {
-        return self.color;
+impl<P, T: Float + Into<f64>> Toon<P, T> {
+    // Brightness of the band light arriving at cosine `cos` to the normal
+    // falls into: the middle of the band, so on average the bands reflect
+    // as much as a matt surface.
+    fn level(&self, cos: T) -> T {
+        let bands = self.bands as f64;
+        return T::from_f64(((cos.into() * bands).floor().min(bands - 1.0) + 0.5) / bands);
     }
-    fn reflected(&self, _n: Vector3<T>, _i: Vector3<T>, _o: Vector3<T>) -> P {
+}
+
+impl<T: Float + Into<f64>, P: Pixel<Subpixel = T> + Black + Send + Sync> Surface<T, P>
+    for Toon<P, T>
+{
+    fn emitted(&self, _p: Point<T>) -> P {
         return P::black();
     }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        if !same_side(p.n, i, o) {
+            return P::black();
+        }
+
+        let abs = |x: T| if x < T::zero() { -x } else { x };
+
+        if abs(vecmath::vec3_dot(o, p.n)) < self.outline {
+            return P::black();
+        }
+
+        // The tracer scales by the cosine, undo that. Along the surface
+        // there is no light to undo it on.
+        let cos = abs(vecmath::vec3_dot(i, p.n));
+        if cos <= T::from_f64(1e-6) {
+            return P::black();
+        }
+
+        let level = self.level(cos);
+        return self.color.map(|c| c * level / cos);
+    }
+
+    /// Uniform over the hemisphere rather than by the cosine, which
+    /// `reflected` divides by, so grazing directions don't get weighted
+    /// up without bound.
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        let side = if vecmath::vec3_dot(o, p.n) < T::zero() {
+            p.n
+        } else {
+            vecmath::vec3_neg(p.n)
+        };
+        let i = sampling::uniform_cone(side, T::zero(), rng);
+
+        if self.reflected(p, i, o) == P::black() {
+            return None;
+        }
+
+        // BRDF (`reflected` over pi) times the cosine over the pdf.
+        let level = self.level(vecmath::vec3_dot(i, side));
+        return Some((
+            i,
+            T::from_f64(0.5 / std::f64::consts::PI),
+            self.color.map(|c| c * level * T::from_f64(2.0)),
+        ));
+    }
+
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        if self.reflected(p, i, o) == P::black() {
+            return T::zero();
+        }
+
+        return T::from_f64(0.5 / std::f64::consts::PI);
+    }
+}
+
+/// Blurred mirror: reflects into a cone around the mirror direction whose
+/// width is given by `roughness` in [0, 1] (1 is a 90° half angle).
+pub fn glossy<'a, T: Float, P: 'a + Pixel<Subpixel = T> + Black + Send + Sync>(
+    color: P,
+    roughness: T,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    Arc::new(Glossy { color, roughness })
+}
+
+struct Glossy<T, P> {
+    color: P,
+    roughness: T,
+}
+
+impl<T: Float, P: Pixel<Subpixel = T> + Black + Send + Sync> Surface<T, P> for Glossy<T, P> {
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, _p: Point<T>, _i: Vector3<T>, _o: Vector3<T>) -> P {
+        return P::black();
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        // Rings of directions around the mirror direction.
+        const RINGS: u32 = 2;
+        const PER_RING: u32 = 6;
+
+        let r = reflect(p.n, o);
+
+        if self.roughness == T::zero() {
+            return vec![(r, self.color)];
+        }
+
+        let [t, b] = geom::basis(r);
+        let max_angle = self.roughness * T::_90().deg_to_rad();
+        let side = -vecmath::vec3_dot(o, p.n);
+
+        let mut dirs = vec![r];
+
+        for ring in 1..=RINGS {
+            let theta = max_angle * T::from_u32(ring) / T::from_u32(RINGS);
+            let count = PER_RING * ring;
+
+            for k in 0..count {
+                // Offset every other ring, so rays don't line up.
+                let phi = T::_360().deg_to_rad()
+                    * (T::from_u32(k) + T::from_u32(ring % 2) / T::from_u32(2))
+                    / T::from_u32(count);
+
+                let d = vecmath::vec3_add(
+                    vecmath::vec3_scale(r, theta.cos()),
+                    vecmath::vec3_add(
+                        vecmath::vec3_scale(t, theta.sin() * phi.cos()),
+                        vecmath::vec3_scale(b, theta.sin() * phi.sin()),
+                    ),
+                );
+
+                // Skip directions that went below the surface.
+                if vecmath::vec3_dot(d, p.n) * side > T::zero() {
+                    dirs.push(d);
+                }
+            }
+        }
+
+        let total = T::from_u32(1 + PER_RING * RINGS * (RINGS + 1) / 2);
+        let weight = self.color.map(|c| c / total);
+
+        return dirs.into_iter().map(|d| (d, weight)).collect();
+    }
+}
+
+/// Blends surfaces `a` and `b`: `weight` 0 is all `a`, 1 is all `b`.
+pub fn mix<'a, T, P, A, B>(a: A, b: B, weight: T) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float,
+    P: 'a + Pixel<Subpixel = T>,
+    A: 'a + Surface<T, P>,
+    B: 'a + Surface<T, P>,
+{
+    let a: Arc<dyn 'a + Surface<T, P>> = Arc::new(a);
+    let b: Arc<dyn 'a + Surface<T, P>> = Arc::new(b);
+
+    // The blend of the stand-ins, if either has one.
+    let indirect = if a.indirect().is_some() || b.indirect().is_some() {
+        let indirect: Arc<dyn 'a + Surface<T, P>> = Arc::new(Mix {
+            a: indirect_of(&a),
+            b: indirect_of(&b),
+            weight,
+            indirect: None,
+        });
+        Some(indirect)
+    } else {
+        None
+    };
+
+    Arc::new(Mix {
+        a,
+        b,
+        weight,
+        indirect,
+    })
+}
+
+struct Mix<S, T> {
+    a: S,
+    b: S,
+    weight: T,
+    indirect: Option<S>,
+}
+
+impl<T: Float, P: Pixel<Subpixel = T>, S: Surface<T, P>> Surface<T, P> for Mix<S, T> {
+    fn emitted(&self, p: Point<T>) -> P {
+        let w = self.weight;
+        return self
+            .a
+            .emitted(p)
+            .map2(&self.b.emitted(p), |a, b| a + (b - a) * w);
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        let w = self.weight;
+        return self
+            .a
+            .reflected(p, i, o)
+            .map2(&self.b.reflected(p, i, o), |a, b| a + (b - a) * w);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        let wa = T::one() - self.weight;
+        let wb = self.weight;
+
+        let a = self.a.specular(p, o).into_iter();
+        let b = self.b.specular(p, o).into_iter();
+
+        return a
+            .map(|(d, c)| (d, c.map(|x| x * wa)))
+            .chain(b.map(|(d, c)| (d, c.map(|x| x * wb))))
+            .collect();
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        // Sample `a` or `b` by their weights. Their discrete directions keep
+        // their throughput, as the weight and the odds of the pick cancel.
+        let (dir, pdf, weight) = if T::from_f64(rng.unit()) < self.weight {
+            self.b.sample(p, o, rng)?
+        } else {
+            self.a.sample(p, o, rng)?
+        };
+
+        if pdf == T::from_f64(f64::INFINITY) {
+            return Some((dir, pdf, weight));
+        }
+
+        let pdf = self.pdf(p, o, dir);
+        return continuous(self.reflected(p, dir, o), p, dir, pdf).map(|w| (dir, pdf, w));
+    }
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        let w = self.weight;
+        let a = self.a.pdf(p, o, i);
+        return a + (self.b.pdf(p, o, i) - a) * w;
+    }
+    fn bias(&self) -> Option<T> {
+        return self.a.bias().or_else(|| self.b.bias());
+    }
+    fn opaque(&self, p: Point<T>) -> bool {
+        return self.a.opaque(p) || self.b.opaque(p);
+    }
+    fn indirect(&self) -> Option<&dyn Surface<T, P>> {
+        return self.indirect.as_ref().map(|s| s as &dyn Surface<T, P>);
+    }
+    fn interior(&self) -> Option<P> {
+        return self.a.interior().or_else(|| self.b.interior());
+    }
+    fn ior(&self) -> Option<T> {
+        return self.a.ior().or_else(|| self.b.ior());
+    }
+    fn priority(&self) -> u32 {
+        return self.a.priority().max(self.b.priority());
+    }
+}
+
+/// Throughput of the light arriving from the continuous direction `i`,
+/// picked with density `pdf`, for the `reflected` light `f`. `None` if `i`
+/// can't be picked.
+fn continuous<T: Float, P: Pixel<Subpixel = T>>(
+    f: P,
+    p: Point<T>,
+    i: Vector3<T>,
+    pdf: T,
+) -> Option<P> {
+    if pdf <= T::zero() {
+        return None;
+    }
+
+    let cos = vecmath::vec3_dot(i, p.n);
+    let cos = if cos < T::zero() { -cos } else { cos };
+
+    // `reflected` is the BRDF scaled by pi.
+    let weight = cos / (T::from_f64(std::f64::consts::PI) * pdf);
+    return Some(f.map(|x| x * weight));
+}
+
+/// Shades as the `indirect` stand-in of `surface`, or `surface` itself if
+/// it has none.
+fn indirect_of<'a, T: 'a, P: 'a>(
+    surface: &Arc<dyn 'a + Surface<T, P>>,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    return Arc::new(IndirectOf(surface.clone()));
+}
+
+struct IndirectOf<S>(S);
+
+impl<S> IndirectOf<S> {
+    fn get<T, P>(&self) -> &dyn Surface<T, P>
+    where
+        S: Surface<T, P>,
+    {
+        return self.0.indirect().unwrap_or(&self.0);
+    }
+}
+
+impl<T, P, S: Surface<T, P>> Surface<T, P> for IndirectOf<S> {
+    fn emitted(&self, p: Point<T>) -> P {
+        return self.get().emitted(p);
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        return self.get().reflected(p, i, o);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        return self.get().specular(p, o);
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.get().sample(p, o, rng);
+    }
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.get().pdf(p, o, i);
+    }
+    fn bias(&self) -> Option<T> {
+        return self.get().bias();
+    }
+    fn opaque(&self, p: Point<T>) -> bool {
+        return self.get().opaque(p);
+    }
+    fn interior(&self) -> Option<P> {
+        return self.get().interior();
+    }
+    fn ior(&self) -> Option<T> {
+        return self.get().ior();
+    }
+    fn priority(&self) -> u32 {
+        return self.get().priority();
+    }
+}
+
+/// Matt surface with a 3D checkerboard of `color_a` and `color_b` in
+/// world space, with cubes of edge length `scale`.
+pub fn checker<'a, T, P>(color_a: P, color_b: P, scale: T) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Into<f64>,
+    P: 'a + Black + Copy + Send + Sync,
+{
+    Arc::new(Checker {
+        color_a,
+        color_b,
+        scale,
+    })
+}
+
+struct Checker<P, T> {
+    color_a: P,
+    color_b: P,
+    scale: T,
+}
+
+impl<T: Float + Into<f64>, P: Copy + Black + Send + Sync> Surface<T, P> for Checker<P, T> {
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        if !same_side(p.n, i, o) {
+            return P::black();
+        }
+
+        let cell = |x: T| (x / self.scale).into().floor() as i64;
+        let parity = cell(p.pos[0]) + cell(p.pos[1]) + cell(p.pos[2]);
+
+        return if parity.rem_euclid(2) == 0 {
+            self.color_a
+        } else {
+            self.color_b
+        };
+    }
+}
+
+/// Matt surface with its albedo looked up in `image` at the texture
+/// coordinates of the hit, filtered over the footprint of the ray. The
+/// image repeats outside of [0, 1]², v points up.
+pub fn image_texture<'a, T, P>(image: DynamicImage) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Into<f64> + image::Primitive,
+    P: 'a + FromRgb<T> + Black,
+{
+    Arc::new(ImageTexture {
+        texture: Texture::color(image),
+    })
+}
+
+struct ImageTexture {
+    texture: Texture,
+}
+
+impl<T: Float + Into<f64> + image::Primitive, P: FromRgb<T> + Black> Surface<T, P>
+    for ImageTexture
+{
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        if !same_side(p.n, i, o) {
+            return P::black();
+        }
+
+        let uv = [p.uv[0].into(), p.uv[1].into()];
+        let [r, g, b] = self.texture.sample(uv, p.uv_footprint.into());
+
+        return P::from_rgb(Rgb([T::from_f64(r), T::from_f64(g), T::from_f64(b)]));
+    }
+}
+
+/// Light emitting `image` at the texture coordinates of the hit, scaled
+/// by `brightness` (the emission of a white texel).
+pub fn image_light<'a, T, P>(image: DynamicImage, brightness: T) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Into<f64> + image::Primitive + Send + Sync,
+    P: 'a + FromRgb<T> + Black,
+{
+    let texture = Texture::color(image);
+
+    return light_map(move |p: Point<T>| {
+        let uv = [p.uv[0].into(), p.uv[1].into()];
+        let c = texture.sample(uv, p.uv_footprint.into());
+        P::from_rgb(Rgb(c.map(|x| T::from_f64(x) * brightness)))
+    });
+}
+
+/// Surface parameter (e.g. for `ggx_map`) read from the luminance of
+/// `image` at the texture coordinates of the hit, in [0, 1].
+pub fn image_map<T: Float + Into<f64>>(
+    image: DynamicImage,
+) -> impl Fn(Point<T>) -> T + Send + Sync {
+    let texture = Texture::new(image);
+
+    return move |p: Point<T>| {
+        let uv = [p.uv[0].into(), p.uv[1].into()];
+        let [r, g, b] = texture.sample(uv, p.uv_footprint.into());
+        T::from_f64(0.2126 * r + 0.7152 * g + 0.0722 * b)
+    };
+}
+
+/// Like `image_texture`, but ignores the texture coordinates of the hit.
+/// Instead, the image is projected along each axis (repeating every `scale`
+/// units of world space) and the projections are blended by how much the
+/// normal faces the axis, so polys without proper UVs can be textured.
+pub fn triplanar<'a, T, P>(image: DynamicImage, scale: T) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Into<f64> + image::Primitive,
+    P: 'a + FromRgb<T> + Black,
+{
+    Arc::new(Triplanar {
+        texture: Texture::color(image),
+        scale,
+    })
+}
+
+struct Triplanar<T> {
+    texture: Texture,
+    scale: T,
+}
+
+impl<T: Float + Into<f64> + image::Primitive, P: FromRgb<T> + Black> Surface<T, P>
+    for Triplanar<T>
+{
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        if !same_side(p.n, i, o) {
+            return P::black();
+        }
+
+        let s = self.scale.into();
+        let [x, y, z] = [
+            p.pos[0].into() / s,
+            p.pos[1].into() / s,
+            p.pos[2].into() / s,
+        ];
+        let footprint = p.footprint.into() / s;
+
+        // Sharpen the blend, so mostly one projection shows.
+        let w = p.n.map(|c| c.into().powi(4));
+        let sum = w[0] + w[1] + w[2];
+
+        let mut res = [0.0; 3];
+        for (k, uv) in [[z, y], [x, z], [x, y]].iter().enumerate() {
+            let c = self.texture.sample(*uv, footprint);
+            for (r, c) in res.iter_mut().zip(c.iter()) {
+                *r += c * w[k] / sum;
+            }
+        }
+
+        return P::from_rgb(Rgb(res.map(T::from_f64)));
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Pattern {
+    Marble,
+    Wood,
+    Turbulence,
+}
+
+/// Matt surface veined like marble, blending from `color_a` to `color_b`.
+/// `frequency` is the number of veins per unit along x, `octaves` the
+/// detail of the noise distorting them.
+pub fn marble<'a, T, P>(
+    color_a: P,
+    color_b: P,
+    frequency: T,
+    octaves: u32,
+) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Into<f64>,
+    P: 'a + Pixel<Subpixel = T> + Black + Send + Sync,
+{
+    return noisy(Pattern::Marble, color_a, color_b, frequency, octaves);
+}
+
+/// Matt surface with the rings of wood grown around the y axis, blending
+/// from `color_a` to `color_b`. `frequency` is the number of rings per unit.
+pub fn wood<'a, T, P>(
+    color_a: P,
+    color_b: P,
+    frequency: T,
+    octaves: u32,
+) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Into<f64>,
+    P: 'a + Pixel<Subpixel = T> + Black + Send + Sync,
+{
+    return noisy(Pattern::Wood, color_a, color_b, frequency, octaves);
+}
+
+/// Matt surface blending from `color_a` to `color_b` with turbulent noise
+/// of the given base `frequency` and number of `octaves`.
+pub fn turbulence<'a, T, P>(
+    color_a: P,
+    color_b: P,
+    frequency: T,
+    octaves: u32,
+) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Into<f64>,
+    P: 'a + Pixel<Subpixel = T> + Black + Send + Sync,
+{
+    return noisy(Pattern::Turbulence, color_a, color_b, frequency, octaves);
+}
+
+fn noisy<'a, T, P>(
+    pattern: Pattern,
+    color_a: P,
+    color_b: P,
+    frequency: T,
+    octaves: u32,
+) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Into<f64>,
+    P: 'a + Pixel<Subpixel = T> + Black + Send + Sync,
+{
+    Arc::new(Noisy {
+        pattern,
+        color_a,
+        color_b,
+        frequency,
+        octaves,
+    })
+}
+
+struct Noisy<P, T> {
+    pattern: Pattern,
+    color_a: P,
+    color_b: P,
+    frequency: T,
+    octaves: u32,
+}
+
+impl<P, T: Float + Into<f64>> Noisy<P, T> {
+    /// Blend weight between the two colors at `p` on object `id`, in
+    /// [0, 1]. Objects cut from the same material look up the noise in
+    /// different places, so their veins and grain differ.
+    fn weight(&self, p: Vector3<T>, id: u32) -> f64 {
+        let f = self.frequency.into();
+        let [x, y, z] = [p[0].into() * f, p[1].into() * f, p[2].into() * f];
+        let s = id as f64 * 17.31;
+
+        return match self.pattern {
+            Pattern::Marble => {
+                let t = noise::turbulence([x + s, y + s, z + s], self.octaves);
+                0.5 + 0.5 * (std::f64::consts::PI * (x + 4.0 * t)).sin()
+            }
+            Pattern::Wood => {
+                let grain = noise::fbm([x + s, y * 0.1 + s, z + s], self.octaves);
+                let r = (x * x + z * z).sqrt() + 0.3 * grain;
+                r - r.floor()
+            }
+            Pattern::Turbulence => noise::turbulence([x + s, y + s, z + s], self.octaves),
+        };
+    }
+}
+
+impl<T, P> Surface<T, P> for Noisy<P, T>
+where
+    T: Float + Into<f64>,
+    P: Pixel<Subpixel = T> + Black + Send + Sync,
+{
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        if !same_side(p.n, i, o) {
+            return P::black();
+        }
+
+        let w = T::from_f64(self.weight(p.pos, p.id));
+
+        return self.color_a.map2(&self.color_b, |a, b| a + (b - a) * w);
+    }
+}
+
+/// Layers `top` (e.g. a clear coat) over `base`. Light reaching `base`
+/// passes through a dielectric interface with index of refraction `ior`
+/// twice and is attenuated by its Fresnel transmittance each time.
+pub fn layer<'a, T, P, A, B>(top: A, base: B, ior: T) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float,
+    P: 'a + Pixel<Subpixel = T>,
+    A: 'a + Surface<T, P>,
+    B: 'a + Surface<T, P>,
+{
+    let top: Arc<dyn 'a + Surface<T, P>> = Arc::new(top);
+    let base: Arc<dyn 'a + Surface<T, P>> = Arc::new(base);
+
+    // The stand-ins layered alike, if either has one.
+    let indirect = if top.indirect().is_some() || base.indirect().is_some() {
+        let indirect: Arc<dyn 'a + Surface<T, P>> = Arc::new(Layer {
+            top: indirect_of(&top),
+            base: indirect_of(&base),
+            ior,
+            indirect: None,
+        });
+        Some(indirect)
+    } else {
+        None
+    };
+
+    Arc::new(Layer {
+        top,
+        base,
+        ior,
+        indirect,
+    })
+}
+
+struct Layer<S, T> {
+    top: S,
+    base: S,
+    ior: T,
+    indirect: Option<S>,
+}
+
+impl<S, T: Float> Layer<S, T> {
+    fn transmittance(&self, n: Vector3<T>, d: Vector3<T>) -> T {
+        let r0 = (T::one() - self.ior) / (T::one() + self.ior);
+        let cos = vecmath::vec3_dot(n, d);
+        let cos = if cos < T::zero() { -cos } else { cos };
+
+        return T::one() - microfacet::schlick(r0 * r0, cos);
+    }
+
+    // Odds of `sample` sampling `top` for a ray coming from `o`: the
+    // reflectance of the interface, but at least a tenth each.
+    fn top_odds(&self, n: Vector3<T>, o: Vector3<T>) -> T {
+        let r = T::one() - self.transmittance(n, o);
+        return r.max(T::from_f64(0.1)).min(T::from_f64(0.9));
+    }
+}
+
+impl<T: Float, P: Pixel<Subpixel = T>, S: Surface<T, P>> Surface<T, P> for Layer<S, T> {
+    fn emitted(&self, p: Point<T>) -> P {
+        return self
+            .top
+            .emitted(p)
+            .map2(&self.base.emitted(p), |a, b| a + b);
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        let t = self.transmittance(p.n, i) * self.transmittance(p.n, o);
+
+        return self
+            .top
+            .reflected(p, i, o)
+            .map2(&self.base.reflected(p, i, o), |a, b| a + b * t);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        let to = self.transmittance(p.n, o);
+
+        let base = self.base.specular(p, o).into_iter().map(|(d, c)| {
+            let t = to * self.transmittance(p.n, d);
+            (d, c.map(|x| x * t))
+        });
+
+        return self.top.specular(p, o).into_iter().chain(base).collect();
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        let q = self.top_odds(p.n, o);
+
+        let (dir, pdf, weight) = if T::from_f64(rng.unit()) < q {
+            let (dir, pdf, weight) = self.top.sample(p, o, rng)?;
+            (dir, pdf, weight.map(|x| x / q))
+        } else {
+            let (dir, pdf, weight) = self.base.sample(p, o, rng)?;
+            let t = self.transmittance(p.n, o) * self.transmittance(p.n, dir) / (T::one() - q);
+            (dir, pdf, weight.map(|x| x * t))
+        };
+
+        if pdf == T::from_f64(f64::INFINITY) {
+            return Some((dir, pdf, weight));
+        }
+
+        let pdf = self.pdf(p, o, dir);
+        return continuous(self.reflected(p, dir, o), p, dir, pdf).map(|w| (dir, pdf, w));
+    }
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        let q = self.top_odds(p.n, o);
+        return q * self.top.pdf(p, o, i) + (T::one() - q) * self.base.pdf(p, o, i);
+    }
+    fn bias(&self) -> Option<T> {
+        return self.top.bias().or_else(|| self.base.bias());
+    }
+    fn opaque(&self, p: Point<T>) -> bool {
+        return self.base.opaque(p);
+    }
+    fn indirect(&self) -> Option<&dyn Surface<T, P>> {
+        return self.indirect.as_ref().map(|s| s as &dyn Surface<T, P>);
+    }
+    fn interior(&self) -> Option<P> {
+        return self.base.interior();
+    }
+    fn ior(&self) -> Option<T> {
+        return self.base.ior();
+    }
+    fn priority(&self) -> u32 {
+        return self.base.priority();
+    }
+}
+
+/// Covers `base` with a clear coat of index of refraction `ior` (1.5 is
+/// typical), e.g. for car paint or varnished wood: a sharp reflection on top
+/// whose strength follows the Fresnel reflectance of the coat.
+pub fn clearcoat<'a, T, P, S>(base: S, ior: T) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float + Send + Sync,
+    P: 'a + Pixel<Subpixel = T> + Black + Send + Sync,
+    S: 'a + Surface<T, P>,
+{
+    return layer(Coat { ior }, base, ior);
+}
+
+struct Coat<T> {
+    ior: T,
+}
+
+impl<T: Float + Send + Sync, P: Pixel<Subpixel = T> + Black> Surface<T, P> for Coat<T> {
+    fn emitted(&self, _p: Point<T>) -> P {
+        return P::black();
+    }
+    fn reflected(&self, _p: Point<T>, _i: Vector3<T>, _o: Vector3<T>) -> P {
+        return P::black();
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        let r0 = (T::one() - self.ior) / (T::one() + self.ior);
+        let cos = vecmath::vec3_dot(p.n, o);
+        let cos = if cos < T::zero() { -cos } else { cos };
+
+        let fresnel = microfacet::schlick(r0 * r0, cos);
+
+        return vec![(reflect(p.n, o), P::black().map(|_| fresnel))];
+    }
+}
+
+/// Coats `base` with a thin film of index of refraction `ior` and
+/// `thickness` (in nanometers), e.g. soap or oil. Interference in the film
+/// makes its reflection iridescent: the Fresnel reflectance differs per
+/// color channel and with the viewing angle. The film sits on a medium with
+/// index of refraction 1.5 and reflects like a mirror.
+pub fn thin_film<'a, T, S>(base: S, thickness: T, ior: T) -> Arc<dyn 'a + Surface<T, Rgb<T>>>
+where
+    T: 'a + Float + image::Primitive,
+    S: 'a + Surface<T, Rgb<T>>,
+{
+    Arc::new(ThinFilm {
+        base,
+        thickness,
+        ior,
+    })
+}
+
+struct ThinFilm<S, T> {
+    base: S,
+    thickness: T,
+    ior: T,
+}
+
+impl<S, T: Float + image::Primitive> ThinFilm<S, T> {
+    /// Reflectance of the film for light along `d`.
+    fn reflectance(&self, n: Vector3<T>, d: Vector3<T>) -> Rgb<T> {
+        let cos = vecmath::vec3_dot(n, d);
+        let cos = if cos < T::from_f64(0.0) { -cos } else { cos };
+
+        return Rgb(WAVELENGTHS.map(|w| {
+            microfacet::thin_film(
+                self.ior,
+                T::from_f64(1.5),
+                self.thickness,
+                T::from_f64(w),
+                cos,
+            )
+        }));
+    }
+
+    /// Fraction of the light along `d` passing through the film.
+    fn transmittance(&self, n: Vector3<T>, d: Vector3<T>) -> Rgb<T> {
+        return self.reflectance(n, d).map(|r| T::from_f64(1.0) - r);
+    }
+
+    // Odds of `sample` taking the reflection off the film for a ray coming
+    // from `o`: its mean reflectance, but at most 0.9 to leave some for the
+    // base.
+    fn film_odds(&self, n: Vector3<T>, o: Vector3<T>) -> T {
+        let [r, g, b] = self.reflectance(n, o).0;
+        return ((r + g + b) / T::from_f64(3.0)).min(T::from_f64(0.9));
+    }
+}
+
+impl<T: Float + image::Primitive, S: Surface<T, Rgb<T>>> Surface<T, Rgb<T>> for ThinFilm<S, T> {
+    fn emitted(&self, p: Point<T>) -> Rgb<T> {
+        return self.base.emitted(p);
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> Rgb<T> {
+        let ti = self.transmittance(p.n, i);
+        let to = self.transmittance(p.n, o);
+
+        return self
+            .base
+            .reflected(p, i, o)
+            .map2(&ti, |b, t| b * t)
+            .map2(&to, |b, t| b * t);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, Rgb<T>)> {
+        let to = self.transmittance(p.n, o);
+
+        let base = self.base.specular(p, o).into_iter().map(|(d, c)| {
+            let td = self.transmittance(p.n, d);
+            (d, c.map2(&to, |c, t| c * t).map2(&td, |c, t| c * t))
+        });
+
+        let film = (reflect(p.n, o), self.reflectance(p.n, o));
+
+        return std::iter::once(film).chain(base).collect();
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, Rgb<T>)>
+    where
+        T: Float,
+        Rgb<T>: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        // Take the reflection off the film as often as it reflects on
+        // average, and `base` otherwise.
+        let q = self.film_odds(p.n, o);
+        let infinity = T::from_f64(f64::INFINITY);
+
+        if T::from_f64(rng.unit()) < q {
+            let weight = self.reflectance(p.n, o).map(|r| r / q);
+            return Some((reflect(p.n, o), infinity, weight));
+        }
+
+        let (dir, pdf, weight) = self.base.sample(p, o, rng)?;
+        if pdf == infinity {
+            let to = self.transmittance(p.n, o);
+            let td = self.transmittance(p.n, dir);
+            let weight = weight
+                .map2(&to, |c, t| c * t)
+                .map2(&td, |c, t| c * t / (T::from_f64(1.0) - q));
+            return Some((dir, pdf, weight));
+        }
+
+        let pdf = self.pdf(p, o, dir);
+        return continuous(self.reflected(p, dir, o), p, dir, pdf).map(|w| (dir, pdf, w));
+    }
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        Rgb<T>: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return (T::from_f64(1.0) - self.film_odds(p.n, o)) * self.base.pdf(p, o, i);
+    }
+    fn bias(&self) -> Option<T> {
+        return self.base.bias();
+    }
+    fn opaque(&self, p: Point<T>) -> bool {
+        return self.base.opaque(p);
+    }
+    fn indirect(&self) -> Option<&dyn Surface<T, Rgb<T>>> {
+        return self.base.indirect();
+    }
+    fn interior(&self) -> Option<Rgb<T>> {
+        return self.base.interior();
+    }
+    fn ior(&self) -> Option<T> {
+        return self.base.ior();
+    }
+    fn priority(&self) -> u32 {
+        return self.base.priority();
+    }
+}
+
+/// Reflects `o` about the normal `n`.
+pub fn reflect<T: Float>(n: Vector3<T>, o: Vector3<T>) -> Vector3<T> {
+    let d = vecmath::vec3_dot(o, n);
+    return vecmath::vec3_sub(o, vecmath::vec3_scale(n, d + d));
+}
+
+/// Wraps `surface`, overriding the secondary ray offset of its polys.
+pub fn biased<'a, T: 'a + Copy + Send + Sync, P: 'a, S: 'a + Surface<T, P>>(
+    surface: S,
+    bias: T,
+) -> Arc<dyn 'a + Surface<T, P>> {
+    Arc::new(Biased { surface, bias })
+}
+
+struct Biased<S, T> {
+    surface: S,
+    bias: T,
+}
+
+impl<T: Copy + Send + Sync, P, S: Surface<T, P>> Surface<T, P> for Biased<S, T> {
+    fn emitted(&self, p: Point<T>) -> P {
+        return self.surface.emitted(p);
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        return self.surface.reflected(p, i, o);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        return self.surface.specular(p, o);
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.surface.sample(p, o, rng);
+    }
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.surface.pdf(p, o, i);
+    }
+    fn bias(&self) -> Option<T> {
+        return Some(self.bias);
+    }
+    fn opaque(&self, p: Point<T>) -> bool {
+        return self.surface.opaque(p);
+    }
+    fn indirect(&self) -> Option<&dyn Surface<T, P>> {
+        return self.surface.indirect();
+    }
+    fn interior(&self) -> Option<P> {
+        return self.surface.interior();
+    }
+    fn ior(&self) -> Option<T> {
+        return self.surface.ior();
+    }
+    fn priority(&self) -> u32 {
+        return self.surface.priority();
+    }
+}
+
+/// Shades camera ray hits with `surface` and all others with `indirect`,
+/// e.g. a constant albedo for a textured surface or a `ggx` for a `glossy`
+/// one.
+pub fn with_indirect<'a, T, P, S, I>(surface: S, indirect: I) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a,
+    P: 'a,
+    S: 'a + Surface<T, P>,
+    I: 'a + Surface<T, P>,
+{
+    Arc::new(WithIndirect { surface, indirect })
+}
+
+struct WithIndirect<S, I> {
+    surface: S,
+    indirect: I,
+}
+
+impl<T, P, S: Surface<T, P>, I: Surface<T, P>> Surface<T, P> for WithIndirect<S, I> {
+    fn emitted(&self, p: Point<T>) -> P {
+        return self.surface.emitted(p);
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        return self.surface.reflected(p, i, o);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        return self.surface.specular(p, o);
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.surface.sample(p, o, rng);
+    }
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.surface.pdf(p, o, i);
+    }
+    fn bias(&self) -> Option<T> {
+        return self.surface.bias();
+    }
+    fn opaque(&self, p: Point<T>) -> bool {
+        return self.surface.opaque(p);
+    }
+    fn indirect(&self) -> Option<&dyn Surface<T, P>> {
+        return Some(&self.indirect);
+    }
+    fn interior(&self) -> Option<P> {
+        return self.surface.interior();
+    }
+    fn ior(&self) -> Option<T> {
+        return self.surface.ior();
+    }
+    fn priority(&self) -> u32 {
+        return self.surface.priority();
+    }
+}
+
+/// Cuts holes into `surface` where `mask` (e.g. an `image_map`) is below
+/// 0.5, e.g. for leaves or fences. Rays pass through the holes as if the
+/// poly was not there.
+pub fn cutout<'a, T, P, S, M>(surface: S, mask: M) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a + Float,
+    P: 'a,
+    S: 'a + Surface<T, P>,
+    M: 'a + Fn(Point<T>) -> T + Send + Sync,
+{
+    Arc::new(Cutout { surface, mask })
+}
+
+struct Cutout<S, M> {
+    surface: S,
+    mask: M,
+}
+
+impl<T, P, S, M> Surface<T, P> for Cutout<S, M>
+where
+    T: Float,
+    S: Surface<T, P>,
+    M: Fn(Point<T>) -> T + Send + Sync,
+{
+    fn emitted(&self, p: Point<T>) -> P {
+        return self.surface.emitted(p);
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        return self.surface.reflected(p, i, o);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        return self.surface.specular(p, o);
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.surface.sample(p, o, rng);
+    }
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.surface.pdf(p, o, i);
+    }
+    fn bias(&self) -> Option<T> {
+        return self.surface.bias();
+    }
+    fn opaque(&self, p: Point<T>) -> bool {
+        return (self.mask)(p) >= T::from_f64(0.5) && self.surface.opaque(p);
+    }
+    fn indirect(&self) -> Option<&dyn Surface<T, P>> {
+        return self.surface.indirect();
+    }
+    fn interior(&self) -> Option<P> {
+        return self.surface.interior();
+    }
+    fn ior(&self) -> Option<T> {
+        return self.surface.ior();
+    }
+    fn priority(&self) -> u32 {
+        return self.surface.priority();
+    }
+}
+
+/// `surface` bounding a closed object filled with a medium that absorbs
+/// the share `absorption` of the light per unit of distance, e.g. colored
+/// glass or murky water: light crossing it is dimmed the more the longer
+/// its way through. The normals of the object must point out.
+pub fn with_interior<'a, T, P, S>(surface: S, absorption: P) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a,
+    P: 'a + Copy + Send + Sync,
+    S: 'a + Surface<T, P>,
+{
+    Arc::new(Interior {
+        surface,
+        absorption,
+    })
+}
+
+struct Interior<S, P> {
+    surface: S,
+    absorption: P,
+}
+
+impl<T, P: Copy + Send + Sync, S: Surface<T, P>> Surface<T, P> for Interior<S, P> {
+    fn emitted(&self, p: Point<T>) -> P {
+        return self.surface.emitted(p);
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        return self.surface.reflected(p, i, o);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        return self.surface.specular(p, o);
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.surface.sample(p, o, rng);
+    }
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.surface.pdf(p, o, i);
+    }
+    fn bias(&self) -> Option<T> {
+        return self.surface.bias();
+    }
+    fn opaque(&self, p: Point<T>) -> bool {
+        return self.surface.opaque(p);
+    }
+    fn indirect(&self) -> Option<&dyn Surface<T, P>> {
+        return self.surface.indirect();
+    }
+    fn interior(&self) -> Option<P> {
+        return Some(self.absorption);
+    }
+    fn ior(&self) -> Option<T> {
+        return self.surface.ior();
+    }
+    fn priority(&self) -> u32 {
+        return self.surface.priority();
+    }
+}
+
+/// `surface` with the given `Surface::priority`, e.g. for an ice cube (2)
+/// floating in a glass of water (1): the water fills the glass up to the
+/// ice, whose surface refracts relative to the water.
+pub fn with_priority<'a, T, P, S>(surface: S, priority: u32) -> Arc<dyn 'a + Surface<T, P>>
+where
+    T: 'a,
+    P: 'a,
+    S: 'a + Surface<T, P>,
+{
+    Arc::new(Priority { surface, priority })
+}
+
+struct Priority<S> {
+    surface: S,
+    priority: u32,
+}
+
+impl<T, P, S: Surface<T, P>> Surface<T, P> for Priority<S> {
+    fn emitted(&self, p: Point<T>) -> P {
+        return self.surface.emitted(p);
+    }
+    fn reflected(&self, p: Point<T>, i: Vector3<T>, o: Vector3<T>) -> P {
+        return self.surface.reflected(p, i, o);
+    }
+    fn specular(&self, p: Point<T>, o: Vector3<T>) -> Vec<(Vector3<T>, P)> {
+        return self.surface.specular(p, o);
+    }
+    fn sample(&self, p: Point<T>, o: Vector3<T>, rng: &mut Rng) -> Option<(Vector3<T>, T, P)>
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.surface.sample(p, o, rng);
+    }
+    fn pdf(&self, p: Point<T>, o: Vector3<T>, i: Vector3<T>) -> T
+    where
+        T: Float,
+        P: Pixel<Subpixel = T> + Black + PartialEq,
+    {
+        return self.surface.pdf(p, o, i);
+    }
+    fn bias(&self) -> Option<T> {
+        return self.surface.bias();
+    }
+    fn opaque(&self, p: Point<T>) -> bool {
+        return self.surface.opaque(p);
+    }
+    fn indirect(&self) -> Option<&dyn Surface<T, P>> {
+        return self.surface.indirect();
+    }
+    fn interior(&self) -> Option<P> {
+        return self.surface.interior();
+    }
+    fn ior(&self) -> Option<T> {
+        return self.surface.ior();
+    }
+    fn priority(&self) -> u32 {
+        return self.priority;
+    }
 }
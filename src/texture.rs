@@ -0,0 +1,121 @@
+use image::{DynamicImage, ImageBuffer, Rgb};
+
+type Level = ImageBuffer<Rgb<f32>, Vec<f32>>;
+
+/// Mipmapped RGB image, sampled with trilinear filtering.
+///
+/// The image repeats outside of [0, 1]², v points up.
+pub struct Texture {
+    // Full size first, each level half the size of the previous one,
+    // down to 1×1, in [0, 1].
+    levels: Vec<Level>,
+}
+
+impl Texture {
+    /// Texture of data, like roughness, taking `image` as is.
+    pub fn new(image: DynamicImage) -> Texture {
+        return Texture::levels(image, |c| c);
+    }
+
+    /// Texture of colors, decoding the sRGB of `image`, so the mip levels
+    /// average (and `sample` returns) light rather than its encoding.
+    pub fn color(image: DynamicImage) -> Texture {
+        return Texture::levels(image, |c| {
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        });
+    }
+
+    // Builds the mip levels of `image`, with `decode` applied to each
+    // channel (in [0, 1]) first.
+    fn levels<D: Fn(f32) -> f32>(image: DynamicImage, decode: D) -> Texture {
+        let image = image.into_rgb8();
+        let full = Level::from_fn(image.width(), image.height(), |x, y| {
+            Rgb(image.get_pixel(x, y).0.map(|c| decode(c as f32 / 255.0)))
+        });
+
+        let mut levels = vec![full];
+
+        loop {
+            let prev = levels.last().unwrap();
+            let (w, h) = prev.dimensions();
+
+            if w <= 1 && h <= 1 {
+                break;
+            }
+
+            let next = Level::from_fn((w / 2).max(1), (h / 2).max(1), |x, y| {
+                // Average of the (up to) 2×2 texels this one covers.
+                let (x0, y0) = (2 * x, 2 * y);
+                let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+
+                let mut sum = [0.0; 3];
+                for (tx, ty) in [(x0, y0), (x1, y0), (x0, y1), (x1, y1)].iter() {
+                    for (s, c) in sum.iter_mut().zip(prev.get_pixel(*tx, *ty).0.iter()) {
+                        *s += *c;
+                    }
+                }
+
+                Rgb(sum.map(|s| s / 4.0))
+            });
+
+            levels.push(next);
+        }
+
+        return Texture { levels };
+    }
+
+    /// Color (in [0, 1]) at `uv`, filtered over a square of `footprint`
+    /// (in texture coordinates) by blending the two closest mip levels.
+    pub fn sample(&self, uv: [f64; 2], footprint: f64) -> [f64; 3] {
+        let (w, h) = self.levels[0].dimensions();
+
+        // Texels of the full size image the footprint covers.
+        let texels = footprint * w.max(h) as f64;
+        let level = if texels > 1.0 { texels.log2() } else { 0.0 };
+        let level = level.min((self.levels.len() - 1) as f64);
+
+        let lo = level.floor() as usize;
+        let hi = (lo + 1).min(self.levels.len() - 1);
+        let t = level - lo as f64;
+
+        let a = bilinear(&self.levels[lo], uv);
+        if t == 0.0 {
+            return a;
+        }
+
+        let b = bilinear(&self.levels[hi], uv);
+        return [0, 1, 2].map(|c| a[c] * (1.0 - t) + b[c] * t);
+    }
+}
+
+fn bilinear(image: &Level, uv: [f64; 2]) -> [f64; 3] {
+    let (w, h) = image.dimensions();
+
+    // Texel centers are at half integers.
+    let x = uv[0] * w as f64 - 0.5;
+    let y = (1.0 - uv[1]) * h as f64 - 0.5;
+
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+
+    let texel = |dx: i64, dy: i64| {
+        let tx = (x0 as i64 + dx).rem_euclid(w as i64) as u32;
+        let ty = (y0 as i64 + dy).rem_euclid(h as i64) as u32;
+        image.get_pixel(tx, ty).0
+    };
+
+    let (t00, t10, t01, t11) = (texel(0, 0), texel(1, 0), texel(0, 1), texel(1, 1));
+
+    let mut res = [0.0; 3];
+    for (c, r) in res.iter_mut().enumerate() {
+        let top = t00[c] as f64 * (1.0 - fx) + t10[c] as f64 * fx;
+        let bottom = t01[c] as f64 * (1.0 - fx) + t11[c] as f64 * fx;
+        *r = top * (1.0 - fy) + bottom * fy;
+    }
+
+    return res;
+}
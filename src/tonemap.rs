@@ -0,0 +1,69 @@
+use image::Rgb;
+
+/// How the light of a pixel (255 for white), scaled by the exposure, is
+/// brought into the 0 to 255 of an image. All but `Clip` roll off the
+/// highlights instead of blowing them out, at the price of a darker white:
+/// raise the exposure to make up for it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Tonemap {
+    /// As is, so anything brighter than white clips.
+    Clip,
+    /// Reinhard's `x / (1 + x)` of the luminance, keeping the hue.
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve, per channel: contrasty,
+    /// and washing out the brightest colors to white.
+    Aces,
+    /// `1 - e^-x` per channel, as of film exposed to the light.
+    Exponential,
+}
+
+// Light of white.
+const WHITE: f64 = 255.0;
+
+impl Tonemap {
+    pub fn from_name(name: &str) -> Option<Tonemap> {
+        return match name {
+            "clip" => Some(Tonemap::Clip),
+            "reinhard" => Some(Tonemap::Reinhard),
+            "aces" => Some(Tonemap::Aces),
+            "exponential" => Some(Tonemap::Exponential),
+            _ => None,
+        };
+    }
+
+    /// `rgb` exposed by `exposure` stops and mapped into 0 to 255.
+    pub fn apply(self, rgb: Rgb<f64>, exposure: f64) -> Rgb<f64> {
+        let scale = exposure.exp2();
+        let [r, g, b] = rgb.0.map(|c| c * scale);
+
+        // Of the curves, with white at 1.
+        let curve = |f: fn(f64) -> f64| [r, g, b].map(|c| f(c / WHITE) * WHITE);
+
+        let mapped = match self {
+            Tonemap::Clip => [r, g, b],
+            Tonemap::Reinhard => {
+                let lum = (0.2126 * r + 0.7152 * g + 0.0722 * b) / WHITE;
+                [r, g, b].map(|c| c / (1.0 + lum.max(0.0)))
+            }
+            Tonemap::Aces => curve(|x| x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14)),
+            Tonemap::Exponential => curve(|x| 1.0 - (-x).exp()),
+        };
+
+        return Rgb(mapped.map(|c| c.clamp(0.0, WHITE)));
+    }
+}
+
+/// sRGB encoding of the linear `c`, both in 0 to 255, as image viewers
+/// expect of 8-bit images: finer steps in the shadows, where the eye tells
+/// them apart.
+pub fn srgb(c: f64) -> f64 {
+    let x = c / WHITE;
+
+    let encoded = if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    };
+
+    return encoded * WHITE;
+}
@@ -0,0 +1,843 @@
+use image::Pixel;
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::fingerprint::Fingerprint;
+use crate::geom::{Hit, Poly, Ray, Sides};
+use crate::medium::{self, Fog, Stack};
+use crate::passes::{Lobe, Passes};
+use crate::phase;
+use crate::sampling::{self, Rng};
+use crate::surface::{self, Black, Surface};
+use crate::{bounds_medium, crossed, interior, opaque, outside, point_at, Scene};
+
+// Light arriving from one direction, as for `Light::incident`, with the
+// emitter it comes from (if any), the light groups and shadow intensity of
+// its source, and the shadow rays per steradian cast that way.
+type Incident<'a, T, C, S> = (Vector3<T>, T, C, Option<&'a Poly<T, S>>, (u32, T), T);
+
+// A hit to shade, see `Tracer::shaded`.
+type Shaded<'a, T, C> = (&'a dyn Surface<T, C>, surface::Point<T>, Passes<C>);
+
+/// Bounces after which paths end for certain, see `Tracer::limit`.
+pub const MAX_PATH_DEPTH: u32 = 64;
+
+/// What the integrators tracing rays from the camera (`Grid`, `Whitted`,
+/// `PathTracer`, `PhotonMapper` and `Bidir`) share: following rays through
+/// fog and media to the surfaces they hit, and the light of the lights and
+/// the sampled emitters there. Each shades the surfaces in its own way.
+pub trait Tracer<T: Float + Into<f64>> {
+    /// Bounces after which light is no longer followed, or for paths,
+    /// Russian roulette may end them.
+    fn max_depth(&self) -> u32;
+
+    /// Limits of the light from each bounce on, see `clamped`.
+    fn clamp(&self) -> &[T];
+
+    /// Light leaving `poly` at `hit` into the direction of `ray`.
+    /// `from` is the poly the ray was shot from, if any, `diffuse` and
+    /// `throughput` and `media` as for `trace`. `rng` picks the directions of
+    /// paths.
+    #[allow(clippy::too_many_arguments)]
+    fn shade<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Hit<T>,
+        poly: &'a Poly<T, S>,
+        from: Option<&'a Poly<T, S>>,
+        depth: u32,
+        diffuse: Option<T>,
+        throughput: C,
+        media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C>;
+
+    /// Bounces after which rays end: `max_depth`, but for paths, which
+    /// Russian roulette ends, only a safety net for lossless mirrors.
+    fn limit(&self) -> u32 {
+        return self.max_depth();
+    }
+
+    /// Weight of the light of the sampled emitters on surfaces, see
+    /// `emitted_at`.
+    fn sampled_scale(&self) -> T {
+        return T::one();
+    }
+
+    /// Adds `max_depth` and `clamp` to `h`, for `Integrator::fingerprint`.
+    fn write_limits(&self, h: &mut Fingerprint) {
+        h.write_u32(self.max_depth());
+        h.write_u32(self.clamp().len() as u32);
+        for max in self.clamp().iter() {
+            h.write_f64(*max);
+        }
+    }
+
+    /// Light arriving along the camera ray `ray`, which first hits `hit`
+    /// (the hit and the poly), if anything, as for `Integrator::light`.
+    fn primary<C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Option<(Hit<T>, &Poly<T, S>)>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let dist = match hit {
+            Some((hit, _)) => vecmath::vec3_len(vecmath::vec3_sub(hit.point, ray.orig)),
+            None => T::from_f64(f64::INFINITY),
+        };
+
+        let surface = |throughput: C, rng: &mut Rng| {
+            return match hit {
+                None => Passes::emitted(scene.background(ray), 0),
+                Some((hit, poly)) => self.hit(
+                    scene,
+                    ray,
+                    hit,
+                    poly,
+                    None,
+                    0,
+                    None,
+                    throughput,
+                    &Stack::default(),
+                    rng,
+                ),
+            };
+        };
+
+        let white = C::black().map(|_| T::one());
+        let media = Stack::default();
+        let light = self.through_fog(scene, ray, dist, 0, white, surface, &media, rng);
+
+        // Haze in front of the surface seen, which the environment's own
+        // radiance has in front of the sky already.
+        let aerial = match (hit, &scene.environment) {
+            (Some(_), Some(env)) => env.aerial(ray.orig, ray.dir, dist),
+            _ => None,
+        };
+
+        return match aerial {
+            Some((through, haze)) => {
+                let haze = Passes::emitted(haze, 1).tagged(Lobe::Diffuse);
+                light.zip(&haze, |x, y| {
+                    x.map2(&through, |a, b| a * b).map2(y, |a, b| a + b)
+                })
+            }
+            None => light,
+        };
+    }
+
+    /// Light arriving along `ray`, shot from `exclude` (if any). `diffuse`
+    /// rays sample `Surface::reflected` with the given density (see
+    /// `Surface::pdf`) and share the light of the environment and the
+    /// sampled emitters with light sampling, which takes all of it at 0.
+    /// `throughput` weights the light on its way to the camera. The ray runs
+    /// inside `media`.
+    #[allow(clippy::too_many_arguments)]
+    fn trace<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        exclude: Option<&'a Poly<T, S>>,
+        depth: u32,
+        diffuse: Option<T>,
+        throughput: C,
+        media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        if depth > self.limit() {
+            return Passes::black();
+        }
+
+        let hit = scene.shoot(ray, exclude, &opaque::<T, C, S>(ray));
+        let dist = match hit {
+            Some((hit, _)) => vecmath::vec3_len(vecmath::vec3_sub(hit.point, ray.orig)),
+            None => T::from_f64(f64::INFINITY),
+        };
+
+        let surface = |throughput: C, rng: &mut Rng| {
+            let light = match hit {
+                None => {
+                    let light = match diffuse {
+                        _ if exclude.is_some_and(|p| !scene.environment_lights(p)) => C::black(),
+                        Some(pdf) => scene.sampled_background(ray, pdf),
+                        None => scene.background(ray),
+                    };
+                    Passes::emitted(light, depth)
+                }
+                Some((hit, poly)) => self.hit(
+                    scene, ray, hit, poly, exclude, depth, diffuse, throughput, media, rng,
+                ),
+            };
+
+            return match exclude {
+                Some(from) if scene.shadow_links => {
+                    let leaked = Passes::emitted(self.leaked(scene, ray, from, diffuse), depth);
+                    light.zip(&leaked, |x, y| x.map2(y, |a, b| a + b))
+                }
+                _ => light,
+            };
+        };
+
+        return self.through_fog(scene, ray, dist, depth, throughput, surface, media, rng);
+    }
+
+    /// Light arriving along `ray` at `depth` from `dist` away, given by
+    /// `surface` for the throughput there, dimmed by the scene's fog, with
+    /// the light the fog scatters into the ray and gives off along it. The
+    /// ray runs inside `media`.
+    #[allow(clippy::too_many_arguments)]
+    fn through_fog<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        dist: T,
+        depth: u32,
+        throughput: C,
+        surface: impl FnOnce(C, &mut Rng) -> Passes<C>,
+        media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let fog = match &scene.fog {
+            Some(fog) => fog,
+            None => return surface(throughput, rng),
+        };
+
+        let light = self.lit_fog(
+            scene, fog, ray, dist, depth, throughput, surface, media, rng,
+        );
+
+        if fog.emission.is_none() {
+            return light;
+        }
+
+        let glow = fog.emitted_along(ray.orig, ray.dir, dist, rng);
+        let glow = Passes::emitted(glow, depth);
+        return light.zip(&glow, |x, y| x.map2(y, |a, b| a + b));
+    }
+
+    /// `through_fog` in `fog`, but for the light the fog gives off. Unless
+    /// overridden, the fog scatters the light of the lights and the sampled
+    /// emitters into the ray once.
+    #[allow(clippy::too_many_arguments)]
+    fn lit_fog<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        fog: &Fog<C>,
+        ray: &Ray<T>,
+        dist: T,
+        depth: u32,
+        throughput: C,
+        surface: impl FnOnce(C, &mut Rng) -> Passes<C>,
+        _media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let through = fog.transmittance_along(ray.orig, ray.dir, dist, rng);
+        let light = surface(throughput.map2(&through, |a, b| a * b), rng)
+            .map(|x| x.map2(&through, |a, b| a * b));
+
+        if depth >= self.limit() || fog.scattering == C::black() {
+            return light;
+        }
+
+        // Single scattering: the light of the lights and the sampled
+        // emitters, at one random point of the fog.
+        let (t, pdf) = match self.in_fog(scene, fog, ray, dist, rng) {
+            Some(pick) => pick,
+            None => return light,
+        };
+        let p = vecmath::vec3_add(ray.orig, vecmath::vec3_scale(ray.dir, t));
+
+        let incident = scene
+            .lights
+            .iter()
+            .flat_map(|l| {
+                let links = (l.light_groups(), l.shadow_intensity());
+                l.incident(p)
+                    .into_iter()
+                    .map(move |(dir, dist, irradiance)| (dir, dist, irradiance, None, links))
+            })
+            .chain(self.emitted_at(scene, ray, p, None).map(
+                |(dir, dist, irradiance, emitter, links, _)| {
+                    (dir, dist, irradiance, emitter, links)
+                },
+            ));
+
+        let mut sum = C::black();
+
+        for (dir, dist, irradiance, emitter, links) in incident {
+            let through = fog.transmittance_along(p, dir, dist, rng);
+
+            if through == C::black() {
+                continue;
+            }
+
+            let pass = self.visible(scene, p, dir, dist, emitter, links, ray.time);
+            let phase = phase::eval(&*fog.phase, ray.dir, dir) * pass;
+            sum = sum.map2(&irradiance.map2(&through, |a, b| a * b * phase), |a, b| {
+                a + b
+            });
+        }
+
+        let scale = fog.density_at(p) / pdf;
+        let back = fog.transmittance_along(ray.orig, ray.dir, t, rng);
+        let scattered = sum
+            .map2(&fog.scattering, |a, b| a * b * scale)
+            .map2(&back, |a, b| a * b);
+
+        let scattered = Passes::emitted(scattered, depth + 1).tagged(Lobe::Diffuse);
+        return light.zip(&scattered, |x, y| x.map2(y, |a, b| a + b));
+    }
+
+    /// Random point of the fog along the first `dist` of `ray` to scatter
+    /// light into it at, as its distance, with the density of the pick. It
+    /// is picked towards one of the points lights shine from at random, by
+    /// how the light from there falls off (equiangular sampling), so the
+    /// bright fog around lights and shafts of light are found. Light from
+    /// elsewhere is still found, if less often.
+    fn in_fog<C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &Scene<T, S, C>,
+        fog: &Fog<C>,
+        ray: &Ray<T>,
+        dist: T,
+        rng: &mut Rng,
+    ) -> Option<(T, T)> {
+        let centers: Vec<_> = scene
+            .lights
+            .iter()
+            .filter_map(|l| l.position())
+            .chain(scene.emitters.samples().iter().map(|(_, q, _)| *q))
+            .collect();
+
+        if centers.is_empty() {
+            return fog.pick(ray.orig, ray.dir, dist, rng);
+        }
+
+        let (from, to) = fog.range(ray.orig, ray.dir, dist)?;
+
+        let c = centers[(rng.next() % centers.len() as u64) as usize];
+        let u = T::from_f64(rng.unit());
+        let t = sampling::equiangular(ray.orig, ray.dir, c, from, to, u);
+
+        // Of picking it towards any of them.
+        let pdf = centers.iter().fold(T::zero(), |sum, c| {
+            sum + sampling::equiangular_density(ray.orig, ray.dir, *c, from, to, t)
+        }) / T::from_f64(centers.len() as f64);
+
+        return Some((t, pdf));
+    }
+
+    /// Fraction of the light from `dist` away in direction `dir` of the
+    /// point `p` in fog at `time` that arrives, as for `unblocked`.
+    #[allow(clippy::too_many_arguments)]
+    fn visible<C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &Scene<T, S, C>,
+        p: Vector3<T>,
+        dir: Vector3<T>,
+        dist: T,
+        emitter: Option<&Poly<T, S>>,
+        links: (u32, T),
+        time: T,
+    ) -> T {
+        let shadow = Ray {
+            orig: p,
+            dir,
+            width: T::zero(),
+            spread: T::zero(),
+            diffs: None,
+            time,
+        };
+
+        return self.unblocked(scene, &shadow, None, dist, emitter, Some(links));
+    }
+
+    /// Direct light of an emitter or the environment behind the closest hit
+    /// of `ray` (shot from `from`) that shadow linking or shadow intensity
+    /// lets through.
+    fn leaked<C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &Scene<T, S, C>,
+        ray: &Ray<T>,
+        from: &Poly<T, S>,
+        diffuse: Option<T>,
+    ) -> C {
+        let opaque = opaque::<T, C, S>(ray);
+        let hits: Vec<_> = scene
+            .hits(ray, Some(from))
+            .into_iter()
+            .filter(|(_, hit, p)| opaque(p, hit))
+            .collect();
+
+        for (i, (dist, hit, emitter)) in hits.iter().enumerate() {
+            let emitted = emitter.surface.emitted(point_at(ray, hit, emitter));
+
+            if emitted == C::black() {
+                continue;
+            }
+
+            if i == 0 || !from.lit_by(emitter.light_groups()) {
+                // Unoccluded emitters are taken care of by `shade`.
+                return C::black();
+            }
+
+            // Its shadow rays may already let some of the light through.
+            let share = match diffuse {
+                Some(pdf) => scene.emitter_share(ray, *dist, emitter, pdf),
+                None => T::one(),
+            };
+
+            let links = (emitter.light_groups(), emitter.shadow_intensity());
+            let pass = hits[..i]
+                .iter()
+                .fold(share, |t, (_, _, p)| t * p.transmittance(links));
+
+            return emitted.map(|x| x * pass);
+        }
+
+        // Past all of them the environment.
+        let env = match &scene.environment {
+            Some(env) if !hits.is_empty() && from.lit_by(env.light_groups()) => env,
+            _ => return C::black(),
+        };
+
+        let links = (env.light_groups(), env.shadow_intensity());
+        let pass = hits
+            .iter()
+            .fold(T::one(), |t, (_, _, p)| t * p.transmittance(links));
+
+        let background = match diffuse {
+            Some(pdf) => scene.sampled_background(ray, pdf),
+            None => scene.background(ray),
+        };
+
+        return background.map(|x| x * pass);
+    }
+
+    /// Light arriving along `ray` from its hit of `poly` `hit`,
+    /// dimmed by the medium it runs in, with the rest as for `trace`. Where
+    /// `poly` lies within an object of a higher priority, the path passes
+    /// it, else it is shaded.
+    #[allow(clippy::too_many_arguments)]
+    fn hit<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Hit<T>,
+        poly: &'a Poly<T, S>,
+        exclude: Option<&'a Poly<T, S>>,
+        depth: u32,
+        diffuse: Option<T>,
+        throughput: C,
+        media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let dist = vecmath::vec3_len(vecmath::vec3_sub(hit.point, ray.orig));
+
+        // Where the path isn't known to be in anything, the back of a poly
+        // is the inside of its object.
+        let through = match media.top() {
+            Some(p) => p.surface.interior().map(|a| medium::beer(&a, dist)),
+            None => interior(ray, hit.point, poly),
+        };
+        let throughput = match &through {
+            Some(t) => throughput.map2(t, |a, b| a * b),
+            None => throughput,
+        };
+
+        let light = if bounds_medium(poly) && media.skips(poly.surface.priority()) {
+            let bias = poly.surface.bias().unwrap_or_else(|| poly.bias());
+            let r = Ray {
+                width: ray.footprint(dist),
+                spread: ray.spread,
+                diffs: ray.bounced(dist, *poly.n(), false),
+                time: ray.time,
+                ..Ray::from_surface(hit.point, *poly.n(), ray.dir, bias)
+            };
+            let media = crossed(media, poly, ray.dir, true);
+            self.trace(
+                scene,
+                &r,
+                Some(poly),
+                depth,
+                diffuse,
+                throughput,
+                &media,
+                rng,
+            )
+        } else {
+            self.shade(
+                scene, ray, hit, poly, exclude, depth, diffuse, throughput, media, rng,
+            )
+        };
+
+        return match through {
+            Some(t) => light.map(|x| x.map2(&t, |a, b| a * b)),
+            None => light,
+        };
+    }
+
+    /// Where `shade` starts: the surface to shade `hit` of `poly` by `ray`
+    /// (shot from `from`) with, the point of it hit, and the light it gives
+    /// off, for `diffuse` and `media` as for `trace`. None where the back of
+    /// `poly` is black.
+    #[allow(clippy::too_many_arguments)]
+    fn shaded<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Hit<T>,
+        poly: &'a Poly<T, S>,
+        from: Option<&'a Poly<T, S>>,
+        depth: u32,
+        diffuse: Option<T>,
+        media: &Stack<'a, T, S>,
+    ) -> Option<Shaded<'a, T, C>> {
+        if poly.sides() == Sides::BackBlack && poly.is_back(ray.dir) {
+            return None;
+        }
+
+        // Secondary rays may use a cheaper stand-in.
+        let surface: &dyn Surface<T, C> = match poly.surface.indirect() {
+            Some(s) if depth > 0 => s,
+            _ => &poly.surface,
+        };
+
+        let at = surface::Point {
+            outside: outside(media, poly, ray.dir),
+            ..point_at(ray, &hit, poly)
+        };
+
+        let share = match diffuse {
+            Some(pdf) => scene.emitter_share(ray, at.dist, poly, pdf),
+            None => T::one(),
+        };
+
+        let emitted = match from {
+            Some(p) if !p.lit_by(poly.light_groups()) => Passes::black(),
+            _ if share == T::zero() => Passes::black(),
+            _ => Passes::emitted(surface.emitted(at).map(|x| x * share), depth),
+        };
+
+        return Some((surface, at, emitted));
+    }
+
+    /// `light` and the light of the lights without geometry, which
+    /// directions picked off `surface` can't find, and of the environment
+    /// and the sampled emitters, which they skip or share, reflected at the
+    /// point `at` of the hit of `poly` at `hit_point` into the direction of
+    /// `ray`. With `paths`, the light is weighted against the directions
+    /// `surface` samples finding it too.
+    #[allow(clippy::too_many_arguments)]
+    fn direct<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        light: Passes<C>,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit_point: Vector3<T>,
+        poly: &'a Poly<T, S>,
+        surface: &dyn Surface<T, C>,
+        at: surface::Point<T>,
+        depth: u32,
+        paths: bool,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let mut all_light = light;
+
+        let bias = surface.bias().unwrap_or_else(|| poly.bias());
+
+        let infinity = T::from_f64(f64::INFINITY);
+
+        // With the density of the shadow rays (only paths need it).
+        let incident = scene
+            .lights
+            .iter()
+            .filter(|l| depth < self.limit() && poly.lit_by(l.light_groups()))
+            .flat_map(|l| {
+                let links = (l.light_groups(), l.shadow_intensity());
+                l.incident(hit_point)
+                    .into_iter()
+                    .map(move |(dir, dist, irradiance)| {
+                        (dir, dist, irradiance, None, links, infinity)
+                    })
+            })
+            .chain(
+                scene
+                    .environment
+                    .iter()
+                    .filter(|e| depth < self.limit() && poly.lit_by(e.light_groups()))
+                    .flat_map(|e| {
+                        let links = (e.light_groups(), e.shadow_intensity());
+                        e.incident(hit_point)
+                            .into_iter()
+                            .map(move |(dir, dist, irradiance)| {
+                                let density = if paths {
+                                    e.density(hit_point, dir)
+                                } else {
+                                    infinity
+                                };
+                                (dir, dist, irradiance, None, links, density)
+                            })
+                    }),
+            )
+            .chain(
+                self.emitted_at(scene, ray, hit_point, Some(poly))
+                    .filter(|_| depth < self.limit()),
+            );
+
+        for (dir, dist, irradiance, emitter, links, density) in incident {
+            let refl = surface.reflected(at, dir, ray.dir);
+
+            if refl == C::black() {
+                continue;
+            }
+
+            let shadow = Ray {
+                time: ray.time,
+                ..Ray::from_surface(hit_point, *poly.n(), dir, bias)
+            };
+            let pass = self.unblocked(scene, &shadow, Some(poly), dist, emitter, Some(links));
+
+            if pass == T::zero() {
+                continue;
+            }
+
+            let irradiance = match &scene.fog {
+                Some(fog) => {
+                    let through = fog.transmittance_along(hit_point, dir, dist, rng);
+                    irradiance.map2(&through, |a, b| a * b)
+                }
+                None => irradiance,
+            };
+
+            // Paths may find the light too.
+            let share = if paths {
+                sampling::power_heuristic(density, surface.pdf(at, ray.dir, dir))
+            } else {
+                T::one()
+            };
+
+            // `reflected` is the BRDF scaled by pi.
+            let v = vecmath::vec3_dot(dir, *poly.n());
+            let lambert = if v < T::zero() { -v } else { v } * pass * share
+                / T::from_f64(std::f64::consts::PI);
+
+            let light = Passes::emitted(irradiance.map2(&refl, |a, b| a * b * lambert), depth + 1)
+                .tagged(Lobe::Diffuse);
+
+            all_light = all_light.zip(&light, |x, y| x.map2(y, |a, b| a + b));
+        }
+
+        return all_light;
+    }
+
+    /// `light` and the light `surface` reflects and transmits at the point
+    /// `at` of the hit of `poly` at `hit_point` into the direction of `ray`
+    /// off mirrors and lenses, see `Surface::specular`, with `throughput`
+    /// and `media` as for `trace`.
+    #[allow(clippy::too_many_arguments)]
+    fn specular<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        light: Passes<C>,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit_point: Vector3<T>,
+        poly: &'a Poly<T, S>,
+        surface: &dyn Surface<T, C>,
+        at: surface::Point<T>,
+        depth: u32,
+        throughput: C,
+        media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let mut all_light = light;
+
+        let bias = surface.bias().unwrap_or_else(|| poly.bias());
+
+        let footprint = ray.footprint(at.dist);
+
+        for (dir, weight) in surface.specular(at, ray.dir) {
+            let through = (vecmath::vec3_dot(dir, *poly.n()) > T::zero())
+                == (vecmath::vec3_dot(ray.dir, *poly.n()) > T::zero());
+
+            // The cone continues through mirrors and lenses as if they
+            // were flat.
+            let r = Ray {
+                width: footprint,
+                spread: ray.spread,
+                diffs: ray.bounced(at.dist, *poly.n(), !through),
+                time: ray.time,
+                ..Ray::from_surface(hit_point, *poly.n(), dir, bias)
+            };
+            let lobe = if through {
+                Lobe::Transmission
+            } else {
+                Lobe::Specular
+            };
+
+            let t = throughput.map2(&weight, |a, b| a * b);
+            let media = crossed(media, poly, ray.dir, through);
+            let light = self.trace(scene, &r, Some(poly), depth + 1, None, t, &media, rng);
+            let light = self
+                .clamped(light, &t, depth + 1)
+                .tagged(lobe)
+                .map(|x| x.map2(&weight, |a, b| a * b));
+
+            all_light = all_light.zip(&light, |x, y| x.map2(y, |a, b| a + b));
+        }
+
+        return all_light;
+    }
+
+    /// `light` arriving by a ray at `depth`, scaled down so that no channel
+    /// of it weighted by `throughput` (that is, arriving at the camera)
+    /// exceeds the limit of `clamp` for the depth: the first limit applies
+    /// to the light arriving at the first hit, the last to all deeper
+    /// bounces as well. Rare, very bright paths (fireflies, e.g. off one
+    /// mirror onto another into a small lamp) are cut down, trading a
+    /// little light for far less noise.
+    fn clamped<C: Pixel<Subpixel = T> + Black>(
+        &self,
+        light: Passes<C>,
+        throughput: &C,
+        depth: u32,
+    ) -> Passes<C> {
+        let clamp = self.clamp();
+        let max = match clamp.get(depth as usize - 1).or(clamp.last()) {
+            Some(max) => *max,
+            None => return light,
+        };
+
+        let peak = light
+            .beauty
+            .map2(throughput, |a, b| a * b)
+            .channels()
+            .iter()
+            .fold(T::zero(), |m, x| m.max(*x));
+
+        if peak <= max {
+            return light;
+        }
+
+        let scale = max / peak;
+        return light.map(|x| x.map(|v| v * scale));
+    }
+
+    /// Light of the sampled emitters arriving at `hit_point` on `poly` (or
+    /// in fog), hit by `ray`, as for `Light::incident`, with the emitter it
+    /// comes from and the shadow rays per steradian towards it.
+    fn emitted_at<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &'a self,
+        scene: &'a Scene<T, S, C>,
+        ray: &'a Ray<T>,
+        hit_point: Vector3<T>,
+        poly: Option<&'a Poly<T, S>>,
+    ) -> impl 'a + Iterator<Item = Incident<'a, T, C, S>> {
+        // In fog light is weighted properly.
+        let scale = if poly.is_none() {
+            T::one()
+        } else {
+            self.sampled_scale()
+        };
+
+        return scene
+            .emitters
+            .samples()
+            .iter()
+            .filter_map(move |(i, q, area)| {
+                let emitter = &scene.polys[*i];
+
+                if poly
+                    .is_some_and(|p| std::ptr::eq(emitter, p) || !p.lit_by(emitter.light_groups()))
+                {
+                    return None;
+                }
+
+                let d = vecmath::vec3_sub(*q, hit_point);
+                let dist2 = vecmath::vec3_dot(d, d);
+
+                if dist2 == T::zero() {
+                    return None;
+                }
+
+                let dist = dist2.sqrt();
+                let dir = vecmath::vec3_scale(d, T::one() / dist);
+
+                if emitter.sides() != Sides::Double && emitter.is_back(dir) {
+                    return None;
+                }
+
+                let to_emitter = Ray {
+                    orig: hit_point,
+                    dir,
+                    width: ray.footprint(vecmath::vec3_len(vecmath::vec3_sub(hit_point, ray.orig))),
+                    spread: ray.spread,
+                    diffs: None,
+                    time: ray.time,
+                };
+                let emitted =
+                    emitter
+                        .surface
+                        .emitted(point_at(&to_emitter, &emitter.hit_at(*q), emitter));
+
+                // Solid angle of the area around `q`.
+                let cos = vecmath::vec3_dot(dir, *emitter.n());
+                let cos = if cos < T::zero() { -cos } else { cos };
+                let w = *area * cos / dist2 * scale;
+
+                let density = scene.emitters.density(*i) * dist2 / cos;
+
+                let links = (emitter.light_groups(), emitter.shadow_intensity());
+                Some((
+                    dir,
+                    dist,
+                    emitted.map(|x| x * w),
+                    Some(emitter),
+                    links,
+                    density,
+                ))
+            });
+    }
+
+    /// Fraction of the light from `dist` along `shadow`, shot from `from`
+    /// (if a poly), that arrives. It passes the `emitter` it comes from, and
+    /// occluders let through what shadow linking or shadow intensity says
+    /// for the `links` of its source (light groups and shadow intensity).
+    /// Without those it is just blocked.
+    #[allow(clippy::too_many_arguments)]
+    fn unblocked<C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &Scene<T, S, C>,
+        shadow: &Ray<T>,
+        from: Option<&Poly<T, S>>,
+        dist: T,
+        emitter: Option<&Poly<T, S>>,
+        links: Option<(u32, T)>,
+    ) -> T {
+        let opaque = opaque::<T, C, S>(shadow);
+
+        if let (true, Some(links)) = (scene.shadow_links, links) {
+            return scene
+                .hits(shadow, from)
+                .into_iter()
+                .take_while(|(d, _, _)| *d < dist)
+                .filter(|(_, hit, p)| {
+                    !emitter.is_some_and(|e| std::ptr::eq(*p, e)) && opaque(p, hit)
+                })
+                .fold(T::one(), |t, (_, _, p)| t * p.transmittance(links));
+        }
+
+        return match scene.shoot(shadow, from, &opaque) {
+            Some((_, p)) if emitter.is_some_and(|e| std::ptr::eq(p, e)) => T::one(),
+            Some((hit, _))
+                if vecmath::vec3_len(vecmath::vec3_sub(hit.point, shadow.orig)) < dist =>
+            {
+                T::zero()
+            }
+            _ => T::one(),
+        };
+    }
+}
@@ -0,0 +1,391 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind};
+use std::path::Path;
+
+use vecmath::traits::Float;
+use vecmath::Vector3;
+
+use crate::fingerprint::Fingerprint;
+use crate::noise;
+
+/// Densities of a medium varying over space (e.g. smoke or a cloud), 0
+/// outside a box.
+pub trait Density: Send + Sync {
+    /// Density at `p`, never negative.
+    fn at(&self, p: Vector3<f64>) -> f64;
+
+    /// Highest density anywhere, or more.
+    fn peak(&self) -> f64;
+
+    /// Corners of the box, min and max.
+    fn bounds(&self) -> [Vector3<f64>; 2];
+
+    fn fingerprint(&self, h: &mut Fingerprint);
+}
+
+/// Values (e.g. densities of smoke or a cloud) on a regular grid of points
+/// spanning the box from `min` to `max`, blended trilinearly in between
+/// and 0 outside.
+pub struct Grid {
+    min: Vector3<f64>,
+    max: Vector3<f64>,
+    size: [usize; 3],
+    values: Vec<f64>, // by z, then y, then x
+    peak: f64,
+}
+
+impl Grid {
+    /// Grid of `size` points per axis, at least 2 each, with `values` for
+    /// all of them (x varying fastest), none negative.
+    pub fn new(min: Vector3<f64>, max: Vector3<f64>, size: [usize; 3], values: Vec<f64>) -> Grid {
+        assert!(size.iter().all(|n| *n >= 2));
+        assert_eq!(values.len(), size[0] * size[1] * size[2]);
+
+        let peak = values.iter().fold(0.0, |m: f64, x| m.max(*x));
+
+        return Grid {
+            min,
+            max,
+            size,
+            values,
+            peak,
+        };
+    }
+
+    /// Value at `p`.
+    pub fn density<T: Float + Into<f64>>(&self, p: Vector3<T>) -> T {
+        let mut cell = [0; 3];
+        let mut frac = [0.0; 3];
+
+        for k in 0..3 {
+            let x = (p[k].into() - self.min[k]) / (self.max[k] - self.min[k]);
+
+            if !(0.0..=1.0).contains(&x) {
+                return T::zero();
+            }
+
+            let x = x * (self.size[k] - 1) as f64;
+            cell[k] = (x as usize).min(self.size[k] - 2);
+            frac[k] = x - cell[k] as f64;
+        }
+
+        let at = |i: usize, j: usize, k: usize| {
+            let [x, y, z] = [cell[0] + i, cell[1] + j, cell[2] + k];
+            self.values[(z * self.size[1] + y) * self.size[0] + x]
+        };
+        let lerp = |a: f64, b: f64, t: f64| a * (1.0 - t) + b * t;
+
+        let plane = |k: usize| {
+            lerp(
+                lerp(at(0, 0, k), at(1, 0, k), frac[0]),
+                lerp(at(0, 1, k), at(1, 1, k), frac[0]),
+                frac[1],
+            )
+        };
+
+        return T::from_f64(lerp(plane(0), plane(1), frac[2]));
+    }
+
+    /// Highest value anywhere.
+    pub fn peak(&self) -> f64 {
+        return self.peak;
+    }
+
+    pub fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_vec3(self.min);
+        h.write_vec3(self.max);
+        for n in self.size.iter() {
+            h.write_u64(*n as u64);
+        }
+        for v in self.values.iter() {
+            h.write_f64(*v);
+        }
+    }
+
+    /// Part of the first `dist` of the ray from `orig` along `dir` inside
+    /// the box of the grid, as distances, `None` if it misses.
+    pub fn span<T: Float + Into<f64>>(
+        &self,
+        orig: Vector3<T>,
+        dir: Vector3<T>,
+        dist: T,
+    ) -> Option<(T, T)> {
+        return span([self.min, self.max], orig, dir, dist);
+    }
+}
+
+impl Density for Grid {
+    fn at(&self, p: Vector3<f64>) -> f64 {
+        return self.density(p);
+    }
+
+    fn peak(&self) -> f64 {
+        return self.peak;
+    }
+
+    fn bounds(&self) -> [Vector3<f64>; 2] {
+        return [self.min, self.max];
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        Grid::fingerprint(self, h);
+    }
+}
+
+/// Noise for `Noise` densities.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Pattern {
+    /// `noise::fbm`: soft, billowing banks.
+    Fbm,
+    /// `noise::worley` inverted: round puffs, like cumulus clouds.
+    Worley,
+}
+
+impl Pattern {
+    pub fn from_name(name: &str) -> Option<Pattern> {
+        return match name {
+            "fbm" => Some(Pattern::Fbm),
+            "worley" => Some(Pattern::Worley),
+            _ => None,
+        };
+    }
+}
+
+/// Densities made up in code, from noise in the box from `min` to `max`
+/// (e.g. clouds or fog banks): how far `pattern` at `frequency`, with
+/// `octaves` layers and taken to [0, 1], exceeds `threshold`. The higher
+/// `threshold`, the sparser and more broken up, and the thinner.
+pub struct Noise {
+    min: Vector3<f64>,
+    max: Vector3<f64>,
+    pattern: Pattern,
+    frequency: f64,
+    octaves: u32,
+    threshold: f64,
+}
+
+impl Noise {
+    pub fn new(
+        min: Vector3<f64>,
+        max: Vector3<f64>,
+        pattern: Pattern,
+        frequency: f64,
+        octaves: u32,
+        threshold: f64,
+    ) -> Noise {
+        return Noise {
+            min,
+            max,
+            pattern,
+            frequency,
+            octaves,
+            threshold,
+        };
+    }
+}
+
+impl Density for Noise {
+    fn at(&self, p: Vector3<f64>) -> f64 {
+        if (0..3).any(|k| p[k] < self.min[k] || p[k] > self.max[k]) {
+            return 0.0;
+        }
+
+        let q = p.map(|x| x * self.frequency);
+        let n = match self.pattern {
+            Pattern::Fbm => 0.5 + 0.5 * noise::fbm(q, self.octaves),
+            Pattern::Worley => 1.0 - noise::worley(q, self.octaves),
+        };
+
+        return (n.clamp(0.0, 1.0) - self.threshold).max(0.0);
+    }
+
+    fn peak(&self) -> f64 {
+        return (1.0 - self.threshold).max(0.0);
+    }
+
+    fn bounds(&self) -> [Vector3<f64>; 2] {
+        return [self.min, self.max];
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_vec3(self.min);
+        h.write_vec3(self.max);
+        h.write_u32(self.pattern as u32);
+        h.write_f64(self.frequency);
+        h.write_u32(self.octaves);
+        h.write_f64(self.threshold);
+    }
+}
+
+/// Part of the first `dist` of the ray from `orig` along `dir` inside the
+/// box with corners `min` and `max`, as distances, `None` if it misses.
+pub fn span<T: Float + Into<f64>>(
+    [min, max]: [Vector3<f64>; 2],
+    orig: Vector3<T>,
+    dir: Vector3<T>,
+    dist: T,
+) -> Option<(T, T)> {
+    let mut near = 0.0;
+    let mut far = dist.into();
+
+    for k in 0..3 {
+        let (o, d) = (orig[k].into(), dir[k].into());
+
+        if d == 0.0 {
+            if o < min[k] || o > max[k] {
+                return None;
+            }
+            continue;
+        }
+
+        let a = (min[k] - o) / d;
+        let b = (max[k] - o) / d;
+        near = a.min(b).max(near);
+        far = a.max(b).min(far);
+    }
+
+    if near >= far {
+        return None;
+    }
+
+    return Some((T::from_f64(near), T::from_f64(far)));
+}
+
+/// What a text file of a volume holds: the density grid, with, if it
+/// glows, the light it emits or its temperature, each times its scale.
+pub struct Volume {
+    pub density: Grid,
+    pub emission: Option<(Grid, f64)>,
+    pub temperature: Option<(Grid, f64)>, // in K
+}
+
+/// Loads a volume from a text file, see `load`.
+pub fn load_file<P: AsRef<Path>>(path: P) -> io::Result<Volume> {
+    return load(BufReader::new(File::open(path)?));
+}
+
+/// Loads a volume from a text stream: a `bounds` line with the corners of
+/// its box (min x, y, z, then max x, y, z), a `size` line with the points
+/// per axis and then the densities of all points, separated by whitespace,
+/// x varying fastest, then y. An `emission` or a `temperature` line, with
+/// an optional scale, starts the same for the light emitted per unit of
+/// distance (gray) or the temperature (in K) of a glowing volume; a
+/// `density` line goes back to the densities. Lines starting with `#` are
+/// comments.
+pub fn load<R: BufRead>(reader: R) -> io::Result<Volume> {
+    let mut bounds = None;
+    let mut size = None;
+
+    // Values by section, and the scales of all but the densities.
+    let mut values = [Vec::new(), Vec::new(), Vec::new()];
+    let mut scales = [None, None];
+    let mut section = 0;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let err =
+            |msg: &str| Error::new(ErrorKind::InvalidData, format!("line {}: {}", i + 1, msg));
+
+        let mut words = line.split_whitespace().peekable();
+
+        match words.peek() {
+            None => continue,
+            Some(w) if w.starts_with('#') => continue,
+            Some(&"bounds") => {
+                words.next();
+                let b: Vec<f64> = words
+                    .map(|w| w.parse().ok().filter(|x: &f64| x.is_finite()))
+                    .collect::<Option<_>>()
+                    .filter(|b: &Vec<f64>| b.len() == 6)
+                    .ok_or_else(|| err("bounds need six coordinates"))?;
+
+                if (0..3).any(|k| b[k] >= b[k + 3]) {
+                    return Err(err("empty bounds"));
+                }
+
+                bounds = Some(([b[0], b[1], b[2]], [b[3], b[4], b[5]]));
+            }
+            Some(&"size") => {
+                words.next();
+                let s: Vec<usize> = words
+                    .map(|w| w.parse().ok().filter(|n| *n >= 2))
+                    .collect::<Option<_>>()
+                    .filter(|s: &Vec<usize>| s.len() == 3)
+                    .ok_or_else(|| err("size needs three counts of at least 2"))?;
+
+                size = Some([s[0], s[1], s[2]]);
+            }
+            Some(&"density") => {
+                if words.nth(1).is_some() {
+                    return Err(err("densities take no scale"));
+                }
+                section = 0;
+            }
+            Some(name @ &"emission") | Some(name @ &"temperature") => {
+                section = if *name == "emission" { 1 } else { 2 };
+                words.next();
+
+                let scale: Vec<f64> = words
+                    .map(|w| w.parse().ok().filter(|x: &f64| *x >= 0.0 && x.is_finite()))
+                    .collect::<Option<_>>()
+                    .filter(|s: &Vec<f64>| s.len() <= 1)
+                    .ok_or_else(|| err("invalid scale"))?;
+
+                scales[section - 1] = Some(scale.first().copied().unwrap_or(1.0));
+            }
+            Some(_) => {
+                for w in words {
+                    let v: f64 = w
+                        .parse()
+                        .ok()
+                        .filter(|v: &f64| *v >= 0.0 && v.is_finite())
+                        .ok_or_else(|| err("invalid value"))?;
+                    values[section].push(v);
+                }
+            }
+        }
+    }
+
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+
+    let (min, max) = bounds.ok_or_else(|| invalid("missing bounds"))?;
+    let size = size.ok_or_else(|| invalid("missing size"))?;
+    let count = size[0]
+        .checked_mul(size[1])
+        .and_then(|n| n.checked_mul(size[2]))
+        .ok_or_else(|| invalid("too many points"))?;
+
+    let [densities, emission, temperature] = values;
+    let grid = |name: &str, values: Vec<f64>| {
+        if values.len() != count {
+            return Err(invalid(&format!(
+                "{} {} for {} points",
+                values.len(),
+                name,
+                count
+            )));
+        }
+
+        return Ok(Grid::new(min, max, size, values));
+    };
+
+    let density = grid("densities", densities)?;
+    let emission = match scales[0] {
+        Some(scale) => Some((grid("emission values", emission)?, scale)),
+        None => None,
+    };
+    let temperature = match scales[1] {
+        Some(scale) => Some((grid("temperatures", temperature)?, scale)),
+        None => None,
+    };
+
+    if emission.is_some() && temperature.is_some() {
+        return Err(invalid("both emission and temperature"));
+    }
+
+    return Ok(Volume {
+        density,
+        emission,
+        temperature,
+    });
+}
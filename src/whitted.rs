@@ -0,0 +1,98 @@
+use image::Pixel;
+
+use vecmath::traits::Float;
+
+use crate::fingerprint::Fingerprint;
+use crate::geom::{Hit, Poly, Ray};
+use crate::integrator::Integrator;
+use crate::medium::Stack;
+use crate::passes::Passes;
+use crate::sampling::Rng;
+use crate::surface::{Black, Surface};
+use crate::tracer::Tracer;
+use crate::Scene;
+
+/// Whitted style ray tracer: shadow rays and mirrors and lenses as with
+/// `Grid`, but no light bounced off other surfaces. Renders in a fraction
+/// of the time, with hard shadows and the same light every run, for
+/// previews while setting up a scene.
+pub struct Whitted<T> {
+    max_depth: u32,
+    // See `Tracer::clamped`.
+    clamp: Vec<T>,
+}
+
+impl<T: Float + Into<f64>> Whitted<T> {
+    pub fn new(max_depth: u32) -> Whitted<T> {
+        return Whitted {
+            max_depth,
+            clamp: Vec::new(),
+        };
+    }
+
+    /// Limits the light rays bring to the camera from each bounce on, see
+    /// `Tracer::clamped`.
+    pub fn with_clamp(mut self, clamp: Vec<T>) -> Whitted<T> {
+        self.clamp = clamp;
+        return self;
+    }
+}
+
+impl<T: Float + Into<f64>> Tracer<T> for Whitted<T> {
+    fn max_depth(&self) -> u32 {
+        return self.max_depth;
+    }
+
+    fn clamp(&self) -> &[T] {
+        return &self.clamp;
+    }
+
+    fn shade<'a, C: Pixel<Subpixel = T> + Black + PartialEq, S: Surface<T, C>>(
+        &self,
+        scene: &'a Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Hit<T>,
+        poly: &'a Poly<T, S>,
+        from: Option<&'a Poly<T, S>>,
+        depth: u32,
+        diffuse: Option<T>,
+        throughput: C,
+        media: &Stack<'a, T, S>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        let (surface, at, emitted) =
+            match self.shaded(scene, ray, hit, poly, from, depth, diffuse, media) {
+                Some(s) => s,
+                None => return Passes::black(),
+            };
+
+        let light = self.direct(
+            emitted, scene, ray, hit.point, poly, surface, at, depth, false, rng,
+        );
+        return self.specular(
+            light, scene, ray, hit.point, poly, surface, at, depth, throughput, media, rng,
+        );
+    }
+}
+
+impl<T, S, C> Integrator<T, S, C> for Whitted<T>
+where
+    T: Float + Into<f64> + Sync,
+    S: Surface<T, C>,
+    C: Pixel<Subpixel = T> + Black + PartialEq,
+{
+    fn light(
+        &self,
+        scene: &Scene<T, S, C>,
+        ray: &Ray<T>,
+        hit: Option<(Hit<T>, &Poly<T, S>)>,
+        rng: &mut Rng,
+    ) -> Passes<C> {
+        return self.primary(scene, ray, hit, rng);
+    }
+
+    fn fingerprint(&self, h: &mut Fingerprint) {
+        h.write_bytes(b"whitted");
+        self.write_limits(h);
+    }
+}